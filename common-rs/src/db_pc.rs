@@ -1,13 +1,16 @@
 use crate::common_db::{
     ensure_db_version, get_db_update_versions_from_args, set_current_db_version,
 };
-use crate::dto_pc::{Block, MinerSnapshot, PayRequest, Payment, Work};
+use crate::dto_pc::{
+    Block, CashuToken, MinerSnapshot, NostrDmPref, NostrPayoutPref, NostrProfileCache, PayRequest,
+    Payment, UserSetting, Work,
+};
 
-use rusqlite::{Connection, Params, Row, Transaction};
+use rusqlite::{Connection, Params, Row, Transaction, params};
 use std::error::Error;
 
 static BLOCKS_WINDOW: u8 = 8;
-pub static LATEST_DB_VERSION: u8 = 4;
+pub static LATEST_DB_VERSION: u8 = 18;
 
 // Upgrade from an older version, versions taken from args
 pub fn db_setup(conn: &Connection) -> Result<(), Box<dyn Error>> {
@@ -15,6 +18,11 @@ pub fn db_setup(conn: &Connection) -> Result<(), Box<dyn Error>> {
     db_setup_from_to(conn, Some(vfrom), Some(vto))
 }
 
+// Create a new DB at the latest version (e.g. an in-memory DB for tests)
+pub fn db_setup_new(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    db_setup_from_to(conn, Some(0), None)
+}
+
 // Upgrade from an older version, versions have default values
 fn db_setup_from_to(
     conn: &Connection,
@@ -27,10 +35,55 @@ fn db_setup_from_to(
 
     if vfrom <= 0 && vto >= 3 {
         db_update_0_3(conn)?;
+        // db_update_0_3 leaves the version at 2 (kept as shipped), though it creates the v3 schema;
+        // without this a new DB could not be updated further (db_update_3_4 expects v3)
+        set_current_db_version(conn, 3)?;
     }
     if vfrom <= 3 && vto >= 4 {
         db_update_3_4(conn)?;
     }
+    if vfrom <= 4 && vto >= 5 {
+        db_update_4_5(conn)?;
+    }
+    if vfrom <= 5 && vto >= 6 {
+        db_update_5_6(conn)?;
+    }
+    if vfrom <= 6 && vto >= 7 {
+        db_update_6_7(conn)?;
+    }
+    if vfrom <= 7 && vto >= 8 {
+        db_update_7_8(conn)?;
+    }
+    if vfrom <= 8 && vto >= 9 {
+        db_update_8_9(conn)?;
+    }
+    if vfrom <= 9 && vto >= 10 {
+        db_update_9_10(conn)?;
+    }
+    if vfrom <= 10 && vto >= 11 {
+        db_update_10_11(conn)?;
+    }
+    if vfrom <= 11 && vto >= 12 {
+        db_update_11_12(conn)?;
+    }
+    if vfrom <= 12 && vto >= 13 {
+        db_update_12_13(conn)?;
+    }
+    if vfrom <= 13 && vto >= 14 {
+        db_update_13_14(conn)?;
+    }
+    if vfrom <= 14 && vto >= 15 {
+        db_update_14_15(conn)?;
+    }
+    if vfrom <= 15 && vto >= 16 {
+        db_update_15_16(conn)?;
+    }
+    if vfrom <= 16 && vto >= 17 {
+        db_update_16_17(conn)?;
+    }
+    if vfrom <= 17 && vto >= 18 {
+        db_update_17_18(conn)?;
+    }
 
     Ok(())
 }
@@ -231,6 +284,301 @@ fn db_update_3_4(conn: &Connection) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn db_update_4_5(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 4)?;
+
+    // Create table NOSTR_PROFILE_CACHE, last known Lightning info from Nostr profiles
+    // Lud16 -- Lightning Address from the profile, may be empty
+    // Lud06 -- LNURL from the profile, may be empty
+    // CreatedAt -- created_at of the profile event
+    // FetchedAt -- Time of the last successful relay lookup
+    // SourceRelay -- Relay where the profile was obtained from
+    let _ = conn.execute(
+        "CREATE TABLE NOSTR_PROFILE_CACHE ( \
+            Npub VARCHAR(100) PRIMARY KEY, \
+            Lud16 VARCHAR(200), \
+            Lud06 VARCHAR(1000), \
+            CreatedAt INTEGER, \
+            FetchedAt INTEGER, \
+            SourceRelay VARCHAR(200))",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 5)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_5_6(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 5)?;
+
+    // MaxFee: Fee cap used for the (last) payment attempt, Msat. 0 if not applicable
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD MaxFee INTEGER", [])?;
+    let _ = conn.execute("UPDATE PAYMENT SET MaxFee = 0", [])?;
+
+    let _ = set_current_db_version(conn, 6)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_6_7(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 6)?;
+
+    // ZapRcptStatus: Zap receipt (kind 9735) status -- 0 NotApplicable 1 Pending 2 Confirmed 3 Missing
+    // ZapRcptId: Event ID of the zap receipt, if confirmed
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD ZapRcptStatus INTEGER", [])?;
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD ZapRcptId VARCHAR(100)", [])?;
+    let _ = conn.execute("UPDATE PAYMENT SET ZapRcptStatus = 0, ZapRcptId = ''", [])?;
+
+    let _ = set_current_db_version(conn, 7)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_7_8(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 7)?;
+
+    // Create table NOSTR_DM_PREF, Nostr DM notification preferences and state, per miner
+    // MinerId -- the base miner username id, as in PAYREQ
+    // OptOut -- 1 if the miner does not want DM notifications
+    // LastSummaryTime -- Time of the last daily summary DM sent
+    let _ = conn.execute(
+        "CREATE TABLE NOSTR_DM_PREF ( \
+            MinerId INTEGER PRIMARY KEY, \
+            OptOut INTEGER, \
+            LastSummaryTime INTEGER)",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 8)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_8_9(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 8)?;
+
+    // PayHash: Payment hash of the invoice being paid (hex), stored before paying
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD PayHash VARCHAR(100)", [])?;
+    let _ = conn.execute("UPDATE PAYMENT SET PayHash = ''", [])?;
+    // A pay request can have at most one successful payment
+    let _ = conn.execute(
+        "CREATE UNIQUE INDEX PaymentReqIdSuccess ON PAYMENT (ReqId) WHERE Status = 2",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 9)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_9_10(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 9)?;
+
+    // NextRetryTime: Time of the next attempt after a nonfinal failure, see the retry policy
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD NextRetryTime INTEGER", [])?;
+    // Previously a fixed retry delay of 600 secs after the failure
+    let _ = conn.execute(
+        "UPDATE PAYMENT SET NextRetryTime = CASE WHEN Status = 3 THEN FailTime + 600 ELSE 0 END",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 10)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_10_11(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 10)?;
+
+    // FallbackMethods: Payment methods to fall back to, in order, comma-separated
+    let _ = conn.execute("ALTER TABLE PAYREQ ADD FallbackMethods VARCHAR(100)", [])?;
+    let _ = conn.execute("UPDATE PAYREQ SET FallbackMethods = ''", [])?;
+    // PayMethod: Payment method of the attempt, a pay request may have several attempts
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD PayMethod VARCHAR(10)", [])?;
+    let _ = conn.execute(
+        "UPDATE PAYMENT SET PayMethod = (SELECT PAYREQ.PayMethod FROM PAYREQ WHERE PAYREQ.Id = PAYMENT.ReqId)",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 11)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_11_12(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 11)?;
+
+    // ZapPubkey: nostrPubkey of the LNURL server of a zap (hex), the signer of its zap receipt
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD ZapPubkey VARCHAR(100)", [])?;
+    let _ = conn.execute("UPDATE PAYMENT SET ZapPubkey = ''", [])?;
+
+    let _ = set_current_db_version(conn, 12)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_12_13(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 12)?;
+
+    // SuccessAction: LNURL success action returned by the receiving wallet, decoded (LUD-09/10)
+    let _ = conn.execute("ALTER TABLE PAYMENT ADD SuccessAction TEXT", [])?;
+    let _ = conn.execute("UPDATE PAYMENT SET SuccessAction = ''", [])?;
+
+    let _ = set_current_db_version(conn, 13)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_13_14(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 13)?;
+
+    // Create table NOSTR_PAYOUT_PREF, payout preferences published by miners (NIP-78 kind 30078)
+    // PayMethod -- Preferred payment method, empty if none
+    // ThresholdMsat -- Preferred payout threshold, 0 if none
+    // LnAddress -- Alternate Lightning Address, empty if none
+    // EventId -- ID of the preferences event, empty if none was found
+    // CreatedAt -- created_at of the preferences event
+    // FetchedAt -- Time of the last relay lookup
+    let _ = conn.execute(
+        "CREATE TABLE NOSTR_PAYOUT_PREF ( \
+            Npub VARCHAR(100) PRIMARY KEY, \
+            PayMethod VARCHAR(10), \
+            ThresholdMsat INTEGER, \
+            LnAddress VARCHAR(200), \
+            EventId VARCHAR(100), \
+            CreatedAt INTEGER, \
+            FetchedAt INTEGER)",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 14)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_14_15(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 14)?;
+
+    // Create table USER_SETTINGS, per-user payout settings set by the operator
+    // (replaces the USER_METHOD_SETTING_OVERRIDE env setting)
+    // UserId -- the base miner username id, as in PAYREQ
+    // PayMethod -- Payment method to use, empty if not set
+    // ThresholdMsat -- Payout threshold, 0 if not set
+    // MaxPayoutMsat -- Maximum payout amount, 0 if not set
+    // Paused -- 1 if payouts to the user are paused
+    // Notes -- Operator notes
+    let _ = conn.execute(
+        "CREATE TABLE USER_SETTINGS ( \
+            UserId INTEGER PRIMARY KEY, \
+            PayMethod VARCHAR(10), \
+            ThresholdMsat INTEGER, \
+            MaxPayoutMsat INTEGER, \
+            Paused INTEGER, \
+            Notes VARCHAR(500), \
+            FOREIGN KEY (UserId) REFERENCES USERLOOKUP(Id))",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 15)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_15_16(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 15)?;
+
+    // Create table CASHU_TOKEN, Cashu tokens minted for payouts, kept for audit
+    // ReqId -- the pay request paid with the token
+    // MintUrl -- URL of the mint
+    // QuoteId -- ID of the mint quote (NUT-04)
+    // AmountSat -- Amount of the token, sats
+    // Token -- The serialized token (cashuA..)
+    // CreatedAt -- Time of minting
+    // DeliveredAt -- Time the token was sent to the miner, 0 if not yet
+    let _ = conn.execute(
+        "CREATE TABLE CASHU_TOKEN ( \
+            Id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            ReqId INTEGER, \
+            MintUrl VARCHAR(200), \
+            QuoteId VARCHAR(100), \
+            AmountSat INTEGER, \
+            Token TEXT, \
+            CreatedAt INTEGER, \
+            DeliveredAt INTEGER, \
+            FOREIGN KEY (ReqId) REFERENCES PAYREQ(Id))",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 16)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_16_17(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 16)?;
+
+    // Bolt12Offer: BOLT12 offer (lno1..) from the profile, may be empty
+    let _ = conn.execute(
+        "ALTER TABLE NOSTR_PROFILE_CACHE ADD Bolt12Offer VARCHAR(1000)",
+        [],
+    )?;
+    let _ = conn.execute("UPDATE NOSTR_PROFILE_CACHE SET Bolt12Offer = ''", [])?;
+
+    let _ = set_current_db_version(conn, 17)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
+fn db_update_17_18(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let _ = ensure_db_version(conn, 17)?;
+
+    // CASHU_TOKEN:
+    // Premint -- Blinded outputs of the mint request with their secrets (JSON), stored before
+    //   the request, to restore the token if the response is lost (NUT-09). Empty once minted.
+    // ProofYs -- Y values of the proofs (hex, comma separated), for audit
+    let _ = conn.execute("ALTER TABLE CASHU_TOKEN ADD Premint TEXT", [])?;
+    let _ = conn.execute("ALTER TABLE CASHU_TOKEN ADD ProofYs TEXT", [])?;
+    let _ = conn.execute("UPDATE CASHU_TOKEN SET Premint = '', ProofYs = ''", [])?;
+    // Tokens are bearer instruments, not kept once delivered
+    let _ = conn.execute(
+        "UPDATE CASHU_TOKEN SET Token = '' WHERE DeliveredAt != 0",
+        [],
+    )?;
+
+    let _ = set_current_db_version(conn, 18)?;
+
+    // Note: auto commit
+
+    Ok(())
+}
+
 pub fn get_status(conn: &Connection) -> Result<(i32, u32, u32, i32, u32), Box<dyn Error>> {
     let mut stmt = conn.prepare(
         "SELECT \
@@ -345,6 +693,12 @@ fn userlookup_get_or_insert_id_nocommit(
     Err(format!("ERROR Could not insert original user {username_string} {typ}").into())
 }
 
+// Check whether a username Id exists
+pub fn userlookup_id_exists(conn: &Connection, id: u32) -> Result<bool, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT Id FROM USERLOOKUP WHERE Id = ?1")?;
+    Ok(stmt.exists((id,))?)
+}
+
 pub fn userlookup_get_string(conn: &Connection, id: u32) -> Result<String, Box<dyn Error>> {
     let mut stmt = conn.prepare("SELECT String FROM USERLOOKUP WHERE Id = ?1")?;
     if let Ok(string) = stmt.query_one((id,), |row| row.get::<_, String>(0)) {
@@ -922,27 +1276,36 @@ fn _payreq_from_raw_combined(row: &Row) -> Result<PayRequest, rusqlite::Error> {
         row.get::<_, String>(3)?,
         row.get::<_, String>(4)?,
         row.get::<_, u32>(5)?,
+        row.get::<_, String>(6)?,
     );
     Ok(pr)
 }
 
 fn _payment_from_raw_combined(row: &Row) -> Result<Payment, rusqlite::Error> {
     let paym = Payment::new(
-        row.get::<_, i32>(6)?,
         row.get::<_, i32>(7)?,
-        row.get::<_, u32>(8)?,
-        row.get::<_, u8>(9)?,
-        row.get::<_, u32>(10)?,
-        row.get::<_, u8>(11)?,
-        row.get::<_, String>(12)?,
-        row.get::<_, u8>(13)?,
-        row.get::<_, u32>(14)?,
-        row.get::<_, String>(15)?,
+        row.get::<_, i32>(8)?,
+        row.get::<_, u32>(9)?,
+        row.get::<_, u8>(10)?,
+        row.get::<_, u32>(11)?,
+        row.get::<_, u8>(12)?,
+        row.get::<_, String>(13)?,
+        row.get::<_, u8>(14)?,
+        row.get::<_, u32>(15)?,
         row.get::<_, String>(16)?,
-        row.get::<_, u64>(17)?,
-        row.get::<_, u32>(18)?,
+        row.get::<_, String>(17)?,
+        row.get::<_, u64>(18)?,
         row.get::<_, u32>(19)?,
-        row.get::<_, String>(20)?,
+        row.get::<_, u32>(20)?,
+        row.get::<_, String>(21)?,
+        row.get::<_, u64>(22)?,
+        row.get::<_, u8>(23)?,
+        row.get::<_, String>(24)?,
+        row.get::<_, String>(25)?,
+        row.get::<_, u32>(26)?,
+        row.get::<_, String>(27)?,
+        row.get::<_, String>(28)?,
+        row.get::<_, String>(29)?,
     );
     Ok(paym)
 }
@@ -966,8 +1329,8 @@ fn _payreq_and_pay_from_raw(row: &Row) -> Result<(PayRequest, Payment), rusqlite
 pub fn payreq_insert_nocommit(conn: &Transaction, pr: &PayRequest) -> Result<u32, Box<dyn Error>> {
     let mut stmt = conn.prepare(
         "INSERT INTO PAYREQ \
-        (MinerId, ReqAmnt, PayMethod, PriId, ReqTime) \
-        VALUES (?1, ?2, ?3, ?4, ?5) \
+        (MinerId, ReqAmnt, PayMethod, PriId, ReqTime, FallbackMethods) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
         RETURNING Id",
     )?;
     let mut rows = stmt.query((
@@ -976,6 +1339,7 @@ pub fn payreq_insert_nocommit(conn: &Transaction, pr: &PayRequest) -> Result<u32
         &pr.pay_method,
         &pr.pri_id,
         pr.req_time,
+        &pr.fallback_methods,
     ))?;
     if let Ok(Some(row)) = rows.next() {
         if let Ok(id) = row.get::<_, u32>(0) {
@@ -1026,17 +1390,19 @@ def payreq_get_id(conn: sqlite3.Connection, id: int) -> PayRequest:
     return pr
 */
 
-// Get all payrequests that are non-final (open): all except those for which a Payment
-// with final state (2 SuccessFinal or 4 FailedFinal) exists.
+// Get all payrequests that are non-final (open): all except those for which the latest Payment
+// has a final state (2 SuccessFinal or 4 FailedFinal). A pay request may have several payments
+// (attempts with fallback methods), only the latest one is returned.
 pub fn payreq_get_all_non_final(
     conn: &Connection,
 ) -> Result<Vec<(PayRequest, Option<Payment>)>, Box<dyn Error>> {
     let mut stmt = conn.prepare(
         "SELECT \
-        PAYREQ.Id, PAYREQ.MinerId, PAYREQ.ReqAmnt, PAYREQ.PayMethod, PAYREQ.PriId, PAYREQ.ReqTime, \
-        PAYMENT.Id, PAYMENT.ReqId, PAYMENT.CreateTime, PAYMENT.Status, PAYMENT.StatusTime, PAYMENT.ErrorCode, PAYMENT.ErrorStr, PAYMENT.RetryCnt, PAYMENT.FailTime, PAYMENT.SeconId, PAYMENT.TertiId, PAYMENT.PaidAmnt, PAYMENT.PaidFee, PAYMENT.PayTime, PAYMENT.PayRef \
+        PAYREQ.Id, PAYREQ.MinerId, PAYREQ.ReqAmnt, PAYREQ.PayMethod, PAYREQ.PriId, PAYREQ.ReqTime, PAYREQ.FallbackMethods, \
+        PAYMENT.Id, PAYMENT.ReqId, PAYMENT.CreateTime, PAYMENT.Status, PAYMENT.StatusTime, PAYMENT.ErrorCode, PAYMENT.ErrorStr, PAYMENT.RetryCnt, PAYMENT.FailTime, PAYMENT.SeconId, PAYMENT.TertiId, PAYMENT.PaidAmnt, PAYMENT.PaidFee, PAYMENT.PayTime, PAYMENT.PayRef, PAYMENT.MaxFee, PAYMENT.ZapRcptStatus, PAYMENT.ZapRcptId, PAYMENT.PayHash, PAYMENT.NextRetryTime, PAYMENT.PayMethod, PAYMENT.ZapPubkey, PAYMENT.SuccessAction \
         FROM PAYREQ \
         LEFT OUTER JOIN PAYMENT ON PAYREQ.Id = PAYMENT.ReqId \
+            AND PAYMENT.Id = (SELECT MAX(P2.Id) FROM PAYMENT P2 WHERE P2.ReqId = PAYREQ.Id) \
         WHERE (PAYMENT.Status IS NULL OR (PAYMENT.Status != 2 AND PAYMENT.Status != 4)) \
         ORDER BY PAYREQ.ReqTime ASC")?;
     let res = stmt
@@ -1047,6 +1413,44 @@ pub fn payreq_get_all_non_final(
     Ok(res)
 }
 
+// Work summary for a pay request, e.g. for a message to the miner:
+// the number of work items of the miner and the number of blocks, in the period since
+// the previous pay request of the miner (or since the first work item), until the request.
+// Return the number of work items, the number of blocks, and the period start time.
+pub fn payreq_get_work_summary(
+    conn: &Connection,
+    pr: &PayRequest,
+) -> Result<(u32, u32, u32), Box<dyn Error>> {
+    let mut stmt =
+        conn.prepare("SELECT MAX(ReqTime) FROM PAYREQ WHERE MinerId == ?1 AND Id < ?2")?;
+    let prev_req_time =
+        stmt.query_one((pr.miner_id, pr.id), |row| Ok(row.get::<_, u32>(0).ok()))?;
+    let from_time = match prev_req_time {
+        Some(t) => t,
+        None => {
+            let mut stmt = conn.prepare("SELECT MIN(TimeAdd) FROM WORK WHERE UNameO == ?1")?;
+            stmt.query_one((pr.miner_id,), |row| {
+                Ok(row
+                    .get::<_, f64>(0)
+                    .map(|t| t.floor() as u32)
+                    .unwrap_or(pr.req_time))
+            })?
+        }
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT COUNT(*) FROM WORK WHERE UNameO == ?1 AND TimeAdd >= ?2 AND TimeAdd < ?3",
+    )?;
+    let work_cnt = stmt.query_one((pr.miner_id, from_time, pr.req_time), |row| {
+        Ok(row.get::<_, u32>(0).unwrap_or(0))
+    })?;
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM PC_BLOCK WHERE Time >= ?1 AND Time < ?2")?;
+    let block_cnt = stmt.query_one((from_time, pr.req_time), |row| {
+        Ok(row.get::<_, u32>(0).unwrap_or(0))
+    })?;
+    Ok((work_cnt, block_cnt, from_time))
+}
+
 /*
 # Return Id
 def payment_insert_nocommit(cursor: sqlite3.Cursor, p: Payment):
@@ -1072,9 +1476,9 @@ pub fn payment_update_or_insert_nocommit(
 ) -> Result<u32, Box<dyn Error>> {
     let _ = conn.execute(
         "UPDATE PAYMENT \
-        SET ReqId = ?1, CreateTime = ?2, Status = ?3, StatusTime = ?4, ErrorCode = ?5, ErrorStr = ?6, RetryCnt = ?7, FailTime = ?8, SeconId = ?9, TertiId = ?10, PaidAmnt = ?11, PaidFee = ?12, PayTime = ?13, PayRef = ?14 \
-        WHERE Id = ?15",
-        (p.req_id, p.create_time, p.status, p.status_time, p.error_code, &p.error_str, p.retry_cnt, p.fail_time, &p.secon_id, &p.terti_id, p.paid_amnt, p.paid_fee, p.pay_time, &p.pay_ref, p.id))?;
+        SET ReqId = ?1, CreateTime = ?2, Status = ?3, StatusTime = ?4, ErrorCode = ?5, ErrorStr = ?6, RetryCnt = ?7, FailTime = ?8, SeconId = ?9, TertiId = ?10, PaidAmnt = ?11, PaidFee = ?12, PayTime = ?13, PayRef = ?14, MaxFee = ?15, ZapRcptStatus = ?16, ZapRcptId = ?17, PayHash = ?18, NextRetryTime = ?19, PayMethod = ?20, ZapPubkey = ?21, SuccessAction = ?22 \
+        WHERE Id = ?23",
+        params![p.req_id, p.create_time, p.status, p.status_time, p.error_code, &p.error_str, p.retry_cnt, p.fail_time, &p.secon_id, &p.terti_id, p.paid_amnt, p.paid_fee, p.pay_time, &p.pay_ref, p.max_fee, p.zap_rcpt_status, &p.zap_rcpt_id, &p.pay_hash, p.next_retry_time, &p.pay_method, &p.zap_pubkey, &p.success_action, p.id])?;
 
     let mut stmt = conn.prepare("SELECT Id FROM PAYMENT WHERE Id = ?1")?;
     if let Ok(id) = stmt.query_one((p.id,), |row| row.get::<_, u32>(0)) {
@@ -1083,11 +1487,11 @@ pub fn payment_update_or_insert_nocommit(
         // Not present
         let mut stmt = conn.prepare(
             "INSERT INTO PAYMENT \
-            (ReqId, CreateTime, Status, StatusTime, ErrorCode, ErrorStr, RetryCnt, FailTime, SeconId, TertiId, PaidAmnt, PaidFee, PayTime, PayRef) \
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14) \
+            (ReqId, CreateTime, Status, StatusTime, ErrorCode, ErrorStr, RetryCnt, FailTime, SeconId, TertiId, PaidAmnt, PaidFee, PayTime, PayRef, MaxFee, ZapRcptStatus, ZapRcptId, PayHash, NextRetryTime, PayMethod, ZapPubkey, SuccessAction) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22) \
             RETURNING Id")?;
         if let Ok(id) = stmt.query_one(
-            (
+            params![
                 p.req_id,
                 p.create_time,
                 p.status,
@@ -1102,7 +1506,15 @@ pub fn payment_update_or_insert_nocommit(
                 p.paid_fee,
                 p.pay_time,
                 &p.pay_ref,
-            ),
+                p.max_fee,
+                p.zap_rcpt_status,
+                &p.zap_rcpt_id,
+                &p.pay_hash,
+                p.next_retry_time,
+                &p.pay_method,
+                &p.zap_pubkey,
+                &p.success_action,
+            ],
             |row| row.get::<_, u32>(0),
         ) {
             Ok(id)
@@ -1170,8 +1582,8 @@ pub fn payment_get_all_after_time(
 ) -> Result<Vec<(PayRequest, Payment)>, Box<dyn Error>> {
     let mut stmt = conn.prepare(
         "SELECT \
-            PAYREQ.Id, PAYREQ.MinerId, PAYREQ.ReqAmnt, PAYREQ.PayMethod, PAYREQ.PriId, PAYREQ.ReqTime, \
-            PAYMENT.Id, PAYMENT.ReqId, PAYMENT.CreateTime, PAYMENT.Status, PAYMENT.StatusTime, PAYMENT.ErrorCode, PAYMENT.ErrorStr, PAYMENT.RetryCnt, PAYMENT.FailTime, PAYMENT.SeconId, PAYMENT.TertiId, PAYMENT.PaidAmnt, PAYMENT.PaidFee, PAYMENT.PayTime, PAYMENT.PayRef \
+            PAYREQ.Id, PAYREQ.MinerId, PAYREQ.ReqAmnt, PAYREQ.PayMethod, PAYREQ.PriId, PAYREQ.ReqTime, PAYREQ.FallbackMethods, \
+            PAYMENT.Id, PAYMENT.ReqId, PAYMENT.CreateTime, PAYMENT.Status, PAYMENT.StatusTime, PAYMENT.ErrorCode, PAYMENT.ErrorStr, PAYMENT.RetryCnt, PAYMENT.FailTime, PAYMENT.SeconId, PAYMENT.TertiId, PAYMENT.PaidAmnt, PAYMENT.PaidFee, PAYMENT.PayTime, PAYMENT.PayRef, PAYMENT.MaxFee, PAYMENT.ZapRcptStatus, PAYMENT.ZapRcptId, PAYMENT.PayHash, PAYMENT.NextRetryTime, PAYMENT.PayMethod, PAYMENT.ZapPubkey, PAYMENT.SuccessAction \
             FROM PAYMENT \
             INNER JOIN PAYREQ ON PAYMENT.ReqId = PAYREQ.Id \
             WHERE PAYMENT.StatusTime > ?1 \
@@ -1184,6 +1596,36 @@ pub fn payment_get_all_after_time(
     Ok(res)
 }
 
+// Get the successful payments with a pending zap receipt
+pub fn payment_get_zap_rcpt_pending(
+    conn: &Connection,
+) -> Result<Vec<(PayRequest, Payment)>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT \
+            PAYREQ.Id, PAYREQ.MinerId, PAYREQ.ReqAmnt, PAYREQ.PayMethod, PAYREQ.PriId, PAYREQ.ReqTime, PAYREQ.FallbackMethods, \
+            PAYMENT.Id, PAYMENT.ReqId, PAYMENT.CreateTime, PAYMENT.Status, PAYMENT.StatusTime, PAYMENT.ErrorCode, PAYMENT.ErrorStr, PAYMENT.RetryCnt, PAYMENT.FailTime, PAYMENT.SeconId, PAYMENT.TertiId, PAYMENT.PaidAmnt, PAYMENT.PaidFee, PAYMENT.PayTime, PAYMENT.PayRef, PAYMENT.MaxFee, PAYMENT.ZapRcptStatus, PAYMENT.ZapRcptId, PAYMENT.PayHash, PAYMENT.NextRetryTime, PAYMENT.PayMethod, PAYMENT.ZapPubkey, PAYMENT.SuccessAction \
+            FROM PAYMENT \
+            INNER JOIN PAYREQ ON PAYMENT.ReqId = PAYREQ.Id \
+            WHERE PAYMENT.Status == 2 AND PAYMENT.ZapRcptStatus == 1 \
+            ORDER BY PAYMENT.PayTime ASC")?;
+    let res = stmt
+        .query_map((), |row| _payreq_and_pay_from_raw(row))?
+        .filter(|res| res.is_ok())
+        .map(|res| res.unwrap())
+        .collect::<Vec<(PayRequest, Payment)>>();
+    Ok(res)
+}
+
+/// Check if a pay request has a successful payment already
+pub fn payreq_has_successful_payment(
+    conn: &Connection,
+    req_id: i32,
+) -> Result<bool, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM PAYMENT WHERE ReqId = ?1 AND Status == 2")?;
+    let cnt = stmt.query_one((req_id,), |row| row.get::<_, u32>(0))?;
+    Ok(cnt > 0)
+}
+
 /*
 # Get all payments updated after a certain time, for a user
 # Time comparison is strict
@@ -1247,6 +1689,269 @@ def payment_get_total_amount_for_user(conn: sqlite3.Connection, user_id: int) ->
     return None
 */
 
+pub fn nostr_profile_cache_get(
+    conn: &Connection,
+    npub: &str,
+) -> Result<Option<NostrProfileCache>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT Npub, Lud16, Lud06, CreatedAt, FetchedAt, SourceRelay, Bolt12Offer \
+        FROM NOSTR_PROFILE_CACHE \
+        WHERE Npub = ?1",
+    )?;
+    let mut rows = stmt.query((npub,))?;
+    if let Some(row) = rows.next()? {
+        return Ok(Some(NostrProfileCache::new(
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, u64>(3)?,
+            row.get::<_, u32>(4)?,
+            row.get::<_, String>(5)?,
+            row.get::<_, String>(6)?,
+        )));
+    }
+    Ok(None)
+}
+
+// Insert, or replace existing entry for the npub
+pub fn nostr_profile_cache_upsert(
+    conn: &Connection,
+    entry: &NostrProfileCache,
+) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "INSERT OR REPLACE INTO NOSTR_PROFILE_CACHE \
+        (Npub, Lud16, Lud06, CreatedAt, FetchedAt, SourceRelay, Bolt12Offer) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            &entry.npub,
+            &entry.lud16,
+            &entry.lud06,
+            entry.created_at,
+            entry.fetched_at,
+            &entry.source_relay,
+            &entry.bolt12_offer,
+        ),
+    )?;
+    Ok(())
+}
+
+pub fn nostr_payout_pref_get(
+    conn: &Connection,
+    npub: &str,
+) -> Result<Option<NostrPayoutPref>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT Npub, PayMethod, ThresholdMsat, LnAddress, EventId, CreatedAt, FetchedAt \
+        FROM NOSTR_PAYOUT_PREF \
+        WHERE Npub = ?1",
+    )?;
+    let mut rows = stmt.query((npub,))?;
+    if let Some(row) = rows.next()? {
+        return Ok(Some(NostrPayoutPref::new(
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, u64>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+            row.get::<_, u64>(5)?,
+            row.get::<_, u32>(6)?,
+        )));
+    }
+    Ok(None)
+}
+
+// Insert, or replace existing entry for the npub
+pub fn nostr_payout_pref_upsert(
+    conn: &Connection,
+    entry: &NostrPayoutPref,
+) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "INSERT OR REPLACE INTO NOSTR_PAYOUT_PREF \
+        (Npub, PayMethod, ThresholdMsat, LnAddress, EventId, CreatedAt, FetchedAt) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            &entry.npub,
+            &entry.pay_method,
+            entry.threshold_msat,
+            &entry.ln_address,
+            &entry.event_id,
+            entry.created_at,
+            entry.fetched_at,
+        ),
+    )?;
+    Ok(())
+}
+
+// Get the DM preferences of a miner, default if not set
+pub fn nostr_dm_pref_get(conn: &Connection, miner_id: u32) -> Result<NostrDmPref, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT MinerId, OptOut, LastSummaryTime \
+        FROM NOSTR_DM_PREF \
+        WHERE MinerId = ?1",
+    )?;
+    let mut rows = stmt.query((miner_id,))?;
+    if let Some(row) = rows.next()? {
+        return Ok(NostrDmPref::new(
+            row.get::<_, u32>(0)?,
+            row.get::<_, bool>(1)?,
+            row.get::<_, u32>(2)?,
+        ));
+    }
+    Ok(NostrDmPref::new(miner_id, false, 0))
+}
+
+// Insert, or replace existing entry for the miner
+pub fn nostr_dm_pref_upsert(conn: &Connection, pref: &NostrDmPref) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "INSERT OR REPLACE INTO NOSTR_DM_PREF \
+        (MinerId, OptOut, LastSummaryTime) \
+        VALUES (?1, ?2, ?3)",
+        (pref.miner_id, pref.opt_out, pref.last_summary_time),
+    )?;
+    Ok(())
+}
+
+fn _user_setting_from_row(row: &Row) -> Result<UserSetting, rusqlite::Error> {
+    Ok(UserSetting::new(
+        row.get::<_, u32>(0)?,
+        row.get::<_, String>(1)?,
+        row.get::<_, u64>(2)?,
+        row.get::<_, u64>(3)?,
+        row.get::<_, bool>(4)?,
+        row.get::<_, String>(5)?,
+    ))
+}
+
+// Get the settings of a user, None if not set
+pub fn user_setting_get(
+    conn: &Connection,
+    user_id: u32,
+) -> Result<Option<UserSetting>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT UserId, PayMethod, ThresholdMsat, MaxPayoutMsat, Paused, Notes \
+        FROM USER_SETTINGS \
+        WHERE UserId = ?1",
+    )?;
+    let mut rows = stmt.query((user_id,))?;
+    if let Some(row) = rows.next()? {
+        return Ok(Some(_user_setting_from_row(row)?));
+    }
+    Ok(None)
+}
+
+pub fn user_setting_get_all(conn: &Connection) -> Result<Vec<UserSetting>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT UserId, PayMethod, ThresholdMsat, MaxPayoutMsat, Paused, Notes \
+        FROM USER_SETTINGS \
+        ORDER BY UserId",
+    )?;
+    let res = stmt
+        .query_map([], _user_setting_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(res)
+}
+
+// Insert, or replace existing entry for the user
+pub fn user_setting_upsert(conn: &Connection, entry: &UserSetting) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "INSERT OR REPLACE INTO USER_SETTINGS \
+        (UserId, PayMethod, ThresholdMsat, MaxPayoutMsat, Paused, Notes) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (
+            entry.user_id,
+            &entry.pay_method,
+            entry.threshold_msat,
+            entry.max_payout_msat,
+            entry.paused,
+            &entry.notes,
+        ),
+    )?;
+    Ok(())
+}
+
+// Return true if there was an entry
+pub fn user_setting_delete(conn: &Connection, user_id: u32) -> Result<bool, Box<dyn Error>> {
+    let cnt = conn.execute("DELETE FROM USER_SETTINGS WHERE UserId = ?1", (user_id,))?;
+    Ok(cnt > 0)
+}
+
+// Get the Cashu token minted for a pay request, None if none
+pub fn cashu_token_get_by_req(
+    conn: &Connection,
+    req_id: i32,
+) -> Result<Option<CashuToken>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT Id, ReqId, MintUrl, QuoteId, AmountSat, Token, CreatedAt, DeliveredAt, \
+            Premint, ProofYs \
+        FROM CASHU_TOKEN \
+        WHERE ReqId = ?1 \
+        ORDER BY Id DESC LIMIT 1",
+    )?;
+    let mut rows = stmt.query((req_id,))?;
+    if let Some(row) = rows.next()? {
+        return Ok(Some(CashuToken::new(
+            row.get::<_, i32>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, u64>(4)?,
+            row.get::<_, String>(5)?,
+            row.get::<_, u32>(6)?,
+            row.get::<_, u32>(7)?,
+            row.get::<_, String>(8)?,
+            row.get::<_, String>(9)?,
+        )));
+    }
+    Ok(None)
+}
+
+// Return the ID of the new entry
+pub fn cashu_token_insert(conn: &Connection, token: &CashuToken) -> Result<i32, Box<dyn Error>> {
+    let _ = conn.execute(
+        "INSERT INTO CASHU_TOKEN \
+        (ReqId, MintUrl, QuoteId, AmountSat, Token, CreatedAt, DeliveredAt, Premint, ProofYs) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            token.req_id,
+            &token.mint_url,
+            &token.quote_id,
+            token.amount_sat,
+            &token.token,
+            token.created_at,
+            token.delivered_at,
+            &token.premint,
+            &token.proof_ys,
+        ),
+    )?;
+    Ok(conn.last_insert_rowid() as i32)
+}
+
+// Store the minted token and its proof Y values, the premint outputs are not needed any more
+pub fn cashu_token_set_minted(
+    conn: &Connection,
+    id: i32,
+    token: &str,
+    proof_ys: &str,
+) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "UPDATE CASHU_TOKEN SET Token = ?1, ProofYs = ?2, Premint = '' WHERE Id = ?3",
+        (token, proof_ys, id),
+    )?;
+    Ok(())
+}
+
+// The token is not kept once delivered
+pub fn cashu_token_set_delivered(
+    conn: &Connection,
+    id: i32,
+    delivered_at: u32,
+) -> Result<(), Box<dyn Error>> {
+    let _ = conn.execute(
+        "UPDATE CASHU_TOKEN SET DeliveredAt = ?1, Token = '', Premint = '' WHERE Id = ?2",
+        (delivered_at, id),
+    )?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1258,6 +1963,47 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_db_setup_every_migration() -> Result<(), Box<dyn Error>> {
+        // A new DB, updated one version at a time
+        let conn = Connection::open_in_memory()?;
+        db_setup_from_to(&conn, Some(0), Some(3))?;
+        ensure_db_version(&conn, 3)?;
+        for v in 3..LATEST_DB_VERSION {
+            db_setup_from_to(&conn, Some(v), Some(v + 1))?;
+            ensure_db_version(&conn, v + 1)?;
+        }
+
+        // A new DB, in one go
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+        ensure_db_version(&conn, LATEST_DB_VERSION)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_update_17_18_clears_delivered_tokens() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_from_to(&conn, Some(0), Some(17))?;
+        for (req_id, delivered_at) in [(4, 2000), (5, 0)] {
+            let _ = conn.execute(
+                "INSERT INTO CASHU_TOKEN \
+                (ReqId, MintUrl, QuoteId, AmountSat, Token, CreatedAt, DeliveredAt) \
+                VALUES (?1, 'https://mint.example.com', 'q1', 21, 'cashuAeyJ0b2tlbiI6W119', 1000, ?2)",
+                (req_id, delivered_at),
+            )?;
+        }
+        db_setup_from_to(&conn, Some(17), Some(18))?;
+        let delivered = cashu_token_get_by_req(&conn, 4)?.unwrap();
+        assert_eq!(delivered.token, "");
+        assert_eq!(delivered.quote_id, "q1");
+        assert_eq!(delivered.premint, "");
+        // Not delivered yet, still to be sent
+        let pending = cashu_token_get_by_req(&conn, 5)?.unwrap();
+        assert_eq!(pending.token, "cashuAeyJ0b2tlbiI6W119");
+        Ok(())
+    }
+
     #[test]
     fn test_status() -> Result<(), Box<dyn Error>> {
         let mut conn = Connection::open_in_memory()?;
@@ -1290,4 +2036,303 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_setup_new() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+        ensure_db_version(&conn, LATEST_DB_VERSION)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_payreq_get_work_summary() -> Result<(), Box<dyn Error>> {
+        let mut conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+        for (uname_o, time_add) in [(7, 1000.5), (7, 1500.0), (8, 1600.0), (7, 2500.0)] {
+            let _ = conn.execute(
+                "INSERT INTO WORK (UNameO, TimeAdd) VALUES (?1, ?2)",
+                (uname_o, time_add),
+            )?;
+        }
+        for time in [1200, 2200, 3500] {
+            let _ = conn.execute("INSERT INTO PC_BLOCK (Time) VALUES (?1)", (time,))?;
+        }
+        let tx = conn.transaction()?;
+        let mut pr1 = PayRequest::new(0, 7, 5000, "ZAP".into(), "npub1a".into(), 2000, "".into());
+        pr1.id = payreq_insert_nocommit(&tx, &pr1)? as i32;
+        let mut pr2 = PayRequest::new(0, 7, 6000, "ZAP".into(), "npub1a".into(), 3000, "".into());
+        pr2.id = payreq_insert_nocommit(&tx, &pr2)? as i32;
+        tx.commit()?;
+
+        // From the first work item
+        assert_eq!(payreq_get_work_summary(&conn, &pr1)?, (2, 1, 1000));
+        // From the previous request
+        assert_eq!(payreq_get_work_summary(&conn, &pr2)?, (1, 1, 2000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nostr_dm_pref() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+
+        let pref = nostr_dm_pref_get(&conn, 7)?;
+        assert_eq!(pref.miner_id, 7);
+        assert!(!pref.opt_out);
+        assert_eq!(pref.last_summary_time, 0);
+
+        nostr_dm_pref_upsert(&conn, &NostrDmPref::new(7, true, 1000))?;
+        let pref = nostr_dm_pref_get(&conn, 7)?;
+        assert!(pref.opt_out);
+        assert_eq!(pref.last_summary_time, 1000);
+        assert!(!nostr_dm_pref_get(&conn, 8)?.opt_out);
+        Ok(())
+    }
+
+    #[test]
+    fn test_payment_one_success_per_payreq() -> Result<(), Box<dyn Error>> {
+        let mut conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+        let conntx = conn.transaction()?;
+        let pr_id = payreq_insert_nocommit(
+            &conntx,
+            &PayRequest::new(0, 7, 5000, "LNAD".into(), "a@b.c".into(), 2000, "".into()),
+        )? as i32;
+        conntx.commit()?;
+
+        let new_payment = |status: u8| {
+            Payment::new(
+                -1,
+                pr_id,
+                2000,
+                status,
+                2000,
+                0,
+                "".into(),
+                0,
+                0,
+                "".into(),
+                "".into(),
+                0,
+                0,
+                0,
+                "".into(),
+                0,
+                0,
+                "".into(),
+                "".into(),
+                0,
+                "LNAD".into(),
+                "".into(),
+                "".into(),
+            )
+        };
+        let conntx = conn.transaction()?;
+        let _ = payment_update_or_insert_nocommit(&conntx, &new_payment(3))?;
+        assert!(!payreq_has_successful_payment(&conntx, pr_id)?);
+        let _ = payment_update_or_insert_nocommit(&conntx, &new_payment(2))?;
+        assert!(payreq_has_successful_payment(&conntx, pr_id)?);
+        // A second successful payment is refused
+        assert!(payment_update_or_insert_nocommit(&conntx, &new_payment(2)).is_err());
+        conntx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_payreq_latest_payment() -> Result<(), Box<dyn Error>> {
+        let mut conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+        let conntx = conn.transaction()?;
+        let pr_id = payreq_insert_nocommit(
+            &conntx,
+            &PayRequest::new(
+                0,
+                7,
+                5000,
+                "ZAP".into(),
+                "npub1a".into(),
+                2000,
+                "NOLN".into(),
+            ),
+        )? as i32;
+        conntx.commit()?;
+
+        let new_payment = |status: u8, pay_method: &str| {
+            Payment::new(
+                -1,
+                pr_id,
+                2000,
+                status,
+                2000,
+                0,
+                "".into(),
+                0,
+                0,
+                "".into(),
+                "".into(),
+                0,
+                0,
+                0,
+                "".into(),
+                0,
+                0,
+                "".into(),
+                "".into(),
+                0,
+                pay_method.into(),
+                "".into(),
+                "".into(),
+            )
+        };
+        let conntx = conn.transaction()?;
+        let _ = payment_update_or_insert_nocommit(&conntx, &new_payment(4, "ZAP"))?;
+        conntx.commit()?;
+        assert!(payreq_get_all_non_final(&conn)?.is_empty());
+
+        // Fallback attempt, the pay request is open again, with its latest payment
+        let conntx = conn.transaction()?;
+        let _ = payment_update_or_insert_nocommit(&conntx, &new_payment(0, "NOLN"))?;
+        conntx.commit()?;
+        let open = payreq_get_all_non_final(&conn)?;
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].0.fallback_methods, "NOLN");
+        assert_eq!(open[0].1.as_ref().unwrap().pay_method, "NOLN");
+        assert_eq!(payment_get_all_after_time(&conn, 0)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nostr_profile_cache() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+
+        assert!(nostr_profile_cache_get(&conn, "npub1a")?.is_none());
+        let mut entry = NostrProfileCache::new(
+            "npub1a".into(),
+            "miner@example.com".into(),
+            "".into(),
+            1000,
+            2000,
+            "wss://nos.lol".into(),
+            "".into(),
+        );
+        nostr_profile_cache_upsert(&conn, &entry)?;
+        let read = nostr_profile_cache_get(&conn, "npub1a")?.unwrap();
+        assert_eq!(read.lud16, "miner@example.com");
+        assert_eq!(read.created_at, 1000);
+        assert_eq!(read.fetched_at, 2000);
+        assert_eq!(read.source_relay, "wss://nos.lol");
+
+        entry.lud16 = "new@example.com".into();
+        entry.fetched_at = 3000;
+        entry.bolt12_offer = "lno1qgsq".into();
+        nostr_profile_cache_upsert(&conn, &entry)?;
+        let read = nostr_profile_cache_get(&conn, "npub1a")?.unwrap();
+        assert_eq!(read.lud16, "new@example.com");
+        assert_eq!(read.bolt12_offer, "lno1qgsq");
+        assert_eq!(read.fetched_at, 3000);
+        assert!(nostr_profile_cache_get(&conn, "npub1b")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nostr_payout_pref() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+
+        assert!(nostr_payout_pref_get(&conn, "npub1a")?.is_none());
+        let mut entry = NostrPayoutPref::new(
+            "npub1a".into(),
+            "ZAP".into(),
+            21_000_000,
+            "".into(),
+            "ab12".into(),
+            1000,
+            2000,
+        );
+        nostr_payout_pref_upsert(&conn, &entry)?;
+        let read = nostr_payout_pref_get(&conn, "npub1a")?.unwrap();
+        assert_eq!(read.pay_method, "ZAP");
+        assert_eq!(read.threshold_msat, 21_000_000);
+        assert_eq!(read.event_id, "ab12");
+        assert_eq!(read.created_at, 1000);
+
+        entry.ln_address = "alt@example.com".into();
+        entry.fetched_at = 3000;
+        nostr_payout_pref_upsert(&conn, &entry)?;
+        let read = nostr_payout_pref_get(&conn, "npub1a")?.unwrap();
+        assert_eq!(read.ln_address, "alt@example.com");
+        assert_eq!(read.fetched_at, 3000);
+        assert!(nostr_payout_pref_get(&conn, "npub1b")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_setting() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+
+        assert!(user_setting_get(&conn, 7)?.is_none());
+        let mut entry =
+            UserSetting::new(7, "LNAD".into(), 50_000, 0, false, "asked by mail".into());
+        user_setting_upsert(&conn, &entry)?;
+        user_setting_upsert(&conn, &UserSetting::new_empty(3))?;
+        assert_eq!(user_setting_get(&conn, 7)?, Some(entry.clone()));
+
+        entry.paused = true;
+        entry.max_payout_msat = 1_000_000;
+        user_setting_upsert(&conn, &entry)?;
+        let all = user_setting_get_all(&conn)?;
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].user_id, 3);
+        assert_eq!(all[1], entry);
+
+        assert!(user_setting_delete(&conn, 7)?);
+        assert!(!user_setting_delete(&conn, 7)?);
+        assert!(user_setting_get(&conn, 7)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cashu_token() -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        db_setup_new(&conn)?;
+
+        assert!(cashu_token_get_by_req(&conn, 4)?.is_none());
+        let token = CashuToken::new(
+            -1,
+            4,
+            "https://mint.example.com".into(),
+            "q1".into(),
+            21,
+            "".into(),
+            1000,
+            0,
+            "[{\"amount\":1}]".into(),
+            "".into(),
+        );
+        let id = cashu_token_insert(&conn, &token)?;
+        let read = cashu_token_get_by_req(&conn, 4)?.unwrap();
+        assert_eq!(read.id, id);
+        assert_eq!(read.quote_id, "q1");
+        assert_eq!(read.amount_sat, 21);
+        assert_eq!(read.delivered_at, 0);
+        assert_eq!(read.premint, token.premint);
+
+        cashu_token_set_minted(&conn, id, "cashuAeyJ0b2tlbiI6W119", "02ab,03cd")?;
+        let read = cashu_token_get_by_req(&conn, 4)?.unwrap();
+        assert_eq!(read.token, "cashuAeyJ0b2tlbiI6W119");
+        assert_eq!(read.proof_ys, "02ab,03cd");
+        assert_eq!(read.premint, "");
+
+        cashu_token_set_delivered(&conn, id, 2000)?;
+        let read = cashu_token_get_by_req(&conn, 4)?.unwrap();
+        assert_eq!(read.delivered_at, 2000);
+        // Only the audit data is kept
+        assert_eq!(read.token, "");
+        assert_eq!(read.proof_ys, "02ab,03cd");
+        assert!(cashu_token_get_by_req(&conn, 5)?.is_none());
+        Ok(())
+    }
 }
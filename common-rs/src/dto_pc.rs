@@ -208,6 +208,8 @@ pub struct PayRequest {
     pub pay_method: String,
     pub pri_id: String,
     pub req_time: u32,
+    /// Payment methods to fall back to after the pay method, in order, comma-separated (e.g. "NOLN")
+    pub fallback_methods: String,
 }
 
 impl PayRequest {
@@ -218,6 +220,7 @@ impl PayRequest {
         pay_method: String,
         pri_id: String,
         req_time: u32,
+        fallback_methods: String,
     ) -> Self {
         Self {
             id,
@@ -226,6 +229,7 @@ impl PayRequest {
             pay_method,
             pri_id,
             req_time,
+            fallback_methods,
         }
     }
 }
@@ -247,6 +251,22 @@ pub struct Payment {
     pub paid_fee: u32,
     pub pay_time: u32,
     pub pay_ref: String,
+    /// Fee cap used for the payment attempt, Msat. 0 if not applicable
+    pub max_fee: u64,
+    /// Zap receipt status, see ZAP_RCPT_*
+    pub zap_rcpt_status: u8,
+    /// Event ID of the zap receipt, if confirmed
+    pub zap_rcpt_id: String,
+    /// Payment hash of the invoice being paid (hex), stored before paying
+    pub pay_hash: String,
+    /// Time of the next attempt after a nonfinal failure
+    pub next_retry_time: u32,
+    /// Payment method of this attempt (the pay method of the pay request, or a fallback method)
+    pub pay_method: String,
+    /// Zaps: nostrPubkey of the LNURL server (hex), the expected signer of the zap receipt
+    pub zap_pubkey: String,
+    /// LNURL success action returned with the invoice, decoded, e.g. "message: Thanks!" (LUD-09/10)
+    pub success_action: String,
 }
 
 impl Payment {
@@ -266,6 +286,14 @@ impl Payment {
         paid_fee: u32,
         pay_time: u32,
         pay_ref: String,
+        max_fee: u64,
+        zap_rcpt_status: u8,
+        zap_rcpt_id: String,
+        pay_hash: String,
+        next_retry_time: u32,
+        pay_method: String,
+        zap_pubkey: String,
+        success_action: String,
     ) -> Self {
         Self {
             id,
@@ -283,6 +311,206 @@ impl Payment {
             paid_fee,
             pay_time,
             pay_ref,
+            max_fee,
+            zap_rcpt_status,
+            zap_rcpt_id,
+            pay_hash,
+            next_retry_time,
+            pay_method,
+            zap_pubkey,
+            success_action,
+        }
+    }
+}
+
+// Last known Lightning info from the Nostr profile of an npub
+#[derive(Clone, Debug)]
+pub struct NostrProfileCache {
+    pub npub: String,
+    // Lightning Address, may be empty
+    pub lud16: String,
+    // LNURL, may be empty
+    pub lud06: String,
+    // created_at of the profile event
+    pub created_at: u64,
+    // Time of the last successful relay lookup
+    pub fetched_at: u32,
+    pub source_relay: String,
+    // BOLT12 offer (lno1..), may be empty
+    pub bolt12_offer: String,
+}
+
+impl NostrProfileCache {
+    pub fn new(
+        npub: String,
+        lud16: String,
+        lud06: String,
+        created_at: u64,
+        fetched_at: u32,
+        source_relay: String,
+        bolt12_offer: String,
+    ) -> Self {
+        Self {
+            npub,
+            lud16,
+            lud06,
+            created_at,
+            fetched_at,
+            source_relay,
+            bolt12_offer,
+        }
+    }
+}
+
+// Payout preferences published by a miner with an npub, as a NIP-78 app data event (kind 30078)
+#[derive(Clone, Debug)]
+pub struct NostrPayoutPref {
+    pub npub: String,
+    // Preferred payment method, empty if none
+    pub pay_method: String,
+    // Preferred payout threshold, 0 if none
+    pub threshold_msat: u64,
+    // Alternate Lightning Address, empty if none
+    pub ln_address: String,
+    // ID of the preferences event, empty if none was found
+    pub event_id: String,
+    // created_at of the preferences event
+    pub created_at: u64,
+    // Time of the last relay lookup
+    pub fetched_at: u32,
+}
+
+impl NostrPayoutPref {
+    pub fn new(
+        npub: String,
+        pay_method: String,
+        threshold_msat: u64,
+        ln_address: String,
+        event_id: String,
+        created_at: u64,
+        fetched_at: u32,
+    ) -> Self {
+        Self {
+            npub,
+            pay_method,
+            threshold_msat,
+            ln_address,
+            event_id,
+            created_at,
+            fetched_at,
+        }
+    }
+}
+
+// Nostr DM notification preferences and state of a miner
+#[derive(Clone, Debug)]
+pub struct NostrDmPref {
+    pub miner_id: u32,
+    // No DM notifications at all
+    pub opt_out: bool,
+    // Time of the last daily summary DM sent
+    pub last_summary_time: u32,
+}
+
+impl NostrDmPref {
+    pub fn new(miner_id: u32, opt_out: bool, last_summary_time: u32) -> Self {
+        Self {
+            miner_id,
+            opt_out,
+            last_summary_time,
+        }
+    }
+}
+
+// Per-user payout settings, set by the operator
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserSetting {
+    // The base miner username id, as in USERLOOKUP and PAYREQ
+    pub user_id: u32,
+    // Payment method to use, empty if not set
+    pub pay_method: String,
+    // Payout threshold, 0 if not set
+    pub threshold_msat: u64,
+    // Maximum payout amount, 0 if not set
+    pub max_payout_msat: u64,
+    // No payouts while set
+    pub paused: bool,
+    // Free-form operator notes
+    pub notes: String,
+}
+
+impl UserSetting {
+    pub fn new(
+        user_id: u32,
+        pay_method: String,
+        threshold_msat: u64,
+        max_payout_msat: u64,
+        paused: bool,
+        notes: String,
+    ) -> Self {
+        Self {
+            user_id,
+            pay_method,
+            threshold_msat,
+            max_payout_msat,
+            paused,
+            notes,
+        }
+    }
+
+    /// Empty settings of a user
+    pub fn new_empty(user_id: u32) -> Self {
+        Self::new(user_id, "".into(), 0, 0, false, "".into())
+    }
+}
+
+// Cashu token minted for a payout. The token is kept only until delivered,
+// the proof Y values are kept for audit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CashuToken {
+    pub id: i32,
+    // The pay request paid with the token
+    pub req_id: i32,
+    pub mint_url: String,
+    // ID of the mint quote (NUT-04)
+    pub quote_id: String,
+    pub amount_sat: u64,
+    // The serialized token (cashuA..), empty if not minted yet, or delivered already
+    pub token: String,
+    pub created_at: u32,
+    // Time the token was sent to the miner, 0 if not yet
+    pub delivered_at: u32,
+    // Blinded outputs of the mint request with their secrets (JSON), stored before the request,
+    // to restore the token if the response is lost (NUT-09). Empty once minted.
+    pub premint: String,
+    // Y values of the proofs (hex, comma separated), to check their state at the mint (NUT-07)
+    pub proof_ys: String,
+}
+
+impl CashuToken {
+    pub fn new(
+        id: i32,
+        req_id: i32,
+        mint_url: String,
+        quote_id: String,
+        amount_sat: u64,
+        token: String,
+        created_at: u32,
+        delivered_at: u32,
+        premint: String,
+        proof_ys: String,
+    ) -> Self {
+        Self {
+            id,
+            req_id,
+            mint_url,
+            quote_id,
+            amount_sat,
+            token,
+            created_at,
+            delivered_at,
+            premint,
+            proof_ys,
         }
     }
 }
@@ -4,19 +4,44 @@ pub const STATUS_SUCCESS_FINAL: u8 = 2;
 pub const STATUS_NONFINAL_FAILURE: u8 = 3;
 pub const STATUS_FINAL_FAILURE: u8 = 4;
 
+// Zap receipt (kind 9735) status, of successful zap payments
+pub const ZAP_RCPT_NA: u8 = 0;
+pub const ZAP_RCPT_PENDING: u8 = 1;
+pub const ZAP_RCPT_CONFIRMED: u8 = 2;
+pub const ZAP_RCPT_MISSING: u8 = 3;
+
 pub const ERROR_OK: u8 = 0;
 #[allow(dead_code)]
 pub const ERROR_GENERIC_NONFINAL_FAILURE: u8 = 1;
 pub const ERROR_GENERIC_FINAL_FAILURE: u8 = 2;
+// Payment attempt did not finish within the per-payment timeout
+pub const ERROR_PAYMENT_TIMEOUT: u8 = 3;
+// The pay request is paid already (by another attempt), the attempt is not paid and not retried
+pub const ERROR_PAYREQ_ALREADY_PAID: u8 = 4;
 pub const ERROR_LN_ADDRESS_NONFINAL_FAILURE: u8 = 101;
 pub const ERROR_LN_ADDRESS_FINAL_FAILURE: u8 = 102;
 pub const ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE: u8 = 111;
 pub const ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE: u8 = 112;
 #[allow(dead_code)]
 pub const ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS: u8 = 113;
+// Invoice does not match the request (amount, expiry, description hash), not paid
+pub const ERROR_LN_BOLT11_INVOICE_MISMATCH: u8 = 114;
+// No route within the fee cap
+pub const ERROR_LN_BOLT11_FEE_CAP_EXCEEDED: u8 = 115;
 pub const ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE: u8 = 151;
 #[allow(dead_code)]
 pub const ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE: u8 = 152;
 pub const ERROR_NOSTR_ZAP_NONFINAL_FAILURE: u8 = 161;
 #[allow(dead_code)]
 pub const ERROR_NOSTR_ZAP_FINAL_FAILURE: u8 = 162;
+// LNURL server of the recipient does not support zaps (no allowsNostr or nostrPubkey), not paid
+pub const ERROR_NOSTR_ZAP_NOT_SUPPORTED: u8 = 163;
+// Minting or delivering a Cashu token failed
+pub const ERROR_CASHU_NONFINAL_FAILURE: u8 = 171;
+pub const ERROR_CASHU_FINAL_FAILURE: u8 = 172;
+// Mint quote paid and issued, but the token could not be restored from the mint, to be resolved
+// by the operator
+pub const ERROR_CASHU_NEEDS_REVIEW: u8 = 173;
+// Obtaining an invoice for a BOLT12 offer failed, not paid
+pub const ERROR_BOLT12_OFFER_NONFINAL_FAILURE: u8 = 181;
+pub const ERROR_BOLT12_OFFER_FINAL_FAILURE: u8 = 182;
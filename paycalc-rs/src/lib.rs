@@ -2,4 +2,4 @@ pub mod db_oc;
 mod dto_oc;
 pub mod paycalc_earn;
 pub mod paycalc_payreq;
-mod payment_method;
+pub mod payment_method;
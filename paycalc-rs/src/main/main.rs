@@ -2,6 +2,7 @@ use common_rs::common_db::get_db_file;
 use paycalc_rs::paycalc_earn::loop_iterations;
 use paycalc_rs::paycalc_payreq::loop_iterations as payreq_loop_iterations;
 use payer::payer::loop_iterations as payer_loop_iterations;
+use payer::work_queue::PayerWakeup;
 
 use dotenv;
 use rusqlite::{Connection, OpenFlags};
@@ -29,17 +30,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let dbfile = get_db_file("paycalc.db", false);
     let mut conn = Connection::open(&dbfile)?;
 
+    // The payreq loop wakes up the payer when it creates new pay requests
+    let payer_wakeup = PayerWakeup::new();
+    let payreq_wakeup = payer_wakeup.clone();
+
     // Start Payreq loop in background thread
-    thread::spawn(|| match payreq_loop_iterations() {
+    thread::spawn(move || match payreq_loop_iterations(payreq_wakeup) {
         Err(e) => println!("Error: {e}"),
         Ok(_) => {}
     });
 
     // Start payment loop in background thread with async runtime
-    thread::spawn(|| {
+    thread::spawn(move || {
         let rt = Runtime::new().expect("Failed to create Tokio runtime");
         rt.block_on(async {
-            match payer_loop_iterations().await {
+            match payer_loop_iterations(payer_wakeup).await {
                 Err(e) => println!("Error: {e}"),
                 Ok(_) => {}
             }
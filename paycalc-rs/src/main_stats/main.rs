@@ -28,7 +28,7 @@ async fn count_zaps(
             INNER JOIN PAYREQ ON PAYMENT.ReqId = PAYREQ.Id \
             WHERE PAYMENT.PayTime >= ?1 AND \
             PAYMENT.PayTime <= ?2 AND \
-            PAYREQ.PayMethod == \"ZAP\" AND \
+            PAYMENT.PayMethod == \"ZAP\" AND \
             PAYMENT.Status == 2 \
             ",
     )?;
@@ -73,7 +73,7 @@ async fn count_users_with_zaps(
             INNER JOIN USERLOOKUP ON PAYREQ.MinerId == USERLOOKUP.Id \
             WHERE PAYMENT.PayTime >= ?1 AND \
             PAYMENT.PayTime <= ?2 AND \
-            PAYREQ.PayMethod == \"ZAP\" AND \
+            PAYMENT.PayMethod == \"ZAP\" AND \
             PAYMENT.Status == 2 \
             GROUP BY USERLOOKUP.Id \
             ",
@@ -0,0 +1,307 @@
+//! Admin tool to view and edit per-user payout settings (USER_SETTINGS)
+use common_rs::common_db::get_db_file;
+use common_rs::db_pc as db;
+use common_rs::dto_pc::{NostrDmPref, UserSetting};
+use paycalc_rs::payment_method::parse_user_method_setting_override;
+use payer::common::PaymentMethod;
+
+use rusqlite::Connection;
+
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+
+fn print_usage(progname: &str) {
+    println!("{}:  View or edit per-user payout settings", progname);
+    println!();
+    println!("{}  list", progname);
+    println!("{}  show <user>", progname);
+    println!(
+        "{}  set <user> [--method <LNAD|NOLN|ZAP|CASHU|BOLT12|->] [--threshold <msat>] [--max <msat>] [--paused <true|false>] [--dm-opt-out <true|false>] [--notes <text>]",
+        progname
+    );
+    println!("{}  delete <user>", progname);
+    println!("{}  import-env", progname);
+    println!("  <user>      User ID, or base username");
+    println!("  --method    Payment method, '-' to unset");
+    println!("  --threshold Payout threshold, 0 to unset");
+    println!("  --max       Maximum payout amount, 0 to unset");
+    println!("  --dm-opt-out No Nostr DM notifications to the user");
+    println!("  import-env  Import the legacy USER_METHOD_SETTING_OVERRIDE setting");
+    println!();
+}
+
+fn print_user_setting(conn: &Connection, us: &UserSetting) -> Result<(), Box<dyn Error>> {
+    println!(
+        "  {} \t {} \t {} \t {} \t {} \t {} \t {}",
+        us.user_id,
+        db::userlookup_get_string(conn, us.user_id)?,
+        if us.pay_method.is_empty() {
+            "-"
+        } else {
+            &us.pay_method
+        },
+        us.threshold_msat,
+        us.max_payout_msat,
+        if us.paused { "PAUSED" } else { "-" },
+        us.notes
+    );
+    Ok(())
+}
+
+fn print_dm_pref(pref: &NostrDmPref) {
+    println!(
+        "  DM notifications: {}",
+        if pref.opt_out { "opted out" } else { "on" }
+    );
+}
+
+/// User ID from a number or a username, of a known user
+fn resolve_user(conn: &Connection, user: &str) -> Result<u32, Box<dyn Error>> {
+    if let Ok(id) = user.parse::<u32>() {
+        if db::userlookup_id_exists(conn, id)? {
+            return Ok(id);
+        }
+        return Err(format!("Unknown user ID {id}").into());
+    }
+    db::userlookup_get_id(conn, user)?.ok_or(format!("Unknown user '{user}'").into())
+}
+
+/// Import the entries of the legacy USER_METHOD_SETTING_OVERRIDE setting, of known users.
+/// Return the number of entries imported.
+fn import_env(conn: &Connection, envstr: &str) -> Result<usize, Box<dyn Error>> {
+    let mut cnt = 0;
+    for (user_id, pm) in parse_user_method_setting_override(envstr) {
+        if !db::userlookup_id_exists(conn, user_id)? {
+            println!("WARNING: Unknown user ID {user_id}, skipped");
+            continue;
+        }
+        let mut us =
+            db::user_setting_get(conn, user_id)?.unwrap_or(UserSetting::new_empty(user_id));
+        us.pay_method = pm.to_string();
+        db::user_setting_upsert(conn, &us)?;
+        print_user_setting(conn, &us)?;
+        cnt += 1;
+    }
+    Ok(cnt)
+}
+
+/// Apply the --option value pairs to the settings and DM preferences
+fn apply_set_args(
+    us: &mut UserSetting,
+    dm_pref: &mut NostrDmPref,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let mut i = 0;
+    while i < args.len() {
+        let value = args
+            .get(i + 1)
+            .ok_or(format!("{} requires a value", args[i]))?;
+        match args[i].as_str() {
+            "--method" => {
+                us.pay_method = if value == "-" {
+                    "".to_string()
+                } else {
+                    PaymentMethod::from_str(value)?.to_string()
+                }
+            }
+            "--threshold" => us.threshold_msat = value.parse::<u64>()?,
+            "--max" => us.max_payout_msat = value.parse::<u64>()?,
+            "--paused" => us.paused = value.parse::<bool>()?,
+            "--dm-opt-out" => dm_pref.opt_out = value.parse::<bool>()?,
+            "--notes" => us.notes = value.clone(),
+            a => return Err(format!("Unknown argument {a}").into()),
+        }
+        i += 2;
+    }
+    if us.max_payout_msat != 0 && us.max_payout_msat < us.threshold_msat {
+        return Err("Maximum payout is less than the threshold".into());
+    }
+    Ok(())
+}
+
+fn execute(conn: &Connection, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let command = args.get(1).map(|s| s.as_str()).unwrap_or("");
+    let user_arg = || args.get(2).ok_or("<user> missing");
+    match command {
+        "list" => {
+            let all = db::user_setting_get_all(conn)?;
+            println!("User settings: ({})", all.len());
+            println!("  id \t user \t method \t threshold \t max \t paused \t notes");
+            for us in &all {
+                print_user_setting(conn, us)?;
+            }
+        }
+        "show" => {
+            let user_id = resolve_user(conn, user_arg()?)?;
+            match db::user_setting_get(conn, user_id)? {
+                None => println!("No settings for user {user_id}"),
+                Some(us) => print_user_setting(conn, &us)?,
+            }
+            print_dm_pref(&db::nostr_dm_pref_get(conn, user_id)?);
+        }
+        "set" => {
+            let user_id = resolve_user(conn, user_arg()?)?;
+            let mut us =
+                db::user_setting_get(conn, user_id)?.unwrap_or(UserSetting::new_empty(user_id));
+            let mut dm_pref = db::nostr_dm_pref_get(conn, user_id)?;
+            apply_set_args(&mut us, &mut dm_pref, &args[3..])?;
+            db::user_setting_upsert(conn, &us)?;
+            db::nostr_dm_pref_upsert(conn, &dm_pref)?;
+            print_user_setting(conn, &us)?;
+            print_dm_pref(&dm_pref);
+        }
+        "delete" => {
+            let user_id = resolve_user(conn, user_arg()?)?;
+            if db::user_setting_delete(conn, user_id)? {
+                println!("Settings deleted for user {user_id}");
+            } else {
+                println!("No settings for user {user_id}");
+            }
+        }
+        "import-env" => {
+            let envstr = env::var("USER_METHOD_SETTING_OVERRIDE")
+                .map_err(|_| "USER_METHOD_SETTING_OVERRIDE is not set")?;
+            let cnt = import_env(conn, &envstr)?;
+            println!("Imported {cnt} entries, USER_METHOD_SETTING_OVERRIDE can be removed");
+        }
+        _ => {
+            print_usage(&args[0]);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Load environment variables from .env file
+    dotenv::dotenv().ok();
+
+    let args: Vec<String> = env::args().collect();
+    let dbfile = get_db_file("paycalc.db", false);
+    let conn = Connection::open(&dbfile)?;
+
+    execute(&conn, &args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // DB with the given users in USERLOOKUP, IDs from 1
+    fn create_test_db(users: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        for user in users {
+            let _ = conn
+                .execute(
+                    "INSERT INTO USERLOOKUP (String, Type, TimeAdd) VALUES (?1, 11, 0)",
+                    (user,),
+                )
+                .unwrap();
+        }
+        conn
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        let mut res = vec!["main_user_settings".to_string()];
+        res.extend(list.iter().map(|s| s.to_string()));
+        res
+    }
+
+    #[test]
+    fn test_resolve_user() {
+        let conn = create_test_db(&["miner1", "miner2"]);
+        assert_eq!(resolve_user(&conn, "2").unwrap(), 2);
+        assert_eq!(resolve_user(&conn, "miner1").unwrap(), 1);
+        assert!(resolve_user(&conn, "3").is_err());
+        assert!(resolve_user(&conn, "miner3").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let conn = create_test_db(&["miner1"]);
+        execute(
+            &conn,
+            &args(&["set", "miner1", "--method", "ZAP", "--threshold", "50000"]),
+        )
+        .unwrap();
+        let us = db::user_setting_get(&conn, 1).unwrap().unwrap();
+        assert_eq!(us.pay_method, "ZAP");
+        assert_eq!(us.threshold_msat, 50000);
+        assert!(!us.paused);
+
+        // Existing settings are kept
+        execute(
+            &conn,
+            &args(&["set", "1", "--paused", "true", "--method", "-"]),
+        )
+        .unwrap();
+        let us = db::user_setting_get(&conn, 1).unwrap().unwrap();
+        assert_eq!(us.pay_method, "");
+        assert_eq!(us.threshold_msat, 50000);
+        assert!(us.paused);
+
+        // Invalid
+        assert!(execute(&conn, &args(&["set", "1", "--max", "1000"])).is_err());
+        assert!(execute(&conn, &args(&["set", "1", "--method", "NO_SUCH_PM"])).is_err());
+        assert!(execute(&conn, &args(&["set", "1", "--threshold"])).is_err());
+        assert!(execute(&conn, &args(&["set", "1", "--color", "red"])).is_err());
+        assert_eq!(db::user_setting_get(&conn, 1).unwrap().unwrap(), us);
+
+        // Unknown user
+        assert!(execute(&conn, &args(&["set", "2", "--paused", "true"])).is_err());
+        assert!(db::user_setting_get(&conn, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_set_dm_opt_out() {
+        let conn = create_test_db(&["miner1"]);
+        db::nostr_dm_pref_upsert(&conn, &NostrDmPref::new(1, false, 1000)).unwrap();
+        execute(&conn, &args(&["set", "miner1", "--dm-opt-out", "true"])).unwrap();
+        let pref = db::nostr_dm_pref_get(&conn, 1).unwrap();
+        assert!(pref.opt_out);
+        // Summary state kept
+        assert_eq!(pref.last_summary_time, 1000);
+
+        execute(&conn, &args(&["set", "1", "--dm-opt-out", "false"])).unwrap();
+        assert!(!db::nostr_dm_pref_get(&conn, 1).unwrap().opt_out);
+        assert!(execute(&conn, &args(&["set", "1", "--dm-opt-out", "yes"])).is_err());
+        assert!(!db::nostr_dm_pref_get(&conn, 1).unwrap().opt_out);
+    }
+
+    #[test]
+    fn test_delete() {
+        let conn = create_test_db(&["miner1", "miner2"]);
+        execute(&conn, &args(&["set", "miner1", "--method", "LNAD"])).unwrap();
+        execute(&conn, &args(&["set", "miner2", "--method", "NOLN"])).unwrap();
+
+        execute(&conn, &args(&["delete", "miner1"])).unwrap();
+        assert!(db::user_setting_get(&conn, 1).unwrap().is_none());
+        assert!(db::user_setting_get(&conn, 2).unwrap().is_some());
+        // No settings, nothing to do
+        execute(&conn, &args(&["delete", "1"])).unwrap();
+        assert!(execute(&conn, &args(&["delete", "miner3"])).is_err());
+        assert!(execute(&conn, &args(&["delete"])).is_err());
+    }
+
+    #[test]
+    fn test_import_env() {
+        let conn = create_test_db(&["miner1", "miner2"]);
+        execute(&conn, &args(&["set", "miner2", "--threshold", "70000"])).unwrap();
+
+        // Unknown user 3 and invalid entries skipped
+        assert_eq!(
+            import_env(&conn, "1:LNAD,2:ZAP,3:NOLN,4:NO_SUCH_PM").unwrap(),
+            2
+        );
+        let us1 = db::user_setting_get(&conn, 1).unwrap().unwrap();
+        assert_eq!(us1.pay_method, "LNAD");
+        let us2 = db::user_setting_get(&conn, 2).unwrap().unwrap();
+        assert_eq!(us2.pay_method, "ZAP");
+        // Other settings kept
+        assert_eq!(us2.threshold_msat, 70000);
+        assert!(db::user_setting_get(&conn, 3).unwrap().is_none());
+
+        assert_eq!(import_env(&conn, "").unwrap(), 0);
+    }
+}
@@ -1,18 +1,28 @@
 use crate::payment_method::{
     adjusted_primary_id, determine_payment_method, get_default_payment_method_from_env,
+    npub_of_primary_id, parse_user_method_setting_override,
 };
 
 use common_rs::common_db::get_db_file;
 use common_rs::db_pc as db;
-use common_rs::dto_pc::{MinerSnapshot, PayRequest};
+use common_rs::dto_pc::{MinerSnapshot, NostrPayoutPref, PayRequest, UserSetting};
 use payer::common::{PaymentMethod, shorten_id};
+use payer::lnurl_pay::fetch_pay_params;
+use payer::nostr_payout_prefs::refresh_payout_prefs;
+use payer::nostr_profile::get_nostr_ln_address_cached;
+use payer::nostr_relays::get_nostr_relays_from_config;
+use payer::work_queue::PayerWakeup;
 
 use dotenv;
-use rusqlite::{Connection, Transaction};
+use futures_util::future::join_all;
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
+use std::str::FromStr;
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -22,6 +32,8 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// The portion of earning considered for payout of the the only-estimated-not-committed amount
 const PAYOUT_RATIO_FOR_ESTIMATED: f64 = 0.67;
+/// Max. time of the network lookups of an iteration (payout prefs, wallet amount limits)
+const LOOKUP_DEADLINE: Duration = Duration::from_secs(30);
 
 fn print_miner_snapshot(ss: &MinerSnapshot) {
     print!(
@@ -55,6 +67,7 @@ pub fn print_updated_miner_snapshots(conn: &Connection) -> Result<(), Box<dyn Er
         .unwrap_or_default()
         .as_secs() as u32;
 
+    let pool_limits = get_pool_payout_limits()?;
     let snapshots = db::miner_ss_get_all(conn)?;
     println!("");
     println!("Updated snapshots: ({})", snapshots.len());
@@ -63,8 +76,10 @@ pub fn print_updated_miner_snapshots(conn: &Connection) -> Result<(), Box<dyn Er
         let mut ss_copy = ss.clone();
         let changed = update_miner_snapshot(conn, &mut ss_copy)?;
         // See if this would create a payrequest now
+        let (user_setting, nostr_pref) = get_miner_payout_settings(conn, &ss_copy)?;
+        let limits = pool_limits.for_miner(user_setting.as_ref(), nostr_pref.as_ref());
         let (topay_now_opt, _reject_reason) =
-            calculate_to_pay_for_miner(&mut ss_copy, now_utc).unwrap();
+            calculate_to_pay_for_miner(&mut ss_copy, now_utc, &limits).unwrap();
         if topay_now_opt.is_some() {
             print!("!"); // should be paid
         } else {
@@ -118,29 +133,135 @@ pub fn print_pay_requests(conn: &Connection) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-// Return PAYOUT_THRESHOLD_MSAT, PAYOUT_MAXIMUM_MSAT and PAYOUT_GRANULARITY_MSAT from env
-fn get_payout_threshold() -> Result<(u64, u64, u32), Box<dyn Error>> {
-    let mut threshold = env::var("PAYOUT_THRESHOLD_MSAT")
+/// Pool-wide payout amount settings, msat
+#[derive(Clone, Debug)]
+struct PoolPayoutLimits {
+    /// Default threshold
+    threshold: u64,
+    /// Default maximum
+    maximum: u64,
+    granularity: u32,
+    /// Lowest threshold a miner can have
+    threshold_min: u64,
+    /// Highest maximum a miner can have
+    maximum_max: u64,
+}
+
+/// Payout amount limits of a miner, msat
+#[derive(Clone, Debug, PartialEq)]
+struct PayoutLimits {
+    threshold: u64,
+    maximum: u64,
+    granularity: u32,
+}
+
+fn round_up_to(amount: u64, granularity: u32) -> u64 {
+    amount.div_ceil(granularity as u64) * granularity as u64
+}
+
+fn round_down_to(amount: u64, granularity: u32) -> u64 {
+    amount / granularity as u64 * granularity as u64
+}
+
+// Read PAYOUT_THRESHOLD_MSAT, PAYOUT_MAXIMUM_MSAT, PAYOUT_GRANULARITY_MSAT,
+// PAYOUT_THRESHOLD_MIN_MSAT and PAYOUT_MAXIMUM_MAX_MSAT from env
+fn get_pool_payout_limits() -> Result<PoolPayoutLimits, Box<dyn Error>> {
+    let threshold = env::var("PAYOUT_THRESHOLD_MSAT")
         .unwrap_or("5000".into())
         .parse::<u64>()?;
-    let mut maximum = env::var("PAYOUT_MAXIMUM_MSAT")
+    let maximum = env::var("PAYOUT_MAXIMUM_MSAT")
         .unwrap_or("20000000".into())
         .parse::<u64>()?;
     let granularity = env::var("PAYOUT_GRANULARITY_MSAT")
         .unwrap_or("1000".into())
         .parse::<u32>()?;
-    threshold = (((threshold as f64) / (granularity as f64)) * (granularity as f64)).ceil() as u64;
-    maximum = (((maximum as f64) / (granularity as f64)) * (granularity as f64)).floor() as u64;
-    // println!("{threshold} {maximum} {granularity}");
-    Ok((threshold, maximum, granularity))
+    let threshold_min = env::var("PAYOUT_THRESHOLD_MIN_MSAT")
+        .unwrap_or("1000".into())
+        .parse::<u64>()?;
+    let maximum_max = env::var("PAYOUT_MAXIMUM_MAX_MSAT")
+        .unwrap_or("100000000".into())
+        .parse::<u64>()?;
+    if granularity == 0 {
+        return Err("PAYOUT_GRANULARITY_MSAT must not be 0".into());
+    }
+    if threshold_min > threshold || maximum_max < maximum || threshold > maximum {
+        return Err("Inconsistent payout limits, check PAYOUT_THRESHOLD_MIN_MSAT <= PAYOUT_THRESHOLD_MSAT <= PAYOUT_MAXIMUM_MSAT <= PAYOUT_MAXIMUM_MAX_MSAT".into());
+    }
+    Ok(PoolPayoutLimits {
+        threshold: round_up_to(threshold, granularity),
+        maximum: round_down_to(maximum, granularity),
+        granularity,
+        threshold_min: round_up_to(threshold_min, granularity),
+        maximum_max: round_down_to(maximum_max, granularity),
+    })
+}
+
+impl PoolPayoutLimits {
+    /// Payout limits of a miner: the threshold and maximum from the user settings, or the threshold
+    /// preferred by the miner, or the pool defaults; bounded by the pool-wide min/max
+    fn for_miner(
+        &self,
+        user_setting: Option<&UserSetting>,
+        nostr_pref: Option<&NostrPayoutPref>,
+    ) -> PayoutLimits {
+        let setting_threshold = user_setting.map(|us| us.threshold_msat).unwrap_or(0);
+        let pref_threshold = nostr_pref.map(|p| p.threshold_msat).unwrap_or(0);
+        let threshold = if setting_threshold > 0 {
+            setting_threshold
+        } else if pref_threshold > 0 {
+            pref_threshold
+        } else {
+            self.threshold
+        };
+        let threshold =
+            round_up_to(threshold, self.granularity).clamp(self.threshold_min, self.maximum_max);
+
+        let setting_maximum = user_setting.map(|us| us.max_payout_msat).unwrap_or(0);
+        let maximum = if setting_maximum > 0 {
+            round_down_to(setting_maximum, self.granularity)
+        } else {
+            self.maximum
+        };
+        // A threshold above the default maximum raises the maximum
+        let maximum = maximum.clamp(threshold, self.maximum_max);
+
+        PayoutLimits {
+            threshold,
+            maximum,
+            granularity: self.granularity,
+        }
+    }
+}
+
+impl PayoutLimits {
+    /// Narrowed to the amount range accepted by the LNURL-pay service of the miner (LUD-06)
+    fn with_sendable(&self, min_sendable: u64, max_sendable: u64) -> PayoutLimits {
+        PayoutLimits {
+            threshold: std::cmp::max(self.threshold, round_up_to(min_sendable, self.granularity)),
+            maximum: std::cmp::min(self.maximum, round_down_to(max_sendable, self.granularity)),
+            granularity: self.granularity,
+        }
+    }
 }
 
 // Return to-pay amount (if to be paid now) and reject reason (it not)
 fn calculate_to_pay_for_miner(
     miner: &MinerSnapshot,
     now: u32,
+    limits: &PayoutLimits,
 ) -> Result<(Option<u64>, Option<String>), Box<dyn Error>> {
-    let (threshold, maximum, granularity) = get_payout_threshold()?;
+    let (threshold, maximum, granularity) = (limits.threshold, limits.maximum, limits.granularity);
+
+    // No amount is acceptable, e.g. the wallet's minSendable is above the maximum
+    if threshold > maximum {
+        return Ok((
+            None,
+            Some(format!(
+                "Payout threshold above maximum {} {}  user {} {}",
+                threshold, maximum, miner.user_id, miner.user_s
+            )),
+        ));
+    }
 
     // Amount too low, don't pay now
     if miner.unpaid_cons < threshold as i64 {
@@ -180,18 +301,29 @@ fn calculate_to_pay_for_miner(
 
     let unpaid_cons = miner.unpaid_cons as u64; // it is non-negative by now
     // Clap to min, max
-    let mut to_pay = std::cmp::min(std::cmp::max(unpaid_cons, threshold), maximum);
-    // Round to granularity (typically sat)
-    to_pay = granularity as u64 * ((to_pay as f64) / (granularity as f64)).round() as u64;
+    let to_pay = std::cmp::min(std::cmp::max(unpaid_cons, threshold), maximum);
+    // Round to granularity (typically sat), staying within the limits
+    let to_pay = (granularity as u64 * ((to_pay as f64) / (granularity as f64)).round() as u64)
+        .clamp(threshold, maximum);
     Ok((Some(to_pay), None))
 }
 
 fn create_pay_request_if_needed(
     miner: &mut MinerSnapshot,
     default_payment_method: PaymentMethod,
+    user_setting: Option<&UserSetting>,
+    nostr_pref: Option<&NostrPayoutPref>,
+    limits: &PayoutLimits,
     now: u32,
 ) -> Result<Option<PayRequest>, Box<dyn Error>> {
-    let (to_pay, reject_reason) = calculate_to_pay_for_miner(miner, now)?;
+    if user_setting.map(|us| us.paused).unwrap_or(false) {
+        println!(
+            "No pay request now: Payouts paused for user {} {}",
+            miner.user_id, miner.user_s
+        );
+        return Ok(None);
+    }
+    let (to_pay, reject_reason) = calculate_to_pay_for_miner(miner, now, limits)?;
 
     if to_pay.is_none() {
         if let Some(reason) = reject_reason {
@@ -218,8 +350,19 @@ fn create_pay_request_if_needed(
     }
     //println!(primary_id);
 
-    let payment_method =
-        determine_payment_method(miner.user_id, &primary_id, default_payment_method)?;
+    let (payment_methods, primary_id) = determine_payment_method(
+        miner.user_id,
+        &primary_id,
+        default_payment_method,
+        user_setting,
+        nostr_pref,
+    )?;
+    let payment_method = payment_methods[0];
+    let fallback_methods = payment_methods[1..]
+        .iter()
+        .map(|pm| pm.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
     let adj_primary_id = adjusted_primary_id(payment_method, &primary_id)?;
     if adj_primary_id != primary_id {
         println!("Adjusted primary id: {}  ({})", adj_primary_id, primary_id);
@@ -232,26 +375,80 @@ fn create_pay_request_if_needed(
         payment_method.to_string(),
         adj_primary_id,
         miner.time,
+        fallback_methods,
     );
     Ok(Some(pr))
 }
 
-fn create_and_save_pay_request_if_needed(
-    conn: &Transaction,
-    miner: &mut MinerSnapshot,
-    default_payment_method: PaymentMethod,
+/// Settings of a miner for the payout: the user settings, and the cached Nostr payout preferences
+fn get_miner_payout_settings(
+    conn: &Connection,
+    miner: &MinerSnapshot,
+) -> Result<(Option<UserSetting>, Option<NostrPayoutPref>), Box<dyn Error>> {
+    let user_setting = db::user_setting_get(conn, miner.user_id)?;
+    let nostr_pref = match npub_of_primary_id(&miner.user_s) {
+        None => None,
+        Some(npub) => db::nostr_payout_pref_get(conn, &npub)?,
+    };
+    Ok((user_setting, nostr_pref))
+}
+
+/// The amount range accepted by the LNURL-pay service of the payout address of a pay request
+/// (minSendable, maxSendable), unbounded for BOLT12 offers.
+/// For Nostr methods the address is taken from the profile (cached).
+async fn get_sendable_limits(
+    conn: &Connection,
+    pr: &PayRequest,
+    relays: &Vec<String>,
+    now_utc: u32,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    let ln_address = match PaymentMethod::from_str(&pr.pay_method)? {
+        PaymentMethod::PmLnAddress => pr.pri_id.clone(),
+        PaymentMethod::PmNostrLightning | PaymentMethod::PmNostrZap => {
+            get_nostr_ln_address_cached(conn, &pr.pri_id, relays, now_utc)
+                .await
+                .map_err(|(_, e)| e)?
+        }
+        // Minted by us in whole sats, no wallet to ask
+        PaymentMethod::PmCashu => return Ok((1000, u64::MAX)),
+        // The offer node is asked when fetching the invoice
+        PaymentMethod::PmBolt12 => return Ok((0, u64::MAX)),
+    };
+    let params = fetch_pay_params(&ln_address).await?;
+    Ok((params.min_sendable, params.max_sendable))
+}
+
+/// Check a pay request against the amount range accepted by the miner's wallet, adjust its amount.
+/// Return None if the wallet would reject the payout now.
+fn apply_sendable_limits(
+    mut pr: PayRequest,
+    miner: &MinerSnapshot,
+    limits: &PayoutLimits,
+    sendable: (u64, u64),
     now: u32,
-) -> Result<(), Box<dyn Error>> {
-    if let Some(pr) = create_pay_request_if_needed(miner, default_payment_method, now)? {
-        let pr_id = db::payreq_insert_nocommit(conn, &pr)?;
-        miner.payreq_id = pr_id as i32;
-        println!(
-            "Payment request created, ID {}, user {}",
-            miner.payreq_id, miner.user_s
-        );
-        Ok(())
-    } else {
-        Ok(())
+) -> Result<Option<PayRequest>, Box<dyn Error>> {
+    let limits = limits.with_sendable(sendable.0, sendable.1);
+    let (to_pay, reject_reason) = calculate_to_pay_for_miner(miner, now, &limits)?;
+    match to_pay {
+        None => {
+            println!(
+                "No pay request now: {}  (sendable {}-{})",
+                reject_reason.unwrap_or_default(),
+                sendable.0,
+                sendable.1
+            );
+            Ok(None)
+        }
+        Some(to_pay) => {
+            if to_pay != pr.req_amnt {
+                println!(
+                    "Pay request amount adjusted to the wallet limits: {} -> {}  (sendable {}-{})",
+                    pr.req_amnt, to_pay, sendable.0, sendable.1
+                );
+                pr.req_amnt = to_pay;
+            }
+            Ok(Some(pr))
+        }
     }
 }
 
@@ -358,13 +555,48 @@ fn update_miner_snapshots_nocommit(conn: &Transaction) -> Result<u32, Box<dyn Er
     Ok(cnt)
 }
 
+/// Insert the pay requests, except for miners who have an open pay request by now
+/// (the open pay requests are re-read, as network lookups may have taken a while since).
+/// Return the number of pay requests created.
+fn insert_pay_requests_nocommit(
+    conntx: &Transaction,
+    snapshots: &mut [MinerSnapshot],
+    pay_requests: Vec<(usize, PayRequest)>,
+) -> Result<u32, Box<dyn Error>> {
+    let miner_ids_with_open_pay_request = db::payreq_get_all_non_final(conntx)?
+        .iter()
+        .map(|(pr, _paym)| pr.miner_id)
+        .collect::<HashSet<_>>();
+    let mut cnt_created = 0;
+    for (idx, pr) in pay_requests {
+        if miner_ids_with_open_pay_request.contains(&pr.miner_id) {
+            println!(
+                "WARNING: Miner {} has a payrequest by now, not creating another",
+                pr.miner_id
+            );
+            continue;
+        }
+        let miner = &mut snapshots[idx];
+        let pr_id = db::payreq_insert_nocommit(conntx, &pr)?;
+        miner.payreq_id = pr_id as i32;
+        println!(
+            "Payment request created, ID {}, user {}",
+            miner.payreq_id, miner.user_s
+        );
+        cnt_created += 1;
+    }
+    Ok(cnt_created)
+}
+
 // Update miner snapshots, including totals.
 // TODO: invoke only for changed items; but how?
 // Also computes amount scheduled for payment.
+// Return the number of pay requests created
 fn update_miner_snapshots_and_create_payreqs(
     conn: &mut Connection,
+    rt: &Runtime,
     default_payment_method: PaymentMethod,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<u32, Box<dyn Error>> {
     let now_utc = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
@@ -376,19 +608,43 @@ fn update_miner_snapshots_and_create_payreqs(
     let _ = update_miner_snapshots_nocommit(&conntx)?;
     let _ = conntx.commit()?;
 
-    let conntx = conn.transaction()?;
     println!("update_miner_snapshots_and_create_payreqs: Snapshots updated.");
 
     // Record open pay requests, not to create new request for the same miners
-    let open_pay_requests = db::payreq_get_all_non_final(&conntx)?;
+    let open_pay_requests = db::payreq_get_all_non_final(conn)?;
     let mut miner_ids_with_open_pay_request = HashMap::<u32, PayRequest>::new();
     for (pr, _paym) in &open_pay_requests {
         let _ = miner_ids_with_open_pay_request.insert(pr.miner_id, pr.clone());
     }
 
-    let mut snapshots = db::miner_ss_get_all(&conntx)?;
+    // Refresh the payout preferences of Nostr miners who may be paid now
+    let pool_limits = get_pool_payout_limits()?;
+    let npubs = db::miner_ss_get_all(conn)?
+        .iter()
+        .filter(|ss| {
+            ss.unpaid_cons >= pool_limits.threshold_min as i64
+                && !miner_ids_with_open_pay_request.contains_key(&ss.user_id)
+        })
+        .filter_map(|ss| npub_of_primary_id(&ss.user_s))
+        .collect::<Vec<_>>();
+    if !npubs.is_empty() {
+        match rt.block_on(timeout(
+            LOOKUP_DEADLINE,
+            refresh_payout_prefs(conn, &npubs, now_utc),
+        )) {
+            Err(_) => println!("WARNING: Refreshing payout prefs timed out, using cached ones"),
+            Ok(res) => println!(
+                "update_miner_snapshots_and_create_payreqs: Payout prefs refreshed ({})",
+                res?
+            ),
+        }
+    }
+
+    // Draft pay requests
+    let mut snapshots = db::miner_ss_get_all(conn)?;
     let mut cnt = 0;
-    for ss in &mut snapshots {
+    let mut drafts = Vec::new();
+    for (idx, ss) in snapshots.iter_mut().enumerate() {
         let id = ss.user_id;
 
         if miner_ids_with_open_pay_request.contains_key(&id) {
@@ -398,41 +654,116 @@ fn update_miner_snapshots_and_create_payreqs(
                 id, pr.id, pr.req_amnt
             );
         } else {
-            let _ = create_and_save_pay_request_if_needed(
-                &conntx,
+            let (user_setting, nostr_pref) = get_miner_payout_settings(conn, ss)?;
+            let limits = pool_limits.for_miner(user_setting.as_ref(), nostr_pref.as_ref());
+            if let Some(pr) = create_pay_request_if_needed(
                 ss,
                 default_payment_method,
+                user_setting.as_ref(),
+                nostr_pref.as_ref(),
+                &limits,
                 now_utc,
-            )?;
+            )? {
+                drafts.push((idx, pr, limits));
+            }
         }
         cnt += 1;
     }
 
+    // Check the drafts against the amount limits of the miners' wallets (LNURL minSendable/maxSendable).
+    // If the limits can't be obtained now, the pay request is created, the payer will retry.
+    let relays = if drafts.is_empty() {
+        Vec::new()
+    } else {
+        get_nostr_relays_from_config()?
+    };
+    // Concurrently, each bounded by the deadline
+    let sendable_limits = rt.block_on(join_all(drafts.iter().map(|(_, pr, _)| {
+        timeout(
+            LOOKUP_DEADLINE,
+            get_sendable_limits(conn, pr, &relays, now_utc),
+        )
+    })));
+    let mut pay_requests = Vec::new();
+    for ((idx, pr, limits), sendable) in drafts.into_iter().zip(sendable_limits) {
+        match sendable {
+            Err(_) => {
+                println!(
+                    "WARNING: Timed out getting the amount limits of the wallet of {}",
+                    pr.pri_id
+                );
+                pay_requests.push((idx, pr));
+            }
+            Ok(Err(e)) => {
+                println!(
+                    "WARNING: Could not get the amount limits of the wallet of {}, {}",
+                    pr.pri_id, e
+                );
+                pay_requests.push((idx, pr));
+            }
+            Ok(Ok(sendable)) => {
+                if let Some(pr) =
+                    apply_sendable_limits(pr, &snapshots[idx], &limits, sendable, now_utc)?
+                {
+                    pay_requests.push((idx, pr));
+                }
+            }
+        }
+    }
+
+    // Immediate, not to interleave with another writer between the re-read and the inserts
+    let conntx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let cnt_created = insert_pay_requests_nocommit(&conntx, &mut snapshots, pay_requests)?;
     let _ = conntx.commit()?;
     println!(
-        "update_miner_snapshots_and_create_payreqs: Updated miner snapshots (cnt {cnt}), created pay requests"
+        "update_miner_snapshots_and_create_payreqs: Updated miner snapshots (cnt {cnt}), created pay requests ({cnt_created})"
     );
+    Ok(cnt_created)
+}
+
+/// The legacy USER_METHOD_SETTING_OVERRIDE setting is not applied any more, fail if it has
+/// entries not imported into USER_SETTINGS, not to silently change the payment method of users
+fn check_user_method_setting_override_imported(
+    conn: &Connection,
+    envstr: &str,
+) -> Result<(), Box<dyn Error>> {
+    for (user_id, pm) in parse_user_method_setting_override(envstr) {
+        let imported =
+            db::user_setting_get(conn, user_id)?.is_some_and(|us| us.pay_method == pm.to_string());
+        if !imported {
+            return Err(format!(
+                "USER_METHOD_SETTING_OVERRIDE is set, but its entry for user {user_id} is not in USER_SETTINGS; \
+                import it with 'main_user_settings import-env', or remove the setting"
+            )
+            .into());
+        }
+    }
     Ok(())
 }
 
+// Return the number of pay requests created
 fn iteration(
     conn: &mut Connection,
+    rt: &Runtime,
     default_payment_method: PaymentMethod,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<u32, Box<dyn Error>> {
     println!("paycalc_payreq iteration: create ...");
-    let _ = update_miner_snapshots_and_create_payreqs(conn, default_payment_method)?;
+    let cnt_created = update_miner_snapshots_and_create_payreqs(conn, rt, default_payment_method)?;
     println!("paycalc_payreq iteration: create done, print");
     let _ = print_miner_snapshots(conn)?;
     let _ = print_pay_requests(conn)?;
-    Ok(())
+    Ok(cnt_created)
 }
 
-pub fn loop_iterations() -> Result<(), Box<dyn Error>> {
+/// Payreq loop; the payer is woken up when new pay requests are created
+pub fn loop_iterations(payer_wakeup: PayerWakeup) -> Result<(), Box<dyn Error>> {
     // Load environment variables from .env file
     dotenv::dotenv().ok();
 
     let dbfile = get_db_file("paycalc.db", false);
     let mut conn = Connection::open(&dbfile)?;
+    // For the network lookups (payout prefs, wallet amount limits)
+    let rt = Runtime::new()?;
 
     let payout_period_secs = env::var("PAYOUT_PERIOD_SECS")
         .unwrap_or("86400".into())
@@ -443,6 +774,12 @@ pub fn loop_iterations() -> Result<(), Box<dyn Error>> {
         payout_period_secs,
         default_payment_method.to_string()
     );
+    if let Ok(envstr) = env::var("USER_METHOD_SETTING_OVERRIDE") {
+        check_user_method_setting_override_imported(&conn, &envstr)?;
+        println!(
+            "WARNING: USER_METHOD_SETTING_OVERRIDE is no longer used (imported into USER_SETTINGS), it can be removed"
+        );
+    }
 
     loop {
         let now_utc = SystemTime::now()
@@ -482,10 +819,15 @@ pub fn loop_iterations() -> Result<(), Box<dyn Error>> {
 
         // Time!
         println!("paycalc_payreq loop_iteration: Start iteration ...");
-        let res = iteration(&mut conn, default_payment_method);
+        let res = iteration(&mut conn, &rt, default_payment_method);
         println!("paycalc_payreq loop_iteration: iteration done ({:?})", res);
-        if let Err(e) = res {
-            println!("ERROR in iteration, {e}");
+        match res {
+            Err(e) => println!("ERROR in iteration, {e}"),
+            Ok(cnt_created) => {
+                if cnt_created > 0 {
+                    payer_wakeup.wake();
+                }
+            }
         }
     }
     // Ok(())
@@ -537,6 +879,138 @@ mod tests {
         assert_eq!(unpaid_cons, -1665);
     }
 
+    fn test_pool_limits() -> PoolPayoutLimits {
+        PoolPayoutLimits {
+            threshold: 5_000,
+            maximum: 20_000_000,
+            granularity: 1000,
+            threshold_min: 2_000,
+            maximum_max: 100_000_000,
+        }
+    }
+
+    fn test_miner(unpaid_cons: i64) -> MinerSnapshot {
+        let now_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        MinerSnapshot::new(
+            1,
+            "test_user".to_string(),
+            now_utc - 86400,
+            100_000,
+            105_000,
+            93_000,
+            unpaid_cons,
+            unpaid_cons,
+            7,
+            now_utc,
+        )
+    }
+
+    #[test]
+    fn test_payout_limits_for_miner() {
+        let pool = test_pool_limits();
+        let limits = |threshold, maximum| PayoutLimits {
+            threshold,
+            maximum,
+            granularity: 1000,
+        };
+        assert_eq!(pool.for_miner(None, None), limits(5_000, 20_000_000));
+
+        let mut us = UserSetting::new_empty(1);
+        us.threshold_msat = 500_000;
+        us.max_payout_msat = 2_000_000;
+        assert_eq!(pool.for_miner(Some(&us), None), limits(500_000, 2_000_000));
+        // Rounded, bounded by the pool-wide min/max
+        us.threshold_msat = 1_500;
+        us.max_payout_msat = 900_000_000;
+        assert_eq!(pool.for_miner(Some(&us), None), limits(2_000, 100_000_000));
+        // Threshold above the default maximum raises the maximum
+        us.threshold_msat = 30_000_000;
+        us.max_payout_msat = 0;
+        assert_eq!(
+            pool.for_miner(Some(&us), None),
+            limits(30_000_000, 30_000_000)
+        );
+
+        // Threshold from the Nostr prefs, user settings take precedence
+        let pref =
+            NostrPayoutPref::new("npub1".into(), "".into(), 3_000, "".into(), "".into(), 0, 0);
+        assert_eq!(pool.for_miner(None, Some(&pref)), limits(3_000, 20_000_000));
+        us.threshold_msat = 8_000;
+        assert_eq!(
+            pool.for_miner(Some(&us), Some(&pref)),
+            limits(8_000, 20_000_000)
+        );
+
+        // Sendable range of the wallet
+        let l = limits(5_000, 20_000_000);
+        assert_eq!(l.with_sendable(1_000, 100_000_000), l);
+        assert_eq!(
+            l.with_sendable(10_500, 8_000_500),
+            limits(11_000, 8_000_000)
+        );
+    }
+
+    #[test]
+    fn test_calculate_to_pay_with_limits() {
+        let now_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let limits = PayoutLimits {
+            threshold: 5_000,
+            maximum: 8_000,
+            granularity: 1000,
+        };
+        let to_pay = |unpaid_cons, limits: &PayoutLimits| {
+            calculate_to_pay_for_miner(&test_miner(unpaid_cons), now_utc, limits)
+                .unwrap()
+                .0
+        };
+        assert_eq!(to_pay(4_999, &limits), None);
+        assert_eq!(to_pay(6_400, &limits), Some(6_000));
+        assert_eq!(to_pay(12_000, &limits), Some(8_000));
+        // minSendable of the wallet above the amount
+        assert_eq!(
+            to_pay(6_400, &limits.with_sendable(10_000, 1_000_000)),
+            None
+        );
+        // minSendable above the maximum: never payable
+        let unpayable = limits.with_sendable(9_000, 1_000_000);
+        assert_eq!(to_pay(12_000, &unpayable), None);
+    }
+
+    #[test]
+    fn test_apply_sendable_limits() {
+        let now_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let miner = test_miner(12_000);
+        let limits = test_pool_limits().for_miner(None, None);
+        let pr = PayRequest::new(
+            0,
+            1,
+            12_000,
+            "LNAD".into(),
+            "test@example.com".into(),
+            now_utc,
+            "".into(),
+        );
+        let res = apply_sendable_limits(pr.clone(), &miner, &limits, (1_000, 1_000_000), now_utc)
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.req_amnt, 12_000);
+        let res = apply_sendable_limits(pr.clone(), &miner, &limits, (1_000, 10_000), now_utc)
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.req_amnt, 10_000);
+        let res = apply_sendable_limits(pr, &miner, &limits, (50_000, 1_000_000), now_utc).unwrap();
+        assert!(res.is_none());
+    }
+
     #[test]
     fn test_create_pay_request_if_needed() {
         let now_utc = SystemTime::now()
@@ -557,8 +1031,15 @@ mod tests {
             7,
             now_utc,
         );
-        let result =
-            create_pay_request_if_needed(&mut miner, PaymentMethod::PmNostrZap, now_utc).unwrap();
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            None,
+            None,
+            &test_pool_limits().for_miner(None, None),
+            now_utc,
+        )
+        .unwrap();
         assert_eq!(result.unwrap().req_amnt, 10_000);
 
         // Below the threshold limit (5000 by default)
@@ -574,8 +1055,15 @@ mod tests {
             7,
             now_utc,
         );
-        let result =
-            create_pay_request_if_needed(&mut miner, PaymentMethod::PmNostrZap, now_utc).unwrap();
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            None,
+            None,
+            &test_pool_limits().for_miner(None, None),
+            now_utc,
+        )
+        .unwrap();
         assert!(result.is_none());
 
         // Stale (very old) with enough to pay --> ignore
@@ -591,8 +1079,180 @@ mod tests {
             7,
             now_utc - 25 * 86400,
         );
-        let result =
-            create_pay_request_if_needed(&mut miner, PaymentMethod::PmNostrZap, now_utc).unwrap();
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            None,
+            None,
+            &test_pool_limits().for_miner(None, None),
+            now_utc,
+        )
+        .unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_create_pay_request_with_nostr_pref() {
+        let now_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let npub = "npub12rv5lskctqxxs2c8rf2zlzc7xx3qpvzs3w4etgemauy9thegr43sf485vg";
+        let mut miner = MinerSnapshot::new(
+            1,
+            npub.to_string(),
+            now_utc - 86400,
+            100_000,
+            105_000,
+            93_000,
+            12_000,
+            10_000,
+            7,
+            now_utc,
+        );
+        let pref = |threshold_msat: u64, ln_address: &str| {
+            NostrPayoutPref::new(
+                npub.into(),
+                "".into(),
+                threshold_msat,
+                ln_address.into(),
+                "id".into(),
+                1000,
+                1000,
+            )
+        };
+
+        // Miner threshold higher than the amount
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            None,
+            Some(&pref(15_000, "")),
+            &test_pool_limits().for_miner(None, Some(&pref(15_000, ""))),
+            now_utc,
+        )
+        .unwrap();
         assert!(result.is_none());
+
+        // Miner threshold lower than the pool default, alternate address
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            None,
+            Some(&pref(1_000, "alt@example.com")),
+            &test_pool_limits().for_miner(None, Some(&pref(1_000, "alt@example.com"))),
+            now_utc,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.req_amnt, 10_000);
+        assert_eq!(result.pay_method, "LNAD");
+        assert_eq!(result.pri_id, "alt@example.com");
+    }
+
+    #[test]
+    fn test_create_pay_request_with_user_setting() {
+        let now_utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let mut miner = MinerSnapshot::new(
+            1,
+            "zappool@blink_sv".to_string(),
+            now_utc - 86400,
+            100_000,
+            105_000,
+            93_000,
+            12_000,
+            10_000,
+            7,
+            now_utc,
+        );
+        let mut us = UserSetting::new_empty(1);
+        us.pay_method = "NOLN".into();
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            Some(&us),
+            None,
+            &test_pool_limits().for_miner(Some(&us), None),
+            now_utc,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.pay_method, "NOLN");
+
+        // Paused
+        us.paused = true;
+        let result = create_pay_request_if_needed(
+            &mut miner,
+            PaymentMethod::PmNostrZap,
+            Some(&us),
+            None,
+            &test_pool_limits().for_miner(Some(&us), None),
+            now_utc,
+        )
+        .unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_insert_pay_requests_skips_open() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let payreq = |miner_id| {
+            PayRequest::new(
+                0,
+                miner_id,
+                5000,
+                "LNAD".into(),
+                "miner@example.com".into(),
+                1000,
+                "".into(),
+            )
+        };
+        // Created meanwhile for miner 1
+        let conntx = conn.transaction().unwrap();
+        let _ = db::payreq_insert_nocommit(&conntx, &payreq(1)).unwrap();
+        conntx.commit().unwrap();
+
+        let mut snapshots = vec![test_miner(10_000), test_miner(10_000)];
+        snapshots[1].user_id = 2;
+        let conntx = conn.transaction().unwrap();
+        let cnt = insert_pay_requests_nocommit(
+            &conntx,
+            &mut snapshots,
+            vec![(0, payreq(1)), (1, payreq(2))],
+        )
+        .unwrap();
+        conntx.commit().unwrap();
+        assert_eq!(cnt, 1);
+        assert_eq!(snapshots[0].payreq_id, 7);
+        assert!(snapshots[1].payreq_id > 1);
+        assert_eq!(db::payreq_get_all_non_final(&conn).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_check_user_method_setting_override_imported() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let envstr = "61:LNAD,62:ZAP";
+        assert!(check_user_method_setting_override_imported(&conn, envstr).is_err());
+
+        let mut us = UserSetting::new_empty(61);
+        us.pay_method = "LNAD".into();
+        db::user_setting_upsert(&conn, &us).unwrap();
+        assert!(check_user_method_setting_override_imported(&conn, envstr).is_err());
+
+        // Set differently since
+        let mut us = UserSetting::new_empty(62);
+        us.pay_method = "NOLN".into();
+        db::user_setting_upsert(&conn, &us).unwrap();
+        assert!(check_user_method_setting_override_imported(&conn, envstr).is_err());
+
+        us.pay_method = "ZAP".into();
+        db::user_setting_upsert(&conn, &us).unwrap();
+        assert!(check_user_method_setting_override_imported(&conn, envstr).is_ok());
+        assert!(check_user_method_setting_override_imported(&conn, "").is_ok());
     }
 }
@@ -1,35 +1,30 @@
 /// Payment method
+use common_rs::dto_pc::{NostrPayoutPref, UserSetting};
 use payer::common::{PaymentMethod, payment_methods};
 
 use std::env;
 use std::error::Error;
 use std::str::FromStr;
 
-fn get_user_method_setting_override_from_envstr(
-    userid: u32,
-    envstr: &str,
-) -> Option<PaymentMethod> {
-    let users = envstr.split(",");
-    for u in users.into_iter() {
-        let parts: Vec<_> = u.split(":").collect();
-        if parts.len() == 2 {
-            if userid.to_string() == parts[0] {
-                return PaymentMethod::from_str(parts[1]).ok();
-            }
+/// Parse the legacy USER_METHOD_SETTING_OVERRIDE setting, "userid:METHOD" pairs, comma-separated.
+/// Invalid entries are skipped. Used for importing into USER_SETTINGS.
+pub fn parse_user_method_setting_override(envstr: &str) -> Vec<(u32, PaymentMethod)> {
+    let mut res = Vec::new();
+    for u in envstr.split(",") {
+        let parts: Vec<_> = u.trim().split(":").collect();
+        if parts.len() == 2
+            && let (Ok(userid), Ok(pm)) =
+                (parts[0].parse::<u32>(), PaymentMethod::from_str(parts[1]))
+        {
+            res.push((userid, pm));
         }
     }
-    // not found
-    None
+    res
 }
 
-fn get_user_method_setting_override(userid: u32) -> Option<PaymentMethod> {
-    match env::var("USER_METHOD_SETTING_OVERRIDE").ok() {
-        None => None,
-        Some(s) => match get_user_method_setting_override_from_envstr(userid, &s) {
-            None => None,
-            Some(p) => Some(p),
-        },
-    }
+/// The payment method set for the user in USER_SETTINGS, if any
+fn get_user_method_setting(user_setting: Option<&UserSetting>) -> Option<PaymentMethod> {
+    user_setting.and_then(|us| PaymentMethod::from_str(&us.pay_method).ok())
 }
 
 pub fn get_default_payment_method_from_env() -> Result<PaymentMethod, Box<dyn Error>> {
@@ -66,6 +61,10 @@ fn guess_payment_method(orig_payment_id: &str) -> Result<Option<PaymentMethod>,
             return Ok(Some(PaymentMethod::PmLnAddress));
         }
     }
+    // A BOLT12 offer
+    if orig_payment_id.to_lowercase().starts_with("lno1") {
+        return Ok(Some(PaymentMethod::PmBolt12));
+    }
     // If it has '@', assume it is LA
     if orig_payment_id.contains("@") {
         return Ok(Some(PaymentMethod::PmLnAddress));
@@ -74,26 +73,100 @@ fn guess_payment_method(orig_payment_id: &str) -> Result<Option<PaymentMethod>,
     Ok(None)
 }
 
+/// The npub of a Nostr primary ID (possibly with a Nostr payment method prefix), None if not an npub
+pub fn npub_of_primary_id(orig_payment_id: &str) -> Option<String> {
+    let mut id = orig_payment_id;
+    for pm in [
+        PaymentMethod::PmNostrLightning,
+        PaymentMethod::PmNostrZap,
+        PaymentMethod::PmCashu,
+        PaymentMethod::PmBolt12,
+    ] {
+        if let Some(rest) = id.strip_prefix(&format!("{}:", pm.to_string())) {
+            id = rest;
+        }
+    }
+    if id.starts_with("npub1") {
+        Some(id.to_string())
+    } else {
+        None
+    }
+}
+
+/// Apply the payout preferences published by a Nostr miner.
+/// Return the payment method and primary ID to use, None if the preferences don't change them.
+fn apply_nostr_payout_pref(pref: &NostrPayoutPref) -> Option<(PaymentMethod, String)> {
+    let pref_pm = PaymentMethod::from_str(&pref.pay_method).ok();
+    match pref_pm {
+        // Alternate Lightning Address, if set, unless a Nostr method is preferred
+        None | Some(PaymentMethod::PmLnAddress) => {
+            if pref.ln_address.is_empty() {
+                None
+            } else {
+                Some((PaymentMethod::PmLnAddress, pref.ln_address.clone()))
+            }
+        }
+        Some(pm) => Some((pm, pref.npub.clone())),
+    }
+}
+
+/// Payment methods to fall back to if a payment method fails, in order.
+/// Only methods usable with the same primary ID (e.g. a Nostr npub) are included.
+fn fallback_methods(payment_method: PaymentMethod) -> Vec<PaymentMethod> {
+    match payment_method {
+        // e.g. no zap support by the LNURL server of the miner
+        PaymentMethod::PmNostrZap => vec![PaymentMethod::PmNostrLightning],
+        PaymentMethod::PmNostrLightning
+        | PaymentMethod::PmLnAddress
+        | PaymentMethod::PmCashu
+        | PaymentMethod::PmBolt12 => Vec::new(),
+    }
+}
+
+/// Determine the payment method of a miner, with its fallback chain.
+/// For Nostr miners the payout preferences published by them are applied (nostr_pref),
+/// the method set in the user settings (user_setting) takes precedence.
+/// Return: the payment methods in order, the first one is the primary one, and the payment ID
+/// (the original one, or an alternate Lightning Address from the preferences)
 pub fn determine_payment_method(
     userid: u32,
     orig_payment_id: &str,
     default_payment_method: PaymentMethod,
-) -> Result<PaymentMethod, Box<dyn Error>> {
-    if let Some(override_pm) = get_user_method_setting_override(userid) {
+    user_setting: Option<&UserSetting>,
+    nostr_pref: Option<&NostrPayoutPref>,
+) -> Result<(Vec<PaymentMethod>, String), Box<dyn Error>> {
+    let mut payment_id = orig_payment_id.to_string();
+    let payment_method = if let Some(override_pm) = get_user_method_setting(user_setting) {
         println!(
-            "Using payment method override {:?} for user {}",
+            "Using payment method setting {:?} for user {}",
             override_pm, userid
         );
-        return Ok(override_pm);
-    }
-    // TODO if guessed is Nostr, check user setting from Nostr
-    let guessed_pm = guess_payment_method(orig_payment_id)?;
-    let guessed_pm = guessed_pm.unwrap_or(default_payment_method);
-    println!(
-        "Using guessed payment method {:?} for user {}",
-        guessed_pm, userid,
-    );
-    Ok(guessed_pm)
+        override_pm
+    } else {
+        let guessed_pm = guess_payment_method(orig_payment_id)?;
+        let guessed_pm = guessed_pm.unwrap_or(default_payment_method);
+        let is_nostr = guessed_pm != PaymentMethod::PmLnAddress;
+        match nostr_pref.and_then(apply_nostr_payout_pref) {
+            Some((pref_pm, pref_id)) if is_nostr => {
+                println!(
+                    "Using payment method {:?} from Nostr payout prefs for user {}",
+                    pref_pm, userid,
+                );
+                payment_id = pref_id;
+                pref_pm
+            }
+            _ => {
+                println!(
+                    "Using guessed payment method {:?} for user {}",
+                    guessed_pm, userid,
+                );
+                guessed_pm
+            }
+        }
+    };
+    let mut chain = vec![payment_method];
+    chain.extend(fallback_methods(payment_method));
+    Ok((chain, payment_id))
 }
 
 pub fn adjusted_primary_id(
@@ -132,41 +205,31 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_get_user_setting_override_from_envstr() {
-        let s = "61:LNAD,62:NOLN,63:ZAP,72:NO_SUCH_PM";
+    fn test_parse_user_method_setting_override() {
+        let s = "61:LNAD,62:NOLN, 63:ZAP,72:NO_SUCH_PM,x:LNAD,64";
         assert_eq!(
-            get_user_method_setting_override_from_envstr(61, s),
-            Some(PaymentMethod::PmLnAddress)
+            parse_user_method_setting_override(s),
+            vec![
+                (61, PaymentMethod::PmLnAddress),
+                (62, PaymentMethod::PmNostrLightning),
+                (63, PaymentMethod::PmNostrZap),
+            ]
         );
-        assert_eq!(
-            get_user_method_setting_override_from_envstr(62, s),
-            Some(PaymentMethod::PmNostrLightning)
-        );
-        assert_eq!(
-            get_user_method_setting_override_from_envstr(63, s),
-            Some(PaymentMethod::PmNostrZap)
-        );
-        // Not present:
-        assert_eq!(get_user_method_setting_override_from_envstr(666, s), None);
-        // Invalid PM
-        assert_eq!(get_user_method_setting_override_from_envstr(72, s), None);
+        assert!(parse_user_method_setting_override("").is_empty());
     }
 
     #[test]
-    fn test_get_user_setting_override() {
-        assert_eq!(get_user_method_setting_override(12), None);
-        unsafe {
-            env::set_var("USER_METHOD_SETTING_OVERRIDE", "661:LNAD,662:NOLN");
-        }
+    fn test_get_user_method_setting() {
+        assert_eq!(get_user_method_setting(None), None);
+        let mut us = UserSetting::new_empty(661);
+        assert_eq!(get_user_method_setting(Some(&us)), None);
+        us.pay_method = "LNAD".into();
         assert_eq!(
-            get_user_method_setting_override(661),
+            get_user_method_setting(Some(&us)),
             Some(PaymentMethod::PmLnAddress)
         );
-        assert_eq!(
-            get_user_method_setting_override(662),
-            Some(PaymentMethod::PmNostrLightning)
-        );
-        assert_eq!(get_user_method_setting_override(666), None);
+        us.pay_method = "NO_SUCH_PM".into();
+        assert_eq!(get_user_method_setting(Some(&us)), None);
     }
 
     const NOSTR_ID1: &str = "npub12rv5lskctqxxs2c8rf2zlzc7xx3qpvzs3w4etgemauy9thegr43sf485vg";
@@ -197,6 +260,23 @@ mod test {
             let r = guess_payment_method(&format!("ZAP:{}", NOSTR_ID1)).unwrap();
             assert_eq!(r, Some(PaymentMethod::PmNostrZap));
         }
+        {
+            // payment method marker, 'CASHU:' cashu token by DM
+            let r = guess_payment_method(&format!("CASHU:{}", NOSTR_ID1)).unwrap();
+            assert_eq!(r, Some(PaymentMethod::PmCashu));
+        }
+        {
+            // payment method marker, 'BOLT12:' offer or nostr profile offer
+            let r = guess_payment_method(&format!("BOLT12:{}", NOSTR_ID1)).unwrap();
+            assert_eq!(r, Some(PaymentMethod::PmBolt12));
+        }
+        {
+            // BOLT12 offer
+            let r =
+                guess_payment_method("lno1qgsqvgnwgcg35z6ee2h3yczraddm72xrfua9uve2rlrm9deu7xyfzrc")
+                    .unwrap();
+            assert_eq!(r, Some(PaymentMethod::PmBolt12));
+        }
         {
             // legacy lightning address "LA:" marker
             let r = guess_payment_method("LA:zappool@blink_sv").unwrap();
@@ -204,31 +284,160 @@ mod test {
         }
     }
 
+    fn nostr_pref(pay_method: &str, ln_address: &str) -> NostrPayoutPref {
+        NostrPayoutPref::new(
+            NOSTR_ID1.into(),
+            pay_method.into(),
+            0,
+            ln_address.into(),
+            "id".into(),
+            1000,
+            1000,
+        )
+    }
+
+    #[test]
+    fn test_npub_of_primary_id() {
+        assert_eq!(npub_of_primary_id(NOSTR_ID1), Some(NOSTR_ID1.to_string()));
+        assert_eq!(
+            npub_of_primary_id(&format!("ZAP:{}", NOSTR_ID1)),
+            Some(NOSTR_ID1.to_string())
+        );
+        assert_eq!(
+            npub_of_primary_id(&format!("NOLN:{}", NOSTR_ID1)),
+            Some(NOSTR_ID1.to_string())
+        );
+        assert_eq!(
+            npub_of_primary_id(&format!("CASHU:{}", NOSTR_ID1)),
+            Some(NOSTR_ID1.to_string())
+        );
+        assert_eq!(
+            npub_of_primary_id(&format!("BOLT12:{}", NOSTR_ID1)),
+            Some(NOSTR_ID1.to_string())
+        );
+        assert_eq!(
+            npub_of_primary_id(
+                "BOLT12:lno1qgsqvgnwgcg35z6ee2h3yczraddm72xrfua9uve2rlrm9deu7xyfzrc"
+            ),
+            None
+        );
+        assert_eq!(npub_of_primary_id("zappool@blink_sv"), None);
+        assert_eq!(npub_of_primary_id(&format!("LNAD:{}", NOSTR_ID1)), None);
+    }
+
     #[test]
     fn test_determine_payment_method() {
         let default_default_pm = PaymentMethod::PmNostrLightning;
         // Cases with No override
         {
-            let res = determine_payment_method(666, NOSTR_ID1, default_default_pm).unwrap();
-            assert_eq!(res, PaymentMethod::PmNostrLightning);
+            let (res, _) =
+                determine_payment_method(666, NOSTR_ID1, default_default_pm, None, None).unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmNostrLightning]);
+        }
+        {
+            let (res, _) =
+                determine_payment_method(666, NOSTR_ID1, PaymentMethod::PmNostrZap, None, None)
+                    .unwrap();
+            // With fallback
+            assert_eq!(
+                res,
+                vec![PaymentMethod::PmNostrZap, PaymentMethod::PmNostrLightning]
+            );
         }
         {
-            let res = determine_payment_method(666, NOSTR_ID1, PaymentMethod::PmNostrZap).unwrap();
-            assert_eq!(res, PaymentMethod::PmNostrZap);
+            let (res, _) = determine_payment_method(
+                666,
+                "LA:zappool@blink_sv",
+                default_default_pm,
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmLnAddress]);
+        }
+        // Cases with Nostr payout prefs
+        {
+            let pref = nostr_pref("ZAP", "");
+            let (res, id) =
+                determine_payment_method(666, NOSTR_ID1, default_default_pm, None, Some(&pref))
+                    .unwrap();
+            assert_eq!(
+                res,
+                vec![PaymentMethod::PmNostrZap, PaymentMethod::PmNostrLightning]
+            );
+            assert_eq!(id, NOSTR_ID1);
+        }
+        {
+            // Alternate Lightning Address
+            let pref = nostr_pref("", "alt@example.com");
+            let (res, id) =
+                determine_payment_method(666, NOSTR_ID1, default_default_pm, None, Some(&pref))
+                    .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmLnAddress]);
+            assert_eq!(id, "alt@example.com");
         }
         {
-            let res =
-                determine_payment_method(666, "LA:zappool@blink_sv", default_default_pm).unwrap();
-            assert_eq!(res, PaymentMethod::PmLnAddress);
+            // Nostr method preferred over the address
+            let pref = nostr_pref("NOLN", "alt@example.com");
+            let (res, id) = determine_payment_method(
+                666,
+                &format!("ZAP:{}", NOSTR_ID1),
+                default_default_pm,
+                None,
+                Some(&pref),
+            )
+            .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmNostrLightning]);
+            assert_eq!(id, NOSTR_ID1);
         }
-        // Cases with With override from env
-        unsafe {
-            env::set_var("USER_METHOD_SETTING_OVERRIDE", "661:LNAD,662:NOLN");
+        {
+            // Empty prefs, no change
+            let pref = nostr_pref("", "");
+            let (res, id) =
+                determine_payment_method(666, NOSTR_ID1, default_default_pm, None, Some(&pref))
+                    .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmNostrLightning]);
+            assert_eq!(id, NOSTR_ID1);
         }
+        // Cases with a method in the user settings
+        let mut us = UserSetting::new_empty(662);
+        us.pay_method = "NOLN".into();
         {
-            let res =
-                determine_payment_method(662, "LA:zappool@blink_sv", default_default_pm).unwrap();
-            assert_eq!(res, PaymentMethod::PmNostrLightning);
+            let (res, _) = determine_payment_method(
+                662,
+                "LA:zappool@blink_sv",
+                default_default_pm,
+                Some(&us),
+                None,
+            )
+            .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmNostrLightning]);
+        }
+        {
+            // User setting wins over the Nostr payout prefs
+            let pref = nostr_pref("ZAP", "");
+            let (res, _) = determine_payment_method(
+                662,
+                NOSTR_ID1,
+                default_default_pm,
+                Some(&us),
+                Some(&pref),
+            )
+            .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmNostrLightning]);
+        }
+        {
+            // No method in the user settings
+            let us = UserSetting::new_empty(662);
+            let (res, _) = determine_payment_method(
+                662,
+                "LA:zappool@blink_sv",
+                default_default_pm,
+                Some(&us),
+                None,
+            )
+            .unwrap();
+            assert_eq!(res, vec![PaymentMethod::PmLnAddress]);
         }
     }
 
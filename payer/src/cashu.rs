@@ -0,0 +1,499 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE as BASE64_URL;
+use hex_conservative::DisplayHex;
+use nostr::Keys;
+use nostr::hashes::{Hash, sha256};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+
+//
+// Cashu ecash client: minting tokens from a mint, paid over Lightning (NUT-00, NUT-01, NUT-04),
+// for payouts as tokens. Tokens of a lost mint response are restored from the mint (NUT-09).
+//
+
+/// Domain separator of hash_to_curve (NUT-00)
+const DOMAIN_SEPARATOR: &[u8] = b"Secp256k1_HashToCurve_Cashu_";
+const TOKEN_V3_PREFIX: &str = "cashuA";
+const UNIT_SAT: &str = "sat";
+
+/// Cashu settings, from config
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CashuConfig {
+    /// URL of the mint, empty if not configured
+    pub mint_url: String,
+}
+
+impl CashuConfig {
+    /// From config: CASHU_MINT_URL
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let mint_url = env::var("CASHU_MINT_URL").unwrap_or_default();
+        Ok(Self {
+            mint_url: mint_url.trim().trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// State of a mint quote
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MintQuoteState {
+    Unpaid,
+    /// Invoice paid, tokens can be minted
+    Paid,
+    /// Tokens minted already
+    Issued,
+}
+
+/// Mint quote (NUT-04): the invoice to pay, for minting tokens
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct MintQuote {
+    pub quote: String,
+    /// BOLT11 invoice to pay
+    pub request: String,
+    #[serde(default)]
+    state: Option<String>,
+    /// Older mints have only a paid flag
+    #[serde(default)]
+    paid: Option<bool>,
+}
+
+impl MintQuote {
+    pub fn state(&self) -> MintQuoteState {
+        match self.state.as_deref() {
+            Some("PAID") => MintQuoteState::Paid,
+            Some("ISSUED") => MintQuoteState::Issued,
+            Some(_) => MintQuoteState::Unpaid,
+            None if self.paid == Some(true) => MintQuoteState::Paid,
+            None => MintQuoteState::Unpaid,
+        }
+    }
+}
+
+/// A token proof (NUT-00)
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Proof {
+    pub amount: u64,
+    /// Keyset ID
+    pub id: String,
+    pub secret: String,
+    #[serde(rename = "C")]
+    pub c: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Keyset {
+    id: String,
+    unit: String,
+    /// Public key of the mint per amount
+    keys: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+struct KeysResponse {
+    keysets: Vec<Keyset>,
+}
+
+/// Blinded message of a mint request, with its secret and blinding factor (NUT-00).
+/// Stored before the request, so that the token can be restored if the response is lost.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PremintOutput {
+    pub amount: u64,
+    /// Keyset ID
+    pub id: String,
+    pub secret: String,
+    /// Blinding factor (hex)
+    pub r: String,
+    #[serde(rename = "B_")]
+    pub b_: String,
+}
+
+impl PremintOutput {
+    fn blinded_message(&self) -> Value {
+        json!({"amount": self.amount, "id": self.id, "B_": self.b_})
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BlindSignature {
+    amount: u64,
+    #[serde(rename = "C_")]
+    c_: String,
+}
+
+#[derive(serde::Deserialize)]
+struct MintResponse {
+    signatures: Vec<BlindSignature>,
+}
+
+/// The blinded messages known to the mint, and their signatures (NUT-09)
+#[derive(serde::Deserialize)]
+struct RestoreResponse {
+    outputs: Vec<Value>,
+    /// Older mints use 'promises'
+    #[serde(alias = "promises")]
+    signatures: Vec<BlindSignature>,
+}
+
+/// Map a point to the curve (NUT-00): the first valid x coordinate of
+/// sha256(sha256(DOMAIN_SEPARATOR || message) || counter)
+pub fn hash_to_curve(message: &[u8]) -> Result<PublicKey, Box<dyn Error>> {
+    let msg_hash = sha256::Hash::hash(&[DOMAIN_SEPARATOR, message].concat());
+    for counter in 0u32..65536 {
+        let hash =
+            sha256::Hash::hash(&[msg_hash.as_byte_array(), &counter.to_le_bytes()[..]].concat());
+        if let Ok(point) = PublicKey::from_slice(&[&[0x02u8][..], hash.as_byte_array()].concat()) {
+            return Ok(point);
+        }
+    }
+    Err("No valid point found in hash_to_curve".into())
+}
+
+/// Blind a secret (BDHKE): B_ = Y + rG, with Y = hash_to_curve(secret)
+pub fn blind_message(secret: &[u8], r: &SecretKey) -> Result<PublicKey, Box<dyn Error>> {
+    let secp = Secp256k1::new();
+    let y = hash_to_curve(secret)?;
+    Ok(y.combine(&r.public_key(&secp))?)
+}
+
+/// Unblind a signature of the mint (BDHKE): C = C_ - rK
+pub fn unblind_signature(
+    c_: &PublicKey,
+    r: &SecretKey,
+    mint_pubkey: &PublicKey,
+) -> Result<PublicKey, Box<dyn Error>> {
+    let secp = Secp256k1::new();
+    let rk = mint_pubkey.mul_tweak(&secp, &Scalar::from(*r))?;
+    Ok(c_.combine(&rk.negate(&secp))?)
+}
+
+/// Y values of proofs (hex), hash_to_curve(secret), identifying them at the mint (NUT-07)
+pub fn proof_ys(proofs: &[Proof]) -> Result<Vec<String>, Box<dyn Error>> {
+    proofs
+        .iter()
+        .map(|p| Ok(hash_to_curve(p.secret.as_bytes())?.to_string()))
+        .collect()
+}
+
+/// Split an amount into powers of 2, ascending, as tokens are of such denominations
+pub fn split_amount(amount: u64) -> Vec<u64> {
+    (0..64)
+        .map(|i| 1u64 << i)
+        .filter(|a| amount & a != 0)
+        .collect()
+}
+
+/// Serialize proofs as a V3 token, "cashuA" + base64url(JSON) (NUT-00)
+pub fn encode_token_v3(mint_url: &str, proofs: &Vec<Proof>, memo: &str) -> String {
+    let token = json!({
+        "token": [{"mint": mint_url, "proofs": proofs}],
+        "unit": UNIT_SAT,
+        "memo": memo,
+    });
+    format!(
+        "{TOKEN_V3_PREFIX}{}",
+        BASE64_URL.encode(token.to_string().as_bytes())
+    )
+}
+
+/// Mint URL and proofs of a V3 token
+pub fn decode_token_v3(token: &str) -> Result<(String, Vec<Proof>), Box<dyn Error>> {
+    let encoded = token
+        .strip_prefix(TOKEN_V3_PREFIX)
+        .ok_or("Not a V3 Cashu token")?;
+    // Padding is optional
+    let mut encoded = encoded.to_string();
+    while encoded.len() % 4 != 0 {
+        encoded.push('=');
+    }
+    let json: Value = serde_json::from_slice(&BASE64_URL.decode(encoded)?)?;
+    let entry = &json["token"][0];
+    let mint_url = entry["mint"]
+        .as_str()
+        .ok_or("Missing mint URL")?
+        .to_string();
+    let proofs = serde_json::from_value(entry["proofs"].clone())?;
+    Ok((mint_url, proofs))
+}
+
+// Random 32 bytes
+fn random_bytes() -> [u8; 32] {
+    Keys::generate().secret_key().secret_bytes()
+}
+
+// Error of a mint request, nonfinal if it may go away on a retry (unreachable, server error)
+async fn mint_response<T: DeserializeOwned>(
+    res: Result<reqwest::Response, reqwest::Error>,
+) -> Result<T, (bool, Box<dyn Error>)> {
+    let resp = res.map_err(|e| (true, format!("Mint request failed, {e}").into()))?;
+    let status = resp.status();
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| (true, format!("Mint request failed, {e}").into()))?;
+    if !status.is_success() {
+        // Error response, {"detail":..,"code":..} (NUT-00)
+        let detail = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| v["detail"].as_str().map(|s| s.to_string()))
+            .unwrap_or(body);
+        return Err((
+            status.is_server_error(),
+            format!("Mint error, status {status}, {detail}").into(),
+        ));
+    }
+    serde_json::from_str(&body).map_err(|e| (true, format!("Invalid mint response, {e}").into()))
+}
+
+async fn mint_get<T: DeserializeOwned>(url: &str) -> Result<T, (bool, Box<dyn Error>)> {
+    mint_response(reqwest::get(url).await).await
+}
+
+async fn mint_post<T: DeserializeOwned>(
+    url: &str,
+    body: &Value,
+) -> Result<T, (bool, Box<dyn Error>)> {
+    let client = reqwest::Client::new();
+    mint_response(client.post(url).json(body).send().await).await
+}
+
+/// Request a mint quote for an amount, in sats (NUT-04)
+pub async fn request_mint_quote(
+    mint_url: &str,
+    amount_sat: u64,
+) -> Result<MintQuote, (bool, Box<dyn Error>)> {
+    mint_post(
+        &format!("{mint_url}/v1/mint/quote/bolt11"),
+        &json!({"amount": amount_sat, "unit": UNIT_SAT}),
+    )
+    .await
+}
+
+/// Get the current state of a mint quote (NUT-04)
+pub async fn get_mint_quote(
+    mint_url: &str,
+    quote_id: &str,
+) -> Result<MintQuote, (bool, Box<dyn Error>)> {
+    mint_get(&format!("{mint_url}/v1/mint/quote/bolt11/{quote_id}")).await
+}
+
+async fn get_sat_keyset(mint_url: &str) -> Result<Keyset, (bool, Box<dyn Error>)> {
+    let keys: KeysResponse = mint_get(&format!("{mint_url}/v1/keys")).await?;
+    keys.keysets
+        .into_iter()
+        .find(|k| k.unit == UNIT_SAT)
+        .ok_or((false, "Mint has no keyset for sats".into()))
+}
+
+/// Blinded messages for an amount, with random secrets, for the sat keyset of the mint.
+/// To be stored before minting with them.
+pub async fn premint_outputs(
+    mint_url: &str,
+    amount_sat: u64,
+) -> Result<Vec<PremintOutput>, (bool, Box<dyn Error>)> {
+    let keyset = get_sat_keyset(mint_url).await?;
+    let mut outputs = Vec::new();
+    for amount in split_amount(amount_sat) {
+        let secret = random_bytes().to_lower_hex_string();
+        let r = SecretKey::from_slice(&random_bytes()).map_err(|e| (false, e.into()))?;
+        let b_ = blind_message(secret.as_bytes(), &r).map_err(|e| (false, e))?;
+        outputs.push(PremintOutput {
+            amount,
+            id: keyset.id.clone(),
+            secret,
+            r: r.secret_bytes().to_lower_hex_string(),
+            b_: b_.to_string(),
+        });
+    }
+    Ok(outputs)
+}
+
+// Proofs from the signatures of the mint, one per output, in order
+async fn unblind_proofs(
+    mint_url: &str,
+    outputs: &[PremintOutput],
+    signatures: &[BlindSignature],
+) -> Result<Vec<Proof>, (bool, Box<dyn Error>)> {
+    if signatures.len() != outputs.len() {
+        return Err((
+            false,
+            format!(
+                "Mint returned {} signatures for {} outputs",
+                signatures.len(),
+                outputs.len()
+            )
+            .into(),
+        ));
+    }
+    let keyset = get_sat_keyset(mint_url).await?;
+    let mut proofs = Vec::new();
+    for (sig, output) in signatures.iter().zip(outputs) {
+        let unblind = || -> Result<PublicKey, Box<dyn Error>> {
+            if sig.amount != output.amount {
+                return Err(format!(
+                    "Signature amount {} differs from {}",
+                    sig.amount, output.amount
+                )
+                .into());
+            }
+            let mint_pubkey = keyset
+                .keys
+                .get(&output.amount.to_string())
+                .ok_or(format!("No mint key for amount {}", output.amount))?;
+            let c_ = PublicKey::from_str(&sig.c_)?;
+            let r = SecretKey::from_str(&output.r)?;
+            unblind_signature(&c_, &r, &PublicKey::from_str(mint_pubkey)?)
+        };
+        let c = unblind().map_err(|e| (false, e))?;
+        proofs.push(Proof {
+            amount: output.amount,
+            id: output.id.clone(),
+            secret: output.secret.clone(),
+            c: c.to_string(),
+        });
+    }
+    Ok(proofs)
+}
+
+/// Mint the proofs of a paid quote: the blinded outputs are signed by the mint,
+/// and unblinded (NUT-04)
+pub async fn mint_proofs(
+    mint_url: &str,
+    quote_id: &str,
+    outputs: &[PremintOutput],
+) -> Result<Vec<Proof>, (bool, Box<dyn Error>)> {
+    let blinded: Vec<Value> = outputs.iter().map(|o| o.blinded_message()).collect();
+    let resp: MintResponse = mint_post(
+        &format!("{mint_url}/v1/mint/bolt11"),
+        &json!({"quote": quote_id, "outputs": blinded}),
+    )
+    .await?;
+    unblind_proofs(mint_url, outputs, &resp.signatures).await
+}
+
+/// Restore the proofs of outputs signed already, e.g. the response of the mint request was lost
+/// (NUT-09). Fails if not all of them are known to the mint.
+pub async fn restore_proofs(
+    mint_url: &str,
+    outputs: &[PremintOutput],
+) -> Result<Vec<Proof>, (bool, Box<dyn Error>)> {
+    let blinded: Vec<Value> = outputs.iter().map(|o| o.blinded_message()).collect();
+    let resp: RestoreResponse = mint_post(
+        &format!("{mint_url}/v1/restore"),
+        &json!({ "outputs": blinded }),
+    )
+    .await?;
+    // Signatures are returned only for the known outputs, in order of the request
+    let restored: Vec<&str> = resp
+        .outputs
+        .iter()
+        .map(|o| o["B_"].as_str().unwrap_or_default())
+        .collect();
+    if restored != outputs.iter().map(|o| o.b_.as_str()).collect::<Vec<_>>() {
+        return Err((
+            false,
+            format!(
+                "Mint restored {} of {} outputs",
+                restored.len(),
+                outputs.len()
+            )
+            .into(),
+        ));
+    }
+    unblind_proofs(mint_url, outputs, &resp.signatures).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use hex_conservative::FromHex;
+
+    #[test]
+    fn test_hash_to_curve() {
+        // Test vectors of NUT-00
+        let msg = <[u8; 32]>::from_hex(
+            "0000000000000000000000000000000000000000000000000000000000000000",
+        )
+        .unwrap();
+        assert_eq!(
+            hash_to_curve(&msg).unwrap().to_string(),
+            "024cce997d3b518f739663b757deaec95bcd9473c30a14ac2fd04023a739d1a725"
+        );
+        let msg = <[u8; 32]>::from_hex(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+        assert_eq!(
+            hash_to_curve(&msg).unwrap().to_string(),
+            "022e7158e11c9506f1aa4248bf531298daa7febd6194f003edcd9b93ade6253acf"
+        );
+    }
+
+    #[test]
+    fn test_blind_sign_unblind() {
+        let secp = Secp256k1::new();
+        let k = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let r = SecretKey::from_slice(&[4u8; 32]).unwrap();
+        let secret = b"test_message";
+        let b_ = blind_message(secret, &r).unwrap();
+        // Signed by the mint: C_ = kB_
+        let c_ = b_.mul_tweak(&secp, &Scalar::from(k)).unwrap();
+        let c = unblind_signature(&c_, &r, &k.public_key(&secp)).unwrap();
+        // C = kY
+        let y = hash_to_curve(secret).unwrap();
+        assert_eq!(c, y.mul_tweak(&secp, &Scalar::from(k)).unwrap());
+    }
+
+    #[test]
+    fn test_split_amount() {
+        assert_eq!(split_amount(0), Vec::<u64>::new());
+        assert_eq!(split_amount(1), vec![1]);
+        assert_eq!(split_amount(21), vec![1, 4, 16]);
+        assert_eq!(split_amount(64), vec![64]);
+    }
+
+    #[test]
+    fn test_token_v3() {
+        let proofs = vec![Proof {
+            amount: 2,
+            id: "009a1f293253e41e".into(),
+            secret: "407915bc212be61a77e3e6d2aeb4c727980bda51cd06a6afc29e2861768a7837".into(),
+            c: "02bc9097997d81afb2cc7346b5e4345a9346bd2a506eb7958598a72f0cf85163ea".into(),
+        }];
+        let token = encode_token_v3("https://mint.example.com", &proofs, "Thanks");
+        assert!(token.starts_with("cashuA"));
+        let (mint_url, decoded) = decode_token_v3(&token).unwrap();
+        assert_eq!(mint_url, "https://mint.example.com");
+        assert_eq!(decoded, proofs);
+        assert!(decode_token_v3("cashuBxyz").is_err());
+    }
+
+    #[test]
+    fn test_mint_quote_state() {
+        let quote = |s: &str| serde_json::from_str::<MintQuote>(s).unwrap().state();
+        assert_eq!(
+            quote(r#"{"quote":"q","request":"ln","state":"UNPAID"}"#),
+            MintQuoteState::Unpaid
+        );
+        assert_eq!(
+            quote(r#"{"quote":"q","request":"ln","state":"PAID"}"#),
+            MintQuoteState::Paid
+        );
+        assert_eq!(
+            quote(r#"{"quote":"q","request":"ln","state":"ISSUED"}"#),
+            MintQuoteState::Issued
+        );
+        assert_eq!(
+            quote(r#"{"quote":"q","request":"ln","paid":true}"#),
+            MintQuoteState::Paid
+        );
+    }
+}
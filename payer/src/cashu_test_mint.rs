@@ -0,0 +1,334 @@
+use crate::mock_backend::MockBackend;
+
+use nostr::hashes::{Hash, sha256};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//
+// Local Cashu mint stand-in, for tests.
+// Serves mint quotes with invoices of a MockBackend (paid once the mock has paid them),
+// the keys of one keyset, and signs blinded messages with per-amount secret keys.
+// Signed blinded messages can be restored (NUT-09).
+//
+
+pub const TEST_KEYSET_ID: &str = "00ad268c4d1f5826";
+
+#[derive(Clone, Debug)]
+pub struct MintServerConfig {
+    /// The first this many mint requests fail with HTTP 500
+    pub mint_failures: u32,
+    /// HTTP status of the quote endpoint
+    pub quote_status: u16,
+    /// The first this many successful mint requests are signed, but answered with HTTP 500,
+    /// as if the response was lost
+    pub lost_mint_responses: u32,
+    /// Restore endpoint available (NUT-09)
+    pub restore_supported: bool,
+}
+
+impl Default for MintServerConfig {
+    fn default() -> Self {
+        Self {
+            mint_failures: 0,
+            quote_status: 200,
+            lost_mint_responses: 0,
+            restore_supported: true,
+        }
+    }
+}
+
+// Mutable state of the server, besides the quotes
+struct ServerState {
+    mint_failures: u32,
+    lost_mint_responses: u32,
+    /// Signatures of the blinded messages signed so far, by B_
+    signatures: HashMap<String, Value>,
+}
+
+#[derive(Clone, Debug)]
+struct TestQuote {
+    amount_sat: u64,
+    invoice: String,
+    issued: bool,
+}
+
+/// A running stand-in mint. Stops when dropped.
+pub struct CashuTestMint {
+    pub port: u16,
+    quotes: Arc<Mutex<HashMap<String, TestQuote>>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for CashuTestMint {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Secret key of the mint for an amount
+pub fn mint_secret_key(amount: u64) -> SecretKey {
+    let hash = sha256::Hash::hash(format!("test mint key {amount}").as_bytes());
+    SecretKey::from_slice(hash.as_byte_array()).unwrap()
+}
+
+impl CashuTestMint {
+    /// Start a mint on a free local port
+    pub async fn start(config: MintServerConfig, mock: MockBackend) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let quotes = Arc::new(Mutex::new(HashMap::new()));
+        let quotes_server = quotes.clone();
+        let handle = tokio::spawn(async move {
+            let mut state = ServerState {
+                mint_failures: config.mint_failures,
+                lost_mint_responses: config.lost_mint_responses,
+                signatures: HashMap::new(),
+            };
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Err(_) => continue,
+                    Ok(s) => s,
+                };
+                handle_connection(stream, &config, &mut state, &mock, &quotes_server).await;
+            }
+        });
+        Self {
+            port,
+            quotes,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    pub fn quote_count(&self) -> usize {
+        self.quotes.lock().unwrap().len()
+    }
+
+    pub fn issued_count(&self) -> usize {
+        self.quotes
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|q| q.issued)
+            .count()
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, status: u16, body: &str) {
+    let resp = format!(
+        "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(resp.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn error_body(detail: &str) -> String {
+    json!({"detail": detail, "code": 0}).to_string()
+}
+
+// Read a request: head and body (by Content-Length)
+async fn read_request(stream: &mut TcpStream) -> (String, String) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        match stream.read(&mut chunk).await {
+            Err(_) | Ok(0) => break None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break Some(pos + 4);
+        }
+    };
+    let head_end = head_end.unwrap_or(buf.len());
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let content_length = head
+        .lines()
+        .find_map(|l| {
+            let (k, v) = l.split_once(":")?;
+            if k.trim().eq_ignore_ascii_case("content-length") {
+                v.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+    while buf.len() < head_end + content_length {
+        match stream.read(&mut chunk).await {
+            Err(_) | Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let body = String::from_utf8_lossy(&buf[head_end..]).to_string();
+    (head, body)
+}
+
+fn quote_json(id: &str, quote: &TestQuote, mock: &MockBackend) -> Value {
+    let paid = mock
+        .paid_invoices()
+        .iter()
+        .any(|p| p.invoice == quote.invoice);
+    let state = if quote.issued {
+        "ISSUED"
+    } else if paid {
+        "PAID"
+    } else {
+        "UNPAID"
+    };
+    json!({"quote": id, "request": quote.invoice, "state": state, "expiry": 0})
+}
+
+// Signatures of the blinded messages of a mint request, or an error
+fn sign_outputs(outputs: &Value, amount_sat: u64) -> Result<Vec<Value>, String> {
+    let secp = Secp256k1::new();
+    let outputs = outputs.as_array().ok_or("Missing outputs")?;
+    let mut total = 0;
+    let mut signatures = Vec::new();
+    for output in outputs {
+        let amount = output["amount"].as_u64().ok_or("Missing amount")?;
+        let b_ = PublicKey::from_str(output["B_"].as_str().unwrap_or_default())
+            .map_err(|e| e.to_string())?;
+        let c_ = b_
+            .mul_tweak(&secp, &Scalar::from(mint_secret_key(amount)))
+            .map_err(|e| e.to_string())?;
+        total += amount;
+        signatures.push(json!({"amount": amount, "id": TEST_KEYSET_ID, "C_": c_.to_string()}));
+    }
+    if total != amount_sat {
+        return Err(format!(
+            "Outputs total {total} differs from quote amount {amount_sat}"
+        ));
+    }
+    Ok(signatures)
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    config: &MintServerConfig,
+    state: &mut ServerState,
+    mock: &MockBackend,
+    quotes: &Arc<Mutex<HashMap<String, TestQuote>>>,
+) {
+    let (head, body) = read_request(&mut stream).await;
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+    let body: Value = serde_json::from_str(&body).unwrap_or_default();
+
+    if method == "GET" && path == "/v1/keys" {
+        let secp = Secp256k1::new();
+        let keys: HashMap<String, String> = (0..20)
+            .map(|i| 1u64 << i)
+            .map(|a| {
+                (
+                    a.to_string(),
+                    mint_secret_key(a).public_key(&secp).to_string(),
+                )
+            })
+            .collect();
+        let resp = json!({"keysets": [{"id": TEST_KEYSET_ID, "unit": "sat", "keys": keys}]});
+        return write_response(&mut stream, 200, &resp.to_string()).await;
+    }
+
+    if method == "POST" && path == "/v1/mint/quote/bolt11" {
+        if config.quote_status != 200 {
+            return write_response(&mut stream, config.quote_status, &error_body("Mock error"))
+                .await;
+        }
+        let amount_sat = body["amount"].as_u64().unwrap_or(0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let invoice = mock.create_invoice(amount_sat * 1000, None, now, 3600);
+        let quote = TestQuote {
+            amount_sat,
+            invoice,
+            issued: false,
+        };
+        let resp = {
+            let mut quotes = quotes.lock().unwrap();
+            let id = format!("quote{}", quotes.len() + 1);
+            let resp = quote_json(&id, &quote, mock);
+            let _ = quotes.insert(id, quote);
+            resp
+        };
+        return write_response(&mut stream, 200, &resp.to_string()).await;
+    }
+
+    if method == "GET"
+        && let Some(id) = path.strip_prefix("/v1/mint/quote/bolt11/")
+    {
+        let resp = quotes
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|q| quote_json(id, q, mock));
+        return match resp {
+            None => write_response(&mut stream, 400, &error_body("Unknown quote")).await,
+            Some(r) => write_response(&mut stream, 200, &r.to_string()).await,
+        };
+    }
+
+    if method == "POST" && path == "/v1/mint/bolt11" {
+        if state.mint_failures > 0 {
+            state.mint_failures -= 1;
+            return write_response(&mut stream, 500, &error_body("Mock error")).await;
+        }
+        let id = body["quote"].as_str().unwrap_or_default().to_string();
+        let res = {
+            let mut quotes = quotes.lock().unwrap();
+            match quotes.get_mut(&id) {
+                None => Err("Unknown quote".to_string()),
+                Some(q) if q.issued => Err("Quote already issued".to_string()),
+                Some(q) if quote_json(&id, q, mock)["state"] != "PAID" => {
+                    Err("Quote not paid".to_string())
+                }
+                Some(q) => sign_outputs(&body["outputs"], q.amount_sat).inspect(|_| {
+                    q.issued = true;
+                }),
+            }
+        };
+        let signatures = match res {
+            Err(e) => return write_response(&mut stream, 400, &error_body(&e)).await,
+            Ok(s) => s,
+        };
+        for (output, sig) in body["outputs"].as_array().unwrap().iter().zip(&signatures) {
+            let b_ = output["B_"].as_str().unwrap_or_default().to_string();
+            let _ = state.signatures.insert(b_, sig.clone());
+        }
+        if state.lost_mint_responses > 0 {
+            state.lost_mint_responses -= 1;
+            return write_response(&mut stream, 500, &error_body("Mock lost response")).await;
+        }
+        let resp = json!({ "signatures": signatures });
+        return write_response(&mut stream, 200, &resp.to_string()).await;
+    }
+
+    if method == "POST" && path == "/v1/restore" && config.restore_supported {
+        // The known outputs and their signatures, in order of the request
+        let mut outputs = Vec::new();
+        let mut signatures = Vec::new();
+        for output in body["outputs"].as_array().cloned().unwrap_or_default() {
+            let b_ = output["B_"].as_str().unwrap_or_default();
+            if let Some(sig) = state.signatures.get(b_) {
+                outputs.push(output.clone());
+                signatures.push(sig.clone());
+            }
+        }
+        let resp = json!({"outputs": outputs, "signatures": signatures});
+        return write_response(&mut stream, 200, &resp.to_string()).await;
+    }
+
+    write_response(&mut stream, 404, "{}").await
+}
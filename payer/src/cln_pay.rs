@@ -1,42 +1,155 @@
 use crate::common::PaymentResult;
+use crate::ln_backend::{
+    BackendBalance, DecodedInvoice, LightningBackend, LookupStatus, PaymentLookup,
+};
 
+use common_rs::error_codes::*;
+
+use async_trait::async_trait;
 use cln_rpc::ClnRpc;
 use cln_rpc::model::{requests, responses};
+use cln_rpc::primitives::{Amount, ChannelState, RpcError, Sha256};
 use hex_conservative::display::DisplayHex;
 
 use std::env;
 use std::error::Error;
 use std::fs;
+use std::str::FromStr;
 
 // from pyln.client import LightningRpc
 // import os
 
-fn get_rpc_path() -> Result<String, Box<dyn Error>> {
+/// Core Lightning backend, through the cln-rpc unix socket
+pub struct ClnBackend {
+    rpc_path: String,
+}
+
+// Default RPC pipe location, for the current user
+fn default_rpc_path() -> String {
     // Create an instance of the LightningRpc object
     // clnrpc = LightningRpc("/tmp/lightning1/lightning-rpc")
-    let user = env::var("USER")?;
+    let user = env::var("USER").unwrap_or_default();
     // print(f"user: {user}")
-    let rpc_pipe_path = format!("/home/{user}/.lightning/{user}/lightning-rpc");
+    format!("/home/{user}/.lightning/{user}/lightning-rpc")
+}
+
+// CLN pay error codes, see lightning-pay(7)
+const PAY_DESTINATION_PERM_FAIL: i32 = 203;
+const PAY_ROUTE_NOT_FOUND: i32 = 205;
+const PAY_ROUTE_TOO_EXPENSIVE: i32 = 206;
+// Messages of pay failures due to our channel balance, e.g.
+// "We don't have sufficient funds available for ..." (no separate error code)
+const PAY_INSUFFICIENT_FUNDS_MESSAGES: &[&str] = &["sufficient funds", "not enough funds"];
+// CLN fetchinvoice error codes, see lightning-fetchinvoice(7)
+const FETCHINVOICE_OFFER_EXPIRED: i32 = 1002;
+const FETCHINVOICE_OFFER_ERROR_REPLY: i32 = 1004;
+// Expiry of a BOLT12 invoice without invoice_relative_expiry, secs (BOLT 12 default)
+const BOLT12_INVOICE_DEFAULT_EXPIRY: u64 = 7200;
+
+// Whether a pay failure is due to our funds, not the route
+fn is_insufficient_funds(err: &RpcError) -> bool {
+    let message = err.message.to_lowercase();
+    PAY_INSUFFICIENT_FUNDS_MESSAGES
+        .iter()
+        .any(|m| message.contains(m))
+}
+
+// Result of a failed pay RPC call
+fn pay_error_result(err: &RpcError, max_fee_msat: u64) -> PaymentResult {
+    let errstr = format!("ERROR: CLN pay failed, {}", err);
+    println!("{errstr}");
+    match err.code {
+        Some(PAY_ROUTE_TOO_EXPENSIVE) => PaymentResult::new(
+            false,
+            true,
+            ERROR_LN_BOLT11_FEE_CAP_EXCEEDED,
+            &format!(
+                "No route within fee cap of {max_fee_msat} msat, {}",
+                err.message
+            ),
+            "",
+            "",
+            0,
+            0,
+            "",
+        ),
+        Some(PAY_DESTINATION_PERM_FAIL) => {
+            PaymentResult::new(false, false, 0, &errstr, "", "", 0, 0, "")
+        }
+        Some(PAY_ROUTE_NOT_FOUND) | None if is_insufficient_funds(err) => PaymentResult::new(
+            false,
+            true,
+            ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS,
+            &errstr,
+            "",
+            "",
+            0,
+            0,
+            "",
+        ),
+        _ => PaymentResult::new(false, true, 0, &errstr, "", "", 0, 0, ""),
+    }
+}
+
+fn check_rpc_path(rpc_pipe_path: &str) -> Result<(), Box<dyn Error>> {
     // print(f"rpc_pipe_path {rpc_pipe_path}")
-    if !fs::exists(&rpc_pipe_path).unwrap_or(false) {
+    if !fs::exists(rpc_pipe_path).unwrap_or(false) {
         let msg = format!(
-            "ERROR: RPC pipe doesn't exists, CLN not runnig or not accessible, pipe '{rpc_pipe_path}' user '{user}'"
+            "ERROR: RPC pipe doesn't exists, CLN not runnig or not accessible, pipe '{rpc_pipe_path}'"
         );
         println!("{msg}");
         Err(msg.into())
     } else {
-        Ok(rpc_pipe_path)
+        Ok(())
     }
 }
 
-/// May throw
-pub async fn pay_invoice(
-    invoice: &str,
-    _amnt_msat: u64,
-    label: &str,
-) -> Result<PaymentResult, Box<dyn Error>> {
-    let rpc_pipe_path = match get_rpc_path() {
-        Err(e) => {
+impl ClnBackend {
+    pub fn new(rpc_path: &str) -> Self {
+        Self {
+            rpc_path: rpc_path.to_string(),
+        }
+    }
+
+    /// RPC pipe from CLN_RPC_PATH, or the default location for the user
+    pub fn new_from_config() -> Self {
+        let rpc_path = env::var("CLN_RPC_PATH").unwrap_or(default_rpc_path());
+        Self::new(&rpc_path)
+    }
+
+    async fn connect(&self) -> Result<ClnRpc, Box<dyn Error>> {
+        if let Err(e) = check_rpc_path(&self.rpc_path) {
+            return Err(format!("CLN not runnig or not accessible, {:?}", e).into());
+        }
+        let rpc = ClnRpc::new(&self.rpc_path).await?;
+        Ok(rpc)
+    }
+
+    // Get funds info
+    async fn get_funds_info(&self) -> Result<responses::ListfundsResponse, Box<dyn Error>> {
+        let mut rpc = self.connect().await?;
+
+        let funds_req = requests::ListfundsRequest { spent: None };
+        let funds_resp: responses::ListfundsResponse = rpc.call_typed(&funds_req).await?;
+        Ok(funds_resp)
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for ClnBackend {
+    fn kind_name(&self) -> String {
+        format!("CLN ({})", self.rpc_path)
+    }
+
+    /// May throw
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        _amnt_msat: u64,
+        label: &str,
+        max_fee_msat: u64,
+    ) -> Result<PaymentResult, Box<dyn Error>> {
+        if let Err(e) = check_rpc_path(&self.rpc_path) {
             return Ok(PaymentResult::new(
                 false,
                 true,
@@ -49,104 +162,264 @@ pub async fn pay_invoice(
                 "",
             ));
         }
-        Ok(p) => p,
-    };
-    let mut rpc = ClnRpc::new(rpc_pipe_path).await?;
-
-    let info_req = requests::GetinfoRequest {};
-    let info_resp: responses::GetinfoResponse = rpc.call_typed(&info_req).await?;
-    println!("info {:?}", info_resp);
-    // funds = clnrpc.listfunds()
-    // print(funds)
-
-    let pay_req = requests::PayRequest {
-        bolt11: invoice.to_string(),
-        label: Some(label.to_string()),
-        amount_msat: None,
-        description: None,
-        exclude: None,
-        exemptfee: None,
-        localinvreqid: None,
-        maxdelay: None,
-        maxfee: None,
-        maxfeepercent: None,
-        partial_msat: None,
-        retry_for: None,
-        riskfactor: None,
-    };
-    let pay_resp: responses::PayResponse = rpc.call_typed(&pay_req).await?;
-    println!("info {:?}", info_resp);
-
-    let status = pay_resp.status;
-    let amount_sent_msat = pay_resp.amount_sent_msat.msat();
-    let amount_msat = pay_resp.amount_msat.msat();
-    let payment_hash = pay_resp.payment_hash.to_string();
-    let payment_preimage = pay_resp.payment_preimage.to_vec().to_lower_hex_string();
-    println!(
-        "DEBUG: CLN response: {:?} {} {} {} {} {:?}",
-        status, amount_sent_msat, amount_msat, payment_hash, payment_preimage, pay_resp
-    );
-
-    if status != responses::PayStatus::COMPLETE {
-        let errstr = format!("ERROR: Non-complete status, {:?}", status);
-        println!("{errstr}");
-        return Ok(PaymentResult::new(
-            false, true, 0, &errstr, "", "", 0, 0, "",
-        ));
+        let mut rpc = ClnRpc::new(&self.rpc_path).await?;
+
+        // BOLT12 invoices are also paid with pay
+        let pay_req = requests::PayRequest {
+            bolt11: invoice.to_string(),
+            label: Some(label.to_string()),
+            amount_msat: None,
+            description: None,
+            exclude: None,
+            exemptfee: None,
+            localinvreqid: None,
+            maxdelay: None,
+            maxfee: Some(Amount::from_msat(max_fee_msat)),
+            maxfeepercent: None,
+            partial_msat: None,
+            retry_for: None,
+            riskfactor: None,
+        };
+        let pay_resp: responses::PayResponse = match rpc.call_typed(&pay_req).await {
+            Err(e) => return Ok(pay_error_result(&e, max_fee_msat)),
+            Ok(r) => r,
+        };
+
+        let status = pay_resp.status;
+        let amount_sent_msat = pay_resp.amount_sent_msat.msat();
+        let amount_msat = pay_resp.amount_msat.msat();
+        let payment_hash = pay_resp.payment_hash.to_string();
+        let payment_preimage = pay_resp.payment_preimage.to_vec().to_lower_hex_string();
+
+        if status != responses::PayStatus::COMPLETE {
+            let errstr = format!("ERROR: Non-complete status, {:?}", status);
+            println!("{errstr}");
+            return Ok(PaymentResult::new(
+                false, true, 0, &errstr, "", "", 0, 0, "",
+            ));
+        }
+
+        let fee = (amount_sent_msat - amount_msat) as u32;
+        let reference = format!("{payment_preimage} {payment_hash}");
+
+        Ok(PaymentResult::new(
+            true,
+            false,
+            0,
+            "OK",
+            "",
+            "",
+            amount_sent_msat,
+            fee,
+            &reference,
+        ))
     }
 
-    let fee = (amount_sent_msat - amount_msat) as u32;
-    let reference = format!("{payment_preimage} {payment_hash}");
-
-    Ok(PaymentResult::new(
-        true,
-        false,
-        0,
-        "OK",
-        "",
-        "",
-        amount_sent_msat,
-        fee,
-        &reference,
-    ))
-}
+    async fn decode_invoice(&self, invoice: &str) -> Result<DecodedInvoice, Box<dyn Error>> {
+        let mut rpc = self.connect().await?;
 
-// Get node info
-#[allow(dead_code)]
-async fn get_info() -> Result<responses::GetinfoResponse, Box<dyn Error>> {
-    let rpc_pipe_path = match get_rpc_path() {
-        Err(e) => return Err(format!("CLN not runnig or not accessible, {:?}", e).into()),
-        Ok(p) => p,
-    };
-    let mut rpc = ClnRpc::new(rpc_pipe_path).await?;
+        let decode_req = requests::DecodeRequest {
+            string: invoice.to_string(),
+        };
+        let decode_resp: responses::DecodeResponse = rpc.call_typed(&decode_req).await?;
+        if !decode_resp.valid {
+            return Err(format!("Invalid invoice, '{invoice}'").into());
+        }
+        if decode_resp.item_type == responses::DecodeType::BOLT12_INVOICE {
+            let payment_hash = match decode_resp.invoice_payment_hash {
+                None => return Err(format!("Invoice has no payment hash, '{invoice}'").into()),
+                Some(h) => h,
+            };
+            return Ok(DecodedInvoice {
+                payment_hash,
+                amount_msat: decode_resp.invoice_amount_msat.map(|a| a.msat()),
+                description_hash: None,
+                created_at: decode_resp.invoice_created_at.unwrap_or(0),
+                expiry: decode_resp
+                    .invoice_relative_expiry
+                    .map(|e| e as u64)
+                    .unwrap_or(BOLT12_INVOICE_DEFAULT_EXPIRY),
+            });
+        }
+        let payment_hash = match decode_resp.payment_hash {
+            None => return Err(format!("Invoice has no payment hash, '{invoice}'").into()),
+            Some(h) => h.to_string(),
+        };
+        Ok(DecodedInvoice {
+            payment_hash,
+            amount_msat: decode_resp.amount_msat.map(|a| a.msat()),
+            description_hash: decode_resp.description_hash.map(|h| h.to_string()),
+            created_at: decode_resp.created_at.unwrap_or(0),
+            expiry: decode_resp.expiry.unwrap_or(0),
+        })
+    }
 
-    let info_req = requests::GetinfoRequest {};
-    let info_resp: responses::GetinfoResponse = rpc.call_typed(&info_req).await?;
-    println!("info {:?}", info_resp);
+    async fn fetch_invoice(
+        &self,
+        offer: &str,
+        amnt_msat: u64,
+        payer_note: &str,
+    ) -> Result<String, (bool, Box<dyn Error>)> {
+        let mut rpc = self.connect().await.map_err(|e| (true, e))?;
 
-    Ok(info_resp)
-}
+        let decode_req = requests::DecodeRequest {
+            string: offer.to_string(),
+        };
+        let decode_resp = match rpc.call_typed(&decode_req).await {
+            Err(e) => return Err((true, format!("CLN decode failed, {e}").into())),
+            Ok(r) => r,
+        };
+        if decode_resp.item_type != responses::DecodeType::BOLT12_OFFER || !decode_resp.valid {
+            return Err((false, format!("Not a valid BOLT12 offer, '{offer}'").into()));
+        }
+        // Amount is given only for offers without a fixed amount
+        let amount_msat = match decode_resp.offer_amount_msat {
+            None => Some(Amount::from_msat(amnt_msat)),
+            Some(a) if a.msat() == amnt_msat => None,
+            Some(a) => {
+                return Err((
+                    false,
+                    format!("Offer amount {} differs from {amnt_msat}", a.msat()).into(),
+                ));
+            }
+        };
+
+        let fetch_req = requests::FetchinvoiceRequest {
+            offer: offer.to_string(),
+            amount_msat,
+            bip353: None,
+            payer_metadata: None,
+            payer_note: Some(payer_note.to_string()),
+            quantity: None,
+            recurrence_counter: None,
+            recurrence_label: None,
+            recurrence_start: None,
+            timeout: None,
+        };
+        match rpc.call_typed(&fetch_req).await {
+            Err(e) => {
+                let nonfinal = !matches!(
+                    e.code,
+                    Some(FETCHINVOICE_OFFER_EXPIRED) | Some(FETCHINVOICE_OFFER_ERROR_REPLY)
+                );
+                Err((nonfinal, format!("CLN fetchinvoice failed, {e}").into()))
+            }
+            Ok(r) => Ok(r.invoice),
+        }
+    }
+
+    async fn get_balance(&self) -> Result<BackendBalance, Box<dyn Error>> {
+        let funds = self.get_funds_info().await?;
+        let mut balance = BackendBalance::default();
+        for ch in &funds.channels {
+            if ch.state == ChannelState::CHANNELD_NORMAL {
+                balance.channel_msat += ch.our_amount_msat.msat();
+            }
+        }
+        for o in &funds.outputs {
+            if o.status == responses::ListfundsOutputsStatus::CONFIRMED {
+                balance.onchain_msat += o.amount_msat.msat();
+            }
+        }
+        Ok(balance)
+    }
 
-// Get funds info
-async fn get_funds_info() -> Result<responses::ListfundsResponse, Box<dyn Error>> {
-    let rpc_pipe_path = match get_rpc_path() {
-        Err(e) => return Err(format!("CLN not runnig or not accessible, {:?}", e).into()),
-        Ok(p) => p,
-    };
-    let mut rpc = ClnRpc::new(rpc_pipe_path).await?;
-
-    let funds_req = requests::ListfundsRequest { spent: None };
-    let funds_resp: responses::ListfundsResponse = rpc.call_typed(&funds_req).await?;
-    Ok(funds_resp)
+    async fn lookup_payment(
+        &self,
+        payment_hash: &str,
+    ) -> Result<Option<PaymentLookup>, Box<dyn Error>> {
+        let mut rpc = self.connect().await?;
+
+        let listpays_req = requests::ListpaysRequest {
+            bolt11: None,
+            index: None,
+            limit: None,
+            payment_hash: Some(Sha256::from_str(payment_hash)?),
+            start: None,
+            status: None,
+        };
+        let listpays_resp: responses::ListpaysResponse = rpc.call_typed(&listpays_req).await?;
+
+        // There may be several attempts, a complete one takes precedence, then pending
+        let mut res: Option<PaymentLookup> = None;
+        for p in &listpays_resp.pays {
+            let status = match p.status {
+                responses::ListpaysPaysStatus::COMPLETE => LookupStatus::Complete,
+                responses::ListpaysPaysStatus::PENDING => LookupStatus::Pending,
+                responses::ListpaysPaysStatus::FAILED => LookupStatus::Failed,
+            };
+            let lookup = PaymentLookup {
+                status,
+                amount_msat: p.amount_msat.map(|a| a.msat()).unwrap_or(0),
+                amount_sent_msat: p.amount_sent_msat.map(|a| a.msat()).unwrap_or(0),
+                preimage: p
+                    .preimage
+                    .as_ref()
+                    .map(|s| s.to_vec().to_lower_hex_string())
+                    .unwrap_or_default(),
+            };
+            res = match res {
+                None => Some(lookup),
+                Some(prev) => {
+                    if prev.status == LookupStatus::Complete
+                        || (prev.status == LookupStatus::Pending
+                            && lookup.status == LookupStatus::Failed)
+                    {
+                        Some(prev)
+                    } else {
+                        Some(lookup)
+                    }
+                }
+            };
+        }
+        Ok(res)
+    }
 }
 
 pub async fn print_node_info() -> Result<(), Box<dyn Error>> {
     // info = get_info()
     // print("LN node info:")
     // print(info)
-    let funds = get_funds_info().await?;
+    let funds = ClnBackend::new_from_config().get_funds_info().await?;
     println!("");
     println!("LN node funds info:");
     println!("{:?}", funds);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rpc_error(code: i32, message: &str) -> RpcError {
+        RpcError {
+            code: Some(code),
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn test_pay_error_result() {
+        let res = pay_error_result(
+            &rpc_error(
+                PAY_ROUTE_NOT_FOUND,
+                "We don't have sufficient funds available for the payment",
+            ),
+            100,
+        );
+        assert!(res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS);
+
+        let res = pay_error_result(&rpc_error(PAY_ROUTE_NOT_FOUND, "No path found"), 100);
+        assert!(res.err_nonfinal);
+        assert_eq!(res.err_code, 0);
+
+        let res = pay_error_result(&rpc_error(PAY_ROUTE_TOO_EXPENSIVE, "Fee exceeds"), 100);
+        assert!(res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_FEE_CAP_EXCEEDED);
+
+        let res = pay_error_result(&rpc_error(PAY_DESTINATION_PERM_FAIL, "Unknown hash"), 100);
+        assert!(!res.err_nonfinal);
+    }
+}
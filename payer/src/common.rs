@@ -1,3 +1,11 @@
+use crate::cashu::CashuConfig;
+use crate::fee_limits::FeeLimits;
+use crate::ln_backend::LightningBackend;
+use crate::nostr_dm::DmConfig;
+use crate::retry_policy::RetryPolicy;
+use crate::zap_message::ZapMessageConfig;
+
+use std::env;
 use std::error::Error;
 use std::str::FromStr;
 
@@ -11,6 +19,10 @@ pub enum PaymentMethod {
     PmNostrLightning,
     /// Nostr Zap: NPub -> Nostr Profile -> Lightning Address -> Lightning payment with Zap
     PmNostrZap,
+    /// Cashu: NPub -> Cashu token minted from our mint (paid by Lightning) -> encrypted DM
+    PmCashu,
+    /// BOLT12: offer (or NPub -> Nostr profile 'lno') -> fetchinvoice -> Lightning payment
+    PmBolt12,
 }
 
 /// Return all payment methods
@@ -22,6 +34,8 @@ const PAYMENT_METHODS: &'static [&'static PaymentMethod] = &[
     &PaymentMethod::PmLnAddress,
     &PaymentMethod::PmNostrLightning,
     &PaymentMethod::PmNostrZap,
+    &PaymentMethod::PmCashu,
+    &PaymentMethod::PmBolt12,
 ];
 
 impl ToString for PaymentMethod {
@@ -30,6 +44,8 @@ impl ToString for PaymentMethod {
             Self::PmLnAddress => "LNAD",
             Self::PmNostrLightning => "NOLN",
             Self::PmNostrZap => "ZAP",
+            Self::PmCashu => "CASHU",
+            Self::PmBolt12 => "BOLT12",
         }
         .to_string()
     }
@@ -49,6 +65,66 @@ impl FromStr for PaymentMethod {
 
 pub struct PayerParameters {
     pub nostr_secret_key: Vec<u8>,
+    pub ln_backend: Box<dyn LightningBackend>,
+    /// Relays for profile lookups and zaps
+    pub nostr_relays: Vec<String>,
+    /// Caps on Lightning routing fees
+    pub fee_limits: FeeLimits,
+    /// Message template and mode of zaps
+    pub zap_message: ZapMessageConfig,
+    /// DM notifications to miners
+    pub dm_config: DmConfig,
+    /// Retrying of payments with a nonfinal error
+    pub retry_policy: RetryPolicy,
+    /// Mint of Cashu payouts
+    pub cashu_config: CashuConfig,
+}
+
+#[cfg(test)]
+impl PayerParameters {
+    /// Default parameters for tests, paying through the given backend
+    pub fn for_test<B: LightningBackend + 'static>(ln_backend: B) -> Self {
+        PayerParameters {
+            nostr_secret_key: [7u8; 32].to_vec(),
+            ln_backend: Box::new(ln_backend),
+            nostr_relays: Vec::new(),
+            fee_limits: FeeLimits::default(),
+            zap_message: ZapMessageConfig::default(),
+            dm_config: DmConfig::default(),
+            retry_policy: RetryPolicy::default(),
+            cashu_config: CashuConfig::default(),
+        }
+    }
+}
+
+/// Payment attempt for tests: in progress since `time`, all results empty
+#[cfg(test)]
+pub fn test_payment(req_id: i32, pay_method: &str, time: u32) -> common_rs::dto_pc::Payment {
+    common_rs::dto_pc::Payment::new(
+        -1,
+        req_id,
+        time,
+        common_rs::error_codes::STATUS_IN_PROGRESS,
+        time,
+        common_rs::error_codes::ERROR_OK,
+        "".into(),
+        0,
+        0,
+        "".into(),
+        "".into(),
+        0,
+        0,
+        0,
+        "".into(),
+        0,
+        common_rs::error_codes::ZAP_RCPT_NA,
+        "".into(),
+        "".into(),
+        0,
+        pay_method.into(),
+        "".into(),
+        "".into(),
+    )
 }
 
 pub struct PaymentResult {
@@ -61,6 +137,10 @@ pub struct PaymentResult {
     pub paid_amount: u64,
     pub paid_fee: u32,
     pub reference: String,
+    /// Zaps: Nostr pubkey of the LNURL server (hex), signing the zap receipt
+    pub zap_pubkey: String,
+    /// LNURL success action returned with the invoice, decoded (LUD-09/10)
+    pub success_action: String,
 }
 
 impl PaymentResult {
@@ -85,6 +165,8 @@ impl PaymentResult {
             paid_amount,
             paid_fee,
             reference: reference.to_string(),
+            zap_pubkey: String::new(),
+            success_action: String::new(),
         }
     }
 }
@@ -107,3 +189,14 @@ pub fn shorten_id_m_n(id: &str, prefix_len: u16, postfix_len: u16) -> String {
 pub fn shorten_id(id: &str) -> String {
     shorten_id_m_n(id, 9, 4)
 }
+
+/// Value of a config setting from the environment, or the default if not set
+pub(crate) fn get_env_or_default<T: FromStr>(name: &str, default: T) -> Result<T, Box<dyn Error>> {
+    match env::var(name) {
+        Err(_) => Ok(default),
+        Ok(s) => s
+            .trim()
+            .parse::<T>()
+            .map_err(|_| format!("Invalid value for {name}, '{s}'").into()),
+    }
+}
@@ -0,0 +1,90 @@
+use crate::common::get_env_or_default;
+
+use dotenv;
+
+use std::error::Error;
+
+/// Default absolute fee cap, Msat
+const DEFAULT_FEE_MAX_MSAT: u64 = 100_000;
+/// Default fee cap, percentage of the amount
+const DEFAULT_FEE_MAX_PERCENT: f64 = 1.0;
+/// Default fee always allowed, even above the percentage cap (for small amounts), Msat
+const DEFAULT_FEE_EXEMPT_MSAT: u64 = 1_000;
+
+/// Caps on the routing fee of Lightning payments
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeLimits {
+    /// Absolute cap, Msat
+    pub max_fee_msat: u64,
+    /// Cap as percentage of the amount
+    pub max_fee_percent: f64,
+    /// Fee allowed regardless of the percentage cap, Msat
+    pub exempt_fee_msat: u64,
+}
+
+impl FeeLimits {
+    pub fn new(max_fee_msat: u64, max_fee_percent: f64, exempt_fee_msat: u64) -> Self {
+        Self {
+            max_fee_msat,
+            max_fee_percent,
+            exempt_fee_msat,
+        }
+    }
+
+    /// Fee caps from config: LN_FEE_MAX_MSAT, LN_FEE_MAX_PERCENT, LN_FEE_EXEMPT_MSAT
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let max_fee_percent = get_env_or_default("LN_FEE_MAX_PERCENT", DEFAULT_FEE_MAX_PERCENT)?;
+        if max_fee_percent.is_nan() || max_fee_percent < 0.0 {
+            return Err(format!("Invalid fee percentage {max_fee_percent}").into());
+        }
+        Ok(Self::new(
+            get_env_or_default("LN_FEE_MAX_MSAT", DEFAULT_FEE_MAX_MSAT)?,
+            max_fee_percent,
+            get_env_or_default("LN_FEE_EXEMPT_MSAT", DEFAULT_FEE_EXEMPT_MSAT)?,
+        ))
+    }
+
+    /// The fee cap for a payment: the percentage of the amount, at most the absolute cap,
+    /// but at least the exempt fee. Msat.
+    pub fn max_fee_for_amount(&self, amount_msat: u64) -> u64 {
+        let percent_fee = (amount_msat as f64 * self.max_fee_percent / 100.0).floor() as u64;
+        u64::max(
+            u64::min(percent_fee, self.max_fee_msat),
+            self.exempt_fee_msat,
+        )
+    }
+}
+
+impl Default for FeeLimits {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_FEE_MAX_MSAT,
+            DEFAULT_FEE_MAX_PERCENT,
+            DEFAULT_FEE_EXEMPT_MSAT,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_max_fee_for_amount() {
+        let limits = FeeLimits::new(100_000, 1.0, 1_000);
+        // Small amount, exempt fee applies
+        assert_eq!(limits.max_fee_for_amount(5_000), 1_000);
+        assert_eq!(limits.max_fee_for_amount(0), 1_000);
+        // Percentage
+        assert_eq!(limits.max_fee_for_amount(1_000_000), 10_000);
+        assert_eq!(limits.max_fee_for_amount(1_234_567), 12_345);
+        // Absolute cap
+        assert_eq!(limits.max_fee_for_amount(50_000_000), 100_000);
+
+        let limits = FeeLimits::new(100_000, 0.0, 0);
+        assert_eq!(limits.max_fee_for_amount(1_000_000), 0);
+    }
+}
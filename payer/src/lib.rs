@@ -1,6 +1,26 @@
+pub mod cashu;
+#[cfg(test)]
+mod cashu_test_mint;
 pub mod cln_pay;
 pub mod common;
+pub mod fee_limits;
 mod ln_address;
-mod nostr_profile;
+pub mod ln_backend;
+pub mod lnurl_pay;
+#[cfg(test)]
+mod lnurl_test_server;
+#[cfg(test)]
+mod mock_backend;
+pub mod nostr_dm;
+pub mod nostr_payout_prefs;
+pub mod nostr_profile;
+pub mod nostr_relays;
+#[cfg(test)]
+mod nostr_test_relay;
 pub mod nostr_zap;
 pub mod payer;
+pub mod reconcile;
+pub mod retry_policy;
+pub mod work_queue;
+pub mod zap_message;
+pub mod zap_receipt;
@@ -1,20 +1,24 @@
+use crate::lnurl_pay::{self, LnurlPayInvoice};
+
+use bech32::FromBase32;
+use reqwest::Url;
+
+use std::env;
 use std::error::Error;
 
-#[derive(Debug, serde::Deserialize)]
-struct LnurlResponseData {
-    callback: Option<String>,
-    #[serde(rename = "minSendable")]
-    min_sendable: Option<u64>,
-    #[serde(rename = "maxSendable")]
-    max_sendable: Option<u64>,
-}
+/// Default LNURL-pay URL of a Lightning Address (LUD-16)
+const LNURLP_URL_TEMPLATE_DEFAULT: &str = "https://{domain}/.well-known/lnurlp/{user}";
 
-#[derive(Debug, serde::Deserialize)]
-struct CallbackResponseData {
-    pr: Option<String>,
+/// LNURL-pay URL template, from LNURLP_URL_TEMPLATE, or the default https one.
+/// The template may contain {user} and {domain}; e.g. "http://{domain}/.well-known/lnurlp/{user}"
+fn get_lnurlp_url_template() -> String {
+    env::var("LNURLP_URL_TEMPLATE").unwrap_or(LNURLP_URL_TEMPLATE_DEFAULT.to_string())
 }
 
-pub fn ln_p_url_from_address(ln_address: &str) -> Result<String, Box<dyn Error>> {
+fn ln_p_url_from_address_template(
+    ln_address: &str,
+    template: &str,
+) -> Result<String, Box<dyn Error>> {
     // Parse the Lightning Address
     let parts = ln_address.split("@").collect::<Vec<&str>>();
     if parts.len() < 2 {
@@ -24,117 +28,68 @@ pub fn ln_p_url_from_address(ln_address: &str) -> Result<String, Box<dyn Error>>
     let domain = parts[1];
 
     // Step 1: Construct and request the Lightning Address URL
-    let lnurlp_url = format!("https://{domain}/.well-known/lnurlp/{username}");
+    let lnurlp_url = template
+        .replace("{domain}", domain)
+        .replace("{user}", username);
     Ok(lnurlp_url)
 }
 
-// Retrieve a BOLT11 incvoice from a Lightning Address
+/// Decode a bech32 LNURL (LUD-01/06, e.g. "lnurl1dp68..."), to the URL it encodes
+fn lnurlp_url_from_lnurl(lnurl: &str) -> Result<String, Box<dyn Error>> {
+    let (hrp, data, _variant) = bech32::decode(lnurl)?;
+    if hrp != "lnurl" {
+        return Err(format!("Expected hrp to be 'lnurl', got '{}'", hrp).into());
+    }
+    let bytes = Vec::<u8>::from_base32(&data)?;
+    let url = String::from_utf8(bytes)?;
+    let parsed =
+        Url::parse(&url).map_err(|e| format!("Invalid LNURL, not an URL: '{url}', {e}"))?;
+    // LUD-01: https, plain http only for onion services
+    let is_onion = parsed
+        .host_str()
+        .is_some_and(|h| h.to_lowercase().ends_with(".onion"));
+    match parsed.scheme() {
+        "https" => {}
+        "http" if is_onion => {}
+        _ => return Err(format!("Invalid LNURL, https is required: '{url}'").into()),
+    }
+    Ok(url)
+}
+
+/// Check if the ID is a bech32 LNURL (lud06), as opposed to a Lightning Address (lud16)
+fn is_lnurl(ln_id: &str) -> bool {
+    !ln_id.contains("@") && ln_id.to_lowercase().starts_with("lnurl1")
+}
+
+/// LNURL-pay URL from a Lightning Address ("user@domain"), or from a bech32 LNURL ("lnurl1...")
+pub fn ln_p_url_from_address(ln_address: &str) -> Result<String, Box<dyn Error>> {
+    let ln_address = ln_address.trim();
+    let ln_address = ln_address
+        .strip_prefix("lightning:")
+        .or(ln_address.strip_prefix("LIGHTNING:"))
+        .unwrap_or(ln_address);
+    if is_lnurl(ln_address) {
+        return lnurlp_url_from_lnurl(ln_address);
+    }
+    ln_p_url_from_address_template(ln_address, &get_lnurlp_url_template())
+}
+
+// Retrieve a BOLT11 incvoice from a Lightning Address (or LNURL), with a comment if allowed
 // In case of error, return:
 // - if the error is nonfinal
 // - the error
 pub async fn get_invoice_from_ln_address(
     ln_address: &str,
     amount_msats: u64,
-) -> Result<String, (bool, Box<dyn Error>)> {
-    // Retrieve a BOLT11 invoice from a Lightning Address.
-    //
-    // Args:
-    //     ln_address: A Lightning Address in the format "user@domain"
-    //     amount_msats: Amount to pay in millisatoshis
-    //
-    // Returns:
-    //     A BOLT11 invoice string or raises an exception if the process fails
-
-    println!("Processing LN address {ln_address} ...");
-
-    // Step 1: Construct and request the Lightning Address URL
-    let lnurlp_url = &ln_p_url_from_address(ln_address).map_err(|e| (false, e.into()))?;
-
-    // Make the initial request to get the LNURL metadata
-    let resp = match reqwest::get(lnurlp_url).await {
-        Err(e) => {
-            return Err((
-                true,
-                format!("HTTP request failed: {} {:?}", lnurlp_url, e).into(),
-            ));
-        }
-        Ok(r) => r,
-    };
-    if resp.status() != reqwest::StatusCode::OK {
-        return Err((
-            true,
-            format!("HTTP request failed: {} {}", resp.status(), lnurlp_url).into(),
-        ));
-    }
-    let lnurlp_data = resp
-        .json::<LnurlResponseData>()
-        .await
-        .map_err(|e| (true, e.into()))?;
-    // println!("lnurlp_data {:?}", lnurlp_data);
-
-    // Extract the callback URL
-    let callback_url = match lnurlp_data.callback {
-        None => {
-            return Err((
-                true,
-                format!("Missing callback: {} {:?}", lnurlp_url, lnurlp_data).into(),
-            ));
-        }
-        Some(c) => c,
-    };
-    // println!("callback {}", callback_url);
-
-    // Check if the callback URL supports the specified amount
-    let min_sendable = lnurlp_data.min_sendable.unwrap_or(1);
-    let max_sendable = lnurlp_data.max_sendable.unwrap_or(u64::max_value());
-
-    if amount_msats < min_sendable {
-        return Err((
-            false,
-            format!("Amount {amount_msats} is below the minimum allowed: {min_sendable}").into(),
-        ));
-    }
-    if amount_msats > max_sendable {
-        return Err((
-            false,
-            format!("Amount {amount_msats} is above the maximum allowed: {max_sendable}").into(),
-        ));
-    }
-
-    // Step 2: Make the callback request with the amount
-    // Some providers expect amount in msats, others in sats - we'll use msats as that's our input
-    let callback_with_amount = &format!("{callback_url}?amount={amount_msats}");
-    // println!("callback_with_amount {callback_with_amount}");
-
-    let resp = match reqwest::get(callback_with_amount).await {
-        Err(e) => {
-            return Err((
-                true,
-                format!("HTTP request failed: {} {:?}", callback_with_amount, e).into(),
-            ));
-        }
-        Ok(r) => r,
-    };
-
-    let callback_data = resp
-        .json::<CallbackResponseData>()
-        .await
-        .map_err(|e| (true, e.into()))?;
-    // println!("callback_data {:?}", callback_data);
-
-    // Check if the response contains a BOLT11 invoice
-    let invoice = match callback_data.pr {
-        None => {
-            return Err((
-                false,
-                format!("Invalid callback response: missing 'pr' field (BOLT11 invoice)").into(),
-            ));
-        }
-        Some(i) => i,
-    };
-
-    // Return the BOLT11 invoice
-    Ok(invoice)
+    comment: &str,
+) -> Result<LnurlPayInvoice, (bool, Box<dyn Error>)> {
+    let params = lnurl_pay::fetch_pay_params(ln_address).await?;
+    let comment = params.fit_comment(comment);
+    let mut extra_params = Vec::new();
+    if let Some(c) = &comment {
+        extra_params.push(("comment", c.as_str()));
+    }
+    Ok(params.request_invoice(amount_msats, &extra_params).await?)
 }
 
 #[allow(dead_code)]
@@ -142,8 +97,219 @@ pub async fn do_try() {
     let invoice = get_invoice_from_ln_address(
         "npub12rv5lskctqxxs2c8rf2zlzc7xx3qpvzs3w4etgemauy9thegr43sf485vg@npub.cash",
         5000,
+        "",
     )
     .await
     .unwrap();
-    println!("Invoice: {}", invoice);
+    println!("Invoice: {}", invoice.pr);
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::ln_backend::LightningBackend;
+    use crate::lnurl_test_server::{
+        CallbackMode, LnurlServerConfig, LnurlTestServer, get_closed_port,
+    };
+    use crate::mock_backend::MockBackend;
+
+    /// Plain-http template, for the local test server. All tests set the same value.
+    pub(crate) fn set_test_url_template() {
+        unsafe {
+            env::set_var(
+                "LNURLP_URL_TEMPLATE",
+                "http://{domain}/.well-known/lnurlp/{user}",
+            );
+        }
+    }
+
+    async fn get_invoice_with_config(
+        config: LnurlServerConfig,
+        amount_msats: u64,
+    ) -> (
+        Result<LnurlPayInvoice, (bool, Box<dyn Error>)>,
+        LnurlTestServer,
+        MockBackend,
+    ) {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(config, mock.clone()).await;
+        let res = get_invoice_from_ln_address(&server.ln_address("miner"), amount_msats, "").await;
+        (res, server, mock)
+    }
+
+    fn assert_err_nonfinal(res: &Result<LnurlPayInvoice, (bool, Box<dyn Error>)>, nonfinal: bool) {
+        match res {
+            Ok(i) => panic!("Expected error, got invoice {}", i.pr),
+            Err((nf, e)) => assert_eq!(*nf, nonfinal, "error {e}"),
+        }
+    }
+
+    #[test]
+    fn test_ln_p_url_from_address_template() {
+        assert_eq!(
+            ln_p_url_from_address_template("miner@example.com", LNURLP_URL_TEMPLATE_DEFAULT)
+                .unwrap(),
+            "https://example.com/.well-known/lnurlp/miner"
+        );
+        assert_eq!(
+            ln_p_url_from_address_template("miner@127.0.0.1:8080", "http://{domain}/lnurlp/{user}")
+                .unwrap(),
+            "http://127.0.0.1:8080/lnurlp/miner"
+        );
+        assert!(ln_p_url_from_address_template("miner", LNURLP_URL_TEMPLATE_DEFAULT).is_err());
+    }
+
+    #[test]
+    fn test_ln_p_url_from_lnurl() {
+        // Example from LUD-01
+        let lnurl = "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS";
+        let url = "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+        assert_eq!(ln_p_url_from_address(lnurl).unwrap(), url);
+        assert_eq!(ln_p_url_from_address(&lnurl.to_lowercase()).unwrap(), url);
+        assert_eq!(
+            ln_p_url_from_address(&format!("lightning:{lnurl}")).unwrap(),
+            url
+        );
+        // Bad checksum
+        assert!(ln_p_url_from_address(&lnurl.replace("FNS", "FNT")).is_err());
+        // Not an URL
+        let not_url = bech32::encode(
+            "lnurl",
+            bech32::ToBase32::to_base32(&"hello"),
+            bech32::Variant::Bech32,
+        )
+        .unwrap();
+        assert!(ln_p_url_from_address(&not_url).is_err());
+    }
+
+    fn encode_lnurl(url: &str) -> String {
+        bech32::encode(
+            "lnurl",
+            bech32::ToBase32::to_base32(&url.as_bytes()),
+            bech32::Variant::Bech32,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ln_p_url_from_lnurl_scheme() {
+        // Plain http only for onion services
+        let onion_url = "http://lnurlexample.onion/.well-known/lnurlp/miner";
+        assert_eq!(
+            ln_p_url_from_address(&encode_lnurl(onion_url)).unwrap(),
+            onion_url
+        );
+        assert!(ln_p_url_from_address(&encode_lnurl("http://example.com/lnurlp/miner")).is_err());
+        assert!(
+            ln_p_url_from_address(&encode_lnurl("http://example.onion.com/lnurlp/miner")).is_err()
+        );
+        assert!(ln_p_url_from_address(&encode_lnurl("ftp://example.com/lnurlp/miner")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_from_lnurl_http_rejected() {
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(LnurlServerConfig::default(), mock.clone()).await;
+        let url = format!("http://127.0.0.1:{}/.well-known/lnurlp/miner", server.port);
+        let res = get_invoice_from_ln_address(&encode_lnurl(&url), 5000, "").await;
+        assert_err_nonfinal(&res, false);
+        assert_eq!(server.callback_request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_ok() {
+        let (res, server, mock) = get_invoice_with_config(LnurlServerConfig::default(), 5000).await;
+        let invoice = res.unwrap().pr;
+        let decoded = mock.decode_invoice(&invoice).await.unwrap();
+        assert_eq!(decoded.amount_msat, Some(5000));
+        assert_eq!(server.callback_request_count(), 1);
+        assert_eq!(server.last_callback_request().unwrap()["amount"], "5000");
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_invalid_address() {
+        let res = get_invoice_from_ln_address("no_at_sign", 5000, "").await;
+        assert_err_nonfinal(&res, false);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_connection_refused() {
+        set_test_url_template();
+        let port = get_closed_port().await;
+        let res = get_invoice_from_ln_address(&format!("miner@127.0.0.1:{port}"), 5000, "").await;
+        assert_err_nonfinal(&res, true);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_metadata_500() {
+        let config = LnurlServerConfig {
+            metadata_status: 500,
+            ..Default::default()
+        };
+        let (res, server, _) = get_invoice_with_config(config, 5000).await;
+        assert_err_nonfinal(&res, true);
+        assert_eq!(server.callback_request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_metadata_malformed() {
+        let config = LnurlServerConfig {
+            metadata_malformed: true,
+            ..Default::default()
+        };
+        let (res, _, _) = get_invoice_with_config(config, 5000).await;
+        assert_err_nonfinal(&res, true);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_missing_callback() {
+        let config = LnurlServerConfig {
+            metadata_no_callback: true,
+            ..Default::default()
+        };
+        let (res, _, _) = get_invoice_with_config(config, 5000).await;
+        assert_err_nonfinal(&res, true);
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_amount_limits() {
+        let config = LnurlServerConfig {
+            min_sendable: 10_000,
+            max_sendable: 20_000,
+            ..Default::default()
+        };
+        let (res, server, _) = get_invoice_with_config(config.clone(), 5000).await;
+        assert_err_nonfinal(&res, false);
+        assert_eq!(server.callback_request_count(), 0);
+        let (res, _, _) = get_invoice_with_config(config.clone(), 25_000).await;
+        assert_err_nonfinal(&res, false);
+        let (res, _, _) = get_invoice_with_config(config, 20_000).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice_callback_errors() {
+        let config = LnurlServerConfig {
+            callback_unreachable: true,
+            ..Default::default()
+        };
+        let (res, _, _) = get_invoice_with_config(config, 5000).await;
+        assert_err_nonfinal(&res, true);
+
+        for (mode, nonfinal) in [
+            (CallbackMode::ServerError, true),
+            (CallbackMode::Malformed, true),
+            (CallbackMode::MissingPr, false),
+            (CallbackMode::StatusError, false),
+        ] {
+            let config = LnurlServerConfig {
+                callback_mode: mode,
+                ..Default::default()
+            };
+            let (res, server, _) = get_invoice_with_config(config, 5000).await;
+            assert_err_nonfinal(&res, nonfinal);
+            assert_eq!(server.callback_request_count(), 1);
+        }
+    }
 }
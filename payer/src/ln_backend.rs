@@ -0,0 +1,151 @@
+use crate::cln_pay::ClnBackend;
+use crate::common::PaymentResult;
+
+use async_trait::async_trait;
+use dotenv;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Lightning backend kinds, selectable from config
+/// See also: get_ln_backend_from_config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightningBackendKind {
+    /// Core Lightning, through the cln-rpc unix socket
+    Cln,
+}
+
+impl fmt::Display for LightningBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cln => "CLN",
+        })
+    }
+}
+
+impl FromStr for LightningBackendKind {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "CLN" => Ok(Self::Cln),
+            _ => Err(format!("Unknown Lightning backend {s}").into()),
+        }
+    }
+}
+
+/// The relevant fields of a decoded BOLT11 (or BOLT12) invoice
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInvoice {
+    pub payment_hash: String,
+    /// Amount in the invoice, Msat. None for zero-amount invoices
+    pub amount_msat: Option<u64>,
+    pub description_hash: Option<String>,
+    pub created_at: u64,
+    /// Relative expiry, in seconds from created_at
+    pub expiry: u64,
+}
+
+/// Funds available on the node, Msat
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendBalance {
+    /// Spendable in channels (our side)
+    pub channel_msat: u64,
+    /// Confirmed on-chain outputs
+    pub onchain_msat: u64,
+}
+
+/// Status of a payment, as known by the node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupStatus {
+    Pending,
+    Failed,
+    Complete,
+}
+
+/// A payment looked up on the node by payment hash
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentLookup {
+    pub status: LookupStatus,
+    pub amount_msat: u64,
+    pub amount_sent_msat: u64,
+    /// Hex preimage, if complete
+    pub preimage: String,
+}
+
+/// A Lightning node, the only way money leaves the pool.
+/// Implementations: ClnBackend (cln_pay)
+#[async_trait(?Send)]
+pub trait LightningBackend {
+    fn kind_name(&self) -> String;
+
+    /// Pay a BOLT11 invoice, with routing fee at most max_fee_msat.
+    /// Payment failures are returned as non-success PaymentResult; Err is for unexpected errors.
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amnt_msat: u64,
+        label: &str,
+        max_fee_msat: u64,
+    ) -> Result<PaymentResult, Box<dyn Error>>;
+
+    /// Decode a BOLT11 invoice, or a BOLT12 invoice (lni1..)
+    async fn decode_invoice(&self, invoice: &str) -> Result<DecodedInvoice, Box<dyn Error>>;
+
+    /// Obtain a BOLT12 invoice (lni1..) for a BOLT12 offer (lno1..), for an amount.
+    /// The invoice is paid with pay_invoice. Err with a flag if nonfinal (e.g. node unreachable).
+    async fn fetch_invoice(
+        &self,
+        offer: &str,
+        amnt_msat: u64,
+        payer_note: &str,
+    ) -> Result<String, (bool, Box<dyn Error>)>;
+
+    async fn get_balance(&self) -> Result<BackendBalance, Box<dyn Error>>;
+
+    /// Look up a previous payment by payment hash (hex). None if the node doesn't know about it.
+    async fn lookup_payment(
+        &self,
+        payment_hash: &str,
+    ) -> Result<Option<PaymentLookup>, Box<dyn Error>>;
+}
+
+fn get_ln_backend_kind_from_env() -> Result<LightningBackendKind, Box<dyn Error>> {
+    match env::var("LN_BACKEND") {
+        Err(_) => Ok(LightningBackendKind::Cln),
+        Ok(s) => LightningBackendKind::from_str(&s),
+    }
+}
+
+/// Create the Lightning backend selected in the config (LN_BACKEND, default CLN)
+pub fn get_ln_backend_from_config() -> Result<Box<dyn LightningBackend>, Box<dyn Error>> {
+    // Load environment variables from .env file
+    dotenv::dotenv().ok();
+
+    let kind = get_ln_backend_kind_from_env()?;
+    let backend: Box<dyn LightningBackend> = match kind {
+        LightningBackendKind::Cln => Box::new(ClnBackend::new_from_config()),
+    };
+    println!("Using Lightning backend {}", backend.kind_name());
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backend_kind_from_str() {
+        assert_eq!(
+            LightningBackendKind::from_str("CLN").unwrap(),
+            LightningBackendKind::Cln
+        );
+        assert_eq!(
+            LightningBackendKind::from_str("cln").unwrap(),
+            LightningBackendKind::Cln
+        );
+        assert!(LightningBackendKind::from_str("LND").is_err());
+        assert_eq!(LightningBackendKind::Cln.to_string(), "CLN");
+    }
+}
@@ -0,0 +1,590 @@
+use crate::ln_address::ln_p_url_from_address;
+
+use aes::Aes256;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use hex_conservative::FromHex;
+use nostr::PublicKey;
+use nostr::hashes::{Hash, sha256};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+//
+// LNURL-pay client (LUD-06), for Lightning Addresses (LUD-16) and LNURLs,
+// used for LN Address, Nostr Lightning and Zap payments.
+//
+
+/// Error of an LNURL-pay request
+#[derive(Debug, PartialEq)]
+pub enum LnurlPayError {
+    /// Not a valid Lightning Address or LNURL
+    InvalidAddress(String),
+    /// HTTP request failed: unreachable, or not OK status
+    Request(String),
+    /// Response is not as expected: not JSON, or missing callback
+    BadResponse(String),
+    /// The LNURL is not a pay request (tag)
+    NotPayRequest(String),
+    /// Error returned by the service, {"status":"ERROR","reason":..}
+    Service(String),
+    /// Amount is outside the minSendable - maxSendable range (msat)
+    AmountOutOfRange { amount: u64, min: u64, max: u64 },
+    /// Payer data required by the service, that we do not send (LUD-18)
+    PayerDataRequired(Vec<String>),
+    /// No invoice in the callback response
+    MissingInvoice,
+    /// Zaps not supported: no allowsNostr, or no valid nostrPubkey (NIP-57)
+    ZapNotSupported(String),
+}
+
+impl LnurlPayError {
+    /// If the error may go away on a retry
+    pub fn is_nonfinal(&self) -> bool {
+        matches!(self, Self::Request(_) | Self::BadResponse(_))
+    }
+}
+
+impl fmt::Display for LnurlPayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(e) => write!(f, "Invalid Lightning Address: {e}"),
+            Self::Request(e) => write!(f, "HTTP request failed: {e}"),
+            Self::BadResponse(e) => write!(f, "Invalid LNURL response: {e}"),
+            Self::NotPayRequest(tag) => write!(f, "Not an LNURL pay request, tag '{tag}'"),
+            Self::Service(reason) => write!(f, "LNURL service error: {reason}"),
+            Self::AmountOutOfRange { amount, min, max } => {
+                write!(
+                    f,
+                    "Amount {amount} is outside the allowed range {min} - {max}"
+                )
+            }
+            Self::PayerDataRequired(fields) => {
+                write!(f, "Payer data required: {}", fields.join(", "))
+            }
+            Self::MissingInvoice => write!(
+                f,
+                "Invalid callback response: missing 'pr' field (BOLT11 invoice)"
+            ),
+            Self::ZapNotSupported(reason) => write!(f, "Zaps not supported, {reason}"),
+        }
+    }
+}
+
+impl Error for LnurlPayError {}
+
+/// In the error form used by the payment flows: if the error is nonfinal, and the error
+impl From<LnurlPayError> for (bool, Box<dyn Error>) {
+    fn from(e: LnurlPayError) -> Self {
+        (e.is_nonfinal(), e.into())
+    }
+}
+
+/// A payer data field requested by the service (LUD-18)
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+pub struct PayerDataField {
+    #[serde(default)]
+    pub mandatory: bool,
+}
+
+/// Success action to show after the payment (LUD-09, LUD-10)
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: String,
+    },
+    /// Encrypted with the payment preimage
+    Aes {
+        description: String,
+        ciphertext: String,
+        iv: String,
+    },
+}
+
+impl SuccessAction {
+    /// Readable form of the success action, to store, e.g. "message: Thanks!".
+    /// An AES one is decrypted with the payment preimage (hex).
+    pub fn decoded(&self, preimage_hex: &str) -> String {
+        match self {
+            Self::Message { message } => format!("message: {message}"),
+            Self::Url { description, url } => format!("url: {description} {url}"),
+            Self::Aes {
+                description,
+                ciphertext,
+                iv,
+            } => match decrypt_aes_success_action(ciphertext, iv, preimage_hex) {
+                Ok(plaintext) => format!("aes: {description} {plaintext}"),
+                Err(e) => format!("aes: {description} (could not decrypt, {e})"),
+            },
+        }
+    }
+}
+
+// AES-256-CBC with the preimage as key, base64 ciphertext and IV (LUD-10)
+fn decrypt_aes_success_action(
+    ciphertext: &str,
+    iv: &str,
+    preimage_hex: &str,
+) -> Result<String, Box<dyn Error>> {
+    let key = <[u8; 32]>::from_hex(preimage_hex)?;
+    let iv: [u8; 16] = BASE64
+        .decode(iv)?
+        .try_into()
+        .map_err(|_| "Invalid IV length")?;
+    let ciphertext = BASE64.decode(ciphertext)?;
+    let plaintext = cbc::Decryptor::<Aes256>::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+        .map_err(|e| format!("{e}"))?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PayParamsResponse {
+    status: Option<String>,
+    reason: Option<String>,
+    tag: Option<String>,
+    callback: Option<String>,
+    #[serde(rename = "minSendable")]
+    min_sendable: Option<u64>,
+    #[serde(rename = "maxSendable")]
+    max_sendable: Option<u64>,
+    metadata: Option<String>,
+    #[serde(rename = "commentAllowed")]
+    comment_allowed: Option<u32>,
+    #[serde(rename = "payerData")]
+    payer_data: Option<HashMap<String, PayerDataField>>,
+    #[serde(rename = "allowsNostr")]
+    allows_nostr: Option<bool>,
+    #[serde(rename = "nostrPubkey")]
+    nostr_pubkey: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InvoiceResponse {
+    status: Option<String>,
+    reason: Option<String>,
+    pr: Option<String>,
+    #[serde(rename = "successAction")]
+    success_action: Option<Value>,
+}
+
+/// Parameters of an LNURL-pay service, from its first response
+#[derive(Clone, Debug, PartialEq)]
+pub struct LnurlPayParams {
+    /// The URL the parameters were obtained from
+    pub lnurlp_url: String,
+    pub callback: String,
+    /// Min. amount, msat
+    pub min_sendable: u64,
+    /// Max. amount, msat
+    pub max_sendable: u64,
+    /// Metadata JSON string, its hash is the invoice description hash
+    pub metadata: String,
+    /// Max. length of a comment (LUD-12), 0 if not allowed
+    pub comment_allowed: u32,
+    /// Payer data fields requested (LUD-18)
+    pub payer_data: HashMap<String, PayerDataField>,
+    /// Zaps (NIP-57)
+    pub allows_nostr: bool,
+    pub nostr_pubkey: Option<String>,
+}
+
+/// Invoice obtained from an LNURL-pay service
+#[derive(Clone, Debug, PartialEq)]
+pub struct LnurlPayInvoice {
+    /// BOLT11 invoice
+    pub pr: String,
+    pub success_action: Option<SuccessAction>,
+    /// SHA256 of the metadata of the pay parameters (hex), the expected description hash
+    /// of the invoice, unless it is a zap (LUD-06)
+    pub metadata_hash: String,
+}
+
+// Error of an LNURL response with status ERROR (LUD-06)
+fn service_error(status: &Option<String>, reason: &Option<String>) -> Option<LnurlPayError> {
+    match status {
+        Some(s) if s.eq_ignore_ascii_case("ERROR") => Some(LnurlPayError::Service(
+            reason.clone().unwrap_or("no reason".into()),
+        )),
+        _ => None,
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, LnurlPayError> {
+    let resp = reqwest::get(url)
+        .await
+        .map_err(|e| LnurlPayError::Request(format!("{url} {e:?}")))?;
+    if resp.status() != reqwest::StatusCode::OK {
+        return Err(LnurlPayError::Request(format!("{} {url}", resp.status())));
+    }
+    resp.json::<T>()
+        .await
+        .map_err(|e| LnurlPayError::BadResponse(format!("{url} {e}")))
+}
+
+fn pay_params_from_response(
+    lnurlp_url: &str,
+    resp: PayParamsResponse,
+) -> Result<LnurlPayParams, LnurlPayError> {
+    if let Some(e) = service_error(&resp.status, &resp.reason) {
+        return Err(e);
+    }
+    if let Some(tag) = resp.tag.as_ref().filter(|t| *t != "payRequest") {
+        return Err(LnurlPayError::NotPayRequest(tag.clone()));
+    }
+    let callback = match resp.callback {
+        None => {
+            return Err(LnurlPayError::BadResponse(format!(
+                "Missing callback: {lnurlp_url}"
+            )));
+        }
+        Some(c) => c,
+    };
+    Ok(LnurlPayParams {
+        lnurlp_url: lnurlp_url.to_string(),
+        callback,
+        min_sendable: resp.min_sendable.unwrap_or(1),
+        max_sendable: resp.max_sendable.unwrap_or(u64::MAX),
+        metadata: resp.metadata.unwrap_or_default(),
+        comment_allowed: resp.comment_allowed.unwrap_or(0),
+        payer_data: resp.payer_data.unwrap_or_default(),
+        allows_nostr: resp.allows_nostr.unwrap_or(false),
+        nostr_pubkey: resp.nostr_pubkey,
+    })
+}
+
+fn invoice_from_response(resp: InvoiceResponse) -> Result<LnurlPayInvoice, LnurlPayError> {
+    if let Some(e) = service_error(&resp.status, &resp.reason) {
+        return Err(e);
+    }
+    let pr = match resp.pr {
+        None => return Err(LnurlPayError::MissingInvoice),
+        Some(pr) => pr,
+    };
+    // An unknown or invalid success action is ignored (LUD-09)
+    let success_action = resp
+        .success_action
+        .and_then(|sa| serde_json::from_value::<SuccessAction>(sa).ok());
+    Ok(LnurlPayInvoice {
+        pr,
+        success_action,
+        metadata_hash: String::new(),
+    })
+}
+
+/// Obtain the LNURL-pay parameters of a Lightning Address ("user@domain") or LNURL ("lnurl1...")
+pub async fn fetch_pay_params(ln_address: &str) -> Result<LnurlPayParams, LnurlPayError> {
+    println!("Processing LN address {ln_address} ...");
+    let lnurlp_url = ln_p_url_from_address(ln_address)
+        .map_err(|e| LnurlPayError::InvalidAddress(e.to_string()))?;
+    let resp = get_json::<PayParamsResponse>(&lnurlp_url).await?;
+    pay_params_from_response(&lnurlp_url, resp)
+}
+
+impl LnurlPayParams {
+    pub fn check_amount(&self, amount_msat: u64) -> Result<(), LnurlPayError> {
+        if amount_msat < self.min_sendable || amount_msat > self.max_sendable {
+            return Err(LnurlPayError::AmountOutOfRange {
+                amount: amount_msat,
+                min: self.min_sendable,
+                max: self.max_sendable,
+            });
+        }
+        Ok(())
+    }
+
+    /// The comment to send, cut to the allowed length; None if comments are not allowed (LUD-12)
+    pub fn fit_comment(&self, comment: &str) -> Option<String> {
+        if self.comment_allowed == 0 || comment.is_empty() {
+            return None;
+        }
+        Some(
+            comment
+                .chars()
+                .take(self.comment_allowed as usize)
+                .collect(),
+        )
+    }
+
+    /// We send no payer data, so the service must not require any
+    pub fn check_payer_data(&self) -> Result<(), LnurlPayError> {
+        let mut mandatory: Vec<String> = self
+            .payer_data
+            .iter()
+            .filter(|(_, f)| f.mandatory)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !mandatory.is_empty() {
+            mandatory.sort();
+            return Err(LnurlPayError::PayerDataRequired(mandatory));
+        }
+        Ok(())
+    }
+
+    /// The Nostr pubkey (hex) signing the zap receipts, if zaps are supported (NIP-57)
+    pub fn zap_pubkey(&self) -> Result<String, LnurlPayError> {
+        if !self.allows_nostr {
+            return Err(LnurlPayError::ZapNotSupported("no allowsNostr".into()));
+        }
+        match self
+            .nostr_pubkey
+            .as_deref()
+            .and_then(|pk| PublicKey::from_hex(pk).ok())
+        {
+            None => Err(LnurlPayError::ZapNotSupported(
+                "missing or invalid nostrPubkey".into(),
+            )),
+            Some(pk) => Ok(pk.to_hex()),
+        }
+    }
+
+    /// Request an invoice from the callback, with the amount and extra query parameters
+    /// (e.g. nostr for zaps)
+    pub async fn request_invoice(
+        &self,
+        amount_msat: u64,
+        extra_params: &[(&str, &str)],
+    ) -> Result<LnurlPayInvoice, LnurlPayError> {
+        self.check_amount(amount_msat)?;
+        self.check_payer_data()?;
+
+        // The callback may have a query already
+        let separator = if self.callback.contains("?") {
+            "&"
+        } else {
+            "?"
+        };
+        let mut url = format!("{}{separator}amount={amount_msat}", self.callback);
+        for (name, value) in extra_params {
+            url.push_str(&format!("&{name}={}", urlencoding::encode(value)));
+        }
+        let resp = get_json::<InvoiceResponse>(&url).await?;
+        let mut invoice = invoice_from_response(resp)?;
+        invoice.metadata_hash = sha256::Hash::hash(self.metadata.as_bytes()).to_string();
+        Ok(invoice)
+    }
+}
+
+/// Obtain an invoice for the amount from a Lightning Address or LNURL
+pub async fn get_invoice(
+    ln_address: &str,
+    amount_msat: u64,
+    extra_params: &[(&str, &str)],
+) -> Result<LnurlPayInvoice, LnurlPayError> {
+    let params = fetch_pay_params(ln_address).await?;
+    params.request_invoice(amount_msat, extra_params).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ln_address::test::set_test_url_template;
+    use crate::lnurl_test_server::{LnurlServerConfig, LnurlTestServer};
+    use crate::mock_backend::MockBackend;
+    use cbc::cipher::BlockEncryptMut;
+    use serde_json::json;
+
+    fn params_from_json(v: Value) -> Result<LnurlPayParams, LnurlPayError> {
+        pay_params_from_response(
+            "https://example.com/lnurlp/miner",
+            serde_json::from_value(v).unwrap(),
+        )
+    }
+
+    fn invoice_from_json(v: Value) -> Result<LnurlPayInvoice, LnurlPayError> {
+        invoice_from_response(serde_json::from_value(v).unwrap())
+    }
+
+    #[test]
+    fn test_pay_params_from_response() {
+        let params = params_from_json(json!({
+            "tag": "payRequest",
+            "callback": "https://example.com/cb",
+            "minSendable": 1000,
+            "maxSendable": 5000,
+            "metadata": "[[\"text/plain\",\"x\"]]",
+            "commentAllowed": 100,
+            "payerData": {"name": {"mandatory": false}, "auth": {"mandatory": false, "k1": "aa"}},
+        }))
+        .unwrap();
+        assert_eq!(params.callback, "https://example.com/cb");
+        assert_eq!(params.comment_allowed, 100);
+        assert_eq!(params.payer_data.len(), 2);
+        assert!(params.check_payer_data().is_ok());
+        assert!(params.check_amount(1000).is_ok());
+        assert_eq!(
+            params.check_amount(5001),
+            Err(LnurlPayError::AmountOutOfRange {
+                amount: 5001,
+                min: 1000,
+                max: 5000
+            })
+        );
+        assert!(!params.allows_nostr);
+        assert!(matches!(
+            params.zap_pubkey(),
+            Err(LnurlPayError::ZapNotSupported(_))
+        ));
+
+        // Mandatory payer data
+        let params = params_from_json(json!({
+            "callback": "https://example.com/cb",
+            "payerData": {"name": {"mandatory": false}, "email": {"mandatory": true}},
+        }))
+        .unwrap();
+        assert_eq!(
+            params.check_payer_data(),
+            Err(LnurlPayError::PayerDataRequired(vec!["email".into()]))
+        );
+
+        // Errors
+        let err = params_from_json(json!({"status": "ERROR", "reason": "No such user"}));
+        assert_eq!(err, Err(LnurlPayError::Service("No such user".into())));
+        assert!(!err.unwrap_err().is_nonfinal());
+        let err = params_from_json(json!({"tag": "withdrawRequest", "callback": "x"}));
+        assert_eq!(
+            err,
+            Err(LnurlPayError::NotPayRequest("withdrawRequest".into()))
+        );
+        let err = params_from_json(json!({"tag": "payRequest"})).unwrap_err();
+        assert!(err.is_nonfinal());
+    }
+
+    #[test]
+    fn test_invoice_from_response() {
+        let inv = invoice_from_json(json!({"pr": "lnbc1", "routes": []})).unwrap();
+        assert_eq!(inv.pr, "lnbc1");
+        assert_eq!(inv.success_action, None);
+
+        let inv = invoice_from_json(json!({
+            "pr": "lnbc1",
+            "successAction": {"tag": "message", "message": "Thanks!"},
+        }))
+        .unwrap();
+        assert_eq!(
+            inv.success_action,
+            Some(SuccessAction::Message {
+                message: "Thanks!".into()
+            })
+        );
+        let inv = invoice_from_json(json!({
+            "pr": "lnbc1",
+            "successAction": {"tag": "url", "description": "Receipt", "url": "https://example.com/r"},
+        }))
+        .unwrap();
+        assert!(matches!(
+            inv.success_action,
+            Some(SuccessAction::Url { .. })
+        ));
+        let inv = invoice_from_json(json!({
+            "pr": "lnbc1",
+            "successAction": {"tag": "aes", "description": "Code", "ciphertext": "abc", "iv": "def"},
+        }))
+        .unwrap();
+        assert!(matches!(
+            inv.success_action,
+            Some(SuccessAction::Aes { .. })
+        ));
+        // Unknown, ignored
+        let inv = invoice_from_json(json!({
+            "pr": "lnbc1",
+            "successAction": {"tag": "foo"},
+        }))
+        .unwrap();
+        assert_eq!(inv.success_action, None);
+
+        // Errors
+        assert_eq!(
+            invoice_from_json(json!({"status": "ERROR", "reason": "Try later"})),
+            Err(LnurlPayError::Service("Try later".into()))
+        );
+        assert_eq!(
+            invoice_from_json(json!({"routes": []})),
+            Err(LnurlPayError::MissingInvoice)
+        );
+    }
+
+    #[test]
+    fn test_fit_comment() {
+        let mut params = params_from_json(json!({"callback": "https://example.com/cb"})).unwrap();
+        assert_eq!(params.fit_comment("ZapPool payout #12"), None);
+        params.comment_allowed = 7;
+        assert_eq!(
+            params.fit_comment("ZapPool payout #12"),
+            Some("ZapPool".into())
+        );
+        params.comment_allowed = 255;
+        assert_eq!(
+            params.fit_comment("ZapPool payout #12"),
+            Some("ZapPool payout #12".into())
+        );
+        assert_eq!(params.fit_comment(""), None);
+    }
+
+    #[test]
+    fn test_success_action_decoded() {
+        let preimage = "0000000000000000000000000000000000000000000000000000000000000001";
+        let msg = SuccessAction::Message {
+            message: "Thanks!".into(),
+        };
+        assert_eq!(msg.decoded(preimage), "message: Thanks!");
+        let url = SuccessAction::Url {
+            description: "Receipt".into(),
+            url: "https://example.com/r".into(),
+        };
+        assert_eq!(url.decoded(preimage), "url: Receipt https://example.com/r");
+
+        // Encrypted with the preimage
+        let key = <[u8; 32]>::from_hex(preimage).unwrap();
+        let iv = [3u8; 16];
+        let ciphertext = cbc::Encryptor::<Aes256>::new(&key.into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(b"secret code");
+        let aes = SuccessAction::Aes {
+            description: "Code".into(),
+            ciphertext: BASE64.encode(ciphertext),
+            iv: BASE64.encode(iv),
+        };
+        assert_eq!(aes.decoded(preimage), "aes: Code secret code");
+        // Wrong preimage
+        let wrong = "0000000000000000000000000000000000000000000000000000000000000002";
+        assert!(
+            aes.decoded(wrong)
+                .starts_with("aes: Code (could not decrypt")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_invoice() {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(LnurlServerConfig::default(), mock.clone()).await;
+        let params = fetch_pay_params(&server.ln_address("miner")).await.unwrap();
+        assert!(params.allows_nostr);
+        assert_eq!(
+            params.zap_pubkey().ok(),
+            LnurlServerConfig::default().nostr_pubkey
+        );
+        let inv = params
+            .request_invoice(5000, &[("comment", "Hello & bye")])
+            .await
+            .unwrap();
+        assert!(inv.pr.starts_with("lnmock"));
+        let callback_req = server.last_callback_request().unwrap();
+        assert_eq!(callback_req["amount"], "5000");
+        assert_eq!(callback_req["comment"], "Hello & bye");
+
+        // Amount checked before the callback
+        let err = get_invoice(&server.ln_address("miner"), 1, &[]).await;
+        assert!(matches!(err, Err(LnurlPayError::AmountOutOfRange { .. })));
+        assert_eq!(server.callback_request_count(), 1);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::mock_backend::MockBackend;
+
+use aes::Aes256;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use cbc::cipher::{BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+use hex_conservative::FromHex;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use nostr::hashes::{Hash, sha256};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//
+// Local LNURL-pay / Lightning Address stand-in server, for tests.
+// Serves /.well-known/lnurlp/<user> and a callback returning invoices of a MockBackend.
+//
+
+/// How the callback endpoint responds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallbackMode {
+    /// Proper response with an invoice for the requested amount
+    Invoice,
+    /// Invoice for a different amount than requested
+    WrongAmountInvoice,
+    /// Invoice already expired
+    ExpiredInvoice,
+    /// Invoice with a description hash not matching the metadata / zap request
+    WrongDescriptionHashInvoice,
+    /// Invoice with the description hash of the metadata, also for zaps (zap request ignored)
+    ZapRequestIgnoredInvoice,
+    /// JSON without the 'pr' field
+    MissingPr,
+    /// LUD-06 error response, {"status":"ERROR","reason":..}
+    StatusError,
+    /// Not a JSON response
+    Malformed,
+    /// HTTP 500
+    ServerError,
+}
+
+#[derive(Clone, Debug)]
+pub struct LnurlServerConfig {
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    pub allows_nostr: bool,
+    pub nostr_pubkey: Option<String>,
+    /// HTTP status of the metadata (.well-known) endpoint
+    pub metadata_status: u16,
+    /// Metadata response is not JSON
+    pub metadata_malformed: bool,
+    /// Metadata response lacks the callback field
+    pub metadata_no_callback: bool,
+    /// Callback URL points to a closed port
+    pub callback_unreachable: bool,
+    pub callback_mode: CallbackMode,
+    /// Max. comment length (LUD-12), 0 for none
+    pub comment_allowed: u32,
+    /// Success action returned with the invoice (LUD-09)
+    pub success_action: Option<serde_json::Value>,
+    /// If set, an AES success action with this text is returned, encrypted with the preimage
+    /// of the invoice (LUD-10)
+    pub aes_success_text: Option<String>,
+}
+
+impl Default for LnurlServerConfig {
+    fn default() -> Self {
+        Self {
+            min_sendable: 1000,
+            max_sendable: 100_000_000,
+            allows_nostr: true,
+            nostr_pubkey: Some(
+                "9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31".to_string(),
+            ),
+            metadata_status: 200,
+            metadata_malformed: false,
+            metadata_no_callback: false,
+            callback_unreachable: false,
+            callback_mode: CallbackMode::Invoice,
+            comment_allowed: 0,
+            success_action: None,
+            aes_success_text: None,
+        }
+    }
+}
+
+/// A running stand-in server. Stops when dropped.
+pub struct LnurlTestServer {
+    pub port: u16,
+    /// Query parameters of the callback requests received
+    pub callback_requests: Arc<Mutex<Vec<HashMap<String, String>>>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for LnurlTestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl LnurlTestServer {
+    /// Start a server on a free local port
+    pub async fn start(config: LnurlServerConfig, mock: MockBackend) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let callback_requests = Arc::new(Mutex::new(Vec::new()));
+        let requests = callback_requests.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Err(_) => continue,
+                    Ok(s) => s,
+                };
+                handle_connection(stream, port, &config, &mock, &requests).await;
+            }
+        });
+        Self {
+            port,
+            callback_requests,
+            handle,
+        }
+    }
+
+    /// Lightning address served by this server, e.g. "miner@127.0.0.1:1234"
+    pub fn ln_address(&self, user: &str) -> String {
+        format!("{user}@127.0.0.1:{}", self.port)
+    }
+
+    pub fn callback_request_count(&self) -> usize {
+        self.callback_requests.lock().unwrap().len()
+    }
+
+    pub fn last_callback_request(&self) -> Option<HashMap<String, String>> {
+        self.callback_requests.lock().unwrap().last().cloned()
+    }
+}
+
+/// Port with nothing listening on it
+pub async fn get_closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn metadata_string(user: &str) -> String {
+    format!("[[\"text/plain\",\"Pay {user}\"],[\"text/identifier\",\"{user}@127.0.0.1\"]]")
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for kv in query.split("&") {
+        if let Some((k, v)) = kv.split_once("=") {
+            let v = urlencoding::decode(v)
+                .map(|d| d.to_string())
+                .unwrap_or(v.to_string());
+            let _ = params.insert(k.to_string(), v);
+        }
+    }
+    params
+}
+
+// AES success action, encrypted with the preimage (hex) (LUD-10)
+fn aes_success_action(text: &str, preimage_hex: &str) -> serde_json::Value {
+    let key = <[u8; 32]>::from_hex(preimage_hex).unwrap();
+    let iv = [5u8; 16];
+    let ciphertext = cbc::Encryptor::<Aes256>::new(&key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(text.as_bytes());
+    json!({
+        "tag": "aes",
+        "description": "Your code",
+        "ciphertext": BASE64.encode(ciphertext),
+        "iv": BASE64.encode(iv),
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, status: u16, body: &str) {
+    let resp = format!(
+        "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(resp.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    port: u16,
+    config: &LnurlServerConfig,
+    mock: &MockBackend,
+    requests: &Arc<Mutex<Vec<HashMap<String, String>>>>,
+) {
+    // Read the request head, body is not used
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match stream.read(&mut chunk).await {
+            Err(_) | Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    let head = String::from_utf8_lossy(&buf).to_string();
+    let target = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let (path, query) = target.split_once("?").unwrap_or((&target, ""));
+
+    if let Some(user) = path.strip_prefix("/.well-known/lnurlp/") {
+        if config.metadata_status != 200 {
+            return write_response(&mut stream, config.metadata_status, "{}").await;
+        }
+        if config.metadata_malformed {
+            return write_response(&mut stream, 200, "<html>not json</html>").await;
+        }
+        let callback_port = if config.callback_unreachable {
+            get_closed_port().await
+        } else {
+            port
+        };
+        let mut metadata = json!({
+            "tag": "payRequest",
+            "callback": format!("http://127.0.0.1:{callback_port}/callback/{user}"),
+            "minSendable": config.min_sendable,
+            "maxSendable": config.max_sendable,
+            "metadata": metadata_string(user),
+            "allowsNostr": config.allows_nostr,
+        });
+        if let Some(pk) = &config.nostr_pubkey {
+            metadata["nostrPubkey"] = json!(pk);
+        }
+        if config.comment_allowed > 0 {
+            metadata["commentAllowed"] = json!(config.comment_allowed);
+        }
+        if config.metadata_no_callback {
+            let _ = metadata.as_object_mut().unwrap().remove("callback");
+        }
+        return write_response(&mut stream, 200, &metadata.to_string()).await;
+    }
+
+    if let Some(user) = path.strip_prefix("/callback/") {
+        let params = parse_query(query);
+        requests.lock().unwrap().push(params.clone());
+        let amount = params
+            .get("amount")
+            .and_then(|a| a.parse::<u64>().ok())
+            .unwrap_or(0);
+        // Description hash: of the zap request for zaps (NIP-57), of the metadata otherwise (LUD-06)
+        let description = match params.get("nostr") {
+            Some(zap_request) if config.callback_mode != CallbackMode::ZapRequestIgnoredInvoice => {
+                zap_request.clone()
+            }
+            _ => metadata_string(user),
+        };
+        let description_hash = sha256::Hash::hash(description.as_bytes()).to_string();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = match config.callback_mode {
+            CallbackMode::Invoice | CallbackMode::ZapRequestIgnoredInvoice => {
+                let pr = mock.create_invoice(amount, Some(description_hash), now, 3600);
+                let mut resp = json!({"pr": pr, "routes": []});
+                if let Some(sa) = &config.success_action {
+                    resp["successAction"] = sa.clone();
+                }
+                if let Some(text) = &config.aes_success_text {
+                    resp["successAction"] =
+                        aes_success_action(text, &mock.invoice_preimage(&pr).unwrap());
+                }
+                resp.to_string()
+            }
+            CallbackMode::WrongAmountInvoice => {
+                let pr = mock.create_invoice(2 * amount, Some(description_hash), now, 3600);
+                json!({"pr": pr, "routes": []}).to_string()
+            }
+            CallbackMode::ExpiredInvoice => {
+                let pr = mock.create_invoice(amount, Some(description_hash), now - 7200, 3600);
+                json!({"pr": pr, "routes": []}).to_string()
+            }
+            CallbackMode::WrongDescriptionHashInvoice => {
+                let wrong_hash = sha256::Hash::hash(b"something else").to_string();
+                let pr = mock.create_invoice(amount, Some(wrong_hash), now, 3600);
+                json!({"pr": pr, "routes": []}).to_string()
+            }
+            CallbackMode::MissingPr => json!({"routes": []}).to_string(),
+            CallbackMode::StatusError => {
+                json!({"status": "ERROR", "reason": "Mock: cannot issue invoice"}).to_string()
+            }
+            CallbackMode::Malformed => "<html>not json</html>".to_string(),
+            CallbackMode::ServerError => {
+                return write_response(&mut stream, 500, "{}").await;
+            }
+        };
+        return write_response(&mut stream, 200, &body).await;
+    }
+
+    write_response(&mut stream, 404, "{}").await
+}
@@ -1,4 +1,10 @@
-use payer::nostr_zap::nostr_zap;
+use payer::fee_limits::FeeLimits;
+use payer::ln_backend::get_ln_backend_from_config;
+use payer::nostr_profile::get_nostr_ln_address;
+use payer::nostr_relays::get_nostr_relays_from_config;
+use payer::nostr_zap::{ZapOptions, nostr_zap};
+use payer::payer::no_before_pay;
+use payer::zap_message::ZapMessageConfig;
 
 use dotenv;
 use seedstore::KeyStore;
@@ -22,12 +28,30 @@ async fn main() {
         .to_vec();
 
     let rec_npub = "npub12rv5lskctqxxs2c8rf2zlzc7xx3qpvzs3w4etgemauy9thegr43sf485vg";
-    let relays = vec![
-        "wss://relay.primal.net/",
-        "wss://relay.damus.io/",
-        "wss://nos.lol/",
-    ];
-    match nostr_zap(2000, &nsec, rec_npub, &relays).await {
+    let relays = get_nostr_relays_from_config().unwrap();
+    let ln_address = get_nostr_ln_address(rec_npub, &relays).await.unwrap();
+    let ln_backend = get_ln_backend_from_config().unwrap();
+    let amount_msat = 2000;
+    let max_fee_msat = FeeLimits::new_from_config()
+        .unwrap()
+        .max_fee_for_amount(amount_msat);
+    let zap_mode = ZapMessageConfig::new_from_config().unwrap().mode;
+    match nostr_zap(
+        amount_msat,
+        &nsec,
+        rec_npub,
+        &ln_address,
+        ln_backend.as_ref(),
+        &relays,
+        ZapOptions {
+            message: "Test zap",
+            zap_mode,
+            max_fee_msat,
+            before_pay: &no_before_pay,
+        },
+    )
+    .await
+    {
         Err(e) => println!("ERROR: {:?}", e),
         Ok(_) => {}
     }
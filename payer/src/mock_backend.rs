@@ -0,0 +1,422 @@
+use crate::common::PaymentResult;
+use crate::ln_backend::{
+    BackendBalance, DecodedInvoice, LightningBackend, LookupStatus, PaymentLookup,
+};
+
+use common_rs::error_codes::*;
+
+use async_trait::async_trait;
+use nostr::hashes::{Hash, sha256};
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Scripted outcome of a payment attempt on the mock backend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MockPayOutcome {
+    Success,
+    NonFinalFailure,
+    FinalFailure,
+    InsufficientFunds,
+    /// Only some of the parts arrived, the payment is not complete
+    Partial,
+    /// No route within the fee cap; also the outcome when the fee is above the cap
+    FeeCapExceeded,
+}
+
+/// An invoice paid (successfully) through the mock backend
+#[derive(Clone, Debug)]
+pub struct MockPaidInvoice {
+    pub invoice: String,
+    pub amount_msat: u64,
+    pub fee_msat: u32,
+    pub label: String,
+}
+
+struct MockState {
+    /// Outcomes for the next attempts, in order; default_outcome when empty
+    outcomes: VecDeque<MockPayOutcome>,
+    default_outcome: MockPayOutcome,
+    fee_msat: u32,
+    balance_msat: u64,
+    invoice_counter: u32,
+    invoices: HashMap<String, (DecodedInvoice, String)>,
+    /// BOLT12 offers, with optional fixed amount
+    offers: HashMap<String, Option<u64>>,
+    lookups: HashMap<String, PaymentLookup>,
+    paid: Vec<MockPaidInvoice>,
+    attempt_count: u32,
+}
+
+/// In-process deterministic Lightning backend, for tests.
+/// Records paid invoices, can be scripted to fail, and reports a fixed fee.
+/// Clones share the same state (e.g. one for the payer, one for an invoice server).
+#[derive(Clone)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                outcomes: VecDeque::new(),
+                default_outcome: MockPayOutcome::Success,
+                fee_msat: 0,
+                balance_msat: 1_000_000_000,
+                invoice_counter: 0,
+                invoices: HashMap::new(),
+                offers: HashMap::new(),
+                lookups: HashMap::new(),
+                paid: Vec::new(),
+                attempt_count: 0,
+            })),
+        }
+    }
+
+    /// Outcomes of the next payment attempts, in order
+    pub fn script_outcomes(&self, outcomes: &[MockPayOutcome]) {
+        let mut state = self.state.lock().unwrap();
+        state.outcomes.extend(outcomes.iter());
+    }
+
+    /// Outcome when no scripted outcome is left
+    pub fn set_default_outcome(&self, outcome: MockPayOutcome) {
+        self.state.lock().unwrap().default_outcome = outcome;
+    }
+
+    pub fn set_fee_msat(&self, fee_msat: u32) {
+        self.state.lock().unwrap().fee_msat = fee_msat;
+    }
+
+    pub fn set_balance_msat(&self, balance_msat: u64) {
+        self.state.lock().unwrap().balance_msat = balance_msat;
+    }
+
+    /// Create an invoice known by the mock (decodable), return the invoice string
+    pub fn create_invoice(
+        &self,
+        amount_msat: u64,
+        description_hash: Option<String>,
+        created_at: u64,
+        expiry: u64,
+    ) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.invoice_counter += 1;
+        let invoice = format!("lnmock{}n{}", amount_msat, state.invoice_counter);
+        let preimage = sha256::Hash::hash(invoice.as_bytes()).to_string();
+        let payment_hash = sha256::Hash::hash(preimage.as_bytes()).to_string();
+        let decoded = DecodedInvoice {
+            payment_hash,
+            amount_msat: Some(amount_msat),
+            description_hash,
+            created_at,
+            expiry,
+        };
+        let _ = state.invoices.insert(invoice.clone(), (decoded, preimage));
+        invoice
+    }
+
+    /// Create a BOLT12 offer known by the mock, with optional fixed amount
+    pub fn create_offer(&self, amount_msat: Option<u64>) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.invoice_counter += 1;
+        let offer = format!("lno1mock{}", state.invoice_counter);
+        let _ = state.offers.insert(offer.clone(), amount_msat);
+        offer
+    }
+
+    /// Preimage (hex) of an invoice created by the mock
+    pub fn invoice_preimage(&self, invoice: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .invoices
+            .get(invoice)
+            .map(|(_, preimage)| preimage.clone())
+    }
+
+    /// Invoices paid successfully so far
+    pub fn paid_invoices(&self) -> Vec<MockPaidInvoice> {
+        self.state.lock().unwrap().paid.clone()
+    }
+
+    /// Number of payment attempts so far (incl. failed ones)
+    pub fn attempt_count(&self) -> u32 {
+        self.state.lock().unwrap().attempt_count
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for MockBackend {
+    fn kind_name(&self) -> String {
+        "MOCK".to_string()
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amnt_msat: u64,
+        label: &str,
+        max_fee_msat: u64,
+    ) -> Result<PaymentResult, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        state.attempt_count += 1;
+        let (decoded, preimage) = match state.invoices.get(invoice) {
+            None => {
+                return Ok(PaymentResult::new(
+                    false,
+                    false,
+                    0,
+                    &format!("Unknown invoice '{invoice}'"),
+                    "",
+                    "",
+                    0,
+                    0,
+                    "",
+                ));
+            }
+            Some((d, p)) => (d.clone(), p.clone()),
+        };
+        let amount_msat = decoded.amount_msat.unwrap_or(amnt_msat);
+        let fee_msat = state.fee_msat;
+        let outcome = match state.outcomes.pop_front() {
+            Some(o) => o,
+            None => state.default_outcome,
+        };
+        let outcome = if outcome == MockPayOutcome::Success && fee_msat as u64 > max_fee_msat {
+            MockPayOutcome::FeeCapExceeded
+        } else if outcome == MockPayOutcome::Success
+            && state.balance_msat < amount_msat + fee_msat as u64
+        {
+            MockPayOutcome::InsufficientFunds
+        } else {
+            outcome
+        };
+
+        let lookup_status = match outcome {
+            MockPayOutcome::Success => LookupStatus::Complete,
+            MockPayOutcome::Partial => LookupStatus::Pending,
+            _ => LookupStatus::Failed,
+        };
+        let _ = state.lookups.insert(
+            decoded.payment_hash.clone(),
+            PaymentLookup {
+                status: lookup_status,
+                amount_msat,
+                amount_sent_msat: amount_msat + fee_msat as u64,
+                preimage: if lookup_status == LookupStatus::Complete {
+                    preimage.clone()
+                } else {
+                    "".to_string()
+                },
+            },
+        );
+
+        let res = match outcome {
+            MockPayOutcome::Success => {
+                state.balance_msat -= amount_msat + fee_msat as u64;
+                state.paid.push(MockPaidInvoice {
+                    invoice: invoice.to_string(),
+                    amount_msat,
+                    fee_msat,
+                    label: label.to_string(),
+                });
+                PaymentResult::new(
+                    true,
+                    false,
+                    0,
+                    "OK",
+                    "",
+                    "",
+                    amount_msat + fee_msat as u64,
+                    fee_msat,
+                    &format!("{} {}", preimage, decoded.payment_hash),
+                )
+            }
+            MockPayOutcome::NonFinalFailure => {
+                PaymentResult::new(false, true, 0, "Mock: no route", "", "", 0, 0, "")
+            }
+            MockPayOutcome::FinalFailure => {
+                PaymentResult::new(false, false, 0, "Mock: invoice rejected", "", "", 0, 0, "")
+            }
+            MockPayOutcome::InsufficientFunds => PaymentResult::new(
+                false,
+                true,
+                ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS,
+                "Mock: insufficient funds",
+                "",
+                "",
+                0,
+                0,
+                "",
+            ),
+            MockPayOutcome::Partial => {
+                PaymentResult::new(false, true, 0, "Mock: partial completion", "", "", 0, 0, "")
+            }
+            MockPayOutcome::FeeCapExceeded => PaymentResult::new(
+                false,
+                true,
+                ERROR_LN_BOLT11_FEE_CAP_EXCEEDED,
+                &format!("Mock: fee {fee_msat} above cap {max_fee_msat}"),
+                "",
+                "",
+                0,
+                0,
+                "",
+            ),
+        };
+        Ok(res)
+    }
+
+    async fn decode_invoice(&self, invoice: &str) -> Result<DecodedInvoice, Box<dyn Error>> {
+        match self.state.lock().unwrap().invoices.get(invoice) {
+            None => Err(format!("Invalid invoice, '{invoice}'").into()),
+            Some((d, _p)) => Ok(d.clone()),
+        }
+    }
+
+    async fn fetch_invoice(
+        &self,
+        offer: &str,
+        amnt_msat: u64,
+        _payer_note: &str,
+    ) -> Result<String, (bool, Box<dyn Error>)> {
+        let mut state = self.state.lock().unwrap();
+        match state.offers.get(offer) {
+            None => return Err((false, format!("Unknown offer, '{offer}'").into())),
+            Some(Some(a)) if *a != amnt_msat => {
+                return Err((
+                    false,
+                    format!("Offer amount {a} differs from {amnt_msat}").into(),
+                ));
+            }
+            Some(_) => {}
+        }
+        state.invoice_counter += 1;
+        let invoice = format!("lnimock{}n{}", amnt_msat, state.invoice_counter);
+        let preimage = sha256::Hash::hash(invoice.as_bytes()).to_string();
+        let payment_hash = sha256::Hash::hash(preimage.as_bytes()).to_string();
+        let decoded = DecodedInvoice {
+            payment_hash,
+            amount_msat: Some(amnt_msat),
+            description_hash: None,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            expiry: 7200,
+        };
+        let _ = state.invoices.insert(invoice.clone(), (decoded, preimage));
+        Ok(invoice)
+    }
+
+    async fn get_balance(&self) -> Result<BackendBalance, Box<dyn Error>> {
+        Ok(BackendBalance {
+            channel_msat: self.state.lock().unwrap().balance_msat,
+            onchain_msat: 0,
+        })
+    }
+
+    async fn lookup_payment(
+        &self,
+        payment_hash: &str,
+    ) -> Result<Option<PaymentLookup>, Box<dyn Error>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .lookups
+            .get(payment_hash)
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_pay_and_lookup() {
+        let mock = MockBackend::new();
+        mock.set_fee_msat(12);
+        let invoice = mock.create_invoice(5000, None, 1000, 600);
+        let decoded = mock.decode_invoice(&invoice).await.unwrap();
+        assert_eq!(decoded.amount_msat, Some(5000));
+
+        let res = mock
+            .pay_invoice(&invoice, 5000, "label", 1000)
+            .await
+            .unwrap();
+        assert!(res.success);
+        assert_eq!(res.paid_amount, 5012);
+        assert_eq!(res.paid_fee, 12);
+        let paid = mock.paid_invoices();
+        assert_eq!(paid.len(), 1);
+        assert_eq!(paid[0].invoice, invoice);
+        assert_eq!(paid[0].amount_msat, 5000);
+        assert_eq!(paid[0].fee_msat, 12);
+        assert_eq!(paid[0].label, "label");
+
+        let lookup = mock
+            .lookup_payment(&decoded.payment_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lookup.status, LookupStatus::Complete);
+        assert!(mock.lookup_payment("00").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mock_scripted_outcomes() {
+        let mock = MockBackend::new();
+        mock.script_outcomes(&[
+            MockPayOutcome::NonFinalFailure,
+            MockPayOutcome::FinalFailure,
+            MockPayOutcome::InsufficientFunds,
+            MockPayOutcome::Partial,
+        ]);
+        let invoice = mock.create_invoice(5000, None, 1000, 600);
+
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(!res.success && res.err_nonfinal);
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(!res.success && !res.err_nonfinal);
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(!res.success && res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS);
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(!res.success && res.err_nonfinal);
+        // Back to default
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(res.success);
+
+        assert_eq!(mock.attempt_count(), 5);
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_balance() {
+        let mock = MockBackend::new();
+        mock.set_balance_msat(4000);
+        let invoice = mock.create_invoice(5000, None, 1000, 600);
+        let res = mock.pay_invoice(&invoice, 5000, "", 1000).await.unwrap();
+        assert!(!res.success);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS);
+        assert_eq!(mock.get_balance().await.unwrap().channel_msat, 4000);
+    }
+
+    #[tokio::test]
+    async fn test_mock_fee_cap() {
+        let mock = MockBackend::new();
+        mock.set_fee_msat(200);
+        let invoice = mock.create_invoice(5000, None, 1000, 600);
+        let res = mock.pay_invoice(&invoice, 5000, "", 199).await.unwrap();
+        assert!(!res.success && res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_FEE_CAP_EXCEEDED);
+        let res = mock.pay_invoice(&invoice, 5000, "", 200).await.unwrap();
+        assert!(res.success);
+        assert_eq!(res.paid_fee, 200);
+    }
+}
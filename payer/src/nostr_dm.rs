@@ -0,0 +1,528 @@
+use crate::common::{PayerParameters, PaymentMethod};
+use crate::nostr_profile::{RELAY_QUERY_DEADLINE, get_nostr_dm_relays, get_nostr_read_relays};
+use crate::nostr_relays::recipient_relays;
+
+use common_rs::db_pc as db;
+use common_rs::dto_pc::{PayRequest, Payment};
+use common_rs::error_codes::*;
+
+use dotenv;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{SinkExt, StreamExt};
+use nostr::nips::nip04;
+use nostr::util::JsonUtil;
+use nostr::{EventBuilder, Keys, Kind, PublicKey, SecretKey, Tag};
+use rusqlite::Connection;
+use serde_json::{Value, json};
+use tokio::time::{Instant as TokioInstant, timeout, timeout_at};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Period of checking for daily summaries to be sent, secs
+pub const DM_SUMMARY_CHECK_PERIOD: u32 = 3600;
+/// At most one summary per this period, secs
+const DM_SUMMARY_PERIOD: u32 = 86400;
+
+/// Protocol of Nostr DMs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmProtocol {
+    /// Gift-wrapped private DM (NIP-17)
+    Nip17,
+    /// Legacy encrypted DM (NIP-04)
+    Nip04,
+}
+
+impl fmt::Display for DmProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Nip17 => "NIP17",
+            Self::Nip04 => "NIP04",
+        })
+    }
+}
+
+impl FromStr for DmProtocol {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().replace("-", "").as_str() {
+            "NIP17" => Ok(Self::Nip17),
+            "NIP04" => Ok(Self::Nip04),
+            _ => Err(format!("Unknown DM protocol {s}").into()),
+        }
+    }
+}
+
+/// DM notification settings, from config
+#[derive(Clone, Debug, PartialEq)]
+pub struct DmConfig {
+    /// Notify miners of final payment failures
+    pub enabled: bool,
+    pub protocol: DmProtocol,
+    /// Also send a daily summary of successful payouts
+    pub daily_summary: bool,
+}
+
+fn get_env_bool(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Err(_) => default,
+        Ok(s) => matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"),
+    }
+}
+
+impl DmConfig {
+    /// From config: NOSTR_DM_NOTIFICATIONS (default true), NOSTR_DM_PROTOCOL (NIP17 or NIP04),
+    /// NOSTR_DM_DAILY_SUMMARY (default false)
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let protocol = match env::var("NOSTR_DM_PROTOCOL") {
+            Err(_) => DmProtocol::Nip17,
+            Ok(s) => DmProtocol::from_str(&s)?,
+        };
+        Ok(Self {
+            enabled: get_env_bool("NOSTR_DM_NOTIFICATIONS", true),
+            protocol,
+            daily_summary: get_env_bool("NOSTR_DM_DAILY_SUMMARY", false),
+        })
+    }
+}
+
+impl Default for DmConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            protocol: DmProtocol::Nip17,
+            daily_summary: false,
+        }
+    }
+}
+
+/// Publish an event to a relay, wait for its OK
+async fn publish_event(
+    relay_url: &str,
+    event: &Value,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let (ws_stream, _) = connect_async(relay_url).await?;
+    let (mut write, mut read) = ws_stream.split();
+    write
+        .send(Message::Text(json!(["EVENT", event]).to_string()))
+        .await?;
+
+    let wait_ok = async {
+        while let Some(msg) = read.next().await {
+            let text = match msg? {
+                Message::Text(t) => t,
+                _ => continue,
+            };
+            let response: Value = serde_json::from_str(&text)?;
+            if response[0] == "OK" && response[1] == event["id"] {
+                if response[2] == true {
+                    return Ok(());
+                }
+                return Err(format!("Event rejected by relay, {}", response[3]).into());
+            }
+        }
+        Err::<(), Box<dyn Error>>("WebSocket connection closed".into())
+    };
+    match timeout(timeout_duration, wait_ok).await {
+        Err(_) => Err("Timeout waiting for OK".into()),
+        Ok(res) => res,
+    }
+}
+
+/// Publish an event to all relays concurrently, return the number of relays that accepted it
+pub(crate) async fn publish_event_to_relays(
+    relays: &[String],
+    event: &Value,
+    deadline: Duration,
+) -> usize {
+    let deadline_instant = TokioInstant::now() + deadline;
+    let mut publishes = relays
+        .iter()
+        .map(|relay_url| async move {
+            let res = publish_event(relay_url, event, deadline).await;
+            (relay_url.clone(), res)
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut accepted = 0;
+    loop {
+        match timeout_at(deadline_instant, publishes.next()).await {
+            Err(_) => break,
+            Ok(None) => break,
+            Ok(Some((relay_url, Err(e)))) => {
+                println!("Could not publish to relay {relay_url}, {e}");
+            }
+            Ok(Some((_relay_url, Ok(())))) => accepted += 1,
+        }
+    }
+    accepted
+}
+
+/// Build a DM event from the sender to the recipient
+async fn build_dm_event(
+    sender_keys: &Keys,
+    rec_pubkey: PublicKey,
+    message: &str,
+    protocol: DmProtocol,
+) -> Result<Value, Box<dyn Error>> {
+    let event = match protocol {
+        DmProtocol::Nip17 => {
+            EventBuilder::private_msg(sender_keys, rec_pubkey, message, []).await?
+        }
+        DmProtocol::Nip04 => {
+            let encrypted = nip04::encrypt(sender_keys.secret_key(), &rec_pubkey, message)?;
+            EventBuilder::new(Kind::EncryptedDirectMessage, encrypted)
+                .tag(Tag::public_key(rec_pubkey))
+                .sign_with_keys(sender_keys)?
+        }
+    };
+    Ok(serde_json::from_str(&event.as_json())?)
+}
+
+/// Send a DM to an npub: to their DM relays (NIP-17) or read relays (NIP-65), and to our relays
+pub async fn send_dm(
+    sender_nsec_vec: &[u8],
+    rec_npub: &str,
+    message: &str,
+    relays: &[String],
+    protocol: DmProtocol,
+) -> Result<(), Box<dyn Error>> {
+    let sender_keys = Keys::new(SecretKey::from_slice(sender_nsec_vec)?);
+    let rec_pubkey = PublicKey::from_str(rec_npub)?;
+
+    let mut rec_relays = Vec::new();
+    if protocol == DmProtocol::Nip17 {
+        rec_relays = get_nostr_dm_relays(rec_npub, relays).await;
+    }
+    if rec_relays.is_empty() {
+        rec_relays = get_nostr_read_relays(rec_npub, relays).await;
+    }
+    let dm_relays = recipient_relays(relays, &rec_relays);
+
+    let event = build_dm_event(&sender_keys, rec_pubkey, message, protocol).await?;
+    let accepted = publish_event_to_relays(&dm_relays, &event, RELAY_QUERY_DEADLINE).await;
+    if accepted == 0 {
+        return Err(format!(
+            "DM to '{rec_npub}' not accepted by any relay ({:?})",
+            dm_relays
+        )
+        .into());
+    }
+    println!("DM sent to '{rec_npub}', {accepted} relays");
+    Ok(())
+}
+
+/// The npub of the miner of a pay request, if paid through Nostr
+fn npub_of_payreq(pr: &PayRequest) -> Option<&str> {
+    if pr.pay_method == PaymentMethod::PmNostrLightning.to_string()
+        || pr.pay_method == PaymentMethod::PmNostrZap.to_string()
+        || pr.pay_method == PaymentMethod::PmCashu.to_string()
+        || (pr.pay_method == PaymentMethod::PmBolt12.to_string() && pr.pri_id.starts_with("npub1"))
+    {
+        Some(&pr.pri_id)
+    } else {
+        None
+    }
+}
+
+/// Reason of a payment failure in human terms, from its error code
+pub fn failure_reason_text(error_code: u8) -> &'static str {
+    match error_code {
+        ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE | ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE => {
+            "we could not find a Lightning Address in your Nostr profile (lud16)"
+        }
+        ERROR_NOSTR_ZAP_NONFINAL_FAILURE | ERROR_NOSTR_ZAP_FINAL_FAILURE => {
+            "the zap to your Lightning Address failed"
+        }
+        ERROR_NOSTR_ZAP_NOT_SUPPORTED => "your Lightning Address provider does not support zaps",
+        ERROR_LN_ADDRESS_NONFINAL_FAILURE => "your Lightning Address server could not be reached",
+        ERROR_LN_ADDRESS_FINAL_FAILURE => "your Lightning Address did not accept the payment",
+        ERROR_LN_BOLT11_INVOICE_MISMATCH => {
+            "the invoice from your wallet did not match the payout amount"
+        }
+        ERROR_LN_BOLT11_FEE_CAP_EXCEEDED => {
+            "we found no Lightning route to your wallet within our fee limit"
+        }
+        ERROR_PAYMENT_TIMEOUT => "the payment attempts kept timing out",
+        ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS => "of a temporary problem on our side",
+        ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE | ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE => {
+            "the Lightning payment to your wallet failed"
+        }
+        ERROR_CASHU_NONFINAL_FAILURE | ERROR_CASHU_FINAL_FAILURE => {
+            "your Cashu token could not be minted or sent"
+        }
+        ERROR_CASHU_NEEDS_REVIEW => "your Cashu token has to be checked by us",
+        ERROR_BOLT12_OFFER_NONFINAL_FAILURE | ERROR_BOLT12_OFFER_FINAL_FAILURE => {
+            "we could not get an invoice from your BOLT12 offer"
+        }
+        _ => "of an unexpected error",
+    }
+}
+
+// Only the wording of the error code, the error string may contain internal details
+fn failure_message(pr: &PayRequest, paym: &Payment) -> String {
+    format!(
+        "ZapPool: your payout of {} sats could not be sent, because {}. \
+        Please check your settings, or contact us.",
+        pr.req_amnt / 1000,
+        failure_reason_text(paym.error_code),
+    )
+}
+
+/// Notify the miner of a final payment failure by DM, if paid through Nostr and not opted out.
+/// Return true if sent.
+pub async fn notify_payment_failure(
+    conn: &Connection,
+    payer_params: &PayerParameters,
+    pr: &PayRequest,
+    paym: &Payment,
+) -> Result<bool, Box<dyn Error>> {
+    let npub = match npub_of_payreq(pr) {
+        None => return Ok(false),
+        Some(n) => n,
+    };
+    if db::nostr_dm_pref_get(conn, pr.miner_id)?.opt_out {
+        println!("Miner {} opted out of DMs, not notifying", pr.miner_id);
+        return Ok(false);
+    }
+    send_dm(
+        &payer_params.nostr_secret_key,
+        npub,
+        &failure_message(pr, paym),
+        &payer_params.nostr_relays,
+        payer_params.dm_config.protocol,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Successful payments of a miner: miner ID, npub, payments (pay time, amount)
+type MinerPayouts = (u32, String, Vec<(u32, u64)>);
+
+/// Send a summary DM of successful payouts in the last day, to miners paid through Nostr,
+/// at most once a day per miner. Miners who opted out are skipped.
+pub async fn send_daily_summaries(
+    conn: &Connection,
+    payer_params: &PayerParameters,
+    now_utc: u32,
+) -> Result<(), Box<dyn Error>> {
+    let start_time = now_utc.saturating_sub(DM_SUMMARY_PERIOD);
+    let payms = db::payment_get_all_after_time(conn, start_time)?;
+
+    let mut per_miner: Vec<MinerPayouts> = Vec::new();
+    for (pr, paym) in &payms {
+        if paym.status != STATUS_SUCCESS_FINAL {
+            continue;
+        }
+        let npub = match npub_of_payreq(pr) {
+            None => continue,
+            Some(n) => n,
+        };
+        match per_miner.iter_mut().find(|(m, _, _)| *m == pr.miner_id) {
+            Some((_, _, paid)) => paid.push((paym.pay_time, paym.paid_amnt)),
+            None => per_miner.push((
+                pr.miner_id,
+                npub.to_string(),
+                vec![(paym.pay_time, paym.paid_amnt)],
+            )),
+        }
+    }
+
+    for (miner_id, npub, paid) in per_miner {
+        let mut pref = db::nostr_dm_pref_get(conn, miner_id)?;
+        if pref.opt_out || now_utc < pref.last_summary_time + DM_SUMMARY_PERIOD {
+            continue;
+        }
+        let new_paid = paid
+            .iter()
+            .filter(|(t, _)| *t > pref.last_summary_time)
+            .collect::<Vec<_>>();
+        if new_paid.is_empty() {
+            continue;
+        }
+        let total_msat: u64 = new_paid.iter().map(|(_, a)| a).sum();
+        let message = format!(
+            "ZapPool daily summary: {} payout(s), {} sats in total. Thank you for mining with us!",
+            new_paid.len(),
+            total_msat / 1000
+        );
+        match send_dm(
+            &payer_params.nostr_secret_key,
+            &npub,
+            &message,
+            &payer_params.nostr_relays,
+            payer_params.dm_config.protocol,
+        )
+        .await
+        {
+            Err(e) => println!("WARNING: Could not send summary DM to '{npub}', {e}"),
+            Ok(_) => {
+                pref.last_summary_time = now_utc;
+                db::nostr_dm_pref_upsert(conn, &pref)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::test_payment;
+    use crate::mock_backend::MockBackend;
+    use crate::nostr_test_relay::{NostrRelayConfig, NostrTestRelay};
+    use crate::nostr_zap::npub_from_secret_vec;
+    use crate::payer::save_payment;
+    use common_rs::dto_pc::NostrDmPref;
+    use nostr::Event;
+    use nostr::nips::nip59;
+
+    const NOW: u32 = 1_760_000_000;
+
+    fn rec_keys() -> Keys {
+        Keys::new(SecretKey::from_slice(&[8u8; 32]).unwrap())
+    }
+
+    fn test_payer_params(relay_url: &str, protocol: DmProtocol) -> PayerParameters {
+        PayerParameters {
+            nostr_relays: vec![relay_url.to_string()],
+            dm_config: DmConfig {
+                enabled: true,
+                protocol,
+                daily_summary: true,
+            },
+            ..PayerParameters::for_test(MockBackend::new())
+        }
+    }
+
+    // DB with a pay request for the test recipient, and its payment with the given status
+    fn create_test_db_with_payment(
+        status: u8,
+        error_code: u8,
+        pay_time: u32,
+    ) -> (Connection, PayRequest, Payment) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            21_000,
+            PaymentMethod::PmNostrLightning.to_string(),
+            rec_npub,
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let mut paym = Payment {
+            status,
+            status_time: pay_time,
+            error_code,
+            error_str: "ERROR: No lud16".into(),
+            retry_cnt: 1,
+            fail_time: NOW,
+            paid_amnt: if status == STATUS_SUCCESS_FINAL {
+                21_000
+            } else {
+                0
+            },
+            pay_time,
+            max_fee: 1000,
+            ..test_payment(pr.id, &pr.pay_method, NOW)
+        };
+        save_payment(&mut conn, &mut paym).unwrap();
+        (conn, pr, paym)
+    }
+
+    async fn decrypt_dm(event: &Value) -> String {
+        let event = Event::from_json(event.to_string()).unwrap();
+        match event.kind {
+            Kind::EncryptedDirectMessage => {
+                nip04::decrypt(rec_keys().secret_key(), &event.pubkey, &event.content).unwrap()
+            }
+            Kind::GiftWrap => {
+                let unwrapped = nip59::extract_rumor(&rec_keys(), &event).await.unwrap();
+                unwrapped.rumor.content
+            }
+            _ => panic!("Unexpected kind {}", event.kind),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notify_payment_failure() {
+        for protocol in [DmProtocol::Nip04, DmProtocol::Nip17] {
+            let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+            let payer_params = test_payer_params(&relay.url(), protocol);
+            let (conn, pr, paym) = create_test_db_with_payment(
+                STATUS_FINAL_FAILURE,
+                ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE,
+                0,
+            );
+
+            assert!(
+                notify_payment_failure(&conn, &payer_params, &pr, &paym)
+                    .await
+                    .unwrap()
+            );
+            let events = relay.events();
+            assert_eq!(events.len(), 1);
+            let message = decrypt_dm(&events[0]).await;
+            assert!(message.contains("21 sats"));
+            assert!(message.contains("Lightning Address in your Nostr profile (lud16)"));
+            // No internal details
+            assert!(!message.contains("ERROR: No lud16"));
+
+            // Opted out
+            db::nostr_dm_pref_upsert(&conn, &NostrDmPref::new(pr.miner_id, true, 0)).unwrap();
+            assert!(
+                !notify_payment_failure(&conn, &payer_params, &pr, &paym)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(relay.events().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_daily_summaries() {
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let payer_params = test_payer_params(&relay.url(), DmProtocol::Nip04);
+        let (conn, _pr, _paym) = create_test_db_with_payment(STATUS_SUCCESS_FINAL, ERROR_OK, NOW);
+
+        send_daily_summaries(&conn, &payer_params, NOW + 100)
+            .await
+            .unwrap();
+        let events = relay.events();
+        assert_eq!(events.len(), 1);
+        let message = decrypt_dm(&events[0]).await;
+        assert!(message.contains("1 payout(s), 21 sats"));
+
+        // Not again on the same day
+        send_daily_summaries(&conn, &payer_params, NOW + 200)
+            .await
+            .unwrap();
+        assert_eq!(relay.events().len(), 1);
+        // No new payouts since
+        send_daily_summaries(&conn, &payer_params, NOW + 100 + DM_SUMMARY_PERIOD)
+            .await
+            .unwrap();
+        assert_eq!(relay.events().len(), 1);
+    }
+
+    #[test]
+    fn test_dm_protocol_from_str() {
+        assert_eq!(DmProtocol::from_str("nip-17").unwrap(), DmProtocol::Nip17);
+        assert_eq!(DmProtocol::from_str("NIP04").unwrap(), DmProtocol::Nip04);
+        assert!(DmProtocol::from_str("NIP99").is_err());
+    }
+}
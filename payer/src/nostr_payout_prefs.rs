@@ -0,0 +1,272 @@
+use crate::common::PaymentMethod;
+use crate::ln_address::ln_p_url_from_address;
+use crate::nostr_profile::{
+    RELAY_QUERY_DEADLINE, get_events_from_relays, npub_to_hex, select_freshest_event,
+};
+use crate::nostr_relays::get_nostr_relays_from_config;
+use crate::zap_receipt::tag_value;
+
+use common_rs::db_pc as db;
+use common_rs::dto_pc::NostrPayoutPref;
+
+use futures_util::future::join_all;
+use rusqlite::Connection;
+use serde_json::{Value, json};
+
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+//
+// Payout preferences of miners, published by them as a NIP-78 app data event (kind 30078),
+// with the d-tag PAYOUT_PREFS_D_TAG, and JSON content, e.g.:
+//   {"method": "ZAP", "threshold_sats": 21000, "ln_address": "miner@wallet.com"}
+// All fields are optional.
+//
+
+/// d-tag of the payout preferences event
+pub const PAYOUT_PREFS_D_TAG: &str = "zappool-payout-prefs";
+const PAYOUT_PREFS_KIND: u32 = 30078;
+/// Cached preferences younger than this are used without querying the relays, secs
+const PAYOUT_PREFS_CACHE_TTL: u32 = 3600;
+
+#[derive(Debug, serde::Deserialize)]
+struct PayoutPrefsContent {
+    method: Option<String>,
+    threshold_sats: Option<u64>,
+    ln_address: Option<String>,
+}
+
+/// Payout preferences from a (verified) preferences event. Invalid values are ignored.
+fn payout_pref_from_event(
+    npub: &str,
+    event: &Value,
+    now_utc: u32,
+) -> Result<NostrPayoutPref, Box<dyn Error>> {
+    let content_str = event["content"].as_str().unwrap_or_default();
+    let content: PayoutPrefsContent = serde_json::from_str(content_str)?;
+    let pay_method = match content.method {
+        None => "".to_string(),
+        Some(m) => match PaymentMethod::from_str(&m) {
+            Err(_) => {
+                println!("WARNING: Invalid payment method in payout prefs of '{npub}', '{m}'");
+                "".to_string()
+            }
+            Ok(pm) => pm.to_string(),
+        },
+    };
+    let ln_address = match content.ln_address {
+        None => "".to_string(),
+        Some(a) => {
+            if ln_p_url_from_address(&a).is_err() {
+                println!("WARNING: Invalid Lightning Address in payout prefs of '{npub}', '{a}'");
+                "".to_string()
+            } else {
+                a.trim().to_string()
+            }
+        }
+    };
+    Ok(NostrPayoutPref::new(
+        npub.to_string(),
+        pay_method,
+        content.threshold_sats.unwrap_or(0).saturating_mul(1000),
+        ln_address,
+        event["id"].as_str().unwrap_or_default().to_string(),
+        event["created_at"].as_u64().unwrap_or(0),
+        now_utc,
+    ))
+}
+
+/// Get the latest payout preferences of an npub from the relays. None if not found.
+async fn fetch_payout_pref(
+    npub: &str,
+    relays: &[String],
+    deadline: Duration,
+    now_utc: u32,
+) -> Result<Option<NostrPayoutPref>, Box<dyn Error>> {
+    let pubkey_hex = npub_to_hex(npub)?;
+    let filter = json!({
+        "kinds": [PAYOUT_PREFS_KIND],
+        "authors": [pubkey_hex],
+        "#d": [PAYOUT_PREFS_D_TAG],
+    });
+    let events = get_events_from_relays(relays, &filter, deadline)
+        .await
+        .into_iter()
+        .filter(|(_, e)| tag_value(e, "d").as_deref() == Some(PAYOUT_PREFS_D_TAG))
+        .collect();
+    match select_freshest_event(events, &pubkey_hex, PAYOUT_PREFS_KIND) {
+        None => Ok(None),
+        Some((_relay, event)) => Ok(Some(payout_pref_from_event(npub, &event, now_utc)?)),
+    }
+}
+
+/// Get the payout preferences of an npub, using the cache (NOSTR_PAYOUT_PREF):
+/// - a cache entry younger than the TTL is used without querying the relays
+/// - preferences from the relays are stored in the cache, unless the cache has newer ones
+/// - if the relays give none, the last known ones are kept (empty if none)
+pub async fn get_payout_pref_cached(
+    conn: &Connection,
+    npub: &str,
+    relays: &[String],
+    now_utc: u32,
+) -> Result<NostrPayoutPref, Box<dyn Error>> {
+    let cached = db::nostr_payout_pref_get(conn, npub)?;
+    if let Some(c) = &cached
+        && now_utc < c.fetched_at + PAYOUT_PREFS_CACHE_TTL
+    {
+        return Ok(c.clone());
+    }
+
+    let fetched = fetch_payout_pref(npub, relays, RELAY_QUERY_DEADLINE, now_utc).await?;
+    let mut pref = match (fetched, cached) {
+        (Some(f), Some(c)) if c.created_at > f.created_at => c,
+        (Some(f), _) => {
+            println!("Payout prefs of '{npub}': {:?}", f);
+            f
+        }
+        (None, Some(c)) => c,
+        (None, None) => NostrPayoutPref::new(
+            npub.to_string(),
+            "".into(),
+            0,
+            "".into(),
+            "".into(),
+            0,
+            now_utc,
+        ),
+    };
+    pref.fetched_at = now_utc;
+    db::nostr_payout_pref_upsert(conn, &pref)?;
+    Ok(pref)
+}
+
+/// Refresh the cached payout preferences of the npubs, from the relays of the config.
+/// Return the number of npubs refreshed.
+pub async fn refresh_payout_prefs(
+    conn: &Connection,
+    npubs: &[String],
+    now_utc: u32,
+) -> Result<u32, Box<dyn Error>> {
+    let relays = get_nostr_relays_from_config()?;
+    // Concurrently, each lookup is bounded by the relay query deadline
+    let results = join_all(
+        npubs
+            .iter()
+            .map(|npub| get_payout_pref_cached(conn, npub, &relays, now_utc)),
+    )
+    .await;
+    let mut cnt = 0;
+    for (npub, res) in npubs.iter().zip(results) {
+        match res {
+            Err(e) => println!("ERROR: Could not get payout prefs of '{npub}', {e}"),
+            Ok(_) => cnt += 1,
+        }
+    }
+    Ok(cnt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nostr_test_relay::{NostrRelayConfig, NostrTestRelay};
+    use crate::nostr_zap::npub_from_secret_vec;
+
+    use nostr::util::JsonUtil;
+    use nostr::{EventBuilder, Keys, Kind, SecretKey, Tag, Timestamp};
+
+    const SECRET: [u8; 32] = [7u8; 32];
+    const NOW: u32 = 1_760_000_000;
+
+    fn prefs_event(secret: &[u8; 32], d_tag: &str, content: Value, created_at: u64) -> Value {
+        let keys = Keys::new(SecretKey::from_slice(secret).unwrap());
+        let event = EventBuilder::new(Kind::ApplicationSpecificData, content.to_string())
+            .tag(Tag::identifier(d_tag))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(&keys)
+            .unwrap();
+        serde_json::from_str(&event.as_json()).unwrap()
+    }
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_payout_pref_from_event() {
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let content =
+            json!({"method": "ZAP", "threshold_sats": 21, "ln_address": "alt@example.com"});
+        let event = prefs_event(&SECRET, PAYOUT_PREFS_D_TAG, content, 1000);
+        let pref = payout_pref_from_event(&npub, &event, NOW).unwrap();
+        assert_eq!(pref.pay_method, "ZAP");
+        assert_eq!(pref.threshold_msat, 21_000);
+        assert_eq!(pref.ln_address, "alt@example.com");
+        assert_eq!(pref.event_id, event["id"].as_str().unwrap());
+        assert_eq!(pref.created_at, 1000);
+
+        // Invalid values ignored
+        let content = json!({"method": "PAYPAL", "ln_address": "no-address"});
+        let event = prefs_event(&SECRET, PAYOUT_PREFS_D_TAG, content, 1000);
+        let pref = payout_pref_from_event(&npub, &event, NOW).unwrap();
+        assert_eq!(pref.pay_method, "");
+        assert_eq!(pref.threshold_msat, 0);
+        assert_eq!(pref.ln_address, "");
+
+        // Not JSON
+        let mut event = event.clone();
+        event["content"] = json!("method=ZAP");
+        assert!(payout_pref_from_event(&npub, &event, NOW).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payout_pref_cached() {
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![
+                prefs_event(&SECRET, PAYOUT_PREFS_D_TAG, json!({"method": "LNAD"}), 1000),
+                prefs_event(&SECRET, PAYOUT_PREFS_D_TAG, json!({"method": "ZAP"}), 2000),
+                // Other app, other author
+                prefs_event(&SECRET, "other-app", json!({"method": "NOLN"}), 3000),
+                prefs_event(
+                    &[8u8; 32],
+                    PAYOUT_PREFS_D_TAG,
+                    json!({"method": "NOLN"}),
+                    3000,
+                ),
+            ],
+        )
+        .await;
+        let conn = test_db();
+        let pref = get_payout_pref_cached(&conn, &npub, &[relay.url()], NOW)
+            .await
+            .unwrap();
+        assert_eq!(pref.pay_method, "ZAP");
+        assert_eq!(pref.created_at, 2000);
+        assert_eq!(
+            db::nostr_payout_pref_get(&conn, &npub)
+                .unwrap()
+                .unwrap()
+                .pay_method,
+            "ZAP"
+        );
+
+        // Relays down: last known kept
+        let pref = get_payout_pref_cached(&conn, &npub, &Vec::new(), NOW + 2 * 3600)
+            .await
+            .unwrap();
+        assert_eq!(pref.pay_method, "ZAP");
+        assert_eq!(pref.fetched_at, NOW + 2 * 3600);
+
+        // None published
+        let npub_other = npub_from_secret_vec(&[9u8; 32].to_vec()).unwrap();
+        let pref = get_payout_pref_cached(&conn, &npub_other, &[relay.url()], NOW)
+            .await
+            .unwrap();
+        assert_eq!(pref.pay_method, "");
+        assert_eq!(pref.event_id, "");
+    }
+}
@@ -1,15 +1,31 @@
+use crate::nostr_relays::{
+    get_nostr_relays_from_config, nip17_dm_relays_from_event, nip65_read_relays_from_event,
+};
+
+use common_rs::db_pc as db;
+use common_rs::dto_pc::NostrProfileCache;
+
 use bech32::{FromBase32, decode};
+use futures_util::stream::FuturesUnordered;
 use futures_util::{SinkExt, StreamExt};
 use hex_conservative::DisplayHex;
+use nostr::Event;
+use nostr::util::JsonUtil;
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use tokio::time::timeout;
+use tokio::time::{Instant as TokioInstant, timeout, timeout_at};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use uuid::Uuid;
 
 use std::error::Error;
 use std::time::{Duration, Instant};
 
+/// Overall deadline for querying the relays
+pub(crate) const RELAY_QUERY_DEADLINE: Duration = Duration::from_secs(8);
+/// Cached profiles younger than this are used without querying the relays, secs
+const PROFILE_CACHE_TTL: u32 = 600;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NostrEvent {
     id: String,
@@ -30,11 +46,14 @@ struct ProfileData {
     banner: Option<String>,
     nip05: Option<String>,
     lud16: Option<String>,
+    lud06: Option<String>,
+    /// BOLT12 offer, not standardized (as used by some wallets)
+    lno: Option<String>,
     website: Option<String>,
 }
 
 /// Convert npub to hex format
-fn npub_to_hex(npub: &str) -> Result<String, Box<dyn Error>> {
+pub(crate) fn npub_to_hex(npub: &str) -> Result<String, Box<dyn Error>> {
     let (hrp, data, _variant) = decode(npub)?;
 
     if hrp != "npub" {
@@ -47,24 +66,23 @@ fn npub_to_hex(npub: &str) -> Result<String, Box<dyn Error>> {
     Ok(hex_pubkey)
 }
 
-/// Get profile data from a Nostr relay
-async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>, Box<dyn Error>> {
-    // Convert npub to hex format
-    let pubkey_hex = npub_to_hex(npub)?;
-    println!("Converted npub to hex: {}", pubkey_hex);
-
+/// Get the events matching a filter from a Nostr relay (raw JSON event objects),
+/// until End of Stored Events or timeout.
+/// The filter should have "kinds", events of other kinds are dropped.
+pub(crate) async fn get_events(
+    relay_url: &str,
+    filter: &Value,
+    timeout_duration: Duration,
+) -> Result<Vec<Value>, Box<dyn Error>> {
     // Create a subscription ID
     let subscription_id = Uuid::new_v4().to_string()[..8].to_string();
+    let kinds = filter
+        .get("kinds")
+        .and_then(|k| k.as_array())
+        .cloned()
+        .unwrap_or_default();
 
-    // Create the request to get user metadata
-    let request = json!([
-        "REQ",
-        subscription_id,
-        {
-            "kinds": [0],  // Kind 0 is for metadata events
-            "authors": [pubkey_hex]
-        }
-    ]);
+    let request = json!(["REQ", subscription_id, filter]);
 
     // Connect to the relay
     println!("Connecting to relay: {}", relay_url);
@@ -77,15 +95,14 @@ async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>,
     println!("Sent request: {}", request_str);
 
     // Wait for the response with a timeout
-    let timeout_duration = Duration::from_secs(10);
     let start_time = Instant::now();
+    let mut events = Vec::new();
 
     while start_time.elapsed() < timeout_duration {
         match timeout(Duration::from_secs(1), read.next()).await {
             Ok(Some(Ok(msg))) => {
                 if let Message::Text(text) = msg {
                     let response_data: Value = serde_json::from_str(&text)?;
-                    println!("{}", serde_json::to_string_pretty(&response_data)?);
 
                     // Check if this is an EVENT message for our subscription
                     if let Some(array) = response_data.as_array() {
@@ -93,28 +110,8 @@ async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>,
                             && array[0].as_str() == Some("EVENT")
                             && array[1].as_str() == Some(&subscription_id)
                         {
-                            if let Some(event_obj) = array[2].as_object() {
-                                if event_obj.get("kind") == Some(&json!(0)) {
-                                    // Parse the content which contains the profile data
-                                    if let Some(content_str) =
-                                        event_obj.get("content").and_then(|c| c.as_str())
-                                    {
-                                        let profile_data: ProfileData =
-                                            serde_json::from_str(content_str)?;
-                                        println!("\nProfile Data:");
-                                        println!(
-                                            "{}",
-                                            serde_json::to_string_pretty(&profile_data)?
-                                        );
-
-                                        // Send CLOSE to end the subscription
-                                        let close_msg = json!(["CLOSE", subscription_id]);
-                                        write
-                                            .send(Message::Text(serde_json::to_string(&close_msg)?))
-                                            .await?;
-                                        return Ok(Some(profile_data));
-                                    }
-                                }
+                            if kinds.contains(&array[2]["kind"]) {
+                                events.push(array[2].clone());
                             }
                         }
 
@@ -123,14 +120,18 @@ async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>,
                             && array[0].as_str() == Some("EOSE")
                             && array[1].as_str() == Some(&subscription_id)
                         {
-                            println!("End of stored events received, no profile data found.");
+                            println!(
+                                "End of stored events received, {} events of kinds {:?} ({relay_url})",
+                                events.len(),
+                                kinds
+                            );
 
                             // Send CLOSE to end the subscription
                             let close_msg = json!(["CLOSE", subscription_id]);
                             write
                                 .send(Message::Text(serde_json::to_string(&close_msg)?))
                                 .await?;
-                            return Ok(None);
+                            return Ok(events);
                         }
                     }
                 }
@@ -148,62 +149,313 @@ async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>,
         }
     }
 
-    println!("Timeout reached, no profile data received.");
-    Ok(None)
+    println!(
+        "Timeout reached, {} events of kinds {:?} received ({relay_url})",
+        events.len(),
+        kinds
+    );
+    Ok(events)
 }
 
-/// Get Lightning Address from profile
-async fn get_nostr_ln_address_relay(
-    npub: &str,
-    relay: &str,
-) -> Result<Option<String>, Box<dyn Error>> {
-    let profile = get_profile(relay, npub).await?;
-
-    if let Some(profile_data) = profile {
-        println!("profile: {:?}", profile_data);
-        if let Some(lud16) = &profile_data.lud16 {
-            if !lud16.is_empty() {
-                return Ok(Some(lud16.clone()));
+/// Get the events matching a filter from all relays, querying them concurrently,
+/// as (relay, event) pairs. Relays not answering before the deadline are ignored.
+pub(crate) async fn get_events_from_relays(
+    relays: &[String],
+    filter: &Value,
+    deadline: Duration,
+) -> Vec<(String, Value)> {
+    let deadline_instant = TokioInstant::now() + deadline;
+
+    let mut queries = relays
+        .iter()
+        .map(|relay_url| async move {
+            let res = get_events(relay_url, filter, deadline).await;
+            (relay_url.clone(), res)
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut events = Vec::new();
+    loop {
+        match timeout_at(deadline_instant, queries.next()).await {
+            Err(_) => {
+                println!("Deadline reached, {} relays not answered", queries.len());
+                break;
+            }
+            // All done
+            Ok(None) => break,
+            Ok(Some((relay_url, Err(e)))) => {
+                println!("Error from relay {relay_url}, {e}");
+            }
+            Ok(Some((relay_url, Ok(evs)))) => {
+                for e in evs {
+                    events.push((relay_url.clone(), e));
+                }
             }
         }
-        println!(
-            "ERROR: User profile for '{}' doesn't contain 'lud16' (relay: {}, profile: {:?})",
-            npub, relay, profile_data
-        );
     }
+    events
+}
 
-    Ok(None)
+/// Check that the event is of the expected author and kind, and its ID and signature are valid
+fn verify_event(event: &Value, pubkey_hex: &str, kind: u32) -> bool {
+    let event_obj = match Event::from_json(event.to_string()) {
+        Err(_) => return false,
+        Ok(e) => e,
+    };
+    if event_obj.pubkey.to_hex() != pubkey_hex || event_obj.kind.as_u16() as u32 != kind {
+        return false;
+    }
+    event_obj.verify().is_ok()
 }
 
-/// Get Lightning Address from multiple relays
-pub async fn get_nostr_ln_address(npub: &str) -> Result<String, Box<dyn Error>> {
-    let relays = [
-        "relay.damus.io",
-        "relay.primal.net",
-        "nostr.wine",
-        "nos.lol",
-        "relay.snort.social",
-        "nostr.land",
-        "nostr.mom",
-        "relay.nostr.band",
-        "nostr.oxtr.dev",
-    ];
-
-    for relay in &relays {
-        let relay_url = format!("wss://{}", relay);
-        if let Ok(Some(ln_addr)) = get_nostr_ln_address_relay(npub, &relay_url).await {
-            return Ok(ln_addr);
+/// Select the valid event with the highest created_at, from (relay, event) pairs
+pub(crate) fn select_freshest_event(
+    events: Vec<(String, Value)>,
+    pubkey_hex: &str,
+    kind: u32,
+) -> Option<(String, Value)> {
+    let mut res: Option<(String, Value)> = None;
+    for (relay, event) in events {
+        if !verify_event(&event, pubkey_hex, kind) {
+            println!("WARNING: Invalid event from relay {relay}, ignoring, {event}");
+            continue;
+        }
+        let created_at = event["created_at"].as_u64().unwrap_or(0);
+        res = match res {
+            Some((r, e)) if e["created_at"].as_u64().unwrap_or(0) >= created_at => Some((r, e)),
+            _ => Some((relay, event)),
+        };
+    }
+    res
+}
+
+/// Get the latest valid event of a kind from an author, querying all relays concurrently.
+/// Relays not answering before the deadline are ignored.
+/// Returns the source relay and the event.
+async fn get_latest_event_from_relays(
+    npub: &str,
+    relays: &[String],
+    kind: u32,
+    deadline: Duration,
+) -> Result<Option<(String, Value)>, Box<dyn Error>> {
+    let pubkey_hex = npub_to_hex(npub)?;
+    // E.g. kind 0 for user metadata
+    let filter = json!({
+        "kinds": [kind],
+        "authors": [pubkey_hex]
+    });
+    let events = get_events_from_relays(relays, &filter, deadline).await;
+    Ok(select_freshest_event(events, &pubkey_hex, kind))
+}
+
+/// Parse the profile data from a metadata event (kind 0)
+fn profile_from_event(event: &Value) -> Result<Option<ProfileData>, Box<dyn Error>> {
+    // Parse the content which contains the profile data
+    let content_str = match event.get("content").and_then(|c| c.as_str()) {
+        None => return Ok(None),
+        Some(c) => c,
+    };
+    let profile_data: ProfileData = serde_json::from_str(content_str)?;
+    Ok(Some(profile_data))
+}
+
+/// Get profile data from a Nostr relay
+async fn get_profile(relay_url: &str, npub: &str) -> Result<Option<ProfileData>, Box<dyn Error>> {
+    let relays = vec![relay_url.to_string()];
+    match get_latest_event_from_relays(npub, &relays, 0, RELAY_QUERY_DEADLINE).await? {
+        None => Ok(None),
+        Some((_relay, event)) => profile_from_event(&event),
+    }
+}
+
+/// Get the Lightning info from the latest profile, from multiple relays. None if no profile found.
+async fn fetch_profile_ln_info(
+    npub: &str,
+    relays: &[String],
+    deadline: Duration,
+    now_utc: u32,
+) -> Result<Option<NostrProfileCache>, Box<dyn Error>> {
+    let (relay, event) = match get_latest_event_from_relays(npub, relays, 0, deadline).await? {
+        None => return Ok(None),
+        Some(re) => re,
+    };
+    let profile_data = match profile_from_event(&event)? {
+        None => return Ok(None),
+        Some(p) => p,
+    };
+    println!("profile: {:?} (relay: {})", profile_data, relay);
+    Ok(Some(NostrProfileCache::new(
+        npub.to_string(),
+        profile_data.lud16.unwrap_or_default(),
+        profile_data.lud06.unwrap_or_default(),
+        event["created_at"].as_u64().unwrap_or(0),
+        now_utc,
+        relay,
+        profile_data.lno.unwrap_or_default(),
+    )))
+}
+
+/// Get the Lightning info of a profile, using the cache:
+/// - a cache entry younger than the TTL is used without querying the relays
+/// - a profile from the relays is stored in the cache, unless the cache has a newer one
+/// - if the relays give no profile (e.g. all down), the last known entry is used, regardless of age
+async fn resolve_profile_ln_info_cached(
+    conn: &Connection,
+    npub: &str,
+    relays: &[String],
+    now_utc: u32,
+    deadline: Duration,
+) -> Result<NostrProfileCache, Box<dyn Error>> {
+    let cached = db::nostr_profile_cache_get(conn, npub)?;
+    if let Some(c) = &cached
+        && now_utc < c.fetched_at + PROFILE_CACHE_TTL
+        && !(c.lud16.is_empty() && c.lud06.is_empty() && c.bolt12_offer.is_empty())
+    {
+        println!(
+            "Using cached profile of '{npub}' (fetched at {})",
+            c.fetched_at
+        );
+        return Ok(c.clone());
+    }
+
+    let fetched = match fetch_profile_ln_info(npub, relays, deadline, now_utc).await {
+        Err(e) => {
+            println!("ERROR: Could not fetch profile of '{npub}', {e}");
+            None
+        }
+        Ok(f) => f,
+    };
+    match (fetched, cached) {
+        (Some(f), Some(mut c)) if c.created_at > f.created_at => {
+            // Relays have an older profile than the one seen before, keep that
+            c.fetched_at = now_utc;
+            db::nostr_profile_cache_upsert(conn, &c)?;
+            Ok(c)
+        }
+        (Some(f), _) => {
+            db::nostr_profile_cache_upsert(conn, &f)?;
+            Ok(f)
+        }
+        (None, Some(c)) if c.lud16.is_empty() && c.lud06.is_empty() && c.bolt12_offer.is_empty() => {
+            Err(format!(
+                "ERROR: Could not obtain profile for '{}', last known has no Lightning info (relays: {:?})",
+                npub, relays
+            )
+            .into())
+        }
+        (None, Some(c)) => {
+            println!(
+                "WARNING: No profile from relays for '{npub}', using last known (fetched at {})",
+                c.fetched_at
+            );
+            Ok(c)
         }
+        (None, None) => Err(format!(
+            "ERROR: Could not obtain profile for '{}' (relays: {:?})",
+            npub, relays
+        )
+        .into()),
     }
+}
 
+/// Lightning Address (lud16) from the profile info, or if missing, the LNURL (lud06).
+/// Both are accepted by the LNURL-pay flow (ln_address).
+fn ln_address_from_profile_info(info: &NostrProfileCache) -> Result<String, Box<dyn Error>> {
+    if !info.lud16.is_empty() {
+        return Ok(info.lud16.clone());
+    }
+    if !info.lud06.is_empty() {
+        println!("No 'lud16' in profile of '{}', using 'lud06'", info.npub);
+        return Ok(info.lud06.clone());
+    }
     let err_msg = format!(
-        "ERROR: Could not obtain LNAddr for '{}' (relays: {:?})",
-        npub, relays
+        "ERROR: User profile for '{}' doesn't contain 'lud16' or 'lud06' (relay: {})",
+        info.npub, info.source_relay
     );
     println!("{err_msg}");
     Err(err_msg.into())
 }
 
+/// Get Lightning Address from multiple relays
+pub async fn get_nostr_ln_address(npub: &str, relays: &[String]) -> Result<String, Box<dyn Error>> {
+    match fetch_profile_ln_info(npub, relays, RELAY_QUERY_DEADLINE, 0).await? {
+        Some(info) => ln_address_from_profile_info(&info),
+        None => {
+            let err_msg = format!(
+                "ERROR: Could not obtain LNAddr for '{}' (relays: {:?})",
+                npub, relays
+            );
+            println!("{err_msg}");
+            Err(err_msg.into())
+        }
+    }
+}
+
+/// Get Lightning Address from multiple relays, using the profile cache (NOSTR_PROFILE_CACHE)
+/// Err with a flag if nonfinal: no profile could be obtained (e.g. relays down) is nonfinal,
+/// a profile without Lightning Address is final.
+pub async fn get_nostr_ln_address_cached(
+    conn: &Connection,
+    npub: &str,
+    relays: &[String],
+    now_utc: u32,
+) -> Result<String, (bool, Box<dyn Error>)> {
+    let info = resolve_profile_ln_info_cached(conn, npub, relays, now_utc, RELAY_QUERY_DEADLINE)
+        .await
+        .map_err(|e| (true, e))?;
+    ln_address_from_profile_info(&info).map_err(|e| (false, e))
+}
+
+/// Get the BOLT12 offer from the profile ('lno' field), using the profile cache (NOSTR_PROFILE_CACHE)
+/// Err with a flag if nonfinal, as for get_nostr_ln_address_cached: a profile without offer is final.
+pub async fn get_nostr_bolt12_offer_cached(
+    conn: &Connection,
+    npub: &str,
+    relays: &[String],
+    now_utc: u32,
+) -> Result<String, (bool, Box<dyn Error>)> {
+    let info = resolve_profile_ln_info_cached(conn, npub, relays, now_utc, RELAY_QUERY_DEADLINE)
+        .await
+        .map_err(|e| (true, e))?;
+    if info.bolt12_offer.is_empty() {
+        return Err((
+            false,
+            format!(
+                "ERROR: User profile for '{}' doesn't contain a BOLT12 offer 'lno' (relay: {})",
+                npub, info.source_relay
+            )
+            .into(),
+        ));
+    }
+    Ok(info.bolt12_offer)
+}
+
+/// Get the relays where the user reads, from their NIP-65 relay list (kind 10002), from multiple relays.
+/// Empty if not found.
+pub async fn get_nostr_read_relays(npub: &str, relays: &[String]) -> Vec<String> {
+    match get_latest_event_from_relays(npub, relays, 10002, RELAY_QUERY_DEADLINE).await {
+        Ok(Some((_relay, event))) => {
+            let read_relays = nip65_read_relays_from_event(&event);
+            println!("NIP-65 read relays of '{npub}': {:?}", read_relays);
+            read_relays
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Get the relays where the user wants to receive DMs, from their NIP-17 DM relay list (kind 10050),
+/// from multiple relays. Empty if not found.
+pub async fn get_nostr_dm_relays(npub: &str, relays: &[String]) -> Vec<String> {
+    match get_latest_event_from_relays(npub, relays, 10050, RELAY_QUERY_DEADLINE).await {
+        Ok(Some((_relay, event))) => {
+            let dm_relays = nip17_dm_relays_from_event(&event);
+            println!("NIP-17 DM relays of '{npub}': {:?}", dm_relays);
+            dm_relays
+        }
+        _ => Vec::new(),
+    }
+}
+
 #[allow(dead_code)]
 async fn do_try() -> Result<(), Box<dyn Error>> {
     // Default values
@@ -218,7 +470,8 @@ async fn do_try() -> Result<(), Box<dyn Error>> {
     println!("Fetching profile for {} from {}", npub, relay_url);
 
     // Test Lightning Address functionality
-    if let Ok(lnaddr) = get_nostr_ln_address(npub).await {
+    let relays = get_nostr_relays_from_config()?;
+    if let Ok(lnaddr) = get_nostr_ln_address(npub, &relays).await {
         println!("Lightning Address: {}", lnaddr);
     }
 
@@ -247,6 +500,9 @@ async fn do_try() -> Result<(), Box<dyn Error>> {
             if let Some(lud16) = &profile.lud16 {
                 println!("Lud16: {}", lud16);
             }
+            if let Some(lud06) = &profile.lud06 {
+                println!("Lud06: {}", lud06);
+            }
             if let Some(website) = &profile.website {
                 println!("Website: {}", website);
             }
@@ -265,6 +521,106 @@ async fn do_try() -> Result<(), Box<dyn Error>> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::nostr_test_relay::{NostrRelayConfig, NostrTestRelay};
+    use crate::nostr_zap::npub_from_secret_vec;
+
+    use nostr::{EventBuilder, Keys, Kind, SecretKey, Timestamp};
+
+    const SECRET: [u8; 32] = [7u8; 32];
+    const NOW: u32 = 1_760_000_000;
+
+    fn profile_event(secret: &[u8; 32], lud16: &str, created_at: u64) -> Value {
+        let keys = Keys::new(SecretKey::from_slice(secret).unwrap());
+        let content = json!({"name": "miner", "lud16": lud16}).to_string();
+        let event = EventBuilder::new(Kind::Metadata, content)
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(&keys)
+            .unwrap();
+        serde_json::from_str(&event.as_json()).unwrap()
+    }
+
+    fn pubkey_hex() -> String {
+        npub_to_hex(&npub_from_secret_vec(&SECRET.to_vec()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_select_freshest_event() {
+        let old = profile_event(&SECRET, "old@example.com", 1000);
+        let new = profile_event(&SECRET, "new@example.com", 2000);
+        let mut forged = profile_event(&SECRET, "new@example.com", 3000);
+        forged["content"] = json!(json!({"lud16": "thief@example.com"}).to_string());
+        let other_author = profile_event(&[8u8; 32], "other@example.com", 4000);
+
+        let events = vec![
+            ("r1".to_string(), old.clone()),
+            ("r2".to_string(), new.clone()),
+            ("r3".to_string(), forged),
+            ("r4".to_string(), other_author),
+        ];
+        let (relay, event) = select_freshest_event(events, &pubkey_hex(), 0).unwrap();
+        assert_eq!(relay, "r2");
+        assert_eq!(event, new);
+
+        assert!(
+            select_freshest_event(vec![("r1".to_string(), old)], &pubkey_hex(), 10002).is_none()
+        );
+        assert!(select_freshest_event(Vec::new(), &pubkey_hex(), 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_profile_freshest_parallel() {
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let relay_old = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&SECRET, "old@example.com", 1000)],
+        )
+        .await;
+        let relay_new = NostrTestRelay::start(
+            NostrRelayConfig {
+                response_delay: Duration::from_millis(300),
+                ..Default::default()
+            },
+            vec![profile_event(&SECRET, "new@example.com", 2000)],
+        )
+        .await;
+        let relay_silent = NostrTestRelay::start(
+            NostrRelayConfig {
+                silent: true,
+                ..Default::default()
+            },
+            vec![profile_event(&SECRET, "silent@example.com", 3000)],
+        )
+        .await;
+        let relays = vec![relay_old.url(), relay_new.url(), relay_silent.url()];
+
+        let start = Instant::now();
+        let info = fetch_profile_ln_info(&npub, &relays, Duration::from_secs(2), NOW)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.lud16, "new@example.com");
+        assert_eq!(info.created_at, 2000);
+        assert_eq!(info.source_relay, relay_new.url());
+        // Bounded by the deadline, not by the silent relay
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_profile_not_found() {
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&[8u8; 32], "other@example.com", 1000)],
+        )
+        .await;
+        let relays = vec![relay.url(), "ws://127.0.0.1:1".to_string()];
+        assert!(
+            fetch_profile_ln_info(&npub, &relays, Duration::from_secs(2), NOW)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
 
     #[test]
     fn test_npub_to_hex() {
@@ -275,4 +631,140 @@ mod test {
             "50d94fc2d8580c682b071a542f8b1e31a200b0508bab95a33bef0855df281d63"
         );
     }
+
+    async fn resolve_cached(conn: &Connection, relays: &[String], now_utc: u32) -> Option<String> {
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        resolve_profile_ln_info_cached(conn, &npub, relays, now_utc, Duration::from_secs(1))
+            .await
+            .ok()
+            .map(|info| info.lud16)
+    }
+
+    #[tokio::test]
+    async fn test_profile_cache() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let relays_down = vec!["ws://127.0.0.1:1".to_string()];
+
+        // Nothing known yet, relays down
+        assert!(resolve_cached(&conn, &relays_down, NOW).await.is_none());
+
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&SECRET, "miner@example.com", 2000)],
+        )
+        .await;
+        let relays = vec![relay.url()];
+        assert_eq!(
+            resolve_cached(&conn, &relays, NOW).await.unwrap(),
+            "miner@example.com"
+        );
+
+        // Within TTL: from cache, relays not needed
+        assert_eq!(
+            resolve_cached(&conn, &relays_down, NOW + 10).await.unwrap(),
+            "miner@example.com"
+        );
+        // After TTL, relays down: fallback to the last known
+        let later = NOW + PROFILE_CACHE_TTL + 10;
+        assert_eq!(
+            resolve_cached(&conn, &relays_down, later).await.unwrap(),
+            "miner@example.com"
+        );
+
+        // After TTL, relay has an older profile: the newer known one is kept
+        let relay_old = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&SECRET, "old@example.com", 1000)],
+        )
+        .await;
+        assert_eq!(
+            resolve_cached(&conn, &[relay_old.url()], later)
+                .await
+                .unwrap(),
+            "miner@example.com"
+        );
+
+        // Newer profile on relay: updated
+        let relay_new = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&SECRET, "new@example.com", 3000)],
+        )
+        .await;
+        let later = later + PROFILE_CACHE_TTL + 10;
+        assert_eq!(
+            resolve_cached(&conn, &[relay_new.url()], later)
+                .await
+                .unwrap(),
+            "new@example.com"
+        );
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let entry = db::nostr_profile_cache_get(&conn, &npub).unwrap().unwrap();
+        assert_eq!(entry.created_at, 3000);
+        assert_eq!(entry.fetched_at, later);
+        assert_eq!(entry.source_relay, relay_new.url());
+    }
+
+    #[test]
+    fn test_ln_address_from_profile_info() {
+        let mut info = NostrProfileCache::new(
+            "npub1a".into(),
+            "miner@example.com".into(),
+            "lnurl1dp68gurn8ghj7".into(),
+            1000,
+            2000,
+            "wss://nos.lol".into(),
+            "".into(),
+        );
+        assert_eq!(
+            ln_address_from_profile_info(&info).unwrap(),
+            "miner@example.com"
+        );
+        info.lud16 = "".into();
+        assert_eq!(
+            ln_address_from_profile_info(&info).unwrap(),
+            "lnurl1dp68gurn8ghj7"
+        );
+        info.lud06 = "".into();
+        assert!(ln_address_from_profile_info(&info).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bolt12_offer_cached() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let npub = npub_from_secret_vec(&SECRET.to_vec()).unwrap();
+        let keys = Keys::new(SecretKey::from_slice(&SECRET).unwrap());
+        let content = json!({"name": "miner", "lno": "lno1qgsqvgnwgcg35z6ee2h3yczraddm72xrfua9uve2rlrm9deu7xyfzrc"});
+        let event = EventBuilder::new(Kind::Metadata, content.to_string())
+            .custom_created_at(Timestamp::from(2000))
+            .sign_with_keys(&keys)
+            .unwrap();
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![serde_json::from_str(&event.as_json()).unwrap()],
+        )
+        .await;
+        let offer = get_nostr_bolt12_offer_cached(&conn, &npub, &[relay.url()], NOW)
+            .await
+            .unwrap();
+        assert!(offer.starts_with("lno1qgsq"));
+        // No offer in the profile
+        let relay_no_offer = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![profile_event(&[8u8; 32], "miner@example.com", 2000)],
+        )
+        .await;
+        let npub_other = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let (err_nonfinal, _) =
+            get_nostr_bolt12_offer_cached(&conn, &npub_other, &[relay_no_offer.url()], NOW)
+                .await
+                .unwrap_err();
+        assert!(!err_nonfinal);
+        // No relay reachable
+        let (err_nonfinal, _) = get_nostr_bolt12_offer_cached(&conn, "npub1x", &[], NOW)
+            .await
+            .unwrap_err();
+        assert!(err_nonfinal);
+    }
 }
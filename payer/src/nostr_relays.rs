@@ -0,0 +1,223 @@
+use dotenv;
+use serde_json::Value;
+
+use std::env;
+use std::error::Error;
+use std::fs;
+
+/// Relays used when no relays are configured
+const DEFAULT_NOSTR_RELAYS: &[&str] = &[
+    "wss://relay.damus.io",
+    "wss://relay.primal.net",
+    "wss://nos.lol",
+    "wss://nostr.wine",
+    "wss://relay.snort.social",
+    "wss://nostr.land",
+    "wss://nostr.mom",
+    "wss://relay.nostr.band",
+    "wss://nostr.oxtr.dev",
+];
+
+/// Max number of relays taken from a recipient's relay list (NIP-65, NIP-17)
+const RECIPIENT_RELAYS_MAX: usize = 5;
+
+/// Normalize a relay URL: trim, add "wss://" if there is no scheme, drop trailing "/"
+/// E.g.: "relay.damus.io/" --> "wss://relay.damus.io"
+pub fn normalize_relay_url(relay: &str) -> String {
+    let relay = relay.trim().trim_end_matches("/");
+    if relay.starts_with("wss://") || relay.starts_with("ws://") {
+        relay.to_string()
+    } else {
+        format!("wss://{relay}")
+    }
+}
+
+/// Parse a relay list, separated by commas, whitespace or newlines. '#' starts a comment.
+fn parse_relay_list(list: &str) -> Vec<String> {
+    let mut relays = Vec::new();
+    for line in list.lines() {
+        let line = line.split("#").next().unwrap_or_default();
+        for r in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if !r.trim().is_empty() {
+                relays.push(normalize_relay_url(r));
+            }
+        }
+    }
+    dedup_relays(relays)
+}
+
+/// Remove duplicates, keeping the order
+fn dedup_relays(relays: Vec<String>) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for r in relays {
+        if !res.contains(&r) {
+            res.push(r);
+        }
+    }
+    res
+}
+
+/// Relays used for profile lookups and zaps, from config:
+/// - NOSTR_RELAYS: comma-separated list, or
+/// - NOSTR_RELAYS_FILE: file with one relay per line
+/// - otherwise the default list
+pub fn get_nostr_relays_from_config() -> Result<Vec<String>, Box<dyn Error>> {
+    // Load environment variables from .env file
+    dotenv::dotenv().ok();
+
+    let relays = if let Ok(list) = env::var("NOSTR_RELAYS") {
+        parse_relay_list(&list)
+    } else if let Ok(file) = env::var("NOSTR_RELAYS_FILE") {
+        let list = fs::read_to_string(&file)
+            .map_err(|e| format!("Could not read relays file '{file}', {e}"))?;
+        parse_relay_list(&list)
+    } else {
+        DEFAULT_NOSTR_RELAYS.iter().map(|r| r.to_string()).collect()
+    };
+    if relays.is_empty() {
+        return Err("No Nostr relays configured".into());
+    }
+    Ok(relays)
+}
+
+/// Relays from a NIP-65 relay list event (kind 10002) where the user reads,
+/// i.e. 'r' tags with no marker or with the "read" marker.
+pub fn nip65_read_relays_from_event(event: &Value) -> Vec<String> {
+    let mut relays = Vec::new();
+    if let Some(tags) = event.get("tags").and_then(|t| t.as_array()) {
+        for tag in tags {
+            let tag = match tag.as_array() {
+                None => continue,
+                Some(t) => t,
+            };
+            if tag.len() < 2 || tag[0].as_str() != Some("r") {
+                continue;
+            }
+            let marker = tag.get(2).and_then(|m| m.as_str()).unwrap_or("read");
+            if marker != "read" {
+                continue;
+            }
+            if let Some(url) = tag[1].as_str()
+                && url.trim().starts_with("wss://")
+            {
+                relays.push(normalize_relay_url(url));
+            }
+        }
+    }
+    dedup_relays(relays)
+}
+
+/// Relays from a NIP-17 DM relay list event (kind 10050), i.e. 'relay' tags
+pub fn nip17_dm_relays_from_event(event: &Value) -> Vec<String> {
+    let mut relays = Vec::new();
+    if let Some(tags) = event.get("tags").and_then(|t| t.as_array()) {
+        for tag in tags.iter().filter_map(|t| t.as_array()) {
+            if tag.len() < 2 || tag[0].as_str() != Some("relay") {
+                continue;
+            }
+            if let Some(url) = tag[1].as_str()
+                && url.trim().starts_with("wss://")
+            {
+                relays.push(normalize_relay_url(url));
+            }
+        }
+    }
+    dedup_relays(relays)
+}
+
+/// Relays for an event to a recipient (zap request, DM):
+/// the recipient's relays (e.g. NIP-65 read relays, limited) first, then the configured ones
+pub fn recipient_relays(configured: &[String], recipient_list: &[String]) -> Vec<String> {
+    let mut relays: Vec<String> = recipient_list
+        .iter()
+        .take(RECIPIENT_RELAYS_MAX)
+        .cloned()
+        .collect();
+    relays.extend(configured.iter().cloned());
+    dedup_relays(relays)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize_relay_url() {
+        assert_eq!(
+            normalize_relay_url("relay.damus.io"),
+            "wss://relay.damus.io"
+        );
+        assert_eq!(normalize_relay_url(" wss://nos.lol/ "), "wss://nos.lol");
+        assert_eq!(
+            normalize_relay_url("ws://localhost:7777"),
+            "ws://localhost:7777"
+        );
+    }
+
+    #[test]
+    fn test_parse_relay_list() {
+        assert_eq!(
+            parse_relay_list("wss://nos.lol/, relay.damus.io,nos.lol"),
+            vec!["wss://nos.lol", "wss://relay.damus.io"]
+        );
+        assert_eq!(
+            parse_relay_list("# relays\nwss://nos.lol\n\nrelay.primal.net # primal\n"),
+            vec!["wss://nos.lol", "wss://relay.primal.net"]
+        );
+        assert!(parse_relay_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_nip65_read_relays_from_event() {
+        let event = json!({
+            "kind": 10002,
+            "tags": [
+                ["r", "wss://read.example.com", "read"],
+                ["r", "wss://write.example.com", "write"],
+                ["r", "wss://both.example.com/"],
+                ["p", "abcd"],
+                ["r"],
+                ["r", "https://not.a.relay.com"],
+            ],
+        });
+        assert_eq!(
+            nip65_read_relays_from_event(&event),
+            vec!["wss://read.example.com", "wss://both.example.com"]
+        );
+        assert!(nip65_read_relays_from_event(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_nip17_dm_relays_from_event() {
+        let event = json!({
+            "kind": 10050,
+            "tags": [
+                ["relay", "wss://inbox.example.com/"],
+                ["relay", "ws://insecure.example.com"],
+                ["r", "wss://other.example.com"],
+            ],
+        });
+        assert_eq!(
+            nip17_dm_relays_from_event(&event),
+            vec!["wss://inbox.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_recipient_relays() {
+        let configured = vec![
+            "wss://nos.lol".to_string(),
+            "wss://relay.damus.io".to_string(),
+        ];
+        let recipient = (0..7)
+            .map(|i| format!("wss://r{i}.example.com"))
+            .chain(["wss://nos.lol".to_string()])
+            .collect::<Vec<String>>();
+        let relays = recipient_relays(&configured, &recipient);
+        assert_eq!(relays.len(), RECIPIENT_RELAYS_MAX + 2);
+        assert_eq!(relays[0], "wss://r0.example.com");
+        assert_eq!(relays[RECIPIENT_RELAYS_MAX], "wss://nos.lol");
+        assert_eq!(recipient_relays(&configured, &[]), configured);
+    }
+}
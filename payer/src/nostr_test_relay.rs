@@ -0,0 +1,164 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//
+// Local Nostr relay stand-in, for tests.
+// Answers REQ with the stored events matching kinds/authors, then EOSE; stores published EVENTs.
+//
+
+#[derive(Clone, Debug, Default)]
+pub struct NostrRelayConfig {
+    /// Delay before answering a REQ
+    pub response_delay: Duration,
+    /// Never answer REQs (no EOSE either)
+    pub silent: bool,
+}
+
+/// A running stand-in relay. Stops when dropped.
+pub struct NostrTestRelay {
+    pub port: u16,
+    events: Arc<Mutex<Vec<Value>>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for NostrTestRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl NostrTestRelay {
+    /// Start a relay on a free local port, with some stored events
+    pub async fn start(config: NostrRelayConfig, events: Vec<Value>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let events = Arc::new(Mutex::new(events));
+        let events_clone = events.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Err(_) => continue,
+                    Ok(s) => s,
+                };
+                tokio::spawn(handle_connection(
+                    stream,
+                    config.clone(),
+                    events_clone.clone(),
+                ));
+            }
+        });
+        Self {
+            port,
+            events,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    /// The stored events, incl. the published ones
+    pub fn events(&self) -> Vec<Value> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+fn filter_matches(filter: &Value, event: &Value) -> bool {
+    if let Some(kinds) = filter.get("kinds").and_then(|k| k.as_array())
+        && !kinds.contains(&event["kind"])
+    {
+        return false;
+    }
+    if let Some(authors) = filter.get("authors").and_then(|a| a.as_array())
+        && !authors.contains(&event["pubkey"])
+    {
+        return false;
+    }
+    // Tag filters, e.g. "#p"
+    for (key, values) in filter.as_object().cloned().unwrap_or_default() {
+        let tag_name = match key.strip_prefix("#") {
+            None => continue,
+            Some(n) => n.to_string(),
+        };
+        let values = values.as_array().cloned().unwrap_or_default();
+        let tags = event["tags"].as_array().cloned().unwrap_or_default();
+        if !tags
+            .iter()
+            .any(|t| t[0] == tag_name.as_str() && values.contains(&t[1]))
+        {
+            return false;
+        }
+    }
+    if let Some(since) = filter.get("since").and_then(|s| s.as_u64())
+        && event["created_at"].as_u64().unwrap_or(0) < since
+    {
+        return false;
+    }
+    true
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    config: NostrRelayConfig,
+    events: Arc<Mutex<Vec<Value>>>,
+) {
+    let ws_stream = match accept_async(stream).await {
+        Err(_) => return,
+        Ok(s) => s,
+    };
+    let (mut write, mut read) = ws_stream.split();
+    while let Some(Ok(msg)) = read.next().await {
+        let text = match msg {
+            Message::Text(t) => t,
+            _ => continue,
+        };
+        let req: Value = match serde_json::from_str(&text) {
+            Err(_) => continue,
+            Ok(v) => v,
+        };
+        let array = match req.as_array() {
+            None => continue,
+            Some(a) => a.clone(),
+        };
+        match array.first().and_then(|t| t.as_str()) {
+            Some("REQ") if array.len() >= 3 => {
+                if config.silent {
+                    continue;
+                }
+                tokio::time::sleep(config.response_delay).await;
+                let sub_id = array[1].clone();
+                let matching = events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|e| filter_matches(&array[2], e))
+                    .cloned()
+                    .collect::<Vec<Value>>();
+                for e in matching {
+                    let _ = write
+                        .send(Message::Text(json!(["EVENT", sub_id, e]).to_string()))
+                        .await;
+                }
+                let _ = write
+                    .send(Message::Text(json!(["EOSE", sub_id]).to_string()))
+                    .await;
+            }
+            Some("EVENT") if array.len() >= 2 => {
+                let event = array[1].clone();
+                events.lock().unwrap().push(event.clone());
+                let _ = write
+                    .send(Message::Text(
+                        json!(["OK", event["id"], true, ""]).to_string(),
+                    ))
+                    .await;
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,169 +1,58 @@
 use crate::common::PaymentResult;
 use crate::ln_address::ln_p_url_from_address;
-use crate::nostr_profile::get_nostr_ln_address;
-use crate::payer::pay_lightning_invoice;
+use crate::ln_backend::LightningBackend;
+use crate::lnurl_pay::{LnurlPayError, fetch_pay_params};
+use crate::nostr_profile::get_nostr_read_relays;
+use crate::nostr_relays::recipient_relays;
+use crate::payer::{
+    BeforePay, check_invoice_before_pay, invoice_pay_error_code, pay_lightning_invoice,
+};
+use crate::zap_message::ZapMode;
 
-use common_rs::error_codes::*;
+use common_rs::error_codes::{ERROR_LN_ADDRESS_NONFINAL_FAILURE, ERROR_NOSTR_ZAP_NOT_SUPPORTED};
 
 use bech32::{FromBase32, ToBase32, encode};
-use nostr::nips::nip57::ZapRequestData;
+use nostr::hashes::{Hash, sha256};
+use nostr::nips::nip57::{self, ZapRequestData};
 use nostr::secp256k1::Secp256k1;
 use nostr::util::JsonUtil;
 use nostr::{EventBuilder, Keys, PublicKey, RelayUrl, SecretKey};
 
 use std::error::Error;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, serde::Deserialize)]
-struct LnurlResponseData {
-    callback: Option<String>,
-    #[serde(rename = "allowsNostr")]
-    allow_nostr: Option<bool>,
-    #[serde(rename = "nostrPubkey")]
-    nostr_pubkey: Option<String>,
-    #[serde(rename = "minSendable")]
-    min_sendable: Option<u64>,
-    #[serde(rename = "maxSendable")]
-    max_sendable: Option<u64>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-struct CallbackResponseData {
-    pr: Option<String>,
-}
-
-// Retrieve a BOLT11 incvoice from a Lightning Address
-// In case of error, return:
-// - if the error is nonfinal
-// - the error
+// Retrieve a BOLT11 invoice for a zap from a Lightning Address
+// Return the invoice, and the Nostr pubkey of the LNURL server (hex), signing the zap receipt.
+// The zap request is only sent to a server that allows it and will sign the receipt (NIP-57).
 pub async fn get_zap_invoice(
     ln_address: &str,
     amount_msats: u64,
     zap_event_str: &str,
-) -> Result<String, (bool, Box<dyn Error>)> {
-    // Retrieve a BOLT11 invoice from a Lightning Address.
-    //
-    // Args:
-    //     ln_address: A Lightning Address in the format "user@domain"
-    //     amount_msats: Amount to pay in millisatoshis
-    //
-    // Returns:
-    //     A BOLT11 invoice string or raises an exception if the process fails
-
-    println!("Processing LN address {ln_address} ...");
-
-    // Step 1: Construct and request the Lightning Address URL
-    let lnurlp_url = &ln_p_url_from_address(ln_address).map_err(|e| (false, e.into()))?;
-
-    // Make the initial request to get the LNURL metadata
-    let resp = match reqwest::get(lnurlp_url).await {
-        Err(e) => {
-            return Err((
-                true,
-                format!("HTTP request failed: {} {:?}", lnurlp_url, e).into(),
-            ));
-        }
-        Ok(r) => r,
-    };
-    if resp.status() != reqwest::StatusCode::OK {
-        return Err((
-            true,
-            format!("HTTP request failed: {} {}", resp.status(), lnurlp_url).into(),
-        ));
-    }
-    let lnurlp_data = resp
-        .json::<LnurlResponseData>()
-        .await
-        .map_err(|e| (true, e.into()))?;
-    // println!("lnurlp_data {:?}", lnurlp_data);
-
-    // Extract the callback URL
-    let callback_url = match lnurlp_data.callback {
-        None => {
-            return Err((
-                true,
-                format!("Missing callback: {} {:?}", lnurlp_url, lnurlp_data).into(),
-            ));
-        }
-        Some(c) => c,
-    };
-    // println!("callback {}", callback_url);
-
+) -> Result<(String, String), LnurlPayError> {
+    let params = fetch_pay_params(ln_address).await?;
     println!(
         "allowsNostr {}  nostr pubkey '{}'",
-        lnurlp_data.allow_nostr.unwrap_or(false),
-        lnurlp_data.nostr_pubkey.unwrap_or_default()
+        params.allows_nostr,
+        params.nostr_pubkey.as_deref().unwrap_or_default()
     );
-
-    // Check if the callback URL supports the specified amount
-    let min_sendable = lnurlp_data.min_sendable.unwrap_or(1);
-    let max_sendable = lnurlp_data.max_sendable.unwrap_or(u64::max_value());
-
-    if amount_msats < min_sendable {
-        return Err((
-            false,
-            format!("Amount {amount_msats} is below the minimum allowed: {min_sendable}").into(),
-        ));
-    }
-    if amount_msats > max_sendable {
-        return Err((
-            false,
-            format!("Amount {amount_msats} is above the maximum allowed: {max_sendable}").into(),
-        ));
-    }
-
-    // let lnurl_bech32 = lnurlp_url.
-    // str(lnurl.Lnurl(lnaddr_url).bech32).lower()
-    // print(lnurl_bech32)
-
-    let zap_event_serialized_quoted = urlencoding::encode(&zap_event_str);
-    println!("Zap event as string: '{zap_event_serialized_quoted}' '{zap_event_str}'");
+    let zap_pubkey = params.zap_pubkey()?;
 
     let lnurlp_url_bech = encode(
         "lnurl",
-        lnurlp_url.as_bytes().to_base32(),
+        params.lnurlp_url.as_bytes().to_base32(),
         bech32::Variant::Bech32,
     )
-    .map_err(|e| (false, e.into()))?;
-
-    // TODO
-
-    // Step 2: Make the callback request with the amount
-    // Some providers expect amount in msats, others in sats - we'll use msats as that's our input
-    let callback_with_amount = &format!(
-        "{callback_url}?amount={amount_msats}&lnurl={lnurlp_url_bech}&nostr={zap_event_serialized_quoted}"
-    );
-    // println!("callback_with_amount {callback_with_amount}");
-
-    let resp = match reqwest::get(callback_with_amount).await {
-        Err(e) => {
-            return Err((
-                true,
-                format!("HTTP request failed: {} {:?}", callback_with_amount, e).into(),
-            ));
-        }
-        Ok(r) => r,
-    };
-
-    let callback_data = resp
-        .json::<CallbackResponseData>()
-        .await
-        .map_err(|e| (true, e.into()))?;
-    // println!("callback_data {:?}", callback_data);
-
-    // Check if the response contains a BOLT11 invoice
-    let invoice = match callback_data.pr {
-        None => {
-            return Err((
-                false,
-                format!("Invalid callback response: missing 'pr' field (BOLT11 invoice)").into(),
-            ));
-        }
-        Some(i) => i,
-    };
-
-    // Return the BOLT11 invoice
-    Ok(invoice)
+    .map_err(|e| LnurlPayError::InvalidAddress(e.to_string()))?;
+    println!("Zap event as string: '{zap_event_str}'");
+
+    let invoice = params
+        .request_invoice(
+            amount_msats,
+            &[("lnurl", &lnurlp_url_bech), ("nostr", zap_event_str)],
+        )
+        .await?;
+    Ok((invoice.pr, zap_pubkey))
 }
 
 /// Helper: npub from nsec
@@ -180,27 +69,41 @@ pub fn npub_from_secret_vec(secret_key_vec: &Vec<u8>) -> Result<String, Box<dyn
     npub_from_secret_obj(&secret_key_obj)
 }
 
+/// Options of a zap payment
+pub struct ZapOptions<'a> {
+    /// Message of the zap request
+    pub message: &'a str,
+    pub zap_mode: ZapMode,
+    pub max_fee_msat: u64,
+    pub before_pay: BeforePay<'a>,
+}
+
+/// Zap to an npub, to its Lightning Address (as obtained from the profile), with a message
 pub async fn nostr_zap(
     amount_msat: u64,
-    sender_nsec_vec: &Vec<u8>,
+    sender_nsec_vec: &[u8],
     rec_npub: &str,
-    relays: &Vec<&str>,
+    ln_address: &str,
+    ln_backend: &dyn LightningBackend,
+    relays: &[String],
+    options: ZapOptions<'_>,
 ) -> Result<PaymentResult, (bool, Box<dyn Error>)> {
+    let ZapOptions {
+        message,
+        zap_mode,
+        max_fee_msat,
+        before_pay,
+    } = options;
     let sender_nsec = SecretKey::from_slice(sender_nsec_vec).map_err(|e| (false, e.into()))?;
     let sender_npub = npub_from_secret_obj(&sender_nsec).map_err(|e| (false, e.into()))?;
     println!("nostr_zap:  {amount_msat}  from {sender_npub}  to {rec_npub}");
 
-    let ln_address = get_nostr_ln_address(rec_npub)
-        .await
-        .map_err(|e| (true, e.into()))?;
     if ln_address.len() == 0 {
         return Err((
             false,
             format!("Could not obtain LN Address for npub '{rec_npub}'").into(),
         ));
     }
-    println!("Obtained LN Address: '{ln_address}'");
-
     let lnurlp_url_str = ln_p_url_from_address(&ln_address).map_err(|e| (false, e.into()))?;
     let lnurlp_url_bech = encode(
         "lnurl",
@@ -213,40 +116,103 @@ pub async fn nostr_zap(
     let rec_npub_bytes =
         Vec::<u8>::from_base32(&rec_npub_parse.1).map_err(|e| (false, e.into()))?;
     let rec_pubkey = PublicKey::from_slice(&rec_npub_bytes).map_err(|e| (false, e.into()))?;
+    // Zap receipt should go to where the recipient reads (NIP-65), and to our relays
+    let rec_read_relays = get_nostr_read_relays(rec_npub, relays).await;
     let mut relay_urls = Vec::new();
-    for rs in relays {
+    for rs in &recipient_relays(relays, &rec_read_relays) {
         let relay = RelayUrl::from_str(rs).map_err(|e| (false, e.into()))?;
         relay_urls.push(relay);
     }
-    let mut zap_req_data = ZapRequestData::new(rec_pubkey, relay_urls);
+    let mut zap_req_data = ZapRequestData::new(rec_pubkey, relay_urls).message(message);
     zap_req_data.amount = Some(amount_msat);
     zap_req_data.lnurl = Some(lnurlp_url_bech);
 
     println!("zap_req_data: {:?}", zap_req_data);
-    let builder = EventBuilder::public_zap_request(zap_req_data);
-    let zap_event = builder
-        .sign_with_keys(&Keys::new(sender_nsec.clone()))
-        .map_err(|e| (false, e.into()))?;
+    let sender_keys = Keys::new(sender_nsec.clone());
+    let zap_event = match zap_mode {
+        ZapMode::Public => EventBuilder::public_zap_request(zap_req_data)
+            .sign_with_keys(&sender_keys)
+            .map_err(|e| (false, e.into()))?,
+        ZapMode::Anonymous => {
+            nip57::anonymous_zap_request(zap_req_data).map_err(|e| (false, e.into()))?
+        }
+        ZapMode::Private => {
+            nip57::private_zap_request(zap_req_data, &sender_keys).map_err(|e| (false, e.into()))?
+        }
+    };
     let zap_event_serialized = &zap_event.as_json().to_string();
 
-    let invoice = get_zap_invoice(&ln_address, amount_msat, &zap_event_serialized).await?;
+    let (invoice, zap_pubkey) =
+        match get_zap_invoice(ln_address, amount_msat, zap_event_serialized).await {
+            Err(LnurlPayError::ZapNotSupported(reason)) => {
+                // Not to be retried as a zap, can fall back to another method
+                return Ok(PaymentResult::new(
+                    false,
+                    false,
+                    ERROR_NOSTR_ZAP_NOT_SUPPORTED,
+                    &format!("Zaps not supported by {ln_address}, {reason}"),
+                    ln_address,
+                    "",
+                    0,
+                    0,
+                    "",
+                ));
+            }
+            Err(e) if e.is_nonfinal() => {
+                // LNURL server error, retried as such (not as a relay error)
+                return Ok(PaymentResult::new(
+                    false,
+                    true,
+                    ERROR_LN_ADDRESS_NONFINAL_FAILURE,
+                    &e.to_string(),
+                    ln_address,
+                    "",
+                    0,
+                    0,
+                    "",
+                ));
+            }
+            Err(e) => return Err(e.into()),
+            Ok(r) => r,
+        };
     println!("Obtained ZAP invoice to be paid:   '{invoice}'");
 
-    let mut pay_res = pay_lightning_invoice(&invoice, amount_msat, &rec_npub)
-        .await
-        .map_err(|e| (true, e.into()))?;
+    // The invoice must commit to our zap request (NIP-57)
+    let zap_request_hash = sha256::Hash::hash(zap_event_serialized.as_bytes()).to_string();
+    let now_utc = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+    if let Some(mut check_res) = check_invoice_before_pay(
+        ln_backend,
+        &invoice,
+        amount_msat,
+        Some(&zap_request_hash),
+        now_utc,
+    )
+    .await
+    {
+        check_res.secon_id = ln_address.to_string();
+        check_res.terti_id = invoice;
+        check_res.zap_pubkey = zap_pubkey;
+        return Ok(check_res);
+    }
+
+    let mut pay_res = pay_lightning_invoice(
+        ln_backend,
+        &invoice,
+        amount_msat,
+        rec_npub,
+        max_fee_msat,
+        before_pay,
+    )
+    .await
+    .map_err(|e| (true, e))?;
 
     pay_res.secon_id = ln_address.to_string();
     pay_res.terti_id = invoice;
-    pay_res.err_code = if !pay_res.success {
-        if pay_res.err_nonfinal {
-            ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE
-        } else {
-            ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE
-        }
-    } else {
-        ERROR_OK
-    };
+    pay_res.zap_pubkey = zap_pubkey;
+    pay_res.err_code = invoice_pay_error_code(&pay_res);
 
     Ok(pay_res)
 }
@@ -254,6 +220,136 @@ pub async fn nostr_zap(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ln_address::test::set_test_url_template;
+    use crate::lnurl_test_server::{CallbackMode, LnurlServerConfig, LnurlTestServer};
+    use crate::mock_backend::MockBackend;
+    use crate::payer::no_before_pay;
+    use common_rs::error_codes::*;
+
+    async fn do_zap(config: LnurlServerConfig) -> (PaymentResult, LnurlTestServer, MockBackend) {
+        do_zap_with_mode(config, ZapMode::Public).await
+    }
+
+    async fn do_zap_with_mode(
+        config: LnurlServerConfig,
+        zap_mode: ZapMode,
+    ) -> (PaymentResult, LnurlTestServer, MockBackend) {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(config, mock.clone()).await;
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let pay_res = nostr_zap(
+            5000,
+            &[7u8; 32].to_vec(),
+            &rec_npub,
+            &server.ln_address("miner"),
+            &mock,
+            &Vec::new(),
+            ZapOptions {
+                message: "Payout: 5 sats",
+                zap_mode,
+                max_fee_msat: 1000,
+                before_pay: &no_before_pay,
+            },
+        )
+        .await
+        .unwrap();
+        (pay_res, server, mock)
+    }
+
+    #[tokio::test]
+    async fn test_nostr_zap_ok() {
+        let (pay_res, server, mock) = do_zap(LnurlServerConfig::default()).await;
+        assert!(pay_res.success);
+        assert_eq!(mock.paid_invoices().len(), 1);
+        let callback_req = server.last_callback_request().unwrap();
+        assert_eq!(callback_req["amount"], "5000");
+        let zap_request: serde_json::Value = serde_json::from_str(&callback_req["nostr"]).unwrap();
+        assert_eq!(zap_request["kind"], 9734);
+        assert_eq!(zap_request["content"], "Payout: 5 sats");
+        let sender_npub = npub_from_secret_vec(&[7u8; 32].to_vec()).unwrap();
+        let sender_hex = crate::nostr_profile::npub_to_hex(&sender_npub).unwrap();
+        assert_eq!(zap_request["pubkey"], sender_hex.as_str());
+        // The receipt signer is remembered
+        assert_eq!(
+            Some(pay_res.zap_pubkey),
+            LnurlServerConfig::default().nostr_pubkey
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nostr_zap_not_supported() {
+        let no_allow = LnurlServerConfig {
+            allows_nostr: false,
+            ..Default::default()
+        };
+        let no_pubkey = LnurlServerConfig {
+            nostr_pubkey: None,
+            ..Default::default()
+        };
+        let bad_pubkey = LnurlServerConfig {
+            nostr_pubkey: Some("not-a-pubkey".into()),
+            ..Default::default()
+        };
+        for config in [no_allow, no_pubkey, bad_pubkey] {
+            let (pay_res, server, mock) = do_zap(config).await;
+            assert!(!pay_res.success && !pay_res.err_nonfinal);
+            assert_eq!(pay_res.err_code, ERROR_NOSTR_ZAP_NOT_SUPPORTED);
+            assert_eq!(pay_res.zap_pubkey, "");
+            // No zap request sent, nothing paid
+            assert_eq!(server.callback_request_count(), 0);
+            assert_eq!(mock.attempt_count(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nostr_zap_anon_and_private() {
+        let sender_npub = npub_from_secret_vec(&[7u8; 32].to_vec()).unwrap();
+        let sender_hex = crate::nostr_profile::npub_to_hex(&sender_npub).unwrap();
+        for zap_mode in [ZapMode::Anonymous, ZapMode::Private] {
+            let (pay_res, server, _mock) =
+                do_zap_with_mode(LnurlServerConfig::default(), zap_mode).await;
+            assert!(pay_res.success);
+            let callback_req = server.last_callback_request().unwrap();
+            let zap_request: serde_json::Value =
+                serde_json::from_str(&callback_req["nostr"]).unwrap();
+            assert_ne!(zap_request["pubkey"], sender_hex.as_str());
+            let tags = zap_request["tags"].as_array().unwrap();
+            let anon_tag = tags.iter().find(|t| t[0] == "anon").unwrap();
+            if zap_mode == ZapMode::Private {
+                // Message is encrypted
+                assert_eq!(zap_request["content"], "");
+                assert!(anon_tag.as_array().unwrap().len() >= 2);
+            } else {
+                assert_eq!(zap_request["content"], "Payout: 5 sats");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nostr_zap_description_hash_mismatch() {
+        let config = LnurlServerConfig {
+            callback_mode: CallbackMode::WrongDescriptionHashInvoice,
+            ..Default::default()
+        };
+        let (pay_res, _, mock) = do_zap(config).await;
+        assert!(!pay_res.success && !pay_res.err_nonfinal);
+        assert_eq!(pay_res.err_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        assert_eq!(mock.attempt_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_nostr_zap_server_error() {
+        let config = LnurlServerConfig {
+            callback_mode: CallbackMode::ServerError,
+            ..Default::default()
+        };
+        let (pay_res, _, mock) = do_zap(config).await;
+        // Retried as an LNURL server error
+        assert!(!pay_res.success && pay_res.err_nonfinal);
+        assert_eq!(pay_res.err_code, ERROR_LN_ADDRESS_NONFINAL_FAILURE);
+        assert_eq!(mock.attempt_count(), 0);
+    }
 
     #[test]
     fn test_npub_from_secret_vec() {
@@ -1,26 +1,52 @@
-use crate::cln_pay::pay_invoice;
+use crate::cashu::{
+    CashuConfig, MintQuoteState, PremintOutput, encode_token_v3, get_mint_quote, mint_proofs,
+    premint_outputs, proof_ys, request_mint_quote, restore_proofs,
+};
 use crate::common::{PayerParameters, PaymentMethod, PaymentResult, shorten_id};
+use crate::fee_limits::FeeLimits;
 use crate::ln_address::get_invoice_from_ln_address;
-use crate::nostr_profile::get_nostr_ln_address;
-use crate::nostr_zap::{nostr_zap, npub_from_secret_vec};
+use crate::ln_backend::{LightningBackend, LookupStatus, get_ln_backend_from_config};
+use crate::lnurl_pay::LnurlPayInvoice;
+use crate::nostr_dm::{
+    DM_SUMMARY_CHECK_PERIOD, DmConfig, notify_payment_failure, send_daily_summaries, send_dm,
+};
+use crate::nostr_profile::{get_nostr_bolt12_offer_cached, get_nostr_ln_address_cached};
+use crate::nostr_relays::get_nostr_relays_from_config;
+use crate::nostr_zap::{ZapOptions, nostr_zap, npub_from_secret_vec};
+use crate::reconcile::{
+    RECONCILE_PERIOD, RECONCILE_TIMEOUT, paid_result, reconcile_in_progress_payments,
+    reconcile_payment,
+};
+use crate::retry_policy::RetryPolicy;
+use crate::work_queue::{PayerWakeup, WorkQueue, WorkQueueConfig, open_db_conn};
+use crate::zap_message::{ZapMessageConfig, zap_message_for_payreq};
+use crate::zap_receipt::{ZAP_RECEIPT_CHECK_PERIOD, check_zap_receipts};
 
 use common_rs::common_db::get_db_file;
 use common_rs::db_pc as db;
-use common_rs::dto_pc::{PayRequest, Payment};
+use common_rs::dto_pc::{CashuToken, PayRequest, Payment};
 use common_rs::error_codes::*;
 
 use dotenv;
+use nostr::PublicKey as NostrPublicKey;
 use rusqlite::Connection;
 use seedstore::KeyStore;
+use tokio::task::{JoinHandle, LocalSet, spawn_local};
+use tokio::time::{Instant as TokioInstant, timeout};
 
 use std::env;
 use std::error::Error;
-use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fmt;
+use std::future::Future;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const RETRY_DELAY: u32 = 600;
-const PAYMENT_RETRIES_MAX: u32 = 10;
 const DEFAULT_SECRET_FILE: &str = "secret.nsec";
+/// Invoices expiring sooner than this are considered expired, secs
+const INVOICE_EXPIRY_MARGIN: u64 = 30;
+/// BOLT11 default expiry, if not specified in the invoice, secs
+const INVOICE_DEFAULT_EXPIRY: u64 = 3600;
 
 pub fn get_nostr_secret_from_config() -> Result<Vec<u8>, Box<dyn Error>> {
     // Load environment variables from .env file
@@ -44,39 +70,190 @@ pub fn print_last_payments(conn: &Connection, period_days: u32) -> Result<(), Bo
     for (pr, p) in &payms {
         let age_hr = ((now_utc - p.status_time) as f64) / 3600.0;
         println!(
-            "  {}  {:.1} \t {} {} \t {:4} {} {} {} {} '{}' '{}'",
+            "  {}  {:.1} \t {} {} \t {:4} {} {} {} {} '{}' '{}' {}",
             shorten_id(&pr.pri_id),
             age_hr,
             pr.req_amnt,
             p.paid_amnt,
-            pr.pay_method,
+            p.pay_method,
             p.status,
             p.retry_cnt,
             p.error_code,
             p.error_str,
             shorten_id(&p.secon_id),
-            shorten_id(&p.terti_id)
+            shorten_id(&p.terti_id),
+            p.zap_rcpt_status
         );
+        if !p.success_action.is_empty() {
+            println!("      success action: {}", p.success_action);
+        }
+    }
+    Ok(())
+}
+
+/// Error of a payment attempt that is not a failure of the payment itself
+#[derive(Debug)]
+pub(crate) enum AttemptError {
+    /// The pay request is paid already (by another attempt), not to be paid again
+    AlreadyPaid(i32),
+    /// A payment of the invoice is pending on the node, to be resolved later (see reconcile)
+    PendingOnNode(String),
+}
+
+impl fmt::Display for AttemptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyPaid(req_id) => {
+                write!(f, "Pay request {req_id} is already paid, not paying again")
+            }
+            Self::PendingOnNode(pay_hash) => {
+                write!(f, "Payment still pending on the node, {pay_hash}")
+            }
+        }
     }
+}
+
+impl Error for AttemptError {}
+
+/// Called with the invoice and its payment hash right before it is paid, to persist them.
+/// If it fails, the invoice is not paid.
+pub type BeforePay<'a> = &'a dyn Fn(&str, &str) -> Result<(), Box<dyn Error>>;
+
+/// No-op BeforePay, for when there is no payment to record
+pub fn no_before_pay(_invoice: &str, _payment_hash: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
 pub async fn pay_lightning_invoice(
+    ln_backend: &dyn LightningBackend,
     invoice: &str,
     req_amnt: u64,
     label: &str,
+    max_fee_msat: u64,
+    before_pay: BeforePay<'_>,
 ) -> Result<PaymentResult, Box<dyn Error>> {
-    let res = pay_invoice(invoice, req_amnt, label).await?;
+    let decoded = ln_backend.decode_invoice(invoice).await?;
+    before_pay(invoice, &decoded.payment_hash)?;
+    let res = ln_backend
+        .pay_invoice(invoice, req_amnt, label, max_fee_msat)
+        .await?;
     Ok(res)
 }
 
+/// Error code for the result of an invoice payment.
+/// A specific error code from the backend (e.g. not enough funds) is kept.
+pub(crate) fn invoice_pay_error_code(pay_res: &PaymentResult) -> u8 {
+    if pay_res.success {
+        ERROR_OK
+    } else if pay_res.err_code != ERROR_OK {
+        pay_res.err_code
+    } else if pay_res.err_nonfinal {
+        ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE
+    } else {
+        ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE
+    }
+}
+
+fn invoice_check_failed(err_nonfinal: bool, err_str: &str) -> Option<PaymentResult> {
+    println!("ERROR: Invoice check failed, {err_str}");
+    let err_code = if err_nonfinal {
+        ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE
+    } else {
+        ERROR_LN_BOLT11_INVOICE_MISMATCH
+    };
+    Some(PaymentResult::new(
+        false,
+        err_nonfinal,
+        err_code,
+        err_str,
+        "",
+        "",
+        0,
+        0,
+        "",
+    ))
+}
+
+/// Check an invoice obtained from an LNURL server before paying it (LUD-06, NIP-57):
+/// - the amount must be the requested amount
+/// - it must not be expired
+/// - the description hash must match, if one is expected (e.g. hash of the zap request)
+///
+/// Returns a failed PaymentResult if the invoice should not be paid, None if OK.
+pub(crate) async fn check_invoice_before_pay(
+    ln_backend: &dyn LightningBackend,
+    invoice: &str,
+    req_amnt: u64,
+    expected_description_hash: Option<&str>,
+    now_utc: u32,
+) -> Option<PaymentResult> {
+    let decoded = match ln_backend.decode_invoice(invoice).await {
+        Err(e) => {
+            return invoice_check_failed(true, &format!("Could not decode invoice, {e}"));
+        }
+        Ok(d) => d,
+    };
+    match decoded.amount_msat {
+        None => {
+            return invoice_check_failed(false, "Invoice has no amount");
+        }
+        Some(a) if a != req_amnt => {
+            return invoice_check_failed(
+                false,
+                &format!("Invoice amount {a} differs from requested {req_amnt}"),
+            );
+        }
+        _ => {}
+    }
+    let expiry = if decoded.expiry == 0 {
+        INVOICE_DEFAULT_EXPIRY
+    } else {
+        decoded.expiry
+    };
+    let expires_at = decoded.created_at + expiry;
+    if expires_at < now_utc as u64 + INVOICE_EXPIRY_MARGIN {
+        return invoice_check_failed(false, &format!("Invoice expired at {expires_at}"));
+    }
+    if let Some(expected) = expected_description_hash
+        && decoded.description_hash.as_deref() != Some(expected)
+    {
+        return invoice_check_failed(
+            false,
+            &format!(
+                "Invoice description hash {:?} differs from expected {expected}",
+                decoded.description_hash
+            ),
+        );
+    }
+    None
+}
+
+/// Comment sent with a payout to a Lightning Address (LUD-12), a reference of the pool
+fn payout_comment(pr: &PayRequest) -> String {
+    format!("ZapPool payout #{}", pr.id)
+}
+
+// The success action returned with the invoice, decoded, if the payment succeeded
+fn decoded_success_action(invoice: &LnurlPayInvoice, pay_res: &PaymentResult) -> String {
+    match &invoice.success_action {
+        Some(sa) if pay_res.success => {
+            // Reference is "<preimage> <hash>"
+            let preimage = pay_res.reference.split(" ").next().unwrap_or_default();
+            sa.decoded(preimage)
+        }
+        _ => "".into(),
+    }
+}
+
 // Handle a lightning address payment
 async fn process_lightning_address_payment(
-    _paym: &Payment,
+    conn: &Connection,
+    paym: &Payment,
     pr: &PayRequest,
+    payer_params: &PayerParameters,
 ) -> Result<PaymentResult, Box<dyn Error>> {
     let ln_address = &pr.pri_id;
-    match get_invoice_from_ln_address(&ln_address, pr.req_amnt).await {
+    match get_invoice_from_ln_address(ln_address, pr.req_amnt, &payout_comment(pr)).await {
         Err((err_nonfinal, err)) => {
             if err_nonfinal {
                 Ok(PaymentResult::new(
@@ -104,23 +281,38 @@ async fn process_lightning_address_payment(
                 ))
             }
         }
-        Ok(invoice) => {
+        Ok(lnurl_invoice) => {
             // Success
+            let invoice = lnurl_invoice.pr.clone();
             println!("Obtained LN invoice: ({invoice})");
 
-            let mut pay_res = pay_lightning_invoice(&invoice, pr.req_amnt, &pr.pri_id).await?;
+            if let Some(mut check_res) = check_invoice_before_pay(
+                payer_params.ln_backend.as_ref(),
+                &invoice,
+                pr.req_amnt,
+                Some(&lnurl_invoice.metadata_hash),
+                now_utc_secs(),
+            )
+            .await
+            {
+                check_res.secon_id = invoice;
+                return Ok(check_res);
+            }
+
+            let mut pay_res = pay_lightning_invoice(
+                payer_params.ln_backend.as_ref(),
+                &invoice,
+                pr.req_amnt,
+                &pr.pri_id,
+                paym.max_fee,
+                &|inv, hash| record_invoice_before_pay(conn, paym, inv, "", hash),
+            )
+            .await?;
 
             pay_res.secon_id = invoice;
             pay_res.terti_id = "".to_string();
-            pay_res.err_code = if !pay_res.success {
-                if pay_res.err_nonfinal {
-                    ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE
-                } else {
-                    ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE
-                }
-            } else {
-                ERROR_OK
-            };
+            pay_res.err_code = invoice_pay_error_code(&pay_res);
+            pay_res.success_action = decoded_success_action(&lnurl_invoice, &pay_res);
 
             Ok(pay_res)
         }
@@ -129,29 +321,40 @@ async fn process_lightning_address_payment(
 
 // Handle a Nostr lightning payment
 async fn process_nostr_lightning_payment(
-    _paym: &Payment,
+    conn: &Connection,
+    paym: &Payment,
     pr: &PayRequest,
+    payer_params: &PayerParameters,
 ) -> Result<PaymentResult, Box<dyn Error>> {
     let npub = &pr.pri_id;
-    let ln_address = match get_nostr_ln_address(npub).await {
-        Err(e) => {
-            return Ok(PaymentResult::new(
-                false,
-                true,
-                ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE,
-                &e.to_string(),
-                "",
-                "",
-                0,
-                0,
-                "",
-            ));
-        }
-        Ok(a) => a,
-    };
+    let ln_address =
+        match get_nostr_ln_address_cached(conn, npub, &payer_params.nostr_relays, now_utc_secs())
+            .await
+        {
+            Err((err_nonfinal, e)) => {
+                // No profile (e.g. relays down) is nonfinal, no Lightning Address in it is final
+                let err_code = if err_nonfinal {
+                    ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE
+                } else {
+                    ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE
+                };
+                return Ok(PaymentResult::new(
+                    false,
+                    err_nonfinal,
+                    err_code,
+                    &e.to_string(),
+                    "",
+                    "",
+                    0,
+                    0,
+                    "",
+                ));
+            }
+            Ok(a) => a,
+        };
     println!("Obtained LN Address: '{ln_address}'");
 
-    match get_invoice_from_ln_address(&ln_address, pr.req_amnt).await {
+    match get_invoice_from_ln_address(&ln_address, pr.req_amnt, &payout_comment(pr)).await {
         Err((err_nonfinal, err)) => {
             if err_nonfinal {
                 return Ok(PaymentResult::new(
@@ -179,22 +382,38 @@ async fn process_nostr_lightning_payment(
                 ));
             }
         }
-        Ok(invoice) => {
+        Ok(lnurl_invoice) => {
             // Success
+            let invoice = lnurl_invoice.pr.clone();
             println!("Obtained LN invoice: ({invoice})");
 
-            let mut pay_res = pay_lightning_invoice(&invoice, pr.req_amnt, &pr.pri_id).await?;
+            if let Some(mut check_res) = check_invoice_before_pay(
+                payer_params.ln_backend.as_ref(),
+                &invoice,
+                pr.req_amnt,
+                Some(&lnurl_invoice.metadata_hash),
+                now_utc_secs(),
+            )
+            .await
+            {
+                check_res.secon_id = ln_address.to_string();
+                check_res.terti_id = invoice;
+                return Ok(check_res);
+            }
+
+            let mut pay_res = pay_lightning_invoice(
+                payer_params.ln_backend.as_ref(),
+                &invoice,
+                pr.req_amnt,
+                &pr.pri_id,
+                paym.max_fee,
+                &|inv, hash| record_invoice_before_pay(conn, paym, &ln_address, inv, hash),
+            )
+            .await?;
             pay_res.secon_id = ln_address.to_string();
             pay_res.terti_id = invoice;
-            pay_res.err_code = if !pay_res.success {
-                if pay_res.err_nonfinal {
-                    ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE
-                } else {
-                    ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE
-                }
-            } else {
-                ERROR_OK
-            };
+            pay_res.err_code = invoice_pay_error_code(&pay_res);
+            pay_res.success_action = decoded_success_action(&lnurl_invoice, &pay_res);
 
             Ok(pay_res)
         }
@@ -203,26 +422,69 @@ async fn process_nostr_lightning_payment(
 
 // Handle a Nostr Zap payment
 async fn process_nostr_zap_payment(
-    _paym: &Payment,
+    conn: &Connection,
+    paym: &Payment,
     pr: &PayRequest,
     payer_params: &PayerParameters,
 ) -> Result<PaymentResult, Box<dyn Error>> {
     let rec_npub = &pr.pri_id;
-    // TODO dynamic list
-    let relays = vec![
-        "wss://relay.primal.net/",
-        "wss://relay.damus.io/",
-        "wss://nos.lol/",
-    ];
+    let ln_address = match get_nostr_ln_address_cached(
+        conn,
+        rec_npub,
+        &payer_params.nostr_relays,
+        now_utc_secs(),
+    )
+    .await
+    {
+        Err((err_nonfinal, e)) => {
+            // No Lightning Address in the profile is final, as for NOLN
+            let err_code = if err_nonfinal {
+                ERROR_NOSTR_ZAP_NONFINAL_FAILURE
+            } else {
+                ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE
+            };
+            return Ok(PaymentResult::new(
+                false,
+                err_nonfinal,
+                err_code,
+                &e.to_string(),
+                "",
+                "",
+                0,
+                0,
+                "",
+            ));
+        }
+        Ok(a) => a,
+    };
+    println!("Obtained LN Address: '{ln_address}'");
+
+    let message = match zap_message_for_payreq(conn, pr, &payer_params.zap_message.template) {
+        Err(e) => {
+            println!("WARNING: Could not render zap message, {e}");
+            "".to_string()
+        }
+        Ok(m) => m,
+    };
 
     match nostr_zap(
         pr.req_amnt,
         &payer_params.nostr_secret_key,
         rec_npub,
-        &relays,
+        &ln_address,
+        payer_params.ln_backend.as_ref(),
+        &payer_params.nostr_relays,
+        ZapOptions {
+            message: &message,
+            zap_mode: payer_params.zap_message.mode,
+            max_fee_msat: paym.max_fee,
+            before_pay: &|inv, hash| record_invoice_before_pay(conn, paym, &ln_address, inv, hash),
+        },
     )
     .await
     {
+        // Not a failure of the zap, see finish_attempt_with_error
+        Err((_, err)) if err.is::<AttemptError>() => Err(err),
         Err((non_final, err)) => {
             return if non_final {
                 Ok(PaymentResult::new(
@@ -254,101 +516,701 @@ async fn process_nostr_zap_payment(
     }
 }
 
-//. Handle a payment by method
-async fn process_payment_generic(
-    paym: &Payment,
-    pr: &PayRequest,
-    payer_params: &PayerParameters,
-) -> Result<PaymentResult, Box<dyn Error>> {
-    if pr.pay_method == PaymentMethod::PmLnAddress.to_string() {
-        return process_lightning_address_payment(paym, pr).await;
-    }
-    if pr.pay_method == PaymentMethod::PmNostrLightning.to_string() {
-        return process_nostr_lightning_payment(paym, pr).await;
-    }
-    if pr.pay_method == PaymentMethod::PmNostrZap.to_string() {
-        return process_nostr_zap_payment(paym, pr, payer_params).await;
-    }
-    Ok(PaymentResult::new(
-        false,
+fn cashu_failure(
+    err_nonfinal: bool,
+    err_str: &str,
+    quote_id: &str,
+    invoice: &str,
+) -> PaymentResult {
+    let err_code = if err_nonfinal {
+        ERROR_CASHU_NONFINAL_FAILURE
+    } else {
+        ERROR_CASHU_FINAL_FAILURE
+    };
+    PaymentResult::new(
         false,
-        ERROR_GENERIC_FINAL_FAILURE,
-        &format!("Unknown payment method {}", pr.pay_method),
-        "",
+        err_nonfinal,
+        err_code,
+        err_str,
+        quote_id,
+        invoice,
+        0,
+        0,
         "",
+    )
+}
+
+// A paid mint quote whose token could not be obtained, the operator has to resolve it
+// (e.g. restore the token with the stored outputs). Not retried.
+fn cashu_needs_review(err_str: &str, quote_id: &str, invoice: &str) -> PaymentResult {
+    println!("ERROR: Cashu payout needs operator review, quote {quote_id}, {err_str}");
+    PaymentResult::new(
+        false,
+        false,
+        ERROR_CASHU_NEEDS_REVIEW,
+        err_str,
+        quote_id,
+        invoice,
         0,
         0,
         "",
-    ))
+    )
 }
 
-fn save_payment(conn: &mut Connection, paym: &mut Payment) -> Result<(), Box<dyn Error>> {
-    let mut conntx = conn.transaction()?;
-    let id = db::payment_update_or_insert_nocommit(&mut conntx, &paym)? as i32;
-    if paym.id != id {
-        paym.id = id;
-        // println!("save_payment: updated id: {}", paym.id);
-    }
-    let _ = conntx.commit()?;
-    Ok(())
+// Mint the stored outputs of a paid quote, or if the mint has signed them already (the response
+// of a previous mint request lost), restore them (NUT-09). The token is stored.
+// Err result if failed, nonfinal if to be retried.
+async fn mint_cashu_token(
+    conn: &Connection,
+    mint_url: &str,
+    mut token: CashuToken,
+    memo: &str,
+    invoice: &str,
+) -> Result<Result<CashuToken, PaymentResult>, Box<dyn Error>> {
+    let outputs: Vec<PremintOutput> = serde_json::from_str(&token.premint)?;
+    let proofs = match mint_proofs(mint_url, &token.quote_id, &outputs).await {
+        Ok(p) => p,
+        Err((_, e)) => {
+            let issued = match get_mint_quote(mint_url, &token.quote_id).await {
+                Ok(q) => q.state() == MintQuoteState::Issued,
+                Err(_) => false,
+            };
+            if !issued {
+                return Ok(Err(cashu_failure(
+                    true,
+                    &format!("Mint quote paid, but minting failed, {e}"),
+                    &token.quote_id,
+                    invoice,
+                )));
+            }
+            println!("Mint quote {} issued already, restoring", token.quote_id);
+            match restore_proofs(mint_url, &outputs).await {
+                Err((true, e)) => {
+                    return Ok(Err(cashu_failure(
+                        true,
+                        &format!("Mint quote issued, but restoring failed, {e}"),
+                        &token.quote_id,
+                        invoice,
+                    )));
+                }
+                Err((false, e)) => {
+                    return Ok(Err(cashu_needs_review(
+                        &format!("Mint quote issued, but the token could not be restored, {e}"),
+                        &token.quote_id,
+                        invoice,
+                    )));
+                }
+                Ok(p) => p,
+            }
+        }
+    };
+    token.token = encode_token_v3(mint_url, &proofs, memo);
+    token.proof_ys = proof_ys(&proofs)?.join(",");
+    token.premint = "".into();
+    db::cashu_token_set_minted(conn, token.id, &token.token, &token.proof_ys)?;
+    println!("Minted Cashu token of {} sats", token.amount_sat);
+    Ok(Ok(token))
 }
 
-async fn process_payment_start(
-    payer_params: &PayerParameters,
-    conn: &mut Connection,
+/// Message of a Cashu payout DM, with the token
+fn cashu_payout_message(pr: &PayRequest, token: &CashuToken) -> String {
+    format!(
+        "ZapPool payout #{}: {} sats in Cashu ecash, from mint {}. \
+        Redeem this token in a Cashu wallet:\n\n{}",
+        pr.id, token.amount_sat, token.mint_url, token.token
+    )
+}
+
+// Result of a Cashu payment with the mint quote paid in a previous attempt, from the node.
+// Success only if the node confirms the payment; still pending on the node: left in progress (Err).
+async fn cashu_paid_result(
+    ln_backend: &dyn LightningBackend,
+    paym: &Payment,
+    quote_id: &str,
+    invoice: &str,
+) -> Result<PaymentResult, Box<dyn Error>> {
+    let payment_hash = if paym.pay_hash.is_empty() {
+        ln_backend.decode_invoice(invoice).await?.payment_hash
+    } else {
+        paym.pay_hash.clone()
+    };
+    Ok(match ln_backend.lookup_payment(&payment_hash).await? {
+        Some(lookup) => match lookup.status {
+            LookupStatus::Complete => paid_result(
+                paym,
+                lookup.amount_msat,
+                lookup.amount_sent_msat,
+                &format!("{} {}", lookup.preimage, payment_hash),
+            ),
+            LookupStatus::Pending => {
+                return Err(AttemptError::PendingOnNode(payment_hash).into());
+            }
+            LookupStatus::Failed => cashu_failure(
+                true,
+                "Mint quote paid, but the payment failed on the node",
+                quote_id,
+                invoice,
+            ),
+        },
+        None => cashu_failure(
+            true,
+            "Mint quote paid, but the payment is not known to the node",
+            quote_id,
+            invoice,
+        ),
+    })
+}
+
+// Handle a BOLT12 offer payment: an invoice is fetched for the offer, and paid.
+// The offer is pri_id, or from the Nostr profile if pri_id is an npub (stored in terti_id).
+// secon_id is the invoice.
+async fn process_bolt12_payment(
+    conn: &Connection,
+    paym: &Payment,
     pr: &PayRequest,
-    paym_orig: &Option<Payment>,
-) -> Result<(), Box<dyn Error>> {
-    let now_utc = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs_f64()
-        .floor() as u32;
+    payer_params: &PayerParameters,
+) -> Result<PaymentResult, Box<dyn Error>> {
+    let (offer, nostr_offer) = if pr.pri_id.starts_with("npub1") {
+        match get_nostr_bolt12_offer_cached(
+            conn,
+            &pr.pri_id,
+            &payer_params.nostr_relays,
+            now_utc_secs(),
+        )
+        .await
+        {
+            Err((err_nonfinal, e)) => {
+                // No offer in the profile is final, relays not reachable is nonfinal
+                let err_code = if err_nonfinal {
+                    ERROR_BOLT12_OFFER_NONFINAL_FAILURE
+                } else {
+                    ERROR_BOLT12_OFFER_FINAL_FAILURE
+                };
+                return Ok(PaymentResult::new(
+                    false,
+                    err_nonfinal,
+                    err_code,
+                    &e.to_string(),
+                    "",
+                    "",
+                    0,
+                    0,
+                    "",
+                ));
+            }
+            Ok(o) => (o.clone(), o),
+        }
+    } else {
+        (pr.pri_id.clone(), "".to_string())
+    };
+    if !offer.to_lowercase().starts_with("lno1") {
+        return Ok(PaymentResult::new(
+            false,
+            false,
+            ERROR_BOLT12_OFFER_FINAL_FAILURE,
+            &format!("Not a BOLT12 offer, '{offer}'"),
+            "",
+            &nostr_offer,
+            0,
+            0,
+            "",
+        ));
+    }
+    println!("Using BOLT12 offer: '{offer}'");
 
-    let mut paym = match paym_orig {
-        Some(p) => p.clone(),
-        None => {
-            let mut paym = Payment::new(
-                -1,
-                pr.id,
-                now_utc,
-                STATUS_NOTTRIED,
-                now_utc,
-                0,
-                "".into(),
-                0,
-                0,
-                "".into(),
-                "".into(),
-                0,
+    let invoice = match payer_params
+        .ln_backend
+        .fetch_invoice(&offer, pr.req_amnt, &payout_comment(pr))
+        .await
+    {
+        Err((err_nonfinal, err)) => {
+            let err_code = if err_nonfinal {
+                ERROR_BOLT12_OFFER_NONFINAL_FAILURE
+            } else {
+                ERROR_BOLT12_OFFER_FINAL_FAILURE
+            };
+            return Ok(PaymentResult::new(
+                false,
+                err_nonfinal,
+                err_code,
+                &err.to_string(),
+                "",
+                &nostr_offer,
                 0,
                 0,
-                "".into(),
-            );
-            let _ = save_payment(conn, &mut paym)?;
-            paym
+                "",
+            ));
         }
+        Ok(i) => i,
     };
+    println!("Obtained BOLT12 invoice: ({invoice})");
 
-    if paym.status == STATUS_FINAL_FAILURE || paym.status == STATUS_SUCCESS_FINAL {
-        println!(
-            "WARNING: Payment is already final, ignoring ({})",
-            paym.status
-        );
-        return Ok(());
+    if let Some(mut check_res) = check_invoice_before_pay(
+        payer_params.ln_backend.as_ref(),
+        &invoice,
+        pr.req_amnt,
+        None,
+        now_utc_secs(),
+    )
+    .await
+    {
+        check_res.secon_id = invoice;
+        check_res.terti_id = nostr_offer;
+        return Ok(check_res);
     }
 
-    if paym.status == STATUS_NONFINAL_FAILURE {
-        let next_retry_time = paym.fail_time + RETRY_DELAY;
-        if now_utc < next_retry_time {
-            // print(f"Payment was failed, retry cnt {paym.retry_cnt}, retrying later, in {next_retry_time - now_utc} secs")
-            return Ok(());
-        } else {
-            println!(
-                "Payment was failed, retry cnt {} {}, retrying now",
-                paym.retry_cnt, paym.fail_time
-            );
-        }
+    let mut pay_res = pay_lightning_invoice(
+        payer_params.ln_backend.as_ref(),
+        &invoice,
+        pr.req_amnt,
+        &pr.pri_id,
+        paym.max_fee,
+        &|inv, hash| record_invoice_before_pay(conn, paym, inv, &nostr_offer, hash),
+    )
+    .await?;
+    pay_res.secon_id = invoice;
+    pay_res.terti_id = nostr_offer;
+    pay_res.err_code = invoice_pay_error_code(&pay_res);
+
+    Ok(pay_res)
+}
+
+// Handle a Cashu payment: a token of the amount (whole sats) is minted from our mint, paying the
+// invoice of a mint quote, and sent to the npub by DM. secon_id is the quote ID, terti_id its invoice.
+// The blinded outputs are stored before minting, and the token before sending it: a retry mints
+// (or restores) the same outputs, or sends the same token (e.g. after a failed DM), and a quote
+// paid in a previous attempt is minted without paying again. The token is not kept once delivered.
+async fn process_cashu_payment(
+    conn: &Connection,
+    paym: &Payment,
+    pr: &PayRequest,
+    payer_params: &PayerParameters,
+) -> Result<PaymentResult, Box<dyn Error>> {
+    let mint_url = &payer_params.cashu_config.mint_url;
+    if mint_url.is_empty() {
+        return Ok(cashu_failure(false, "No Cashu mint configured", "", ""));
+    }
+    if let Err(e) = NostrPublicKey::from_str(&pr.pri_id) {
+        return Ok(cashu_failure(false, &format!("Invalid npub, {e}"), "", ""));
+    }
+    let ln_backend = payer_params.ln_backend.as_ref();
+
+    let mut pay_res = None;
+    let mut invoice = paym.terti_id.clone();
+    let token = match db::cashu_token_get_by_req(conn, pr.id)? {
+        Some(t) if t.delivered_at != 0 => {
+            // Sent in a previous attempt, whose result was not stored
+            println!("Cashu token already delivered");
+            let mut r = cashu_paid_result(ln_backend, paym, &t.quote_id, &invoice).await?;
+            r.secon_id = t.quote_id;
+            r.terti_id = invoice;
+            return Ok(r);
+        }
+        Some(t) if !t.token.is_empty() => {
+            println!("Cashu token already minted, sending it again");
+            t
+        }
+        Some(t) => {
+            // Outputs stored, no token: the previous mint request failed, or its response was lost
+            match mint_cashu_token(conn, mint_url, t, &payout_comment(pr), &invoice).await? {
+                Err(r) => return Ok(r),
+                Ok(t) => t,
+            }
+        }
+        None => {
+            let amount_sat = pr.req_amnt / 1000;
+            if amount_sat == 0 {
+                return Ok(cashu_failure(
+                    false,
+                    &format!("Amount {} is less than 1 sat", pr.req_amnt),
+                    "",
+                    "",
+                ));
+            }
+
+            // The quote of the previous attempt, if paid
+            let mut quote_id = "".to_string();
+            if !paym.secon_id.is_empty() {
+                let quote = match get_mint_quote(mint_url, &paym.secon_id).await {
+                    Err((err_nonfinal, e)) => {
+                        return Ok(cashu_failure(
+                            err_nonfinal,
+                            &e.to_string(),
+                            &paym.secon_id,
+                            &paym.terti_id,
+                        ));
+                    }
+                    Ok(q) => q,
+                };
+                match quote.state() {
+                    MintQuoteState::Paid => quote_id = quote.quote,
+                    MintQuoteState::Issued => {
+                        return Ok(cashu_needs_review(
+                            "Mint quote already issued, but no outputs stored",
+                            &paym.secon_id,
+                            &paym.terti_id,
+                        ));
+                    }
+                    MintQuoteState::Unpaid => {
+                        if !paym.pay_hash.is_empty()
+                            && let Some(lookup) = ln_backend.lookup_payment(&paym.pay_hash).await?
+                        {
+                            match lookup.status {
+                                LookupStatus::Pending => {
+                                    return Err(
+                                        AttemptError::PendingOnNode(paym.pay_hash.clone()).into()
+                                    );
+                                }
+                                LookupStatus::Complete => {
+                                    // The mint has not seen the payment yet, checked again on retry
+                                    return Ok(cashu_failure(
+                                        true,
+                                        &format!(
+                                            "Mint quote {} not paid yet, though paid on the node",
+                                            paym.secon_id
+                                        ),
+                                        &paym.secon_id,
+                                        &paym.terti_id,
+                                    ));
+                                }
+                                LookupStatus::Failed => {}
+                            }
+                        }
+                    }
+                }
+            }
+
+            if quote_id.is_empty() {
+                let quote = match request_mint_quote(mint_url, amount_sat).await {
+                    Err((err_nonfinal, e)) => {
+                        return Ok(cashu_failure(err_nonfinal, &e.to_string(), "", ""));
+                    }
+                    Ok(q) => q,
+                };
+                println!("Obtained mint quote {}: ({})", quote.quote, quote.request);
+
+                if let Some(mut check_res) = check_invoice_before_pay(
+                    ln_backend,
+                    &quote.request,
+                    amount_sat * 1000,
+                    None,
+                    now_utc_secs(),
+                )
+                .await
+                {
+                    check_res.secon_id = quote.quote;
+                    check_res.terti_id = quote.request;
+                    return Ok(check_res);
+                }
+
+                let mut res = pay_lightning_invoice(
+                    ln_backend,
+                    &quote.request,
+                    amount_sat * 1000,
+                    &pr.pri_id,
+                    paym.max_fee,
+                    &|inv, hash| record_invoice_before_pay(conn, paym, &quote.quote, inv, hash),
+                )
+                .await?;
+                res.secon_id = quote.quote.clone();
+                res.terti_id = quote.request.clone();
+                res.err_code = invoice_pay_error_code(&res);
+                if !res.success {
+                    return Ok(res);
+                }
+                quote_id = quote.quote;
+                invoice = quote.request;
+                pay_res = Some(res);
+            }
+
+            // The quote is paid: the outputs are stored, then minted, a failure to mint is retried
+            let outputs = match premint_outputs(mint_url, amount_sat).await {
+                Err((_, e)) => {
+                    return Ok(cashu_failure(
+                        true,
+                        &format!("Mint quote paid, but minting failed, {e}"),
+                        &quote_id,
+                        &invoice,
+                    ));
+                }
+                Ok(o) => o,
+            };
+            let mut token = CashuToken::new(
+                -1,
+                pr.id,
+                mint_url.clone(),
+                quote_id,
+                amount_sat,
+                "".into(),
+                now_utc_secs(),
+                0,
+                serde_json::to_string(&outputs)?,
+                "".into(),
+            );
+            token.id = db::cashu_token_insert(conn, &token)?;
+            match mint_cashu_token(conn, mint_url, token, &payout_comment(pr), &invoice).await? {
+                Err(r) => return Ok(r),
+                Ok(t) => t,
+            }
+        }
+    };
+
+    // Quote paid in a previous attempt: the token is sent only once the node confirms the payment
+    let mut pay_res = match pay_res {
+        Some(r) => r,
+        None => {
+            let r = cashu_paid_result(ln_backend, paym, &token.quote_id, &invoice).await?;
+            if !r.success {
+                return Ok(r);
+            }
+            r
+        }
+    };
+
+    if let Err(e) = send_dm(
+        &payer_params.nostr_secret_key,
+        &pr.pri_id,
+        &cashu_payout_message(pr, &token),
+        &payer_params.nostr_relays,
+        payer_params.dm_config.protocol,
+    )
+    .await
+    {
+        return Ok(cashu_failure(
+            true,
+            &format!("Cashu token minted, but could not be sent, {e}"),
+            &token.quote_id,
+            &invoice,
+        ));
+    }
+    db::cashu_token_set_delivered(conn, token.id, now_utc_secs())?;
+
+    pay_res.secon_id = token.quote_id;
+    pay_res.terti_id = invoice;
+    Ok(pay_res)
+}
+
+// Retry with the invoice of the previous attempt, if it was sent for payment (has a payment hash):
+// an invoice can be paid only once, so this closes the double-pay window across retries.
+// The node is asked first: if paid, it is a success; if still pending, it is left in progress
+// (Err), to be resolved later, see reconcile.
+// Return None if there is no such invoice or it is not valid any more, a new one is to be obtained.
+async fn pay_previous_invoice(
+    conn: &Connection,
+    paym: &Payment,
+    pr: &PayRequest,
+    payer_params: &PayerParameters,
+) -> Result<Option<PaymentResult>, Box<dyn Error>> {
+    let invoice = payment_invoice(paym);
+    if invoice.is_empty() || paym.pay_hash.is_empty() {
+        return Ok(None);
+    }
+    let ln_backend = payer_params.ln_backend.as_ref();
+    if let Some(lookup) = ln_backend.lookup_payment(&paym.pay_hash).await? {
+        match lookup.status {
+            LookupStatus::Complete => {
+                println!("Previous invoice was paid, {}", paym.pay_hash);
+                return Ok(Some(paid_result(
+                    paym,
+                    lookup.amount_msat,
+                    lookup.amount_sent_msat,
+                    &format!("{} {}", lookup.preimage, paym.pay_hash),
+                )));
+            }
+            LookupStatus::Pending => {
+                return Err(AttemptError::PendingOnNode(paym.pay_hash.clone()).into());
+            }
+            LookupStatus::Failed => {}
+        }
+    }
+    if check_invoice_before_pay(ln_backend, invoice, pr.req_amnt, None, now_utc_secs())
+        .await
+        .is_some()
+    {
+        println!("Previous invoice not valid any more, obtaining a new one");
+        return Ok(None);
+    }
+
+    println!("Reusing the invoice of the previous attempt: ({invoice})");
+    let mut pay_res = pay_lightning_invoice(
+        ln_backend,
+        invoice,
+        pr.req_amnt,
+        &pr.pri_id,
+        paym.max_fee,
+        &|_inv, hash| record_invoice_before_pay(conn, paym, &paym.secon_id, &paym.terti_id, hash),
+    )
+    .await?;
+    pay_res.secon_id = paym.secon_id.clone();
+    pay_res.terti_id = paym.terti_id.clone();
+    pay_res.err_code = invoice_pay_error_code(&pay_res);
+    Ok(Some(pay_res))
+}
+
+//. Handle a payment by method
+async fn process_payment_generic(
+    conn: &Connection,
+    paym: &Payment,
+    pr: &PayRequest,
+    payer_params: &PayerParameters,
+) -> Result<PaymentResult, Box<dyn Error>> {
+    // The invoice of a mint quote is not reused by itself, the quote is, see there
+    if paym.pay_method == PaymentMethod::PmCashu.to_string() {
+        return process_cashu_payment(conn, paym, pr, payer_params).await;
+    }
+    if let Some(pay_res) = pay_previous_invoice(conn, paym, pr, payer_params).await? {
+        return Ok(pay_res);
+    }
+    // The method of this attempt, it may be a fallback method of the pay request
+    if paym.pay_method == PaymentMethod::PmLnAddress.to_string() {
+        return process_lightning_address_payment(conn, paym, pr, payer_params).await;
+    }
+    if paym.pay_method == PaymentMethod::PmNostrLightning.to_string() {
+        return process_nostr_lightning_payment(conn, paym, pr, payer_params).await;
+    }
+    if paym.pay_method == PaymentMethod::PmNostrZap.to_string() {
+        return process_nostr_zap_payment(conn, paym, pr, payer_params).await;
+    }
+    if paym.pay_method == PaymentMethod::PmBolt12.to_string() {
+        return process_bolt12_payment(conn, paym, pr, payer_params).await;
+    }
+    Ok(PaymentResult::new(
+        false,
+        false,
+        ERROR_GENERIC_FINAL_FAILURE,
+        &format!("Unknown payment method {}", paym.pay_method),
+        "",
+        "",
+        0,
+        0,
+        "",
+    ))
+}
+
+pub(crate) fn save_payment(
+    conn: &mut Connection,
+    paym: &mut Payment,
+) -> Result<(), Box<dyn Error>> {
+    let mut conntx = conn.transaction()?;
+    let id = db::payment_update_or_insert_nocommit(&mut conntx, &paym)? as i32;
+    if paym.id != id {
+        paym.id = id;
+        // println!("save_payment: updated id: {}", paym.id);
+    }
+    let _ = conntx.commit()?;
+    Ok(())
+}
+
+// Store the invoice about to be paid (and the LN Address) and its payment hash, with the payment
+// still in progress. After a crash, the payment can be resolved from the node, see reconcile.
+// Fails if the pay request has been paid already, not to pay it twice.
+fn record_invoice_before_pay(
+    conn: &Connection,
+    paym: &Payment,
+    secon_id: &str,
+    terti_id: &str,
+    pay_hash: &str,
+) -> Result<(), Box<dyn Error>> {
+    if db::payreq_has_successful_payment(conn, paym.req_id)? {
+        return Err(AttemptError::AlreadyPaid(paym.req_id).into());
+    }
+    let mut paym = paym.clone();
+    paym.secon_id = secon_id.to_string();
+    paym.terti_id = terti_id.to_string();
+    paym.pay_hash = pay_hash.to_string();
+    let conntx = conn.unchecked_transaction()?;
+    let _ = db::payment_update_or_insert_nocommit(&conntx, &paym)?;
+    conntx.commit()?;
+    Ok(())
+}
+
+/// The invoice of a payment attempt, if already obtained:
+/// in secon_id for LN Address and BOLT12 payments, in terti_id for Nostr and Cashu ones
+/// (secon_id is the LN Address, or the mint quote ID)
+pub(crate) fn payment_invoice(paym: &Payment) -> &str {
+    if paym.pay_method == PaymentMethod::PmLnAddress.to_string()
+        || paym.pay_method == PaymentMethod::PmBolt12.to_string()
+    {
+        &paym.secon_id
+    } else {
+        &paym.terti_id
+    }
+}
+
+// Whether an attempt is due for a pay request with this payment: not final, not in progress
+// (see reconcile), and past the next retry time after a failure. See also prepare_payment_attempt.
+pub(crate) fn attempt_due(paym: &Option<Payment>, now_utc: u32) -> bool {
+    match paym {
+        None => true,
+        Some(p) => match p.status {
+            STATUS_FINAL_FAILURE | STATUS_SUCCESS_FINAL | STATUS_IN_PROGRESS => false,
+            STATUS_NONFINAL_FAILURE => now_utc >= p.next_retry_time,
+            _ => true,
+        },
+    }
+}
+
+// Prepare a payment for an attempt: create it if needed, check its status and retry time,
+// and mark it as in progress, with the fee cap for the attempt.
+// Return None if no attempt should be made now.
+fn prepare_payment_attempt(
+    conn: &mut Connection,
+    pr: &PayRequest,
+    paym_orig: &Option<Payment>,
+    max_fee: u64,
+    now_utc: u32,
+) -> Result<Option<Payment>, Box<dyn Error>> {
+    let mut paym = match paym_orig {
+        Some(p) => p.clone(),
+        None => {
+            let mut paym = Payment::new(
+                -1,
+                pr.id,
+                now_utc,
+                STATUS_NOTTRIED,
+                now_utc,
+                0,
+                "".into(),
+                0,
+                0,
+                "".into(),
+                "".into(),
+                0,
+                0,
+                0,
+                "".into(),
+                0,
+                ZAP_RCPT_NA,
+                "".into(),
+                "".into(),
+                0,
+                pr.pay_method.clone(),
+                "".into(),
+                "".into(),
+            );
+            let _ = save_payment(conn, &mut paym)?;
+            paym
+        }
+    };
+
+    if paym.status == STATUS_FINAL_FAILURE || paym.status == STATUS_SUCCESS_FINAL {
+        println!(
+            "WARNING: Payment is already final, ignoring ({})",
+            paym.status
+        );
+        return Ok(None);
+    }
+
+    if paym.status == STATUS_NONFINAL_FAILURE {
+        if now_utc < paym.next_retry_time {
+            // print(f"Payment was failed, retry cnt {paym.retry_cnt}, retrying later, in {next_retry_time - now_utc} secs")
+            return Ok(None);
+        } else {
+            println!(
+                "Payment was failed, retry cnt {} {}, retrying now",
+                paym.retry_cnt, paym.fail_time
+            );
+        }
     }
 
     println!(
@@ -365,33 +1227,50 @@ async fn process_payment_start(
     );
 
     if paym.status == STATUS_IN_PROGRESS {
-        println!("WARNING: Payment marked as in progress, ignoring...");
+        // It may have been paid; it is resolved from the node, not retried, see reconcile
+        println!("WARNING: Payment marked as in progress, not retrying");
+        return Ok(None);
     }
 
     paym.status = STATUS_IN_PROGRESS;
     paym.status_time = now_utc;
+    paym.max_fee = max_fee;
 
     let _ = save_payment(conn, &mut paym)?;
+    Ok(Some(paym))
+}
 
-    let pay_res = process_payment_generic(&paym, pr, payer_params).await?;
-
-    // Process and store error
-    let now_utc = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs_f64()
-        .floor() as u32;
+// Update the payment from the result of an attempt: status, retry count and next retry time,
+// error, paid amounts
+fn update_payment_with_result(
+    paym: &mut Payment,
+    pay_res: PaymentResult,
+    retry_policy: &RetryPolicy,
+    now_utc: u32,
+) {
+    if paym.secon_id != pay_res.secon_id || paym.terti_id != pay_res.terti_id {
+        // The payment hash is of an invoice sent for payment, not of this one
+        paym.pay_hash = "".into();
+    }
+    if !pay_res.zap_pubkey.is_empty() {
+        paym.zap_pubkey = pay_res.zap_pubkey.clone();
+    }
     let status;
     if !pay_res.success {
         // Error
         paym.retry_cnt = paym.retry_cnt + 1;
         paym.fail_time = now_utc;
+        paym.next_retry_time = 0;
         if pay_res.err_nonfinal {
-            if paym.retry_cnt as u32 >= PAYMENT_RETRIES_MAX {
-                println!("WARNING: Failing after {} retries!", paym.retry_cnt);
-                status = STATUS_FINAL_FAILURE;
-            } else {
-                status = STATUS_NONFINAL_FAILURE;
+            match retry_policy.next_retry_time(paym, pay_res.err_code, now_utc) {
+                None => {
+                    println!("WARNING: Failing after {} retries!", paym.retry_cnt);
+                    status = STATUS_FINAL_FAILURE;
+                }
+                Some(t) => {
+                    paym.next_retry_time = t;
+                    status = STATUS_NONFINAL_FAILURE;
+                }
             }
         } else {
             status = STATUS_FINAL_FAILURE;
@@ -402,14 +1281,17 @@ async fn process_payment_start(
         paym.paid_fee = 0;
         paym.pay_time = 0;
         paym.pay_ref = "".into();
+        paym.success_action = "".into();
     } else {
         status = STATUS_SUCCESS_FINAL;
+        paym.next_retry_time = 0;
         paym.secon_id = pay_res.secon_id;
         paym.terti_id = pay_res.terti_id;
         paym.paid_amnt = pay_res.paid_amount;
         paym.paid_fee = pay_res.paid_fee;
         paym.pay_time = now_utc;
         paym.pay_ref = pay_res.reference;
+        paym.success_action = pay_res.success_action;
     }
     if paym.status != status {
         paym.status = status;
@@ -417,18 +1299,25 @@ async fn process_payment_start(
     }
     paym.error_code = pay_res.err_code;
     paym.error_str = pay_res.err_str;
+}
 
-    let _ = save_payment(conn, &mut paym)?;
+// Process and store the result of a payment attempt
+pub(crate) fn finish_payment_attempt(
+    conn: &mut Connection,
+    paym: &mut Payment,
+    pay_res: PaymentResult,
+    retry_policy: &RetryPolicy,
+    now_utc: u32,
+) -> Result<(), Box<dyn Error>> {
+    let err_nonfinal = pay_res.err_nonfinal;
+    update_payment_with_result(paym, pay_res, retry_policy, now_utc);
+
+    save_payment(conn, paym)?;
 
     if paym.status != STATUS_SUCCESS_FINAL {
         println!(
             "ERROR: There was an error in payment: {} {}  {} {} {} '{}'",
-            paym.id,
-            paym.req_id,
-            paym.status,
-            paym.error_code,
-            pay_res.err_nonfinal,
-            paym.error_str
+            paym.id, paym.req_id, paym.status, paym.error_code, err_nonfinal, paym.error_str
         );
     } else {
         println!(
@@ -436,66 +1325,1668 @@ async fn process_payment_start(
             paym.id, paym.req_id, paym.paid_amnt, paym.pay_time
         );
     }
-
     Ok(())
 }
 
-async fn iteration(
+// Final errors after which the next payment method of the pay request is tried:
+// the method is not usable for the miner (e.g. no zap support), and no invoice was paid
+fn is_fallback_error(err_code: u8) -> bool {
+    // The only fallback is ZAP -> NOLN (see paycalc fallback_methods): only errors of the zap
+    // itself, the Lightning Address of the profile is the same for NOLN
+    matches!(
+        err_code,
+        ERROR_NOSTR_ZAP_FINAL_FAILURE
+            | ERROR_NOSTR_ZAP_NOT_SUPPORTED
+            | ERROR_LN_BOLT11_INVOICE_MISMATCH
+    )
+}
+
+/// The payment method after the given one in the fallback chain of a pay request
+/// (its pay method, then its fallback methods), if any
+pub(crate) fn next_fallback_method(pr: &PayRequest, pay_method: &str) -> Option<PaymentMethod> {
+    let chain: Vec<&str> = std::iter::once(pr.pay_method.as_str())
+        .chain(
+            pr.fallback_methods
+                .split(',')
+                .map(|m| m.trim())
+                .filter(|m| !m.is_empty()),
+        )
+        .collect();
+    let pos = chain.iter().position(|m| *m == pay_method)?;
+    chain[(pos + 1)..]
+        .iter()
+        .find_map(|m| PaymentMethod::from_str(m).ok())
+}
+
+// After a final failure with a fallback error, create a new payment of the pay request,
+// with the next method of its fallback chain, to be attempted right away.
+// Return true if a fallback payment was created.
+fn start_fallback_attempt(
+    conn: &mut Connection,
+    pr: &PayRequest,
+    paym: &Payment,
+    now_utc: u32,
+) -> Result<bool, Box<dyn Error>> {
+    if paym.status != STATUS_FINAL_FAILURE || !is_fallback_error(paym.error_code) {
+        return Ok(false);
+    }
+    let next_method = match next_fallback_method(pr, &paym.pay_method) {
+        None => return Ok(false),
+        Some(m) => m,
+    };
+    println!(
+        "Payment method {} failed, falling back to {}, rid {}",
+        paym.pay_method,
+        next_method.to_string(),
+        pr.id
+    );
+    let mut fallback_paym = Payment::new(
+        -1,
+        pr.id,
+        now_utc,
+        STATUS_NOTTRIED,
+        now_utc,
+        0,
+        "".into(),
+        0,
+        0,
+        "".into(),
+        "".into(),
+        0,
+        0,
+        0,
+        "".into(),
+        0,
+        ZAP_RCPT_NA,
+        "".into(),
+        "".into(),
+        0,
+        next_method.to_string(),
+        "".into(),
+        "".into(),
+    );
+    save_payment(conn, &mut fallback_paym)?;
+    Ok(true)
+}
+
+// The current payment of an open pay request, from the DB
+fn reload_payment(conn: &Connection, pr: &PayRequest) -> Result<Option<Payment>, Box<dyn Error>> {
+    let open_requests = db::payreq_get_all_non_final(conn)?;
+    Ok(open_requests
+        .into_iter()
+        .find(|(p, _)| p.id == pr.id)
+        .and_then(|(_, paym)| paym))
+}
+
+// Record an attempt that did not finish within the timeout as a nonfinal failure,
+// if it is still in progress and had not started paying an invoice
+// (if it had, it is left in progress, to be resolved from the node, see reconcile)
+pub(crate) fn fail_timed_out_attempt(
+    conn: &mut Connection,
+    pr: &PayRequest,
+    timeout_secs: u32,
+    retry_policy: &RetryPolicy,
+    now_utc: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut paym = match reload_payment(conn, pr)? {
+        Some(p) if p.status == STATUS_IN_PROGRESS => p,
+        _ => return Ok(()),
+    };
+    if !payment_invoice(&paym).is_empty() {
+        println!(
+            "WARNING: Timed out payment has an invoice, to be resolved from the node, {}",
+            paym.id
+        );
+        return Ok(());
+    }
+    let pay_res = PaymentResult::new(
+        false,
+        true,
+        ERROR_PAYMENT_TIMEOUT,
+        &format!("Payment attempt timed out after {timeout_secs} secs"),
+        &paym.secon_id,
+        &paym.terti_id,
+        0,
+        0,
+        "",
+    );
+    finish_payment_attempt(conn, &mut paym, pay_res, retry_policy, now_utc)
+}
+
+pub(crate) fn now_utc_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        .floor() as u32
+}
+
+// Finish an attempt that ended with an error instead of a payment result:
+// - the pay request is paid already: final, not retried (and not notified, no fallback)
+// - a payment is pending on the node: left in progress, resolved later (see reconcile)
+// - an invoice was recorded for payment: resolved from the node now (left in progress if not possible)
+// - otherwise: nonfinal failure, retried
+async fn finish_attempt_with_error(
+    conn: &mut Connection,
+    payer_params: &PayerParameters,
+    pr: &PayRequest,
+    mut paym: Payment,
+    err: Box<dyn Error>,
+    now_utc: u32,
+) -> Result<(), Box<dyn Error>> {
+    println!(
+        "ERROR: Payment attempt {} ended with an error, {err}",
+        paym.id
+    );
+    // The invoice may have been recorded, with its payment hash, during the attempt
+    if let Some(p) = reload_payment(conn, pr)? {
+        paym.secon_id = p.secon_id;
+        paym.terti_id = p.terti_id;
+        paym.pay_hash = p.pay_hash;
+    }
+    let (err_nonfinal, err_code) = match err.downcast_ref::<AttemptError>() {
+        Some(AttemptError::AlreadyPaid(_)) => (false, ERROR_PAYREQ_ALREADY_PAID),
+        Some(AttemptError::PendingOnNode(_)) => {
+            println!("Payment {} left in progress, pending on the node", paym.id);
+            return Ok(());
+        }
+        None if !paym.pay_hash.is_empty() => {
+            // The invoice may have reached the node
+            match reconcile_payment(
+                conn,
+                payer_params.ln_backend.as_ref(),
+                &payer_params.retry_policy,
+                pr,
+                &mut paym,
+                now_utc,
+            )
+            .await
+            {
+                Err(e) => println!(
+                    "WARNING: Payment {} left in progress, could not resolve it from the node, {e}",
+                    paym.id
+                ),
+                Ok(outcome) => println!("Payment {} resolved from the node, {outcome:?}", paym.id),
+            }
+            return Ok(());
+        }
+        None => (true, ERROR_GENERIC_NONFINAL_FAILURE),
+    };
+    let pay_res = PaymentResult::new(
+        false,
+        err_nonfinal,
+        err_code,
+        &err.to_string(),
+        &paym.secon_id,
+        &paym.terti_id,
+        0,
+        0,
+        "",
+    );
+    finish_payment_attempt(
+        conn,
+        &mut paym,
+        pay_res,
+        &payer_params.retry_policy,
+        now_utc,
+    )
+}
+
+pub(crate) async fn process_payment_start(
     payer_params: &PayerParameters,
     conn: &mut Connection,
+    pr: &PayRequest,
+    paym_orig: &Option<Payment>,
 ) -> Result<(), Box<dyn Error>> {
-    let open_requests = db::payreq_get_all_non_final(conn)?;
-    if !open_requests.is_empty() {
-        println!("Open pay requests: {}", open_requests.len());
-        for (pr, paym) in &open_requests {
-            let _ = process_payment_start(payer_params, conn, pr, paym).await?;
+    let max_fee = payer_params.fee_limits.max_fee_for_amount(pr.req_amnt);
+    let mut paym = match prepare_payment_attempt(conn, pr, paym_orig, max_fee, now_utc_secs())? {
+        None => return Ok(()),
+        Some(p) => p,
+    };
+
+    let pay_res = match process_payment_generic(conn, &paym, pr, payer_params).await {
+        Err(e) => {
+            return finish_attempt_with_error(conn, payer_params, pr, paym, e, now_utc_secs())
+                .await;
+        }
+        Ok(r) => r,
+    };
+    // The invoice may have been recorded, with its payment hash, during the attempt
+    if let Some(p) = reload_payment(conn, pr)? {
+        paym.secon_id = p.secon_id;
+        paym.terti_id = p.terti_id;
+        paym.pay_hash = p.pay_hash;
+    }
+    if pay_res.success && paym.pay_method == PaymentMethod::PmNostrZap.to_string() {
+        // Receipt is checked later, see check_zap_receipts
+        paym.zap_rcpt_status = ZAP_RCPT_PENDING;
+    }
+
+    // Process and store error
+    finish_payment_attempt(
+        conn,
+        &mut paym,
+        pay_res,
+        &payer_params.retry_policy,
+        now_utc_secs(),
+    )?;
+
+    let fallback = start_fallback_attempt(conn, pr, &paym, now_utc_secs())?;
+
+    if paym.status == STATUS_FINAL_FAILURE && !fallback && payer_params.dm_config.enabled {
+        // Notification is best effort, the payment is final anyway
+        if let Err(e) = notify_payment_failure(conn, payer_params, pr, &paym).await {
+            println!(
+                "WARNING: Could not notify miner of failed payment, {} {e}",
+                paym.id
+            );
         }
     }
     Ok(())
 }
 
-pub async fn loop_iterations() -> Result<(), Box<dyn Error>> {
+// Start a periodic background task (local, on its own DB connection), off the dispatch path of
+// the payer loop; not started again while the previous one is still running.
+fn spawn_background_task<F, Fut>(
+    task: &mut Option<JoinHandle<()>>,
+    name: &'static str,
+    dbfile: &str,
+    f: F,
+) where
+    F: FnOnce(Connection) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error>>> + 'static,
+{
+    if task.as_ref().is_some_and(|t| !t.is_finished()) {
+        println!("WARNING: Previous {name} still running, skipped");
+        return;
+    }
+    let conn = match open_db_conn(dbfile) {
+        Err(e) => {
+            println!("ERROR in {name}, {e}");
+            return;
+        }
+        Ok(c) => c,
+    };
+    let fut = f(conn);
+    *task = Some(spawn_local(async move {
+        if let Err(e) = fut.await {
+            println!("ERROR in {name}, {:?}", e);
+        }
+    }));
+}
+
+/// Payer loop: payment attempts run concurrently from a work queue, started when the DB has due
+/// pay requests; checked on wakeup (e.g. new pay requests), when an attempt finishes, and periodically.
+pub async fn loop_iterations(wakeup: PayerWakeup) -> Result<(), Box<dyn Error>> {
     println!("Payer: initializing ...");
 
     let nostr_secret_key = get_nostr_secret_from_config()?;
     let nostr_pub = npub_from_secret_vec(&nostr_secret_key)?;
     println!("Nostr secret key read from config, npub: {nostr_pub}");
 
-    let payer_params = PayerParameters { nostr_secret_key };
+    let ln_backend = get_ln_backend_from_config()?;
+
+    let nostr_relays = get_nostr_relays_from_config()?;
+    println!("Nostr relays: {:?}", nostr_relays);
+
+    let fee_limits = FeeLimits::new_from_config()?;
+    println!("Fee limits: {:?}", fee_limits);
+
+    let zap_message = ZapMessageConfig::new_from_config()?;
+    println!("Zap message: {:?}", zap_message);
+
+    let dm_config = DmConfig::new_from_config()?;
+    println!("DM notifications: {:?}", dm_config);
+
+    let retry_policy = RetryPolicy::new_from_config()?;
+    println!("Retry policy: {:?}", retry_policy);
+
+    let cashu_config = CashuConfig::new_from_config()?;
+    println!("Cashu: {:?}", cashu_config);
+
+    let queue_config = WorkQueueConfig::new_from_config()?;
+    println!("Work queue: {:?}", queue_config);
+
+    let payer_params = Rc::new(PayerParameters {
+        nostr_secret_key,
+        ln_backend,
+        nostr_relays,
+        fee_limits,
+        zap_message,
+        dm_config,
+        retry_policy,
+        cashu_config,
+    });
 
     // Load environment variables from .env file
     dotenv::dotenv().ok();
 
     let dbfile = get_db_file("paycalc.db", false);
-    let mut conn = Connection::open(&dbfile)?;
+    let mut conn = open_db_conn(&dbfile)?;
 
     println!("Payer: loop starting ...");
 
-    let sleep_secs = 5;
-    let mut next_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs_f64();
-
-    loop {
-        match iteration(&payer_params, &mut conn).await {
-            Ok(_) => {}
-            Err(e) => {
-                println!("ERROR in iteration, {:?}", e);
-                continue;
+    // Payment attempts are local tasks, as the LN backend is not Send
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let poll_period = queue_config.poll_period;
+            let mut queue = WorkQueue::new(payer_params.clone(), &dbfile, queue_config);
+            let mut next_zap_receipt_check = 0;
+            let mut next_dm_summary_check = 0;
+            let mut zap_receipt_task = None;
+            let mut dm_summary_task = None;
+
+            // Payments left in progress by a previous run are resolved before any retry
+            let cnt = reconcile_in_progress_payments(
+                &mut conn,
+                payer_params.ln_backend.as_ref(),
+                &payer_params.retry_policy,
+                &[],
+                now_utc_secs(),
+            )
+            .await?;
+            println!("Payer: in-progress payments resolved: {cnt}");
+            let mut next_reconcile = now_utc_secs() + RECONCILE_PERIOD;
+
+            loop {
+                if let Err(e) = queue.dispatch(&conn, now_utc_secs()) {
+                    println!("ERROR in iteration, {:?}", e);
+                }
+
+                // Not concurrently with the attempts started, but bounded
+                if now_utc_secs() >= next_reconcile {
+                    match timeout(
+                        RECONCILE_TIMEOUT,
+                        reconcile_in_progress_payments(
+                            &mut conn,
+                            payer_params.ln_backend.as_ref(),
+                            &payer_params.retry_policy,
+                            &queue.running_req_ids(),
+                            now_utc_secs(),
+                        ),
+                    )
+                    .await
+                    {
+                        Err(_) => println!("WARNING: Reconciliation timed out"),
+                        Ok(Err(e)) => println!("ERROR in reconciliation, {:?}", e),
+                        Ok(Ok(_)) => {}
+                    }
+                    next_reconcile = now_utc_secs() + RECONCILE_PERIOD;
+                }
+
+                if now_utc_secs() >= next_zap_receipt_check {
+                    let relays = payer_params.nostr_relays.clone();
+                    spawn_background_task(
+                        &mut zap_receipt_task,
+                        "zap receipt check",
+                        &dbfile,
+                        |mut conn| async move {
+                            check_zap_receipts(&mut conn, &relays, now_utc_secs()).await
+                        },
+                    );
+                    next_zap_receipt_check = now_utc_secs() + ZAP_RECEIPT_CHECK_PERIOD;
+                }
+
+                if payer_params.dm_config.enabled
+                    && payer_params.dm_config.daily_summary
+                    && now_utc_secs() >= next_dm_summary_check
+                {
+                    let payer_params = payer_params.clone();
+                    spawn_background_task(
+                        &mut dm_summary_task,
+                        "DM summaries",
+                        &dbfile,
+                        |conn| async move {
+                            send_daily_summaries(&conn, &payer_params, now_utc_secs()).await
+                        },
+                    );
+                    next_dm_summary_check = now_utc_secs() + DM_SUMMARY_CHECK_PERIOD;
+                }
+
+                queue.wait(&wakeup, TokioInstant::now() + poll_period).await;
             }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cashu::{decode_token_v3, hash_to_curve};
+    use crate::cashu_test_mint::{CashuTestMint, MintServerConfig, mint_secret_key};
+    use crate::common::test_payment;
+    use crate::ln_address::test::set_test_url_template;
+    use crate::lnurl_test_server::{CallbackMode, LnurlServerConfig, LnurlTestServer};
+    use crate::mock_backend::{MockBackend, MockPayOutcome};
+    use crate::nostr_dm::DmProtocol;
+    use crate::nostr_test_relay::{NostrRelayConfig, NostrTestRelay};
+    use crate::retry_policy::RetryClass;
+    use common_rs::dto_pc::NostrProfileCache;
+    use nostr::nips::nip04;
+    use nostr::util::JsonUtil;
+    use nostr::{Event, Keys, SecretKey};
+
+    const NOW: u32 = 1_760_000_000;
+
+    // Default policy without jitter, for predictable retry times
+    fn test_retry_policy() -> RetryPolicy {
+        let mut retry_policy = RetryPolicy::default();
+        retry_policy.jitter_percent = 0;
+        retry_policy
+    }
+
+    fn create_test_db_with_payreq() -> (Connection, PayRequest) {
+        create_test_db_with_ln_address_payreq("miner@example.com")
+    }
+
+    fn create_test_db_with_ln_address_payreq(ln_address: &str) -> (Connection, PayRequest) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmLnAddress.to_string(),
+            ln_address.into(),
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        (conn, pr)
+    }
+
+    // One attempt: prepare, pay an invoice through the mock, store result
+    async fn do_attempt(
+        conn: &mut Connection,
+        mock: &MockBackend,
+        pr: &PayRequest,
+        now_utc: u32,
+    ) -> Option<Payment> {
+        let open = db::payreq_get_all_non_final(conn).unwrap();
+        // Not open any more
+        let (_, paym_orig) = open.iter().find(|(opr, _)| opr.id == pr.id)?;
+        let mut paym = prepare_payment_attempt(conn, pr, paym_orig, 1000, now_utc).unwrap()?;
+        assert_eq!(paym.status, STATUS_IN_PROGRESS);
+
+        let invoice = mock.create_invoice(pr.req_amnt, None, now_utc as u64, 600);
+        let mut pay_res = pay_lightning_invoice(
+            mock,
+            &invoice,
+            pr.req_amnt,
+            &pr.pri_id,
+            paym.max_fee,
+            &no_before_pay,
+        )
+        .await
+        .unwrap();
+        pay_res.secon_id = invoice;
+        pay_res.err_code = invoice_pay_error_code(&pay_res);
+        finish_payment_attempt(conn, &mut paym, pay_res, &test_retry_policy(), now_utc).unwrap();
+        Some(paym)
+    }
+
+    #[tokio::test]
+    async fn test_payment_success_with_fee() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        mock.set_fee_msat(3);
+
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW).await.unwrap();
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.error_code, ERROR_OK);
+        assert_eq!(paym.paid_amnt, 5003);
+        assert_eq!(paym.paid_fee, 3);
+        assert_eq!(paym.pay_time, NOW);
+        assert_eq!(mock.paid_invoices().len(), 1);
+        assert!(db::payreq_get_all_non_final(&conn).unwrap().is_empty());
+
+        // Already final, not attempted again
+        assert!(
+            prepare_payment_attempt(&mut conn, &pr, &Some(paym), 1000, NOW + 1000)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_nonfinal_then_success() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        mock.script_outcomes(&[MockPayOutcome::NonFinalFailure, MockPayOutcome::Partial]);
+
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW).await.unwrap();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE);
+        assert_eq!(paym.retry_cnt, 1);
+        assert_eq!(paym.fail_time, NOW);
+        assert_eq!(paym.next_retry_time, NOW + 600);
+
+        // Too early for a retry
+        assert!(do_attempt(&mut conn, &mock, &pr, NOW + 10).await.is_none());
+
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW + 600).await.unwrap();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.retry_cnt, 2);
+        // Backoff
+        assert_eq!(paym.next_retry_time, NOW + 600 + 1200);
+
+        assert!(
+            do_attempt(&mut conn, &mock, &pr, NOW + 1200)
+                .await
+                .is_none()
+        );
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW + 1800).await.unwrap();
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.retry_cnt, 2);
+        assert_eq!(mock.attempt_count(), 3);
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_payment_retries_exhausted() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        mock.set_default_outcome(MockPayOutcome::NonFinalFailure);
+
+        let mut now = NOW;
+        let mut last = None;
+        while let Some(paym) = do_attempt(&mut conn, &mock, &pr, now).await {
+            now = paym.next_retry_time;
+            last = Some(paym);
+        }
+        let paym = last.unwrap();
+        let retries_max = test_retry_policy().rule(RetryClass::Route).max_retries;
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.retry_cnt as u32, retries_max);
+        assert_eq!(mock.attempt_count(), retries_max);
+        assert!(mock.paid_invoices().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_payment_final_failure() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        mock.script_outcomes(&[MockPayOutcome::FinalFailure]);
+
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW).await.unwrap();
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE);
+        assert_eq!(paym.paid_amnt, 0);
+        assert!(db::payreq_get_all_non_final(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_in_progress_not_retried() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let paym = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        record_invoice_before_pay(&conn, &paym, "lnmock5000n1", "", "00").unwrap();
+
+        // E.g. after a crash: left to reconciliation, not paid again
+        let open = db::payreq_get_all_non_final(&conn).unwrap();
+        let paym_orig = open[0].1.clone();
+        assert_eq!(paym_orig.as_ref().unwrap().status, STATUS_IN_PROGRESS);
+        assert_eq!(paym_orig.as_ref().unwrap().secon_id, "lnmock5000n1");
+        assert!(!attempt_due(&paym_orig, NOW + 100_000));
+        assert!(
+            prepare_payment_attempt(&mut conn, &pr, &paym_orig, 1000, NOW + 100_000)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_not_enough_funds() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        mock.set_balance_msat(1000);
+
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW).await.unwrap();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS);
+        // Retried later than a routing failure
+        assert_eq!(paym.next_retry_time, NOW + 1800);
+
+        mock.set_balance_msat(1_000_000);
+        let paym = do_attempt(&mut conn, &mock, &pr, NOW + 1800).await.unwrap();
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+    }
+
+    // Full LN Address payment attempt, through a local LNURL server and the mock
+    async fn do_ln_address_attempt(config: LnurlServerConfig) -> (Payment, MockBackend) {
+        do_ln_address_attempt_with_fee(config, 0, FeeLimits::default()).await
+    }
+
+    async fn do_ln_address_attempt_with_fee(
+        config: LnurlServerConfig,
+        fee_msat: u32,
+        fee_limits: FeeLimits,
+    ) -> (Payment, MockBackend) {
+        let (paym, mock, _server) =
+            do_ln_address_attempt_with_server(config, fee_msat, fee_limits).await;
+        (paym, mock)
+    }
+
+    async fn do_ln_address_attempt_with_server(
+        config: LnurlServerConfig,
+        fee_msat: u32,
+        fee_limits: FeeLimits,
+    ) -> (Payment, MockBackend, LnurlTestServer) {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        mock.set_fee_msat(fee_msat);
+        let server = LnurlTestServer::start(config, mock.clone()).await;
+        let (mut conn, pr) = create_test_db_with_ln_address_payreq(&server.ln_address("miner"));
+        let payer_params = PayerParameters {
+            fee_limits,
+            ..PayerParameters::for_test(mock.clone())
+        };
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let payms = db::payment_get_all_after_time(&conn, 0).unwrap();
+        assert_eq!(payms.len(), 1);
+        (payms[0].1.clone(), mock, server)
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_success() {
+        let (paym, mock) = do_ln_address_attempt(LnurlServerConfig::default()).await;
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.paid_amnt, 5000);
+        assert_eq!(mock.paid_invoices().len(), 1);
+        assert_eq!(paym.secon_id, mock.paid_invoices()[0].invoice);
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_comment_and_success_action() {
+        // Comment allowed, cut to the max. length
+        let config = LnurlServerConfig {
+            comment_allowed: 10,
+            success_action: Some(serde_json::json!({"tag": "message", "message": "Thanks!"})),
+            ..Default::default()
+        };
+        let (paym, _mock, server) =
+            do_ln_address_attempt_with_server(config, 0, FeeLimits::default()).await;
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.success_action, "message: Thanks!");
+        assert_eq!(
+            server.last_callback_request().unwrap()["comment"],
+            "ZapPool pa"
+        );
+
+        // No comment allowed; AES success action, decrypted
+        let config = LnurlServerConfig {
+            aes_success_text: Some("1234-5678".into()),
+            ..Default::default()
+        };
+        let (paym, _mock, server) =
+            do_ln_address_attempt_with_server(config, 0, FeeLimits::default()).await;
+        assert_eq!(paym.success_action, "aes: Your code 1234-5678");
+        let callback_req = server.last_callback_request().unwrap();
+        assert!(!callback_req.contains_key("comment"));
+    }
+
+    #[test]
+    fn test_payout_comment() {
+        let (_conn, pr) = create_test_db_with_payreq();
+        assert_eq!(payout_comment(&pr), format!("ZapPool payout #{}", pr.id));
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_fee_cap() {
+        // Cap is 1% of 5000 msat
+        let fee_limits = FeeLimits::new(100_000, 1.0, 20);
+        let (paym, mock) =
+            do_ln_address_attempt_with_fee(LnurlServerConfig::default(), 51, fee_limits.clone())
+                .await;
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_FEE_CAP_EXCEEDED);
+        assert_eq!(paym.max_fee, 50);
+        assert_eq!(mock.attempt_count(), 1);
+        assert!(mock.paid_invoices().is_empty());
+
+        let (paym, _mock) =
+            do_ln_address_attempt_with_fee(LnurlServerConfig::default(), 50, fee_limits).await;
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.paid_fee, 50);
+        assert_eq!(paym.max_fee, 50);
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_server_error() {
+        let config = LnurlServerConfig {
+            callback_mode: CallbackMode::ServerError,
+            ..Default::default()
+        };
+        let (paym, mock) = do_ln_address_attempt(config).await;
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_ADDRESS_NONFINAL_FAILURE);
+        assert_eq!(mock.attempt_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_below_min_sendable() {
+        let config = LnurlServerConfig {
+            min_sendable: 10_000,
+            ..Default::default()
+        };
+        let (paym, mock) = do_ln_address_attempt(config).await;
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_ADDRESS_FINAL_FAILURE);
+        assert_eq!(mock.attempt_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_invoice_before_pay() {
+        let mock = MockBackend::new();
+        let now = NOW as u64;
+        let invoice = mock.create_invoice(5000, Some("ab".repeat(32)), now, 600);
+        assert!(
+            check_invoice_before_pay(&mock, &invoice, 5000, None, NOW)
+                .await
+                .is_none()
+        );
+        assert!(
+            check_invoice_before_pay(&mock, &invoice, 5000, Some(&"ab".repeat(32)), NOW)
+                .await
+                .is_none()
+        );
+
+        // Amount differs
+        let res = check_invoice_before_pay(&mock, &invoice, 4000, None, NOW)
+            .await
+            .unwrap();
+        assert!(!res.success && !res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        // Description hash differs
+        let res = check_invoice_before_pay(&mock, &invoice, 5000, Some(&"cd".repeat(32)), NOW)
+            .await
+            .unwrap();
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        // Expired, or about to
+        let res = check_invoice_before_pay(&mock, &invoice, 5000, None, NOW + 600)
+            .await
+            .unwrap();
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        let res = check_invoice_before_pay(&mock, &invoice, 5000, None, NOW + 590)
+            .await
+            .unwrap();
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        // Cannot decode
+        let res = check_invoice_before_pay(&mock, "lnbc1garbage", 5000, None, NOW)
+            .await
+            .unwrap();
+        assert!(!res.success && res.err_nonfinal);
+        assert_eq!(res.err_code, ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE);
+        assert_eq!(mock.attempt_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ln_address_payment_invoice_mismatch() {
+        for mode in [
+            CallbackMode::WrongAmountInvoice,
+            CallbackMode::ExpiredInvoice,
+            CallbackMode::WrongDescriptionHashInvoice,
+        ] {
+            let config = LnurlServerConfig {
+                callback_mode: mode,
+                ..Default::default()
+            };
+            let (paym, mock) = do_ln_address_attempt(config).await;
+            assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+            assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+            assert!(paym.secon_id.starts_with("lnmock"));
+            assert_eq!(mock.attempt_count(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nostr_lightning_payment_description_hash_mismatch() {
+        // Invoice not committing to the LNURL metadata (LUD-06)
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let config = LnurlServerConfig {
+            callback_mode: CallbackMode::WrongDescriptionHashInvoice,
+            ..Default::default()
         };
+        let server = LnurlTestServer::start(config, mock.clone()).await;
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        db::nostr_profile_cache_upsert(
+            &conn,
+            &NostrProfileCache::new(
+                rec_npub.clone(),
+                server.ln_address("miner"),
+                "".into(),
+                1000,
+                now_utc_secs(),
+                "".into(),
+                "".into(),
+            ),
+        )
+        .unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmNostrLightning.to_string(),
+            rec_npub,
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let payer_params = PayerParameters::for_test(mock.clone());
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_MISMATCH);
+        assert!(paym.error_str.contains("description hash"));
+        assert_eq!(paym.secon_id, server.ln_address("miner"));
+        assert_eq!(mock.attempt_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_reuses_invoice() {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        mock.script_outcomes(&[MockPayOutcome::NonFinalFailure]);
+        let server = LnurlTestServer::start(LnurlServerConfig::default(), mock.clone()).await;
+        let (mut conn, pr) = create_test_db_with_ln_address_payreq(&server.ln_address("miner"));
+        let payer_params = PayerParameters::for_test(mock.clone());
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let mut paym = db::payment_get_all_after_time(&conn, 0).unwrap()[0]
+            .1
+            .clone();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        let invoice = paym.secon_id.clone();
+        let decoded = mock.decode_invoice(&invoice).await.unwrap();
+        assert_eq!(paym.pay_hash, decoded.payment_hash);
+
+        // Retry: the same invoice is paid, no new one is obtained
+        paym.next_retry_time = 0;
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym))
+            .await
+            .unwrap();
+        let paym = db::payment_get_all_after_time(&conn, 0).unwrap()[0]
+            .1
+            .clone();
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.secon_id, invoice);
+        assert_eq!(server.callback_request_count(), 1);
+        assert_eq!(mock.attempt_count(), 2);
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[test]
+    fn test_not_paid_twice() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mut paym = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        paym.status = STATUS_SUCCESS_FINAL;
+        save_payment(&mut conn, &mut paym).unwrap();
+
+        // Already paid, the invoice is not recorded (and not paid)
+        assert!(record_invoice_before_pay(&conn, &paym, "lnmock5000n1", "", "00").is_err());
+    }
+
+    fn test_payer_params(mock: &MockBackend) -> PayerParameters {
+        PayerParameters {
+            retry_policy: test_retry_policy(),
+            ..PayerParameters::for_test(mock.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attempt_error_already_paid() {
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let mock = MockBackend::new();
+        let mut paid = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        paid.status = STATUS_SUCCESS_FINAL;
+        save_payment(&mut conn, &mut paid).unwrap();
+        // Another attempt of the same pay request
+        let mut paym = paid.clone();
+        paym.id = 0;
+        paym.status = STATUS_IN_PROGRESS;
+        save_payment(&mut conn, &mut paym).unwrap();
+
+        let err = record_invoice_before_pay(&conn, &paym, "lnmock5000n1", "", "00").unwrap_err();
+        finish_attempt_with_error(
+            &mut conn,
+            &test_payer_params(&mock),
+            &pr,
+            paym.clone(),
+            err,
+            NOW,
+        )
+        .await
+        .unwrap();
+        let (_, paym) = db::payment_get_all_after_time(&conn, 0)
+            .unwrap()
+            .into_iter()
+            .find(|(_, p)| p.id == paym.id)
+            .unwrap();
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_PAYREQ_ALREADY_PAID);
+        assert!(!attempt_due(&Some(paym), NOW + 100_000));
+        assert!(db::payreq_get_all_non_final(&conn).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_attempt_error_results() {
+        let mock = MockBackend::new();
+        let payer_params = test_payer_params(&mock);
+
+        // Error before an invoice was recorded: retried
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let paym = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        finish_attempt_with_error(&mut conn, &payer_params, &pr, paym, "RPC error".into(), NOW)
+            .await
+            .unwrap();
+        let paym = reload_payment(&conn, &pr).unwrap().unwrap();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_GENERIC_NONFINAL_FAILURE);
+        assert!(paym.next_retry_time > 0);
+
+        // Pending on the node: left in progress
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let paym = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        record_invoice_before_pay(&conn, &paym, "lnmock5000n1", "", "00").unwrap();
+        let err = AttemptError::PendingOnNode("00".into()).into();
+        finish_attempt_with_error(&mut conn, &payer_params, &pr, paym, err, NOW)
+            .await
+            .unwrap();
+        let paym = reload_payment(&conn, &pr).unwrap().unwrap();
+        assert_eq!(paym.status, STATUS_IN_PROGRESS);
+
+        // Error after an invoice was recorded: resolved from the node, not paid there
+        let (mut conn, pr) = create_test_db_with_payreq();
+        let paym = prepare_payment_attempt(&mut conn, &pr, &None, 1000, NOW)
+            .unwrap()
+            .unwrap();
+        record_invoice_before_pay(&conn, &paym, "lnmock5000n1", "", "00").unwrap();
+        finish_attempt_with_error(&mut conn, &payer_params, &pr, paym, "RPC error".into(), NOW)
+            .await
+            .unwrap();
+        let paym = reload_payment(&conn, &pr).unwrap().unwrap();
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE);
+    }
+
+    #[test]
+    fn test_next_fallback_method() {
+        let pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            "ZAP".into(),
+            "npub1a".into(),
+            NOW,
+            "NOLN,NO_SUCH_PM,LNAD".into(),
+        );
+        assert_eq!(
+            next_fallback_method(&pr, "ZAP"),
+            Some(PaymentMethod::PmNostrLightning)
+        );
+        assert_eq!(
+            next_fallback_method(&pr, "NOLN"),
+            Some(PaymentMethod::PmLnAddress)
+        );
+        assert_eq!(next_fallback_method(&pr, "LNAD"), None);
+        // No fallback
+        let pr = PayRequest::new(0, 7, 5000, "ZAP".into(), "npub1a".into(), NOW, "".into());
+        assert_eq!(next_fallback_method(&pr, "ZAP"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_zap_to_noln() {
+        // Zap invoice is not for the zap request, a plain payment is fine
+        let wrong_hash = LnurlServerConfig {
+            callback_mode: CallbackMode::ZapRequestIgnoredInvoice,
+            ..Default::default()
+        };
+        fallback_zap_to_noln(wrong_hash, ERROR_LN_BOLT11_INVOICE_MISMATCH).await;
+        // No zap support by the LNURL server
+        let no_zaps = LnurlServerConfig {
+            allows_nostr: false,
+            ..Default::default()
+        };
+        fallback_zap_to_noln(no_zaps, ERROR_NOSTR_ZAP_NOT_SUPPORTED).await;
+    }
+
+    async fn fallback_zap_to_noln(config: LnurlServerConfig, zap_error_code: u8) {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(config, mock.clone()).await;
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        db::nostr_profile_cache_upsert(
+            &conn,
+            &NostrProfileCache::new(
+                rec_npub.clone(),
+                server.ln_address("miner"),
+                "".into(),
+                1000,
+                now_utc_secs(),
+                "".into(),
+                "".into(),
+            ),
+        )
+        .unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmNostrZap.to_string(),
+            rec_npub,
+            NOW,
+            PaymentMethod::PmNostrLightning.to_string(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let payer_params = PayerParameters::for_test(mock.clone());
+
+        // Zap fails, a fallback attempt is created
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let open = db::payreq_get_all_non_final(&conn).unwrap();
+        assert_eq!(open.len(), 1);
+        let paym = open[0].1.clone().unwrap();
+        assert_eq!(paym.pay_method, "NOLN");
+        assert_eq!(paym.status, STATUS_NOTTRIED);
+        assert!(attempt_due(&Some(paym.clone()), now_utc_secs()));
+
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym))
+            .await
+            .unwrap();
+        assert!(db::payreq_get_all_non_final(&conn).unwrap().is_empty());
+        let payms = db::payment_get_all_after_time(&conn, 0).unwrap();
+        assert_eq!(payms.len(), 2);
+        let zap = payms.iter().find(|(_, p)| p.pay_method == "ZAP").unwrap();
+        assert_eq!(zap.1.status, STATUS_FINAL_FAILURE);
+        assert_eq!(zap.1.error_code, zap_error_code);
+        let noln = payms.iter().find(|(_, p)| p.pay_method == "NOLN").unwrap();
+        assert_eq!(noln.1.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(noln.1.req_id, pr.id);
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[test]
+    fn test_start_fallback_attempt() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmNostrZap.to_string(),
+            "npub1a".into(),
+            NOW,
+            PaymentMethod::PmNostrLightning.to_string(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let failed_zap = |error_code| Payment {
+            status: STATUS_FINAL_FAILURE,
+            error_code,
+            ..test_payment(pr.id, "ZAP", NOW)
+        };
+
+        // ZAP -> NOLN, for errors of the zap itself
+        for error_code in [
+            ERROR_NOSTR_ZAP_FINAL_FAILURE,
+            ERROR_NOSTR_ZAP_NOT_SUPPORTED,
+            ERROR_LN_BOLT11_INVOICE_MISMATCH,
+        ] {
+            assert!(start_fallback_attempt(&mut conn, &pr, &failed_zap(error_code), NOW).unwrap());
+        }
+        // The Lightning Address would fail for NOLN too
+        for error_code in [
+            ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE,
+            ERROR_LN_ADDRESS_FINAL_FAILURE,
+            ERROR_LN_BOLT11_INVOICE_FINAL_FAILURE,
+        ] {
+            assert!(!start_fallback_attempt(&mut conn, &pr, &failed_zap(error_code), NOW).unwrap());
+        }
+        // Nonfinal: retried, no fallback
+        let paym = Payment {
+            status: STATUS_NONFINAL_FAILURE,
+            ..failed_zap(ERROR_NOSTR_ZAP_FINAL_FAILURE)
+        };
+        assert!(!start_fallback_attempt(&mut conn, &pr, &paym, NOW).unwrap());
+        // NOLN is the last method
+        let paym = Payment {
+            pay_method: "NOLN".into(),
+            ..failed_zap(ERROR_LN_BOLT11_INVOICE_MISMATCH)
+        };
+        assert!(!start_fallback_attempt(&mut conn, &pr, &paym, NOW).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_no_fallback_zap_without_ln_address() {
+        // Profile without Lightning Address, NOLN would fail the same way
+        let mock = MockBackend::new();
+        let keys = Keys::new(SecretKey::from_slice(&[8u8; 32]).unwrap());
+        let content = serde_json::json!({"name": "miner"}).to_string();
+        let event = nostr::EventBuilder::new(nostr::Kind::Metadata, content)
+            .sign_with_keys(&keys)
+            .unwrap();
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![serde_json::from_str(&event.as_json()).unwrap()],
+        )
+        .await;
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmNostrZap.to_string(),
+            rec_npub,
+            NOW,
+            PaymentMethod::PmNostrLightning.to_string(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let payer_params = PayerParameters {
+            nostr_relays: vec![relay.url()],
+            ..PayerParameters::for_test(mock.clone())
+        };
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        assert!(db::payreq_get_all_non_final(&conn).unwrap().is_empty());
+        let payms = db::payment_get_all_after_time(&conn, 0).unwrap();
+        assert_eq!(payms.len(), 1);
+        assert_eq!(payms[0].1.pay_method, "ZAP");
+        assert_eq!(payms[0].1.status, STATUS_FINAL_FAILURE);
+        assert_eq!(payms[0].1.error_code, ERROR_NOSTR_LN_ADDRESS_FINAL_FAILURE);
+        assert!(mock.paid_invoices().is_empty());
+    }
+
+    // DB with a Cashu pay request, payer parameters with the mint and the relays
+    fn create_test_cashu_payreq(
+        mock: &MockBackend,
+        mint: &CashuTestMint,
+        relays: Vec<String>,
+    ) -> (Connection, PayRequest, PayerParameters) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            21_000,
+            PaymentMethod::PmCashu.to_string(),
+            npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap(),
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let payer_params = PayerParameters {
+            nostr_relays: relays,
+            dm_config: DmConfig {
+                enabled: false,
+                protocol: DmProtocol::Nip04,
+                daily_summary: false,
+            },
+            cashu_config: CashuConfig {
+                mint_url: mint.url(),
+            },
+            ..PayerParameters::for_test(mock.clone())
+        };
+        (conn, pr, payer_params)
+    }
 
-        next_time = next_time + sleep_secs as f64;
-        let now_utc = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs_f64();
-        let to_sleep = f64::max(next_time - now_utc, 0.1);
-        if to_sleep > 0.0 {
-            // println!("Sleeping for {:.2} secs... (next_time {:.0})", to_sleep, next_time);
-            thread::sleep(Duration::from_secs_f64(to_sleep));
+    fn last_payment(conn: &Connection) -> Payment {
+        db::payment_get_all_after_time(conn, 0).unwrap()[0]
+            .1
+            .clone()
+    }
+
+    // The token is valid: of the amount, signed by the mint keys
+    fn assert_valid_token(token: &str, mint: &CashuTestMint, amount_sat: u64) {
+        let secp = secp256k1::Secp256k1::new();
+        let (mint_url, proofs) = decode_token_v3(token).unwrap();
+        assert_eq!(mint_url, mint.url());
+        assert_eq!(proofs.iter().map(|p| p.amount).sum::<u64>(), amount_sat);
+        for proof in proofs {
+            let y = hash_to_curve(proof.secret.as_bytes()).unwrap();
+            let k = secp256k1::Scalar::from(mint_secret_key(proof.amount));
+            assert_eq!(proof.c, y.mul_tweak(&secp, &k).unwrap().to_string());
         }
     }
-    // Ok(())
+
+    // The token in the (single) payout DM on the relay
+    fn dm_token(relay: &NostrTestRelay) -> String {
+        assert_eq!(relay.events().len(), 1);
+        let dm = Event::from_json(relay.events()[0].to_string()).unwrap();
+        let rec_keys = Keys::new(SecretKey::from_slice(&[8u8; 32]).unwrap());
+        let message = nip04::decrypt(rec_keys.secret_key(), &dm.pubkey, &dm.content).unwrap();
+        message.rsplit("\n").next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_success() {
+        let mock = MockBackend::new();
+        mock.set_fee_msat(2);
+        let mint = CashuTestMint::start(MintServerConfig::default(), mock.clone()).await;
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let (mut conn, pr, payer_params) =
+            create_test_cashu_payreq(&mock, &mint, vec![relay.url()]);
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.paid_fee, 2);
+        assert_eq!(paym.secon_id, "quote1");
+        assert_eq!(paym.terti_id, mock.paid_invoices()[0].invoice);
+        assert_eq!(mock.paid_invoices()[0].amount_msat, 21_000);
+
+        // Delivered by DM, only the audit data is kept
+        let sent = dm_token(&relay);
+        assert_valid_token(&sent, &mint, 21);
+        let token = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert_eq!(token.quote_id, "quote1");
+        assert_eq!(token.amount_sat, 21);
+        assert!(token.delivered_at > 0);
+        assert_eq!(token.token, "");
+        assert_eq!(token.premint, "");
+        let (_, proofs) = decode_token_v3(&sent).unwrap();
+        assert_eq!(token.proof_ys, proof_ys(&proofs).unwrap().join(","));
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_mint_failure_then_retry() {
+        let mock = MockBackend::new();
+        let config = MintServerConfig {
+            mint_failures: 1,
+            ..Default::default()
+        };
+        let mint = CashuTestMint::start(config, mock.clone()).await;
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let (mut conn, pr, payer_params) =
+            create_test_cashu_payreq(&mock, &mint, vec![relay.url()]);
+
+        // Quote paid, minting fails
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let mut paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_CASHU_NONFINAL_FAILURE);
+        assert_eq!(paym.secon_id, "quote1");
+        // Outputs stored, no token
+        let token = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert!(!token.premint.is_empty());
+        assert_eq!(token.token, "");
+
+        // Retry: minted with the paid quote, not paid again
+        paym.next_retry_time = 0;
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym))
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert!(
+            paym.pay_ref.ends_with(
+                &mock
+                    .decode_invoice(&paym.terti_id)
+                    .await
+                    .unwrap()
+                    .payment_hash
+            )
+        );
+        assert_eq!(mint.quote_count(), 1);
+        assert_eq!(mock.paid_invoices().len(), 1);
+        assert_valid_token(&dm_token(&relay), &mint, 21);
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_mint_response_lost() {
+        let mock = MockBackend::new();
+        let config = MintServerConfig {
+            lost_mint_responses: 1,
+            ..Default::default()
+        };
+        let mint = CashuTestMint::start(config, mock.clone()).await;
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let (mut conn, pr, payer_params) =
+            create_test_cashu_payreq(&mock, &mint, vec![relay.url()]);
+
+        // Signed by the mint, but the response is lost: restored with the stored outputs
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(mint.issued_count(), 1);
+        assert_eq!(mock.paid_invoices().len(), 1);
+        assert_valid_token(&dm_token(&relay), &mint, 21);
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_mint_response_lost_no_restore() {
+        let mock = MockBackend::new();
+        let config = MintServerConfig {
+            lost_mint_responses: 1,
+            restore_supported: false,
+            ..Default::default()
+        };
+        let mint = CashuTestMint::start(config, mock.clone()).await;
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let (mut conn, pr, payer_params) =
+            create_test_cashu_payreq(&mock, &mint, vec![relay.url()]);
+
+        // Not retried, left to the operator, with the outputs to restore from
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_CASHU_NEEDS_REVIEW);
+        assert_eq!(paym.secon_id, "quote1");
+        let token = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert!(!token.premint.is_empty());
+        assert_eq!(token.token, "");
+        assert_eq!(mock.paid_invoices().len(), 1);
+        assert!(relay.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_dm_failure_then_resend() {
+        let mock = MockBackend::new();
+        let mint = CashuTestMint::start(MintServerConfig::default(), mock.clone()).await;
+        // No relays, the DM cannot be sent
+        let (mut conn, pr, mut payer_params) = create_test_cashu_payreq(&mock, &mint, Vec::new());
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let mut paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_CASHU_NONFINAL_FAILURE);
+        let token = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert_eq!(token.delivered_at, 0);
+        assert_valid_token(&token.token, &mint, 21);
+
+        // Retry: the same token is sent
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        payer_params.nostr_relays = vec![relay.url()];
+        paym.next_retry_time = 0;
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym))
+            .await
+            .unwrap();
+        assert_eq!(last_payment(&conn).status, STATUS_SUCCESS_FINAL);
+        assert_eq!(dm_token(&relay), token.token);
+        let resent = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert!(resent.delivered_at > 0);
+        assert_eq!(resent.token, "");
+        assert_eq!(mint.issued_count(), 1);
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_resend_not_confirmed_by_node() {
+        let mock = MockBackend::new();
+        let mint = CashuTestMint::start(MintServerConfig::default(), mock.clone()).await;
+        // No relays, the DM cannot be sent
+        let (mut conn, pr, mut payer_params) = create_test_cashu_payreq(&mock, &mint, Vec::new());
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+
+        // Retry: the node does not know the payment, the token is not sent
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        payer_params.nostr_relays = vec![relay.url()];
+        let mut paym_unknown = paym.clone();
+        paym_unknown.next_retry_time = 0;
+        paym_unknown.pay_hash = "00".repeat(32);
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym_unknown))
+            .await
+            .unwrap();
+        let paym_after = last_payment(&conn);
+        assert_eq!(paym_after.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym_after.error_code, ERROR_CASHU_NONFINAL_FAILURE);
+        assert_eq!(paym_after.paid_amnt, 0);
+        let token = db::cashu_token_get_by_req(&conn, pr.id).unwrap().unwrap();
+        assert_eq!(token.delivered_at, 0);
+        assert_eq!(relay.events().len(), 0);
+
+        // Confirmed by the node: sent, with the amount and fee from the node
+        let mut paym = paym;
+        paym.next_retry_time = 0;
+        process_payment_start(&payer_params, &mut conn, &pr, &Some(paym))
+            .await
+            .unwrap();
+        let paym_after = last_payment(&conn);
+        assert_eq!(paym_after.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym_after.paid_amnt, mock.paid_invoices()[0].amount_msat);
+        assert!(!paym_after.pay_ref.is_empty());
+        assert_eq!(relay.events().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cashu_payment_no_mint() {
+        let mock = MockBackend::new();
+        let mint = CashuTestMint::start(MintServerConfig::default(), mock.clone()).await;
+        let (mut conn, pr, mut payer_params) = create_test_cashu_payreq(&mock, &mint, Vec::new());
+        payer_params.cashu_config = CashuConfig::default();
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_CASHU_FINAL_FAILURE);
+        assert!(mock.paid_invoices().is_empty());
+    }
+
+    fn create_test_bolt12_payreq(
+        mock: &MockBackend,
+        pri_id: &str,
+    ) -> (Connection, PayRequest, PayerParameters) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            21_000,
+            PaymentMethod::PmBolt12.to_string(),
+            pri_id.into(),
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let payer_params = PayerParameters::for_test(mock.clone());
+        (conn, pr, payer_params)
+    }
+
+    #[tokio::test]
+    async fn test_bolt12_payment_success() {
+        let mock = MockBackend::new();
+        mock.set_fee_msat(2);
+        let offer = mock.create_offer(None);
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, &offer);
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.paid_fee, 2);
+        let invoice = mock.paid_invoices()[0].invoice.clone();
+        assert!(invoice.starts_with("lnimock21000"));
+        assert_eq!(paym.secon_id, invoice);
+        assert_eq!(paym.terti_id, "");
+        let preimage = mock.invoice_preimage(&invoice).unwrap();
+        assert!(paym.pay_ref.starts_with(&preimage));
+    }
+
+    #[tokio::test]
+    async fn test_bolt12_payment_offer_amount_mismatch() {
+        let mock = MockBackend::new();
+        let offer = mock.create_offer(Some(10_000));
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, &offer);
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_BOLT12_OFFER_FINAL_FAILURE);
+        assert!(mock.paid_invoices().is_empty());
+
+        // Not an offer
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, "miner@example.com");
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        assert_eq!(
+            last_payment(&conn).error_code,
+            ERROR_BOLT12_OFFER_FINAL_FAILURE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bolt12_payment_offer_from_nostr_profile() {
+        let mock = MockBackend::new();
+        let offer = mock.create_offer(Some(21_000));
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, &rec_npub);
+        db::nostr_profile_cache_upsert(
+            &conn,
+            &NostrProfileCache::new(
+                rec_npub.clone(),
+                "".into(),
+                "".into(),
+                1000,
+                now_utc_secs(),
+                "".into(),
+                offer.clone(),
+            ),
+        )
+        .unwrap();
+
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.secon_id, mock.paid_invoices()[0].invoice);
+        assert_eq!(paym.terti_id, offer);
+    }
+
+    #[tokio::test]
+    async fn test_bolt12_payment_nostr_profile_without_offer() {
+        let mock = MockBackend::new();
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+
+        // No relay reachable: nonfinal
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, &rec_npub);
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_BOLT12_OFFER_NONFINAL_FAILURE);
+
+        // Profile with a Lightning Address, but no 'lno': final
+        let keys = Keys::new(SecretKey::from_slice(&[8u8; 32]).unwrap());
+        let content =
+            serde_json::json!({"name": "miner", "lud16": "miner@example.com"}).to_string();
+        let event = nostr::EventBuilder::new(nostr::Kind::Metadata, content)
+            .sign_with_keys(&keys)
+            .unwrap();
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![serde_json::from_str(&event.as_json()).unwrap()],
+        )
+        .await;
+        let (mut conn, pr, payer_params) = create_test_bolt12_payreq(&mock, &rec_npub);
+        let payer_params = PayerParameters {
+            nostr_relays: vec![relay.url()],
+            ..payer_params
+        };
+        process_payment_start(&payer_params, &mut conn, &pr, &None)
+            .await
+            .unwrap();
+        let paym = last_payment(&conn);
+        assert_eq!(paym.status, STATUS_FINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_BOLT12_OFFER_FINAL_FAILURE);
+        assert!(paym.error_str.contains("'lno'"));
+        assert!(mock.paid_invoices().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_background_task() {
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                let mut task = None;
+                let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+                spawn_background_task(&mut task, "test task", ":memory:", |_conn| async move {
+                    let _ = rx.await;
+                    Ok(())
+                });
+                let first_id = task.as_ref().unwrap().id();
+
+                // Previous one still running, not started again
+                spawn_background_task(
+                    &mut task,
+                    "test task",
+                    ":memory:",
+                    |_conn| async move { Ok(()) },
+                );
+                assert_eq!(task.as_ref().unwrap().id(), first_id);
+
+                tx.send(()).unwrap();
+                while !task.as_ref().unwrap().is_finished() {
+                    tokio::task::yield_now().await;
+                }
+                spawn_background_task(&mut task, "test task", ":memory:", |_conn| async move {
+                    Err("failed".into())
+                });
+                assert_ne!(task.as_ref().unwrap().id(), first_id);
+                task.take().unwrap().await.unwrap();
+            })
+            .await;
+    }
 }
@@ -0,0 +1,307 @@
+use crate::common::{PaymentMethod, PaymentResult};
+use crate::ln_backend::{LightningBackend, LookupStatus};
+use crate::payer::{finish_payment_attempt, payment_invoice};
+use crate::retry_policy::RetryPolicy;
+
+use common_rs::db_pc as db;
+use common_rs::dto_pc::{PayRequest, Payment};
+use common_rs::error_codes::*;
+
+use rusqlite::Connection;
+
+use std::error::Error;
+use std::time::Duration;
+
+/// Period of resolving payments left in progress (e.g. pending on the node), secs
+pub const RECONCILE_PERIOD: u32 = 60;
+/// Timeout of one periodic reconciliation, not to hold up the payer loop on a slow node
+pub const RECONCILE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of resolving an in-progress payment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReconcileOutcome {
+    /// Paid, per the node
+    Paid,
+    /// Not paid (failed on the node, or not reached the node), can be retried
+    NotPaid,
+    /// Still pending on the node, left in progress
+    Pending,
+}
+
+/// Result for a payment the node confirms as complete
+pub(crate) fn paid_result(
+    paym: &Payment,
+    amount_msat: u64,
+    amount_sent_msat: u64,
+    reference: &str,
+) -> PaymentResult {
+    PaymentResult::new(
+        true,
+        false,
+        ERROR_OK,
+        "OK (recovered from node)",
+        &paym.secon_id,
+        &paym.terti_id,
+        amount_msat,
+        amount_sent_msat.saturating_sub(amount_msat) as u32,
+        reference,
+    )
+}
+
+fn not_paid_result(paym: &Payment, err_code: u8, err_str: &str) -> PaymentResult {
+    PaymentResult::new(
+        false,
+        true,
+        err_code,
+        err_str,
+        &paym.secon_id,
+        &paym.terti_id,
+        0,
+        0,
+        "",
+    )
+}
+
+/// Resolve a payment left in progress (e.g. after a crash) from the node, by its invoice:
+/// - no invoice stored: the invoice was not paid, it can be retried
+/// - complete on the node: success (Cashu: the quote is paid, the token is minted on a retry)
+/// - failed on the node, or unknown to it (the pay call did not reach it): it can be retried
+/// - pending on the node: left in progress
+pub async fn reconcile_payment(
+    conn: &mut Connection,
+    ln_backend: &dyn LightningBackend,
+    retry_policy: &RetryPolicy,
+    pr: &PayRequest,
+    paym: &mut Payment,
+    now_utc: u32,
+) -> Result<ReconcileOutcome, Box<dyn Error>> {
+    let invoice = payment_invoice(paym).to_string();
+    let (outcome, pay_res) = if invoice.is_empty() {
+        (
+            ReconcileOutcome::NotPaid,
+            not_paid_result(
+                paym,
+                ERROR_GENERIC_NONFINAL_FAILURE,
+                "Attempt interrupted before paying",
+            ),
+        )
+    } else {
+        let payment_hash = if paym.pay_hash.is_empty() {
+            ln_backend.decode_invoice(&invoice).await?.payment_hash
+        } else {
+            paym.pay_hash.clone()
+        };
+        match ln_backend.lookup_payment(&payment_hash).await? {
+            None => (
+                ReconcileOutcome::NotPaid,
+                not_paid_result(
+                    paym,
+                    ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE,
+                    "Attempt interrupted, invoice not paid by the node",
+                ),
+            ),
+            Some(lookup) => match lookup.status {
+                LookupStatus::Pending => return Ok(ReconcileOutcome::Pending),
+                LookupStatus::Failed => (
+                    ReconcileOutcome::NotPaid,
+                    not_paid_result(
+                        paym,
+                        ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE,
+                        "Attempt interrupted, payment failed on the node",
+                    ),
+                ),
+                // Cashu: the mint quote is paid, the token is minted on the retry
+                LookupStatus::Complete if paym.pay_method == PaymentMethod::PmCashu.to_string() => {
+                    (
+                        ReconcileOutcome::NotPaid,
+                        not_paid_result(
+                            paym,
+                            ERROR_CASHU_NONFINAL_FAILURE,
+                            "Attempt interrupted, mint quote paid, token not sent",
+                        ),
+                    )
+                }
+                LookupStatus::Complete => (
+                    ReconcileOutcome::Paid,
+                    paid_result(
+                        paym,
+                        lookup.amount_msat,
+                        lookup.amount_sent_msat,
+                        &format!("{} {}", lookup.preimage, payment_hash),
+                    ),
+                ),
+            },
+        }
+    };
+
+    println!(
+        "Reconciled in-progress payment {} (rid {}): {:?}",
+        paym.id, pr.id, outcome
+    );
+    if outcome == ReconcileOutcome::Paid && paym.pay_method == PaymentMethod::PmNostrZap.to_string()
+    {
+        // Receipt is checked later, see check_zap_receipts
+        paym.zap_rcpt_status = ZAP_RCPT_PENDING;
+    }
+    finish_payment_attempt(conn, paym, pay_res, retry_policy, now_utc)?;
+    Ok(outcome)
+}
+
+/// Resolve all payments left in progress from the node, except those of the given pay requests
+/// (with an attempt running). Done at startup before any retry, and periodically.
+/// Return the number of payments resolved.
+pub async fn reconcile_in_progress_payments(
+    conn: &mut Connection,
+    ln_backend: &dyn LightningBackend,
+    retry_policy: &RetryPolicy,
+    skip_req_ids: &[i32],
+    now_utc: u32,
+) -> Result<u32, Box<dyn Error>> {
+    let open_requests = db::payreq_get_all_non_final(conn)?;
+    let mut cnt = 0;
+    for (pr, paym) in open_requests {
+        let mut paym = match paym {
+            Some(p) if p.status == STATUS_IN_PROGRESS => p,
+            _ => continue,
+        };
+        if skip_req_ids.contains(&pr.id) {
+            continue;
+        }
+        match reconcile_payment(conn, ln_backend, retry_policy, &pr, &mut paym, now_utc).await {
+            Err(e) => println!(
+                "ERROR: Could not reconcile in-progress payment {} (rid {}), {e}",
+                paym.id, pr.id
+            ),
+            Ok(ReconcileOutcome::Pending) => println!(
+                "Payment {} (rid {}) still pending on the node",
+                paym.id, pr.id
+            ),
+            Ok(_) => cnt += 1,
+        }
+    }
+    Ok(cnt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::test_payment;
+    use crate::mock_backend::{MockBackend, MockPayOutcome};
+    use crate::payer::{attempt_due, save_payment};
+
+    const NOW: u32 = 1_760_000_000;
+
+    // DB with an LN Address pay request and its payment in progress, with the given invoice
+    fn create_test_db_in_progress(invoice: &str) -> (Connection, PayRequest) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmLnAddress.to_string(),
+            "miner@example.com".into(),
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let mut paym = Payment {
+            secon_id: invoice.into(),
+            max_fee: 1000,
+            ..test_payment(pr.id, &pr.pay_method, NOW)
+        };
+        save_payment(&mut conn, &mut paym).unwrap();
+        (conn, pr)
+    }
+
+    fn the_payment(conn: &Connection) -> Payment {
+        db::payment_get_all_after_time(conn, 0).unwrap()[0]
+            .1
+            .clone()
+    }
+
+    async fn reconcile_with(mock: &MockBackend, invoice: &str) -> (u32, Payment) {
+        let (mut conn, _pr) = create_test_db_in_progress(invoice);
+        let cnt =
+            reconcile_in_progress_payments(&mut conn, mock, &RetryPolicy::default(), &[], NOW + 10)
+                .await
+                .unwrap();
+        (cnt, the_payment(&conn))
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_paid_before_crash() {
+        let mock = MockBackend::new();
+        mock.set_fee_msat(3);
+        let invoice = mock.create_invoice(5000, None, NOW as u64, 600);
+        // Paid, but the process died before storing the result
+        let _ = mock.pay_invoice(&invoice, 5000, "l", 1000).await.unwrap();
+
+        let (cnt, paym) = reconcile_with(&mock, &invoice).await;
+        assert_eq!(cnt, 1);
+        assert_eq!(paym.status, STATUS_SUCCESS_FINAL);
+        assert_eq!(paym.paid_amnt, 5000);
+        assert_eq!(paym.paid_fee, 3);
+        assert_eq!(paym.secon_id, invoice);
+        assert!(!attempt_due(&Some(paym), NOW + 100_000));
+        // Not paid again
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_not_paid() {
+        let mock = MockBackend::new();
+
+        // No invoice yet
+        let (cnt, paym) = reconcile_with(&mock, "").await;
+        assert_eq!(cnt, 1);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_GENERIC_NONFINAL_FAILURE);
+
+        // Invoice recorded, but the pay call did not reach the node
+        let invoice = mock.create_invoice(5000, None, NOW as u64, 600);
+        let (cnt, paym) = reconcile_with(&mock, &invoice).await;
+        assert_eq!(cnt, 1);
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(paym.error_code, ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE);
+        assert!(attempt_due(&Some(paym), NOW + 100_000));
+
+        // Failed on the node
+        let invoice = mock.create_invoice(5000, None, NOW as u64, 600);
+        mock.script_outcomes(&[MockPayOutcome::NonFinalFailure]);
+        let _ = mock.pay_invoice(&invoice, 5000, "l", 1000).await.unwrap();
+        let (_cnt, paym) = reconcile_with(&mock, &invoice).await;
+        assert_eq!(paym.status, STATUS_NONFINAL_FAILURE);
+        assert_eq!(mock.paid_invoices().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_pending_and_skipped() {
+        let mock = MockBackend::new();
+        let invoice = mock.create_invoice(5000, None, NOW as u64, 600);
+        mock.script_outcomes(&[MockPayOutcome::Partial]);
+        let _ = mock.pay_invoice(&invoice, 5000, "l", 1000).await.unwrap();
+
+        // Pending on the node: left in progress, not due for a retry
+        let (cnt, paym) = reconcile_with(&mock, &invoice).await;
+        assert_eq!(cnt, 0);
+        assert_eq!(paym.status, STATUS_IN_PROGRESS);
+        assert!(!attempt_due(&Some(paym), NOW + 100_000));
+
+        // Attempt running: not touched
+        let (mut conn, pr) = create_test_db_in_progress("");
+        let cnt = reconcile_in_progress_payments(
+            &mut conn,
+            &mock,
+            &RetryPolicy::default(),
+            &[pr.id],
+            NOW + 10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cnt, 0);
+        assert_eq!(the_payment(&conn).status, STATUS_IN_PROGRESS);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::common::get_env_or_default;
+
+use common_rs::dto_pc::Payment;
+use common_rs::error_codes::*;
+
+use dotenv;
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Default max. total time of retrying a payment, from its creation, secs (3 days)
+const DEFAULT_MAX_WINDOW_SECS: u32 = 259_200;
+/// Default random variation of retry delays, percentage of the delay
+const DEFAULT_JITTER_PERCENT: u32 = 10;
+
+/// Class of a nonfinal error, each retried with its own rule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryClass {
+    /// Nostr relays unreachable, profile or zap could not be obtained
+    Relay,
+    /// LNURL server (or Cashu mint) error or unreachable (e.g. 5xx)
+    Lnurl,
+    /// No route (or none within the fee cap, or to a BOLT12 offer node), or the payment failed
+    Route,
+    /// Not enough funds on the node
+    Funds,
+    /// Anything else, e.g. timed out attempt
+    Other,
+}
+
+const RETRY_CLASSES: &[RetryClass] = &[
+    RetryClass::Relay,
+    RetryClass::Lnurl,
+    RetryClass::Route,
+    RetryClass::Funds,
+    RetryClass::Other,
+];
+
+impl fmt::Display for RetryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Relay => "RELAY",
+            Self::Lnurl => "LNURL",
+            Self::Route => "ROUTE",
+            Self::Funds => "FUNDS",
+            Self::Other => "OTHER",
+        })
+    }
+}
+
+/// The retry class of an error code
+pub fn retry_class_for_error(err_code: u8) -> RetryClass {
+    match err_code {
+        ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE | ERROR_NOSTR_ZAP_NONFINAL_FAILURE => {
+            RetryClass::Relay
+        }
+        // Incl. LNURL server errors of zaps
+        ERROR_LN_ADDRESS_NONFINAL_FAILURE => RetryClass::Lnurl,
+        // Mint unreachable or failed
+        ERROR_CASHU_NONFINAL_FAILURE => RetryClass::Lnurl,
+        ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE | ERROR_LN_BOLT11_FEE_CAP_EXCEEDED => {
+            RetryClass::Route
+        }
+        // fetchinvoice failed, e.g. no route to the offer node or no reply
+        ERROR_BOLT12_OFFER_NONFINAL_FAILURE => RetryClass::Route,
+        ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS => RetryClass::Funds,
+        _ => RetryClass::Other,
+    }
+}
+
+/// Retry rule of an error class: exponential backoff from the base delay, capped
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryRule {
+    /// Delay after the first failure, secs; doubled after each further failure
+    pub base_delay: u32,
+    /// Cap on the delay, secs
+    pub max_delay: u32,
+    /// Failures after which the payment fails finally
+    pub max_retries: u32,
+}
+
+impl RetryRule {
+    pub fn new(base_delay: u32, max_delay: u32, max_retries: u32) -> Self {
+        Self {
+            base_delay,
+            max_delay,
+            max_retries,
+        }
+    }
+
+    /// Delay after the given failure (1 for the first), without jitter, secs
+    pub fn delay_for_retry(&self, retry_cnt: u8) -> u32 {
+        let exp = (retry_cnt.max(1) - 1).min(31) as u32;
+        let delay = (self.base_delay as u64) << exp;
+        delay.min(self.max_delay as u64) as u32
+    }
+
+    fn default_for_class(class: RetryClass) -> Self {
+        match class {
+            RetryClass::Relay => Self::new(300, 3_600, 10),
+            RetryClass::Lnurl => Self::new(600, 21_600, 10),
+            RetryClass::Route => Self::new(600, 10_800, 10),
+            // Node to be refilled, retry slowly but longer
+            RetryClass::Funds => Self::new(1_800, 21_600, 20),
+            RetryClass::Other => Self::new(600, 21_600, 10),
+        }
+    }
+
+    /// From config: PAYER_RETRY_<CLASS>_BASE_SECS, _MAX_SECS, _MAX_RETRIES
+    fn new_from_config(class: RetryClass) -> Result<Self, Box<dyn Error>> {
+        let default = Self::default_for_class(class);
+        let prefix = format!("PAYER_RETRY_{}", class);
+        let rule = Self::new(
+            get_env_or_default(&format!("{prefix}_BASE_SECS"), default.base_delay)?,
+            get_env_or_default(&format!("{prefix}_MAX_SECS"), default.max_delay)?,
+            get_env_or_default(&format!("{prefix}_MAX_RETRIES"), default.max_retries)?,
+        );
+        if rule.max_delay < rule.base_delay {
+            return Err(format!("{prefix}_MAX_SECS is less than {prefix}_BASE_SECS").into());
+        }
+        Ok(rule)
+    }
+}
+
+/// Retry policy of payments with a nonfinal error: rules by error class,
+/// jitter on the delays, and a max. total retry window
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Rules, in the order of RETRY_CLASSES
+    rules: Vec<RetryRule>,
+    /// Random variation of the delays (+-), percentage of the delay
+    pub jitter_percent: u32,
+    /// No retry later than this after the payment was created, secs
+    pub max_window: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(rules: Vec<RetryRule>, jitter_percent: u32, max_window: u32) -> Self {
+        debug_assert_eq!(rules.len(), RETRY_CLASSES.len());
+        Self {
+            rules,
+            jitter_percent,
+            max_window,
+        }
+    }
+
+    /// Retry policy from config: PAYER_RETRY_MAX_WINDOW_SECS, PAYER_RETRY_JITTER_PERCENT,
+    /// and per class PAYER_RETRY_<CLASS>_BASE_SECS, _MAX_SECS, _MAX_RETRIES
+    /// (classes: RELAY, LNURL, ROUTE, FUNDS, OTHER)
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let mut rules = Vec::new();
+        for class in RETRY_CLASSES {
+            rules.push(RetryRule::new_from_config(*class)?);
+        }
+        let jitter_percent =
+            get_env_or_default("PAYER_RETRY_JITTER_PERCENT", DEFAULT_JITTER_PERCENT)?;
+        if jitter_percent > 100 {
+            return Err(format!("Invalid retry jitter percentage {jitter_percent}").into());
+        }
+        Ok(Self::new(
+            rules,
+            jitter_percent,
+            get_env_or_default("PAYER_RETRY_MAX_WINDOW_SECS", DEFAULT_MAX_WINDOW_SECS)?,
+        ))
+    }
+
+    pub fn rule(&self, class: RetryClass) -> &RetryRule {
+        let idx = RETRY_CLASSES.iter().position(|c| *c == class).unwrap_or(0);
+        &self.rules[idx]
+    }
+
+    /// Time of the next attempt of a payment after a nonfinal failure with the given error,
+    /// counted in retry_cnt already. None if it is not to be retried any more: too many failures,
+    /// or the retry would fall outside the retry window.
+    pub fn next_retry_time(&self, paym: &Payment, err_code: u8, now_utc: u32) -> Option<u32> {
+        let rule = self.rule(retry_class_for_error(err_code));
+        if paym.retry_cnt as u32 >= rule.max_retries {
+            return None;
+        }
+        let delay = rule.delay_for_retry(paym.retry_cnt);
+        let next_time = now_utc.saturating_add(self.with_jitter(delay, paym.id, paym.retry_cnt));
+        if next_time > paym.create_time.saturating_add(self.max_window) {
+            return None;
+        }
+        Some(next_time)
+    }
+
+    // Vary the delay by up to jitter_percent, so that payments failing together are not retried
+    // together. Derived from the payment and the retry count, not to need a random source.
+    fn with_jitter(&self, delay: u32, paym_id: i32, retry_cnt: u8) -> u32 {
+        let max_jitter = delay as u64 * self.jitter_percent as u64 / 100;
+        if max_jitter == 0 {
+            return delay;
+        }
+        let mut hasher = DefaultHasher::new();
+        (paym_id, retry_cnt).hash(&mut hasher);
+        let offset = hasher.finish() % (2 * max_jitter + 1);
+        (delay as u64 + offset - max_jitter) as u32
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(
+            RETRY_CLASSES
+                .iter()
+                .map(|c| RetryRule::default_for_class(*c))
+                .collect(),
+            DEFAULT_JITTER_PERCENT,
+            DEFAULT_MAX_WINDOW_SECS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::test_payment;
+
+    fn failed_payment(id: i32, create_time: u32, retry_cnt: u8) -> Payment {
+        Payment {
+            id,
+            status: STATUS_NONFINAL_FAILURE,
+            retry_cnt,
+            fail_time: create_time,
+            ..test_payment(1, "LNAD", create_time)
+        }
+    }
+
+    #[test]
+    fn test_retry_class_for_error() {
+        assert_eq!(
+            retry_class_for_error(ERROR_NOSTR_LN_ADDRESS_NONFINAL_FAILURE),
+            RetryClass::Relay
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_LN_ADDRESS_NONFINAL_FAILURE),
+            RetryClass::Lnurl
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE),
+            RetryClass::Route
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_LN_BOLT11_INVOICE_NOT_ENOUGH_FUNDS),
+            RetryClass::Funds
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_CASHU_NONFINAL_FAILURE),
+            RetryClass::Lnurl
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_BOLT12_OFFER_NONFINAL_FAILURE),
+            RetryClass::Route
+        );
+        assert_eq!(
+            retry_class_for_error(ERROR_PAYMENT_TIMEOUT),
+            RetryClass::Other
+        );
+    }
+
+    #[test]
+    fn test_backoff_and_limits() {
+        let rule = RetryRule::new(600, 3_000, 5);
+        assert_eq!(rule.delay_for_retry(1), 600);
+        assert_eq!(rule.delay_for_retry(2), 1_200);
+        assert_eq!(rule.delay_for_retry(3), 2_400);
+        assert_eq!(rule.delay_for_retry(4), 3_000);
+        assert_eq!(rule.delay_for_retry(200), 3_000);
+
+        let policy = RetryPolicy::new(vec![rule; RETRY_CLASSES.len()], 0, 10_000);
+        let err = ERROR_LN_BOLT11_INVOICE_NONFINAL_FAILURE;
+        assert_eq!(
+            policy.next_retry_time(&failed_payment(1, 1000, 2), err, 2000),
+            Some(3_200)
+        );
+        // Too many failures
+        assert_eq!(
+            policy.next_retry_time(&failed_payment(1, 1000, 5), err, 2000),
+            None
+        );
+        // Outside the window
+        assert_eq!(
+            policy.next_retry_time(&failed_payment(1, 1000, 4), err, 8500),
+            None
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = RetryPolicy::default();
+        let mut times = Vec::new();
+        for id in 1..20 {
+            let t = policy
+                .next_retry_time(
+                    &failed_payment(id, 1000, 1),
+                    ERROR_GENERIC_NONFINAL_FAILURE,
+                    1000,
+                )
+                .unwrap();
+            // 600 +- 10%
+            assert!((1000 + 540..=1000 + 660).contains(&t));
+            times.push(t);
+        }
+        times.dedup();
+        assert!(times.len() > 1);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::common::{PayerParameters, get_env_or_default};
+use crate::payer::{attempt_due, fail_timed_out_attempt, now_utc_secs, process_payment_start};
+
+use common_rs::db_pc as db;
+use common_rs::dto_pc::{PayRequest, Payment};
+
+use dotenv;
+use rusqlite::Connection;
+use tokio::sync::Notify;
+use tokio::task::{Id as TaskId, JoinSet};
+use tokio::time::{Instant as TokioInstant, sleep_until, timeout};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default max. number of payment attempts running at the same time
+const DEFAULT_MAX_CONCURRENT: usize = 4;
+/// Default timeout of one payment attempt, secs
+const DEFAULT_PAYMENT_TIMEOUT_SECS: u64 = 300;
+/// Default period of checking the DB for due payments without a wakeup, secs
+const DEFAULT_POLL_PERIOD_SECS: u64 = 30;
+/// Wait for a locked DB (e.g. the payreq thread writing) this long, secs
+const DB_BUSY_TIMEOUT_SECS: u64 = 10;
+
+/// Wakes up the payer when there is new work, e.g. new pay requests were inserted.
+/// Clones share the same signal; a wakeup with no one waiting is kept for the next wait.
+#[derive(Clone, Default)]
+pub struct PayerWakeup {
+    notify: Arc<Notify>,
+}
+
+impl PayerWakeup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    async fn notified(&self) {
+        self.notify.notified().await
+    }
+}
+
+/// Work queue settings, from config
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkQueueConfig {
+    pub max_concurrent: usize,
+    pub payment_timeout: Duration,
+    pub poll_period: Duration,
+}
+
+impl WorkQueueConfig {
+    /// From config: PAYER_MAX_CONCURRENT, PAYER_PAYMENT_TIMEOUT_SECS, PAYER_POLL_SECS
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let max_concurrent = get_env_or_default("PAYER_MAX_CONCURRENT", DEFAULT_MAX_CONCURRENT)?;
+        if max_concurrent == 0 {
+            return Err("PAYER_MAX_CONCURRENT must be at least 1".into());
+        }
+        Ok(Self {
+            max_concurrent,
+            payment_timeout: Duration::from_secs(get_env_or_default(
+                "PAYER_PAYMENT_TIMEOUT_SECS",
+                DEFAULT_PAYMENT_TIMEOUT_SECS,
+            )?),
+            poll_period: Duration::from_secs(get_env_or_default(
+                "PAYER_POLL_SECS",
+                DEFAULT_POLL_PERIOD_SECS,
+            )?),
+        })
+    }
+}
+
+impl Default for WorkQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            payment_timeout: Duration::from_secs(DEFAULT_PAYMENT_TIMEOUT_SECS),
+            poll_period: Duration::from_secs(DEFAULT_POLL_PERIOD_SECS),
+        }
+    }
+}
+
+/// Open a DB connection for a payment attempt
+pub(crate) fn open_db_conn(dbfile: &str) -> Result<Connection, Box<dyn Error>> {
+    let conn = Connection::open(dbfile)?;
+    conn.busy_timeout(Duration::from_secs(DB_BUSY_TIMEOUT_SECS))?;
+    Ok(conn)
+}
+
+// One payment attempt, on its own DB connection, within the timeout
+async fn run_payment_attempt(
+    payer_params: &PayerParameters,
+    dbfile: &str,
+    pr: &PayRequest,
+    paym: &Option<Payment>,
+    payment_timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = open_db_conn(dbfile)?;
+    match timeout(
+        payment_timeout,
+        process_payment_start(payer_params, &mut conn, pr, paym),
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(_) => {
+            println!(
+                "WARNING: Payment attempt timed out, rid {} priid {}",
+                pr.id, pr.pri_id
+            );
+            fail_timed_out_attempt(
+                &mut conn,
+                pr,
+                payment_timeout.as_secs() as u32,
+                &payer_params.retry_policy,
+                now_utc_secs(),
+            )
+        }
+    }
+}
+
+/// Payment attempts, run concurrently as local tasks (must be used within a LocalSet).
+/// The DB is the source of truth of payment states; the queue only tracks
+/// which pay requests have an attempt running, not to start another one.
+pub(crate) struct WorkQueue {
+    payer_params: Rc<PayerParameters>,
+    dbfile: String,
+    config: WorkQueueConfig,
+    tasks: JoinSet<()>,
+    /// Pay request IDs of the running tasks
+    running: HashMap<TaskId, i32>,
+}
+
+impl WorkQueue {
+    pub(crate) fn new(
+        payer_params: Rc<PayerParameters>,
+        dbfile: &str,
+        config: WorkQueueConfig,
+    ) -> Self {
+        Self {
+            payer_params,
+            dbfile: dbfile.to_string(),
+            config,
+            tasks: JoinSet::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Pay request IDs with an attempt running
+    pub(crate) fn running_req_ids(&self) -> Vec<i32> {
+        self.running.values().cloned().collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn running_count(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Start attempts for the open pay requests that are due and have no running attempt,
+    /// up to the concurrency limit. Return the number of attempts started.
+    pub(crate) fn dispatch(
+        &mut self,
+        conn: &Connection,
+        now_utc: u32,
+    ) -> Result<usize, Box<dyn Error>> {
+        let open_requests = db::payreq_get_all_non_final(conn)?;
+        let mut started = 0;
+        for (pr, paym) in open_requests {
+            if self.running.len() >= self.config.max_concurrent {
+                break;
+            }
+            if self.running.values().any(|id| *id == pr.id) || !attempt_due(&paym, now_utc) {
+                continue;
+            }
+            let pr_id = pr.id;
+            let payer_params = self.payer_params.clone();
+            let dbfile = self.dbfile.clone();
+            let payment_timeout = self.config.payment_timeout;
+            let handle = self.tasks.spawn_local(async move {
+                if let Err(e) =
+                    run_payment_attempt(&payer_params, &dbfile, &pr, &paym, payment_timeout).await
+                {
+                    println!("ERROR in payment attempt, rid {}, {e}", pr.id);
+                }
+            });
+            let _ = self.running.insert(handle.id(), pr_id);
+            started += 1;
+        }
+        if started > 0 {
+            println!(
+                "Payment attempts started: {started}, running: {}",
+                self.running.len()
+            );
+        }
+        Ok(started)
+    }
+
+    /// Wait until an attempt finishes, a wakeup, or the deadline, whichever comes first
+    pub(crate) async fn wait(&mut self, wakeup: &PayerWakeup, deadline: TokioInstant) {
+        tokio::select! {
+            Some(res) = self.tasks.join_next_with_id(), if !self.tasks.is_empty() => {
+                let task_id = match res {
+                    Ok((id, _)) => id,
+                    Err(e) => {
+                        println!("ERROR: Payment task failed, {e}");
+                        e.id()
+                    }
+                };
+                let _ = self.running.remove(&task_id);
+            }
+            _ = wakeup.notified() => {}
+            _ = sleep_until(deadline) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::PaymentMethod;
+    use crate::ln_address::test::set_test_url_template;
+    use crate::lnurl_test_server::{LnurlServerConfig, LnurlTestServer};
+    use crate::mock_backend::MockBackend;
+    use common_rs::error_codes::*;
+    use std::env;
+    use tokio::net::TcpListener;
+    use tokio::task::LocalSet;
+
+    const NOW: u32 = 1_760_000_000;
+
+    // DB in a temp file, as attempts use their own connections
+    struct TestDbFile(String);
+
+    impl TestDbFile {
+        fn new() -> Self {
+            let path = env::temp_dir().join(format!("payer-test-{}.db", uuid::Uuid::new_v4()));
+            Self(path.to_string_lossy().to_string())
+        }
+    }
+
+    impl Drop for TestDbFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn create_test_db_with_payreqs(dbfile: &str, ln_addresses: &[String]) -> Connection {
+        let mut conn = open_db_conn(dbfile).unwrap();
+        db::db_setup_new(&conn).unwrap();
+        let conntx = conn.transaction().unwrap();
+        for (i, ln_address) in ln_addresses.iter().enumerate() {
+            let pr = PayRequest::new(
+                0,
+                7 + i as u32,
+                5000,
+                PaymentMethod::PmLnAddress.to_string(),
+                ln_address.clone(),
+                NOW,
+                "".into(),
+            );
+            let _ = db::payreq_insert_nocommit(&conntx, &pr).unwrap();
+        }
+        conntx.commit().unwrap();
+        conn
+    }
+
+    fn test_payer_params(mock: &MockBackend) -> Rc<PayerParameters> {
+        Rc::new(PayerParameters::for_test(mock.clone()))
+    }
+
+    // Payment status and error code per pay request, in pay request order
+    fn payment_states(conn: &Connection) -> Vec<(u8, u8)> {
+        let mut payms = db::payment_get_all_after_time(conn, 0).unwrap();
+        payms.sort_by_key(|(pr, _)| pr.id);
+        payms
+            .iter()
+            .map(|(_, p)| (p.status, p.error_code))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_slow_server_does_not_stall_others() {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(LnurlServerConfig::default(), mock.clone()).await;
+        // Accepts connections but never responds
+        let hanging = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hanging_address = format!("miner@{}", hanging.local_addr().unwrap());
+
+        let dbfile = TestDbFile::new();
+        let conn =
+            create_test_db_with_payreqs(&dbfile.0, &[hanging_address, server.ln_address("miner")]);
+        let config = WorkQueueConfig {
+            max_concurrent: 2,
+            payment_timeout: Duration::from_secs(2),
+            poll_period: Duration::from_secs(60),
+        };
+        let wakeup = PayerWakeup::new();
+
+        LocalSet::new()
+            .run_until(async {
+                let mut queue = WorkQueue::new(test_payer_params(&mock), &dbfile.0, config);
+                assert_eq!(queue.dispatch(&conn, NOW).unwrap(), 2);
+                // Already running, not started again
+                assert_eq!(queue.dispatch(&conn, NOW).unwrap(), 0);
+
+                // The good one finishes first, while the other hangs
+                queue
+                    .wait(&wakeup, TokioInstant::now() + Duration::from_secs(60))
+                    .await;
+                assert_eq!(queue.running_count(), 1);
+                assert_eq!(
+                    payment_states(&conn),
+                    vec![
+                        (STATUS_IN_PROGRESS, ERROR_OK),
+                        (STATUS_SUCCESS_FINAL, ERROR_OK)
+                    ]
+                );
+
+                // Then the other one times out
+                queue
+                    .wait(&wakeup, TokioInstant::now() + Duration::from_secs(60))
+                    .await;
+                assert_eq!(queue.running_count(), 0);
+                assert_eq!(
+                    payment_states(&conn),
+                    vec![
+                        (STATUS_NONFINAL_FAILURE, ERROR_PAYMENT_TIMEOUT),
+                        (STATUS_SUCCESS_FINAL, ERROR_OK)
+                    ]
+                );
+                // Not due before the retry delay
+                assert_eq!(queue.dispatch(&conn, now_utc_secs()).unwrap(), 0);
+            })
+            .await;
+        assert_eq!(mock.paid_invoices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_and_wakeup() {
+        set_test_url_template();
+        let mock = MockBackend::new();
+        let server = LnurlTestServer::start(LnurlServerConfig::default(), mock.clone()).await;
+        let dbfile = TestDbFile::new();
+        let conn = create_test_db_with_payreqs(
+            &dbfile.0,
+            &[server.ln_address("miner1"), server.ln_address("miner2")],
+        );
+        let config = WorkQueueConfig {
+            max_concurrent: 1,
+            ..WorkQueueConfig::default()
+        };
+        let wakeup = PayerWakeup::new();
+
+        LocalSet::new()
+            .run_until(async {
+                let mut queue = WorkQueue::new(test_payer_params(&mock), &dbfile.0, config);
+                assert_eq!(queue.dispatch(&conn, NOW).unwrap(), 1);
+                queue
+                    .wait(&wakeup, TokioInstant::now() + Duration::from_secs(60))
+                    .await;
+                assert_eq!(queue.dispatch(&conn, NOW).unwrap(), 1);
+                queue
+                    .wait(&wakeup, TokioInstant::now() + Duration::from_secs(60))
+                    .await;
+                assert_eq!(queue.dispatch(&conn, NOW).unwrap(), 0);
+
+                // A wakeup ends the wait, even if given before waiting
+                wakeup.clone().wake();
+                timeout(
+                    Duration::from_secs(5),
+                    queue.wait(&wakeup, TokioInstant::now() + Duration::from_secs(60)),
+                )
+                .await
+                .unwrap();
+            })
+            .await;
+        assert_eq!(mock.paid_invoices().len(), 2);
+    }
+}
@@ -0,0 +1,160 @@
+use common_rs::db_pc as db;
+use common_rs::dto_pc::PayRequest;
+
+use chrono::DateTime;
+use dotenv;
+use rusqlite::Connection;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Default zap message template, see render_zap_message for the placeholders
+const DEFAULT_ZAP_MESSAGE_TEMPLATE: &str =
+    "ZapPool payout: {sats} sats for {shares} shares, {from} - {to}";
+
+/// Visibility of the zap request (NIP-57)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZapMode {
+    /// Signed by the pool, message visible
+    Public,
+    /// Signed by a random key, with "anon" tag
+    Anonymous,
+    /// Sender and message encrypted to the recipient, with "anon" tag
+    Private,
+}
+
+impl fmt::Display for ZapMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Public => "PUBLIC",
+            Self::Anonymous => "ANON",
+            Self::Private => "PRIVATE",
+        })
+    }
+}
+
+impl FromStr for ZapMode {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "PUBLIC" => Ok(Self::Public),
+            "ANON" => Ok(Self::Anonymous),
+            "PRIVATE" => Ok(Self::Private),
+            _ => Err(format!("Unknown zap mode {s}").into()),
+        }
+    }
+}
+
+/// Zap message template and mode, from config
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZapMessageConfig {
+    pub template: String,
+    pub mode: ZapMode,
+}
+
+impl ZapMessageConfig {
+    /// From config: ZAP_MESSAGE_TEMPLATE (empty for no message), ZAP_MODE (PUBLIC, ANON, PRIVATE)
+    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+        // Load environment variables from .env file
+        dotenv::dotenv().ok();
+
+        let template =
+            env::var("ZAP_MESSAGE_TEMPLATE").unwrap_or(DEFAULT_ZAP_MESSAGE_TEMPLATE.to_string());
+        let mode = match env::var("ZAP_MODE") {
+            Err(_) => ZapMode::Public,
+            Ok(s) => ZapMode::from_str(&s)?,
+        };
+        Ok(Self { template, mode })
+    }
+}
+
+impl Default for ZapMessageConfig {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_ZAP_MESSAGE_TEMPLATE.to_string(),
+            mode: ZapMode::Public,
+        }
+    }
+}
+
+/// The values a zap message is rendered from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ZapMessageValues {
+    pub amount_msat: u64,
+    pub shares: u32,
+    pub blocks: u32,
+    /// Start and end of the period paid for
+    pub from_time: u32,
+    pub to_time: u32,
+}
+
+fn format_date(time: u32) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Render a zap message template. Placeholders:
+/// {sats}, {msat}, {shares}, {blocks}, {from}, {to} (dates of the period paid for)
+pub fn render_zap_message(template: &str, values: &ZapMessageValues) -> String {
+    template
+        .replace("{sats}", &(values.amount_msat / 1000).to_string())
+        .replace("{msat}", &values.amount_msat.to_string())
+        .replace("{shares}", &values.shares.to_string())
+        .replace("{blocks}", &values.blocks.to_string())
+        .replace("{from}", &format_date(values.from_time))
+        .replace("{to}", &format_date(values.to_time))
+}
+
+/// Render the zap message for a pay request, with the work summary from the DB
+pub fn zap_message_for_payreq(
+    conn: &Connection,
+    pr: &PayRequest,
+    template: &str,
+) -> Result<String, Box<dyn Error>> {
+    let (shares, blocks, from_time) = db::payreq_get_work_summary(conn, pr)?;
+    let values = ZapMessageValues {
+        amount_msat: pr.req_amnt,
+        shares,
+        blocks,
+        from_time,
+        to_time: pr.req_time,
+    };
+    Ok(render_zap_message(template, &values))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_zap_message() {
+        let values = ZapMessageValues {
+            amount_msat: 21_500,
+            shares: 340,
+            blocks: 2,
+            from_time: 1_760_000_000,
+            to_time: 1_760_086_400,
+        };
+        assert_eq!(
+            render_zap_message(DEFAULT_ZAP_MESSAGE_TEMPLATE, &values),
+            "ZapPool payout: 21 sats for 340 shares, 2025-10-09 - 2025-10-10"
+        );
+        assert_eq!(
+            render_zap_message("{msat} msat, {blocks} blocks {unknown}", &values),
+            "21500 msat, 2 blocks {unknown}"
+        );
+        assert_eq!(render_zap_message("", &values), "");
+    }
+
+    #[test]
+    fn test_zap_mode_from_str() {
+        assert_eq!(ZapMode::from_str("anon").unwrap(), ZapMode::Anonymous);
+        assert_eq!(ZapMode::from_str("PRIVATE").unwrap(), ZapMode::Private);
+        assert_eq!(ZapMode::Public.to_string(), "PUBLIC");
+        assert!(ZapMode::from_str("secret").is_err());
+    }
+}
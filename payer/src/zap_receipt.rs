@@ -0,0 +1,386 @@
+use crate::nostr_profile::{
+    RELAY_QUERY_DEADLINE, get_events_from_relays, get_nostr_read_relays, npub_to_hex,
+};
+use crate::nostr_relays::recipient_relays;
+use crate::payer::save_payment;
+
+use common_rs::db_pc as db;
+use common_rs::error_codes::*;
+
+use futures_util::future::join_all;
+
+use nostr::Event;
+use nostr::util::JsonUtil;
+use rusqlite::Connection;
+use serde_json::{Value, json};
+
+use std::error::Error;
+use std::time::Duration;
+
+/// Period of checking the pending zap receipts, secs
+pub const ZAP_RECEIPT_CHECK_PERIOD: u32 = 60;
+/// A zap receipt not seen this long after the payment is considered missing, secs
+const ZAP_RECEIPT_TIMEOUT: u32 = 3600;
+/// Receipts are looked for from this long before the payment time, secs
+const ZAP_RECEIPT_SINCE_MARGIN: u32 = 600;
+
+/// Value of the first tag with the given name
+pub(crate) fn tag_value(event: &Value, name: &str) -> Option<String> {
+    event
+        .get("tags")?
+        .as_array()?
+        .iter()
+        .filter_map(|t| t.as_array())
+        .find(|t| t.len() >= 2 && t[0].as_str() == Some(name))
+        .and_then(|t| t[1].as_str())
+        .map(|s| s.to_string())
+}
+
+/// Check that the event is a valid zap receipt (kind 9735, NIP-57) for our zap:
+/// signed by the LNURL server (its nostrPubkey, if known), for the recipient, with our invoice,
+/// and with a zap request to the recipient as description.
+fn receipt_matches(event: &Value, rec_pubkey_hex: &str, invoice: &str, signer_hex: &str) -> bool {
+    let event_obj = match Event::from_json(event.to_string()) {
+        Err(_) => return false,
+        Ok(e) => e,
+    };
+    if event_obj.kind.as_u16() != 9735 || event_obj.verify().is_err() {
+        return false;
+    }
+    if !signer_hex.is_empty() && event_obj.pubkey.to_hex() != signer_hex {
+        return false;
+    }
+    if tag_value(event, "p").as_deref() != Some(rec_pubkey_hex) {
+        return false;
+    }
+    match tag_value(event, "bolt11") {
+        Some(b) if b.eq_ignore_ascii_case(invoice) => {}
+        _ => return false,
+    }
+    let zap_request: Value =
+        match tag_value(event, "description").and_then(|d| serde_json::from_str(&d).ok()) {
+            None => return false,
+            Some(z) => z,
+        };
+    zap_request["kind"] == json!(9734)
+        && tag_value(&zap_request, "p").as_deref() == Some(rec_pubkey_hex)
+}
+
+/// A paid zap invoice whose receipt is looked for
+struct ZapInvoice<'a> {
+    rec_pubkey_hex: String,
+    invoice: &'a str,
+    signer_hex: &'a str,
+    since: u32,
+}
+
+/// A relay set, with the zaps to check on it: index in the pending list, recipient pubkey hex
+type RelaySetZaps = (Vec<String>, Vec<(usize, String)>);
+
+/// Look for the zap receipts of paid zap invoices on one relay set, with a single query.
+/// Return the receipt event ID of each invoice, in order.
+async fn find_zap_receipts(
+    zaps: &[ZapInvoice<'_>],
+    relays: &[String],
+    deadline: Duration,
+) -> Vec<Option<String>> {
+    let mut rec_pubkeys: Vec<&str> = zaps.iter().map(|z| z.rec_pubkey_hex.as_str()).collect();
+    rec_pubkeys.sort();
+    rec_pubkeys.dedup();
+    let since = zaps.iter().map(|z| z.since).min().unwrap_or(0);
+    let filter = json!({
+        "kinds": [9735],
+        "#p": rec_pubkeys,
+        "since": since,
+    });
+    let events = get_events_from_relays(relays, &filter, deadline).await;
+    zaps.iter()
+        .map(|z| {
+            events
+                .iter()
+                .find(|(_relay, event)| {
+                    receipt_matches(event, &z.rec_pubkey_hex, z.invoice, z.signer_hex)
+                })
+                .and_then(|(relay, event)| {
+                    println!("Zap receipt found on relay {relay}, {}", event["id"]);
+                    event["id"].as_str().map(|s| s.to_string())
+                })
+        })
+        .collect()
+}
+
+/// Check the successful zaps with pending receipt: mark them confirmed if the receipt is found,
+/// or missing if not found within the timeout.
+/// The receipts are queried once per relay set, the relays of the recipients are looked up concurrently.
+pub async fn check_zap_receipts(
+    conn: &mut Connection,
+    relays: &[String],
+    now_utc: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut pending = db::payment_get_zap_rcpt_pending(conn)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    // The receipt should be published where the recipient reads
+    let mut recipients: Vec<String> = pending.iter().map(|(pr, _)| pr.pri_id.clone()).collect();
+    recipients.sort();
+    recipients.dedup();
+    let read_relays = join_all(
+        recipients
+            .iter()
+            .map(|npub| get_nostr_read_relays(npub, relays)),
+    )
+    .await;
+
+    // Group the zaps by the relay set of their recipient
+    let mut batches: Vec<RelaySetZaps> = Vec::new();
+    for (idx, (pr, paym)) in pending.iter().enumerate() {
+        let rec_pubkey_hex = match npub_to_hex(&pr.pri_id) {
+            Err(e) => {
+                println!(
+                    "ERROR: Could not check zap receipt, {} {}, {e}",
+                    paym.id, pr.pri_id
+                );
+                continue;
+            }
+            Ok(h) => h,
+        };
+        let rec_idx = recipients.binary_search(&pr.pri_id).unwrap_or_default();
+        let mut rcpt_relays = recipient_relays(relays, &read_relays[rec_idx]);
+        rcpt_relays.sort();
+        match batches.iter_mut().find(|(r, _)| *r == rcpt_relays) {
+            Some((_, zaps)) => zaps.push((idx, rec_pubkey_hex)),
+            None => batches.push((rcpt_relays, vec![(idx, rec_pubkey_hex)])),
+        }
+    }
+
+    for (rcpt_relays, batch) in batches {
+        let zaps: Vec<ZapInvoice> = batch
+            .iter()
+            .map(|(idx, rec_pubkey_hex)| {
+                let paym = &pending[*idx].1;
+                ZapInvoice {
+                    rec_pubkey_hex: rec_pubkey_hex.clone(),
+                    invoice: &paym.terti_id,
+                    signer_hex: &paym.zap_pubkey,
+                    since: paym.pay_time.saturating_sub(ZAP_RECEIPT_SINCE_MARGIN),
+                }
+            })
+            .collect();
+        let found = find_zap_receipts(&zaps, &rcpt_relays, RELAY_QUERY_DEADLINE).await;
+        for ((idx, _), rcpt_id) in batch.iter().zip(found) {
+            let (pr, paym) = &mut pending[*idx];
+            match rcpt_id {
+                Some(rcpt_id) => {
+                    paym.zap_rcpt_status = ZAP_RCPT_CONFIRMED;
+                    paym.zap_rcpt_id = rcpt_id;
+                    save_payment(conn, paym)?;
+                }
+                None => {
+                    if now_utc >= paym.pay_time + ZAP_RECEIPT_TIMEOUT {
+                        println!(
+                            "WARNING: No zap receipt published for zap {} {}, LN Address {}",
+                            paym.id, pr.pri_id, paym.secon_id
+                        );
+                        paym.zap_rcpt_status = ZAP_RCPT_MISSING;
+                        save_payment(conn, paym)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::PaymentMethod;
+    use crate::common::test_payment;
+    use crate::nostr_test_relay::{NostrRelayConfig, NostrTestRelay};
+    use crate::nostr_zap::npub_from_secret_vec;
+    use common_rs::dto_pc::{PayRequest, Payment};
+    use nostr::nips::nip57::ZapRequestData;
+    use nostr::{EventBuilder, Keys, PublicKey, SecretKey};
+
+    const NOW: u32 = 1_760_000_000;
+    const INVOICE: &str = "lnmock5000n1";
+
+    fn rec_secret() -> SecretKey {
+        SecretKey::from_slice(&[8u8; 32]).unwrap()
+    }
+
+    // Signer of the receipts, the LNURL server
+    fn server_pubkey_hex() -> String {
+        Keys::new(SecretKey::from_slice(&[9u8; 32]).unwrap())
+            .public_key()
+            .to_hex()
+    }
+
+    // A zap receipt, as published by the LNURL server
+    fn make_receipt(rec_pubkey: PublicKey, invoice: &str) -> Value {
+        let zap_req_data = ZapRequestData::new(rec_pubkey, Vec::new());
+        let zap_request = EventBuilder::public_zap_request(zap_req_data)
+            .sign_with_keys(&Keys::new(SecretKey::from_slice(&[7u8; 32]).unwrap()))
+            .unwrap();
+        let receipt = EventBuilder::zap_receipt(invoice, None::<String>, &zap_request)
+            .sign_with_keys(&Keys::new(SecretKey::from_slice(&[9u8; 32]).unwrap()))
+            .unwrap();
+        serde_json::from_str(&receipt.as_json()).unwrap()
+    }
+
+    fn create_test_db_with_zap(rec_npub: &str) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::db_setup_new(&conn).unwrap();
+        add_test_zap(&mut conn, rec_npub, INVOICE);
+        conn
+    }
+
+    fn add_test_zap(conn: &mut Connection, rec_npub: &str, invoice: &str) {
+        let mut pr = PayRequest::new(
+            0,
+            7,
+            5000,
+            PaymentMethod::PmNostrZap.to_string(),
+            rec_npub.into(),
+            NOW,
+            "".into(),
+        );
+        let conntx = conn.transaction().unwrap();
+        pr.id = db::payreq_insert_nocommit(&conntx, &pr).unwrap() as i32;
+        conntx.commit().unwrap();
+        let mut paym = Payment {
+            status: STATUS_SUCCESS_FINAL,
+            error_str: "OK".into(),
+            secon_id: "miner@example.com".into(),
+            terti_id: invoice.into(),
+            paid_amnt: 5000,
+            pay_time: NOW,
+            max_fee: 1000,
+            zap_rcpt_status: ZAP_RCPT_PENDING,
+            zap_pubkey: server_pubkey_hex(),
+            ..test_payment(pr.id, &pr.pay_method, NOW)
+        };
+        save_payment(conn, &mut paym).unwrap();
+    }
+
+    fn zap_rcpt_state(conn: &Connection) -> (u8, String) {
+        let payms = db::payment_get_all_after_time(conn, 0).unwrap();
+        (payms[0].1.zap_rcpt_status, payms[0].1.zap_rcpt_id.clone())
+    }
+
+    #[test]
+    fn test_receipt_matches() {
+        let rec_pubkey = Keys::new(rec_secret()).public_key();
+        let rec_hex = rec_pubkey.to_hex();
+        let receipt = make_receipt(rec_pubkey, INVOICE);
+        let signer = server_pubkey_hex();
+        assert!(receipt_matches(&receipt, &rec_hex, INVOICE, &signer));
+        assert!(receipt_matches(
+            &receipt,
+            &rec_hex,
+            &INVOICE.to_uppercase(),
+            &signer
+        ));
+        assert!(!receipt_matches(
+            &receipt,
+            &rec_hex,
+            "lnmock5000n2",
+            &signer
+        ));
+        let other_hex = Keys::new(SecretKey::from_slice(&[3u8; 32]).unwrap())
+            .public_key()
+            .to_hex();
+        assert!(!receipt_matches(&receipt, &other_hex, INVOICE, &signer));
+
+        // Signed by someone else than the LNURL server
+        assert!(!receipt_matches(&receipt, &rec_hex, INVOICE, &other_hex));
+        // Signer not known
+        assert!(receipt_matches(&receipt, &rec_hex, INVOICE, ""));
+
+        // Tampered
+        let mut tampered = receipt.clone();
+        tampered["created_at"] = json!(1);
+        assert!(!receipt_matches(&tampered, &rec_hex, INVOICE, &signer));
+    }
+
+    #[tokio::test]
+    async fn test_check_zap_receipts() {
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let rec_pubkey = Keys::new(rec_secret()).public_key();
+
+        // Not (yet) published
+        let relay = NostrTestRelay::start(NostrRelayConfig::default(), Vec::new()).await;
+        let mut conn = create_test_db_with_zap(&rec_npub);
+        check_zap_receipts(&mut conn, &[relay.url()], NOW + 10)
+            .await
+            .unwrap();
+        assert_eq!(zap_rcpt_state(&conn).0, ZAP_RCPT_PENDING);
+        check_zap_receipts(&mut conn, &[relay.url()], NOW + ZAP_RECEIPT_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(zap_rcpt_state(&conn).0, ZAP_RCPT_MISSING);
+
+        // Published, with an unrelated receipt
+        let receipt = make_receipt(rec_pubkey, INVOICE);
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![make_receipt(rec_pubkey, "lnmock5000n2"), receipt.clone()],
+        )
+        .await;
+        let mut conn = create_test_db_with_zap(&rec_npub);
+        check_zap_receipts(&mut conn, &[relay.url()], NOW + 10)
+            .await
+            .unwrap();
+        let (status, rcpt_id) = zap_rcpt_state(&conn);
+        assert_eq!(status, ZAP_RCPT_CONFIRMED);
+        assert_eq!(rcpt_id, receipt["id"].as_str().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_check_zap_receipts_batch() {
+        let rec_npub = npub_from_secret_vec(&[8u8; 32].to_vec()).unwrap();
+        let rec_pubkey = Keys::new(rec_secret()).public_key();
+        let rec2_secret = SecretKey::from_slice(&[6u8; 32]).unwrap();
+        let rec2_npub = npub_from_secret_vec(&[6u8; 32].to_vec()).unwrap();
+        let rec2_pubkey = Keys::new(rec2_secret).public_key();
+
+        // Receipts of zaps to two recipients, found with one query
+        let receipt = make_receipt(rec_pubkey, INVOICE);
+        let receipt2 = make_receipt(rec2_pubkey, "lnmock5000n2");
+        let relay = NostrTestRelay::start(
+            NostrRelayConfig::default(),
+            vec![receipt.clone(), receipt2.clone()],
+        )
+        .await;
+        let mut conn = create_test_db_with_zap(&rec_npub);
+        add_test_zap(&mut conn, &rec2_npub, "lnmock5000n2");
+        add_test_zap(&mut conn, &rec2_npub, "lnmock5000n3");
+        check_zap_receipts(&mut conn, &[relay.url()], NOW + 10)
+            .await
+            .unwrap();
+        let mut states: Vec<(String, u8, String)> = db::payment_get_all_after_time(&conn, 0)
+            .unwrap()
+            .into_iter()
+            .map(|(_, p)| (p.terti_id, p.zap_rcpt_status, p.zap_rcpt_id))
+            .collect();
+        states.sort();
+        assert_eq!(
+            states,
+            vec![
+                (
+                    INVOICE.to_string(),
+                    ZAP_RCPT_CONFIRMED,
+                    receipt["id"].as_str().unwrap().to_string()
+                ),
+                (
+                    "lnmock5000n2".to_string(),
+                    ZAP_RCPT_CONFIRMED,
+                    receipt2["id"].as_str().unwrap().to_string()
+                ),
+                ("lnmock5000n3".to_string(), ZAP_RCPT_PENDING, "".to_string()),
+            ]
+        );
+    }
+}